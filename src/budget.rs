@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::plan::Plan;
+use crate::utils;
+
+/// Number of the largest components listed when the image is over budget.
+const REPORTED_COMPONENTS: usize = 10;
+
+/// Size budgets of the image, so that size regressions fail the build (e.g. in
+/// CI) rather than being noticed once published. Sizes are of the content,
+/// i.e. before compression.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SizeBudgets {
+    /// Maximum size of the whole image.
+    pub image: Option<u64>,
+    /// Maximum size of any single layer.
+    pub layer: Option<u64>,
+}
+
+impl SizeBudgets {
+    pub fn is_empty(&self) -> bool {
+        self.image.is_none() && self.layer.is_none()
+    }
+
+    /// Check the layers of `plan` against the budgets, failing with a report
+    /// of the largest offending components. `component_sizes` are the sizes
+    /// of the components packed into the plan.
+    pub fn check(&self, plan: &Plan, component_sizes: &HashMap<String, u64>) -> Result<()> {
+        let mut problems = Vec::new();
+        let size_of = |name: &String| component_sizes.get(name).copied().unwrap_or(0);
+        // largest first, then by name for a stable report
+        let largest = |names: &mut Vec<&String>| {
+            names.sort_by(|a, b| size_of(b).cmp(&size_of(a)).then_with(|| a.cmp(b)));
+        };
+
+        if let Some(max) = self.image {
+            let size: u64 = plan.layers.iter().map(|l| l.size).sum();
+            if size > max {
+                let mut names: Vec<&String> =
+                    plan.layers.iter().flat_map(|l| &l.components).collect();
+                largest(&mut names);
+                let list: Vec<String> = names
+                    .iter()
+                    .take(REPORTED_COMPONENTS)
+                    .map(|name| format!("{:>10}  {name}", utils::format_size(size_of(name))))
+                    .collect();
+                problems.push(format!(
+                    "image is {}, more than --max-image-size of {}; largest components:\n    {}",
+                    utils::format_size(size),
+                    utils::format_size(max),
+                    list.join("\n    ")
+                ));
+            }
+        }
+
+        if let Some(max) = self.layer {
+            for layer in plan.layers.iter().filter(|l| l.size > max) {
+                let mut names: Vec<&String> = layer.components.iter().collect();
+                largest(&mut names);
+                let list: Vec<String> = names
+                    .iter()
+                    .take(REPORTED_COMPONENTS)
+                    .map(|name| format!("{name} ({})", utils::format_size(size_of(name))))
+                    .collect();
+                let more = names.len().saturating_sub(REPORTED_COMPONENTS);
+                problems.push(format!(
+                    "layer {} is {}, more than --max-layer-size of {}: {}{}",
+                    layer.id,
+                    utils::format_size(layer.size),
+                    utils::format_size(max),
+                    list.join(", "),
+                    if more > 0 {
+                        format!(" and {more} more")
+                    } else {
+                        String::new()
+                    }
+                ));
+            }
+        }
+
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "{} size budget(s) exceeded:\n  {}",
+                problems.len(),
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{ContentClass, PlanLayer};
+
+    #[test]
+    fn test_check() {
+        let layer = |components: &[&str], size| PlanLayer {
+            id: components[0].to_string(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+            size,
+            stability: 0.0,
+            content_class: ContentClass::Hot,
+            estimated_compressed_size: None,
+            digest: None,
+        };
+        let plan = Plan {
+            layers: vec![
+                layer(&["rpm/kernel", "rpm/firmware"], 300),
+                layer(&["rpm/bash"], 50),
+            ],
+            ..Default::default()
+        };
+        let sizes = HashMap::from([
+            ("rpm/kernel".to_string(), 100),
+            ("rpm/firmware".to_string(), 200),
+            ("rpm/bash".to_string(), 50),
+        ]);
+        let check = |image, layer| SizeBudgets { image, layer }.check(&plan, &sizes);
+
+        check(None, None).unwrap();
+        check(Some(350), Some(300)).unwrap();
+
+        let err = check(Some(349), None).unwrap_err().to_string();
+        assert!(err.starts_with("1 size budget(s) exceeded"), "{err}");
+        assert!(
+            err.contains("image is 350 B, more than --max-image-size of 349 B"),
+            "{err}"
+        );
+        // largest first
+        let firmware = err.find("rpm/firmware").unwrap();
+        assert!(firmware < err.find("rpm/kernel").unwrap(), "{err}");
+        assert!(
+            err.find("rpm/kernel").unwrap() < err.find("rpm/bash").unwrap(),
+            "{err}"
+        );
+
+        let err = check(Some(349), Some(100)).unwrap_err().to_string();
+        assert!(err.starts_with("2 size budget(s) exceeded"), "{err}");
+        assert!(
+            err.contains(
+                "layer rpm/kernel is 300 B, more than --max-layer-size of 100 B: rpm/firmware (200 B), rpm/kernel (100 B)"
+            ),
+            "{err}"
+        );
+    }
+}
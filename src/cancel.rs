@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Token for cooperatively cancelling a build.
+///
+/// Long-running operations check the token regularly and bail out with a
+/// [`Cancelled`] error once it's cancelled, unwinding normally so that
+/// partially written outputs and temporary files get cleaned up.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create a new token which isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation. Returns whether this token was already cancelled.
+    pub fn cancel(&self) -> bool {
+        self.0.swap(true, Ordering::Relaxed)
+    }
+
+    /// Whether cancellation was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Return a [`Cancelled`] error if cancellation was requested.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Error returned by operations that were cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether `err` was caused by a cancellation.
+pub fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| e.is::<Cancelled>())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(token.check().is_ok());
+        assert!(!clone.cancel());
+        assert!(clone.cancel());
+        assert!(token.is_cancelled());
+
+        let err = token.check().context("doing something").unwrap_err();
+        assert!(is_cancelled(&err));
+        assert!(!is_cancelled(&anyhow::anyhow!("something else")));
+    }
+}
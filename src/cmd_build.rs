@@ -1,20 +1,36 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::ambient_authority;
 use cap_std_ext::cap_std::fs::Dir;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use ocidir::oci_spec::image as oci_image;
 use serde::{Deserialize, Serialize};
 
-use crate::components::{Component, ComponentsRepos, FileMap};
-use crate::ocibuilder::{Builder, Compression};
-use crate::packing::{PackItem, calculate_packing};
-use crate::utils;
+use crate::budget::SizeBudgets;
+use crate::cancel::CancellationToken;
+use crate::collisions::PathCollisionPolicy;
+use crate::components::{ClaimRules, Component, FileMap, NAMING_SCHEME, ReposLoader};
+use crate::hardlinks::HardlinkPolicy;
+use crate::ocibuilder::{self, Builder, BuiltImage, Compression};
+use crate::owners::OwnerNames;
+use crate::packing::{PackItem, calculate_packing, calculate_seeded_packing, optimize_packing};
+use crate::plan::{ContentClass, Plan, PlanLayer};
+use crate::resume::BlobStaging;
+use crate::sandbox::Sandbox;
+use crate::scan::ScanErrorPolicy;
+use crate::security::SecurityReport;
+use crate::stubs::SpecialDirPolicy;
+use crate::symlinks::SymlinkPolicy;
+use crate::tar::Normalization;
+use crate::{collisions, dedup, hardlinks, registry, rewrite, security, stubs, symlinks, utils};
 
 /// Parsed output target for the built OCI image.
+#[derive(Debug)]
 enum OutputTarget {
     /// OCI archive to stdout.
     Stdout,
@@ -22,6 +38,109 @@ enum OutputTarget {
     OciArchive(Utf8PathBuf),
     /// OCI directory layout.
     OciDir(Utf8PathBuf),
+    /// Individual blob files and the image manifest.
+    Blobs(Utf8PathBuf),
+    /// Image manifest, config and layer file lists, without any blobs.
+    ChunkMap(Utf8PathBuf),
+}
+
+/// Categories of content that can be stripped from the image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StripKind {
+    /// Package documentation (/usr/share/doc)
+    Docs,
+    /// Man pages (/usr/share/man)
+    Man,
+    /// Info pages (/usr/share/info)
+    Info,
+    /// Message catalogs and other translations (/usr/share/locale)
+    Locales,
+    /// Separate debuginfo and debug sources (/usr/lib/debug, /usr/src/debug)
+    Debuginfo,
+}
+
+/// Default maximum number of layers to output.
+const DEFAULT_MAX_LAYERS: usize = 64;
+
+/// What to do when the image exceeds one of the [`LAYER_LIMITS`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum LayerLimitPolicy {
+    /// Don't check
+    Ignore,
+    /// Warn about each limit exceeded
+    #[default]
+    Warn,
+    /// Fail the build
+    Fail,
+}
+
+/// Paths pruned with `--live`: virtual filesystems, runtime and temporary
+/// state, logs and caches which keep changing while the system runs, the
+/// physical root and boot partition of ostree-based systems, user data, and the
+/// identity and secrets of the host. The directories themselves are kept, since
+/// packages own them.
+const LIVE_PRUNE_PATHS: &[&str] = &[
+    "/proc/",
+    "/sys/",
+    "/dev/",
+    "/run/",
+    "/tmp/",
+    "/var/tmp/",
+    "/var/run/",
+    "/var/lock/",
+    "/var/log/",
+    "/var/cache/",
+    "/var/lib/systemd/",
+    "/sysroot/",
+    "/boot/",
+    "/home/",
+    "/var/home/",
+    "/root/",
+    "/var/roothome/",
+    "/mnt/",
+    "/var/mnt/",
+    "/media/",
+    "/var/lib/containers/",
+    "/var/lib/NetworkManager/",
+    "/etc/NetworkManager/system-connections/",
+    "/etc/machine-id",
+    "/etc/ssh/ssh_host_dsa_key",
+    "/etc/ssh/ssh_host_dsa_key.pub",
+    "/etc/ssh/ssh_host_ecdsa_key",
+    "/etc/ssh/ssh_host_ecdsa_key.pub",
+    "/etc/ssh/ssh_host_ed25519_key",
+    "/etc/ssh/ssh_host_ed25519_key.pub",
+    "/etc/ssh/ssh_host_rsa_key",
+    "/etc/ssh/ssh_host_rsa_key.pub",
+];
+
+/// Directories whose content is moved to the companion image with
+/// `--debuginfo-image`: split debug info and the sources it refers to.
+const DEBUGINFO_DIRS: &[&str] = &["/usr/lib/debug", "/usr/src/debug"];
+
+/// Known limits on the number of layers of an image, and what breaks beyond
+/// them.
+const LAYER_LIMITS: &[(usize, &str)] = &[
+    (
+        127,
+        "runtimes passing overlay lower directories verbatim (e.g. Docker) fail to mount it",
+    ),
+    (500, "containers-storage refuses to store it"),
+];
+
+impl StripKind {
+    /// The directories whose contents are stripped for this kind. The
+    /// directories themselves are kept so that packages owning them still
+    /// verify.
+    fn prune_paths(self) -> &'static [&'static str] {
+        match self {
+            StripKind::Docs => &["/usr/share/doc/"],
+            StripKind::Man => &["/usr/share/man/"],
+            StripKind::Info => &["/usr/share/info/"],
+            StripKind::Locales => &["/usr/share/locale/"],
+            StripKind::Debuginfo => &["/usr/lib/debug/", "/usr/src/debug/"],
+        }
+    }
 }
 
 #[derive(Parser, Default)]
@@ -34,13 +153,97 @@ pub struct BuildArgs {
     ///
     /// Supports `oci:PATH` for OCI directory layout and `oci-archive:PATH` for
     /// OCI archive. If no prefix is given, defaults to `oci-archive`. If not
-    /// specified at all, the OCI archive is written to stdout.
-    #[arg(short, long, value_name = "[oci:|oci-archive:]PATH")]
-    output: Option<Utf8PathBuf>,
+    /// specified at all, the OCI archive is written to stdout. Additionally,
+    /// `blobs:PATH` writes the manifest and each blob as individual files, and
+    /// `chunkmap:PATH` only writes the manifest, config and the files of each
+    /// layer, without any blobs.
+    ///
+    /// Can be specified multiple times to write several images from a single
+    /// scan, e.g. with different `--max-layers`.
+    #[arg(short, long, value_name = "[oci:|oci-archive:|blobs:|chunkmap:]PATH")]
+    output: Vec<Utf8PathBuf>,
 
-    /// Maximum number of layers to output
-    #[arg(long, default_value_t = 64)]
-    max_layers: usize,
+    /// Maximum number of layers to output [default: 64]
+    ///
+    /// With multiple `--output`, can be specified once per output in the same
+    /// order. A single value applies to all outputs.
+    #[arg(long, value_name = "N")]
+    max_layers: Vec<usize>,
+
+    /// Keep components together as in the layers of a previous plan
+    ///
+    /// Accepts either a plan (as written by `--write-plan-to`) or the manifest
+    /// of an image chunked by rpm-ostree (e.g. from `skopeo inspect --raw`).
+    /// New components are packed into the remaining layers.
+    #[arg(long, value_name = "PATH", conflicts_with = "previous")]
+    seed_plan: Option<Utf8PathBuf>,
+
+    /// Keep layers stable with a previous build of the image in a registry
+    ///
+    /// Fetches only the manifest and config of the given image (e.g.
+    /// `docker://quay.io/org/img:latest`), not its layers. Components are kept
+    /// together as in its layers (as for --seed-plan), and layers identical to
+    /// its own are reported (as for --compare-to, unless that's also given).
+    /// This needs no artifacts of the previous build on the local machine.
+    /// Requires `skopeo`.
+    #[arg(long, value_name = "IMGREF")]
+    previous: Option<String>,
+
+    /// Spend up to this long optimizing the packing
+    ///
+    /// By default, components are packed using fast heuristics only. With this
+    /// option, the result is then refined for as long as that lowers the
+    /// expected update size, but at most for the given time, after which the
+    /// best packing found so far is used. The packing is only reproducible if
+    /// the optimization finishes in time.
+    #[arg(long, value_name = "SECONDS")]
+    packing_timeout: Option<u64>,
+
+    /// What to do with paths that differ only by case or unicode normalization
+    ///
+    /// Such paths extract incorrectly on case-insensitive or normalizing
+    /// filesystems. Collisions are reported with their owning components.
+    #[arg(long, value_name = "POLICY", default_value = "warn")]
+    path_collisions: PathCollisionPolicy,
+
+    /// What to do with hardlink groups whose links belong to different components
+    ///
+    /// Hardlinks can only be kept within a layer. `duplicate` writes the
+    /// content in each layer with links of the group, `merge` moves all the
+    /// links into the component of the group's first path, and `fail` fails
+    /// the build. The groups are reported either way.
+    #[arg(long, value_name = "POLICY", default_value = "duplicate")]
+    cross_component_hardlinks: HardlinkPolicy,
+
+    /// Fail if any path is nested more than this many levels deep
+    ///
+    /// Some extractors and filesystems fail on deeply nested paths, so this
+    /// catches them at build time rather than when the image is pulled.
+    /// E.g. `/usr/bin/ls` has a depth of 3.
+    #[arg(long, value_name = "N")]
+    max_path_depth: Option<usize>,
+
+    /// What to do if the image has more layers than known runtimes support
+    ///
+    /// Docker fails to mount images with more than 127 layers, and
+    /// containers-storage refuses images with more than 500 layers.
+    #[arg(long, value_name = "POLICY", default_value = "warn")]
+    layer_limits: LayerLimitPolicy,
+
+    /// Fail if the image is larger than this
+    ///
+    /// The size is that of the content, i.e. before compression. Units are
+    /// powers of 1024, e.g. `2G`. The largest components are reported, so
+    /// that size regressions can be caught in CI.
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    max_image_size: Option<u64>,
+
+    /// Fail if any layer is larger than this
+    ///
+    /// As for --max-image-size, but per layer; the components of each layer
+    /// over budget are reported.
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    max_layer_size: Option<u64>,
 
     /// Read image config from a JSON file
     ///
@@ -98,6 +301,26 @@ pub struct BuildArgs {
     #[arg(long, value_name = "LEVEL", default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
     compression_level: u32,
 
+    /// Override the layer compression for matching components
+    ///
+    /// Format: PATTERN=CODEC, where PATTERN is a glob matched against
+    /// component names (e.g. `bigfiles/*`) and CODEC is `none`, `gzip` (at
+    /// --compression-level) or `gzip-LEVEL`. The first matching rule applies;
+    /// for layers holding several components, a rule matching any of them
+    /// applies. Can be specified multiple times.
+    #[arg(long = "layer-compression", value_name = "PATTERN=CODEC")]
+    layer_compression: Vec<String>,
+
+    /// End each gzip layer with metadata about it
+    ///
+    /// The metadata (the components of the layer and its uncompressed size)
+    /// is written as JSON in the header of an empty gzip member at the end of
+    /// the blob, so that tooling can read it without decompressing the layer
+    /// or fetching the manifest. The uncompressed content of the layer is
+    /// unchanged. Uncompressed layers are unaffected.
+    #[arg(long)]
+    layer_metadata: bool,
+
     /// Target architecture for the output image
     ///
     /// If not provided, the architecture from the config is used if found, or
@@ -112,6 +335,44 @@ pub struct BuildArgs {
     #[arg(long)]
     skip_special_files: bool,
 
+    /// How to handle paths which vanish or can't be read during the scan
+    /// [default: abort, or warn with --live]
+    ///
+    /// With `warn`, such paths are left out of the image and listed at the end
+    /// of the scan. With `fail`, they're also listed, then the build fails.
+    /// With `abort`, the build aborts on the first one.
+    #[arg(long, value_name = "POLICY")]
+    scan_errors: Option<ScanErrorPolicy>,
+
+    /// Build from the root of a running system
+    ///
+    /// Stays on the filesystem of the rootfs, prunes virtual filesystems,
+    /// temporary and runtime state, logs, caches, user data and host identity
+    /// and secrets (see the README for the full list), skips special files and
+    /// leaves out paths which vanish during the scan. Prefer building from a
+    /// read-only snapshot of the system where possible.
+    #[arg(long)]
+    live: bool,
+
+    /// What to do with empty mount-point directories
+    ///
+    /// These are `/proc`, `/sys`, `/dev`, `/run` and `/boot/efi` when empty.
+    /// `keep` leaves them as plain directories, `drop` leaves them out of the
+    /// image, and `opaque` marks them opaque so that nothing from lower layers
+    /// shows through.
+    #[arg(long, value_name = "POLICY", default_value = "keep")]
+    mount_points: SpecialDirPolicy,
+
+    /// What to do with directories marked opaque by overlayfs
+    ///
+    /// These are directories with the `trusted.overlay.opaque` or
+    /// `user.overlay.opaque` xattr set, e.g. in a copy of an overlay upper
+    /// directory. `keep` leaves them as plain directories, `drop` leaves them
+    /// out of the image along with their content, and `opaque` marks them
+    /// opaque in the lowest layer containing anything under them.
+    #[arg(long, value_name = "POLICY", default_value = "keep")]
+    opaque_dirs: SpecialDirPolicy,
+
     /// Paths to exclude from the rootfs
     ///
     /// If a directory ends with `/`, its contents are excluded but not the
@@ -120,6 +381,140 @@ pub struct BuildArgs {
     #[arg(long = "prune", value_name = "PATH")]
     prune: Vec<Utf8PathBuf>,
 
+    /// Strip a category of content from the image
+    ///
+    /// This is a shorthand for pruning the directories holding that content,
+    /// for when minimizing the image in the same pass as splitting it. Can be
+    /// specified multiple times.
+    #[arg(long = "strip", value_name = "KIND")]
+    strip: Vec<StripKind>,
+
+    /// Only include components whose name matches this glob
+    ///
+    /// Patterns match full component names (e.g. `rpm/kernel*`), where `*`
+    /// also matches `/`. All other components are left out of the image. Can be
+    /// specified multiple times.
+    #[arg(long = "only-components", value_name = "GLOB")]
+    only_components: Vec<String>,
+
+    /// Leave out components whose name matches this glob
+    ///
+    /// Applied after `--only-components`. Can be specified multiple times.
+    #[arg(long = "skip-components", value_name = "GLOB")]
+    skip_components: Vec<String>,
+
+    /// Take components from a components database instead of detecting them
+    ///
+    /// For build systems which already know which component owns each file.
+    /// The file is JSON mapping component names to their files, e.g.
+    /// `{"components": {"app": {"files": ["/usr/bin/app"]}}}`, optionally with
+    /// a `stability`, `isolated` and `mtime_clamp` per component. The output
+    /// of `--write-manifest-to` can be used as is. Files not listed are
+    /// unclaimed.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["docs_layer", "pki_layer", "volatile_patterns"]
+    )]
+    components_db: Option<Utf8PathBuf>,
+
+    /// Split documentation into dedicated layers
+    ///
+    /// Content under /usr/share/doc, /usr/share/man and /usr/share/info is
+    /// split out of the packages owning it into separate `docs/*` components.
+    #[arg(long)]
+    docs_layer: bool,
+
+    /// Split CA certificates and trust stores into a dedicated layer
+    ///
+    /// Content under /etc/pki, /etc/ssl, /etc/ca-certificates, /usr/share/pki
+    /// and /usr/share/ca-certificates, including generated trust bundles, is
+    /// grouped into a `pki/trust` component.
+    #[arg(long)]
+    pki_layer: bool,
+
+    /// Also group paths matching PATTERN into the volatile state component
+    ///
+    /// Databases which change on nearly every compose (e.g. the hwdb or the
+    /// alternatives state) are grouped into an isolated `volatile/state`
+    /// component so that they don't invalidate the layers of their packages.
+    /// This adds to the built-in list. PATTERN is matched against absolute
+    /// paths, where `*` also matches `/`. Can be specified multiple times.
+    #[arg(long = "volatile", value_name = "PATTERN")]
+    volatile_patterns: Vec<String>,
+
+    /// Relocate a directory tree in the image
+    ///
+    /// Files under FROM are written under TO instead, and absolute symlinks
+    /// pointing into FROM are updated to match. Component assignment still
+    /// happens on the original paths. TO must not already exist. Can be
+    /// specified multiple times.
+    #[arg(long = "rewrite", value_name = "FROM=TO")]
+    rewrites: Vec<String>,
+
+    /// How to handle absolute symlinks and symlinks escaping the rootfs
+    ///
+    /// Such symlinks resolve differently depending on where the image is
+    /// deployed. With `rewrite`, they're rewritten to equivalent relative
+    /// targets, resolving them as if the rootfs was `/`.
+    #[arg(long, value_name = "POLICY", default_value = "ignore")]
+    absolute_symlinks: SymlinkPolicy,
+
+    /// Clamp the mtime of all files to this epoch
+    ///
+    /// By default, files are clamped per component (e.g. to the package build
+    /// time). This additionally clamps every component to the given epoch,
+    /// which maximizes layer reuse across builds of unrelated content.
+    #[arg(long, value_name = "EPOCH")]
+    clamp_mtime: Option<u64>,
+
+    /// Set the permission bits of all directories to 0755, keeping their
+    /// setuid, setgid and sticky bits
+    #[arg(long)]
+    normalize_dir_perms: bool,
+
+    /// Drop `user.*` extended attributes
+    ///
+    /// These are often leftovers from the build environment (e.g. file
+    /// managers or download tools) and needlessly vary between builds.
+    #[arg(long)]
+    drop_user_xattrs: bool,
+
+    /// Record user and group names in layer entries
+    ///
+    /// Entries always carry numeric uids and gids, which is what runtimes use.
+    /// With this option, they also carry the names from the passwd and group
+    /// files of the rootfs, for consumers which need them. Note that
+    /// extracting with e.g. GNU tar then maps names to the ids of the
+    /// extracting system unless it's given `--numeric-owner`.
+    #[arg(long)]
+    owner_names: bool,
+
+    /// Date layer history entries by the newest mtime of their files
+    ///
+    /// By default, history entries are dated by the mtime clamp of the layer's
+    /// components (e.g. the package build time). The mtimes are clamped
+    /// either way, so this stays reproducible.
+    #[arg(long)]
+    history_from_content: bool,
+
+    /// Store identical files within a layer only once, as hardlinks
+    ///
+    /// Only files with the same content, mode, ownership and xattrs are
+    /// linked. Use `chunkah stats` to see how much this would save.
+    #[arg(long)]
+    dedup_hardlinks: bool,
+
+    /// Preserve IMA/EVM signatures for appraisal
+    ///
+    /// `security.ima` and `security.evm` xattrs are always carried over, but
+    /// EVM signatures also cover the SELinux label and file metadata. With
+    /// this, the SELinux label of files with an EVM signature is kept, and
+    /// their metadata is exempt from normalization (e.g.
+    /// --normalize-dir-perms).
+    #[arg(long)]
+    preserve_ima: bool,
+
     /// Tag to apply to the image
     ///
     /// Sets the org.opencontainers.image.ref.name annotation on the manifest
@@ -128,7 +523,92 @@ pub struct BuildArgs {
     #[arg(short = 't', long, value_name = "NAME")]
     tag: Option<String>,
 
-    /// Number of threads for parallel layer writing (0 = auto-detect)
+    /// Also output a single-layer variant of the image with the given tag
+    ///
+    /// The squashed image is added as a second manifest in the output,
+    /// built from the same scan and with the same config. This is useful for
+    /// consumers that prefer a single blob. Does not apply to blobs output.
+    #[arg(long, value_name = "TAG")]
+    also_squashed: Option<String>,
+
+    /// Move debug info and sources to a companion image with the given tag
+    ///
+    /// Files under /usr/lib/debug and /usr/src/debug are left out of the
+    /// image, and instead added as an extra layer on top of its layers in a
+    /// companion image, output as another manifest. Clients pulling the image
+    /// then don't download them. Not supported with blobs or chunkmap output.
+    #[arg(long, value_name = "TAG")]
+    debuginfo_image: Option<String>,
+
+    /// Attach the packing plan to the image as an OCI artifact
+    ///
+    /// The plan is stored as a separate manifest in index.json whose subject
+    /// is the image manifest, so that it's discoverable via the referrers API
+    /// once pushed to a registry. Combine with `--tag` so the image can still
+    /// be selected unambiguously.
+    #[arg(long)]
+    attach_plan: bool,
+
+    /// Write layers to the OCI archive as soon as each one is ready
+    ///
+    /// This overlaps writing the archive with building the remaining layers.
+    /// Layers then appear in the archive in completion order, so the archive
+    /// itself is not reproducible, though the image it contains is. Only
+    /// applies to OCI archive output.
+    #[arg(long)]
+    stream_layers: bool,
+
+    /// Keep the partial output of a failed build, and resume from it
+    ///
+    /// The image is built in a `.NAME.partial` directory next to the output,
+    /// which is kept if the build fails or is interrupted. Running the same
+    /// build again then reuses the layers completed in it rather than writing
+    /// them all again. Only applies to OCI directory and blobs output.
+    #[arg(long)]
+    resume: bool,
+
+    /// Stage layer blobs in this directory, and reuse them across builds
+    ///
+    /// Layer blobs are kept in the directory, named by digest, along with a
+    /// fingerprint of the files and settings they were written from. Later
+    /// builds (e.g. after a crash) skip writing the layers found there whose
+    /// inputs are unchanged, once their blob is checked against its digest.
+    /// Applies to all outputs, and the directory can be shared by concurrent
+    /// builds. It's never cleaned up by chunkah.
+    #[arg(long, value_name = "PATH")]
+    staging_dir: Option<Utf8PathBuf>,
+
+    /// Report layers reused from a published image
+    ///
+    /// After the build, reports which layers are identical to layers of the
+    /// given image (e.g. `docker://quay.io/org/img:stable`) and the share of
+    /// bytes they account for. Layers are compared by their uncompressed
+    /// digest, so compression settings don't matter. Requires `skopeo`.
+    #[arg(long, value_name = "IMGREF")]
+    compare_to: Option<String>,
+
+    /// Write the packing plan JSON to a file
+    #[arg(long, value_name = "PATH")]
+    write_plan_to: Option<Utf8PathBuf>,
+
+    /// Write a JSON report of security-relevant files to a file
+    ///
+    /// Lists the setuid, setgid and world-writable files and those with file
+    /// capabilities, grouped by layer and component, for security review.
+    /// World-writable files are also warned about.
+    #[arg(long, value_name = "PATH")]
+    security_report: Option<Utf8PathBuf>,
+
+    /// Restrict filesystem access during the build
+    ///
+    /// Uses Landlock so that after setup, chunkah can only read the rootfs
+    /// and host system directories, and only write to the directories of
+    /// the output paths, the temporary directory and the --staging-dir.
+    /// Requires Linux 5.19 or later.
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Number of threads for parallel scanning and layer writing (0 = auto-detect)
     #[arg(short = 'T', long, default_value_t = 0, env = "CHUNKAH_THREADS")]
     threads: usize,
 
@@ -142,6 +622,69 @@ pub struct BuildArgs {
 }
 
 impl BuildArgs {
+    /// The number of threads to use, auto-detecting if unset.
+    fn threads(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.threads).unwrap_or_else(|| {
+            match std::thread::available_parallelism() {
+                Ok(n) => n,
+                Err(e) => {
+                    tracing::warn!(err = %e, "failed to detect available parallelism, defaulting to 1");
+                    NonZeroUsize::MIN
+                }
+            }
+        })
+    }
+
+    /// The maximum number of layers of the `index`th output.
+    pub fn max_layers(&self, index: usize) -> usize {
+        match self.max_layers.as_slice() {
+            [] => DEFAULT_MAX_LAYERS,
+            [max_layers] => *max_layers,
+            all => all[index],
+        }
+    }
+
+    /// The target architecture, resolved as for the build.
+    pub fn arch(&self) -> Result<String> {
+        let parsed = load_config(self)?;
+        Ok(target_arch(self, parsed.architecture.as_deref()).to_string())
+    }
+
+    /// The gzip compression level to use.
+    pub fn compression_level(&self) -> u32 {
+        self.compression_level
+    }
+
+    /// The rootfs to build from.
+    pub fn rootfs(&self) -> &Utf8Path {
+        &self.rootfs
+    }
+
+    /// Whether IMA/EVM signatures are preserved for appraisal.
+    pub fn preserve_ima(&self) -> bool {
+        self.preserve_ima
+    }
+
+    /// Whether `user.*` xattrs are dropped from layers.
+    pub fn drop_user_xattrs(&self) -> bool {
+        self.drop_user_xattrs
+    }
+
+    /// The metadata normalization applied to the layers of `rootfs`.
+    pub fn normalization(&self, rootfs: &Dir) -> Result<Normalization> {
+        let owner_names = self
+            .owner_names
+            .then(|| OwnerNames::load(rootfs).context("loading owner names"))
+            .transpose()?
+            .map(Arc::new);
+        Ok(Normalization {
+            dir_perms: self.normalize_dir_perms,
+            drop_user_xattrs: self.drop_user_xattrs,
+            preserve_ima: self.preserve_ima,
+            owner_names,
+        })
+    }
+
     /// Apply CLI overrides to an OCI config, returning a new config.
     fn apply_to_config(&self, config: oci_image::Config) -> Result<oci_image::Config> {
         let mut builder = oci_image::ConfigBuilder::default();
@@ -178,49 +721,102 @@ impl BuildArgs {
 
         builder.build().context("building config")
     }
-}
 
-pub fn run(args: &BuildArgs) -> Result<()> {
-    let output_target = parse_output_target(args.output.as_deref())?;
+    /// Returns the paths to prune from the rootfs, including those implied by
+    /// `--strip`.
+    fn prune_paths(&self) -> Vec<Utf8PathBuf> {
+        let stripped = self
+            .strip
+            .iter()
+            .flat_map(|kind| kind.prune_paths())
+            .map(Utf8PathBuf::from);
+        let live: &[&str] = if self.live { LIVE_PRUNE_PATHS } else { &[] };
+        self.prune
+            .iter()
+            .cloned()
+            .chain(stripped)
+            .chain(live.iter().map(Utf8PathBuf::from))
+            .collect()
+    }
 
-    tracing::info!(rootfs = %args.rootfs, "starting build");
+    fn scan_errors(&self) -> ScanErrorPolicy {
+        match self.scan_errors {
+            Some(policy) => policy,
+            // files come and go on a running system
+            None if self.live => ScanErrorPolicy::Warn,
+            None => ScanErrorPolicy::default(),
+        }
+    }
+}
 
-    const CONTAINERS_STORAGE_LAYER_LIMIT: usize = 500;
-    if args.max_layers > CONTAINERS_STORAGE_LAYER_LIMIT {
-        tracing::warn!(
-            max_layers = args.max_layers,
-            limit = CONTAINERS_STORAGE_LAYER_LIMIT,
-            "image exceeds known containers-storage layer limit"
+pub fn run(args: &BuildArgs, cancellation: &CancellationToken) -> Result<()> {
+    let output_targets = parse_output_targets(&args.output)?;
+    anyhow::ensure!(
+        args.max_layers.len() <= 1 || args.max_layers.len() == output_targets.len(),
+        "--max-layers must be specified once, or once per --output"
+    );
+    anyhow::ensure!(
+        output_targets.len() == 1 || args.write_plan_to.is_none(),
+        "--write-plan-to is not supported with multiple outputs"
+    );
+    anyhow::ensure!(
+        output_targets.len() == 1 || args.security_report.is_none(),
+        "--security-report is not supported with multiple outputs"
+    );
+    if output_targets
+        .iter()
+        .any(|t| matches!(t, OutputTarget::Blobs(_) | OutputTarget::ChunkMap(_)))
+    {
+        // there's no index to hold the artifact manifest
+        anyhow::ensure!(
+            !args.attach_plan,
+            "--attach-plan is not supported with blobs or chunkmap output; use --write-plan-to"
+        );
+        anyhow::ensure!(
+            args.also_squashed.is_none(),
+            "--also-squashed is not supported with blobs or chunkmap output"
+        );
+        anyhow::ensure!(
+            args.debuginfo_image.is_none(),
+            "--debuginfo-image is not supported with blobs or chunkmap output"
+        );
+    }
+    if let Some(squashed_tag) = &args.also_squashed {
+        anyhow::ensure!(
+            args.tag.as_ref() != Some(squashed_tag),
+            "--also-squashed tag must differ from --tag"
+        );
+    }
+    if let Some(debuginfo_tag) = &args.debuginfo_image {
+        anyhow::ensure!(
+            args.tag.as_ref() != Some(debuginfo_tag)
+                && args.also_squashed.as_ref() != Some(debuginfo_tag),
+            "--debuginfo-image tag must differ from --tag and --also-squashed"
         );
     }
 
-    // load base config from file, string, or use empty default
-    let parsed = if let Some(path) = &args.config {
-        tracing::debug!(source = %path, "loading config from file");
-        let content = std::fs::read_to_string(path)
-            .with_context(|| format!("failed to read config file: {}", path))?;
-        parse_config(&content).with_context(|| format!("failed to parse config file: {}", path))?
-    } else if let Some(config_str) = &args.config_str {
-        tracing::debug!("loading config from string");
-        parse_config(config_str).context("failed to parse config string")?
-    } else {
-        tracing::debug!("using default config");
-        ParsedConfig {
-            config: oci_image::Config::default(),
-            annotations: HashMap::new(),
-            architecture: None,
-            created: None,
-        }
-    };
+    tracing::info!(rootfs = %args.rootfs, "starting build");
 
+    let parsed = load_config(args)?;
     let created_epoch = resolve_created_epoch(args.source_date_epoch, &parsed)?;
 
-    let architecture = args.arch.as_deref().or(parsed.architecture.as_deref());
-    // get the current arch if not provided, but even if provided, this
-    // normalizes the arch so that `--arch x86_64` also works
-    let architecture = utils::get_goarch(architecture);
+    let rewrite_rules = rewrite::parse_rewrite_rules(&args.rewrites)?;
+    let compression_rules =
+        ocibuilder::parse_compression_rules(&args.layer_compression, args.compression_level)?;
+
+    let architecture = target_arch(args, parsed.architecture.as_deref());
     tracing::debug!(architecture = architecture, "target architecture");
 
+    // fetch these upfront so that a bad reference fails before the build
+    let seed = load_seed_plan(args, architecture)?;
+    let compare_to_ref = args.compare_to.as_ref().or(args.previous.as_ref());
+    let compare_to = compare_to_ref
+        .map(|imgref| {
+            registry::fetch_config(imgref, architecture)
+                .with_context(|| format!("fetching config of {imgref}"))
+        })
+        .transpose()?;
+
     // merge config and CLI annotations
     let annotations = parse_key_value_pairs(&args.annotations, parsed.annotations)
         .context("parsing annotations")?;
@@ -230,28 +826,282 @@ pub fn run(args: &BuildArgs) -> Result<()> {
 
     let rootfs = Dir::open_ambient_dir(args.rootfs.as_std_path(), ambient_authority())
         .with_context(|| format!("opening rootfs {}", args.rootfs))?;
+    // this creates the directory, which the sandbox needs
+    let blob_staging = args
+        .staging_dir
+        .as_deref()
+        .map(BlobStaging::open)
+        .transpose()?;
+
+    if args.sandbox {
+        apply_sandbox(args, &output_targets).context("setting up sandbox")?;
+    }
+
+    // scan and assign components once; only packing and building is per output
+    let mut components = scan_components(
+        args,
+        &rootfs,
+        created_epoch,
+        &rewrite_rules,
+        None,
+        cancellation,
+    )?;
+    let mut debuginfo = args
+        .debuginfo_image
+        .as_ref()
+        .map(|tag| (tag, split_debuginfo(&mut components)));
+    if debuginfo
+        .as_ref()
+        .is_some_and(|(_, debuginfo)| debuginfo.files.is_empty())
+    {
+        tracing::warn!("no debug info or sources found; not adding a debuginfo image");
+        debuginfo = None;
+    }
 
-    let files = crate::scan::Scanner::new(&rootfs)
-        .skip_special_files(args.skip_special_files)
-        .prune(&args.prune)?
+    let compression = if args.compressed {
+        Compression::Gzip(args.compression_level)
+    } else {
+        Compression::None
+    };
+    let threads = args.threads();
+    let normalization = args.normalization(&rootfs)?;
+
+    let output_count = output_targets.len();
+    for (i, output_target) in output_targets.into_iter().enumerate() {
+        // the last output can have the components rather than a copy
+        let components = if i + 1 < output_count {
+            components.clone()
+        } else {
+            std::mem::take(&mut components)
+        };
+        // packing merges components, so flag files by component beforehand
+        let flagged = security::flag_components(&components);
+        let (mut components, mut plan) = pack(args, args.max_layers(i), seed.as_ref(), components)?;
+        let security_report = SecurityReport::new(flagged, &plan);
+        security_report.log();
+        if let Some(path) = &args.security_report {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("creating security report {path}"))?;
+            serde_json::to_writer_pretty(file, &security_report)
+                .with_context(|| format!("writing security report to {path}"))?;
+        }
+        if args.dedup_hardlinks {
+            dedup_layers(&rootfs, &mut components, cancellation)?;
+        }
+
+        // build the OCI image
+        let mut builder = Builder::new(&rootfs, components)
+            .context("creating builder")?
+            .compression(compression)
+            .compression_rules(compression_rules.clone())
+            .threads(threads)
+            .cancellation(cancellation.clone())
+            .normalization(normalization.clone())
+            .annotations(annotations.clone())
+            .history_from_content(args.history_from_content)
+            .layer_metadata(args.layer_metadata)
+            .config(image_config.clone());
+        if let Some(tag) = &args.tag {
+            builder = builder.tag(tag.clone());
+        }
+        builder = builder.layer_ids(plan.layers.iter().map(|l| l.id.clone()).collect());
+        if args.attach_plan {
+            builder = builder.plan(plan.clone());
+        }
+        if let Some(tag) = &args.also_squashed {
+            builder = builder.squashed(tag.clone());
+        }
+        if let Some((tag, debuginfo)) = &debuginfo {
+            builder = builder.debuginfo(tag.to_string(), debuginfo.clone());
+        }
+        if args.stream_layers {
+            if matches!(
+                output_target,
+                OutputTarget::OciArchive(_) | OutputTarget::Stdout
+            ) {
+                builder = builder.stream_layers(true);
+            } else {
+                tracing::warn!("--stream-layers only applies to OCI archive output; ignoring");
+            }
+        }
+        if args.resume {
+            if matches!(
+                output_target,
+                OutputTarget::OciDir(_) | OutputTarget::Blobs(_) | OutputTarget::ChunkMap(_)
+            ) {
+                builder = builder.resume(true);
+            } else {
+                tracing::warn!(
+                    "--resume only applies to OCI directory, blobs and chunkmap output; ignoring"
+                );
+            }
+        }
+        if let Some(staging) = &blob_staging {
+            builder = builder.blob_staging(staging.try_clone()?);
+        }
+
+        let image = match output_target {
+            OutputTarget::OciDir(ref path) => {
+                // no logging needed here; build_to_oci_dir already logs
+                builder.build_to_oci_dir(path)?
+            }
+            OutputTarget::Blobs(ref path) => builder.build_to_blobs_dir(path)?,
+            OutputTarget::ChunkMap(ref path) => builder.build_to_chunk_map(path)?,
+            OutputTarget::OciArchive(ref path) => {
+                tracing::info!(output = %path, "writing to file");
+                let mut file = std::fs::File::create(path)
+                    .with_context(|| format!("creating output file {}", path))?;
+                match builder.build_to_oci_archive(&mut file) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        // don't leave a truncated archive behind for later stages to consume
+                        drop(file);
+                        if let Err(rm_err) = std::fs::remove_file(path) {
+                            tracing::warn!(output = %path, err = %rm_err, "failed to remove partial output");
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            OutputTarget::Stdout => {
+                tracing::info!("writing to stdout");
+                builder.build_to_oci_archive(&mut std::io::stdout().lock())?
+            }
+        };
+
+        if let Some(path) = &args.write_plan_to {
+            plan.record_digests(&image.manifest);
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("creating plan file {path}"))?;
+            serde_json::to_writer_pretty(file, &plan)
+                .with_context(|| format!("writing plan to {path}"))?;
+        }
+
+        if let (Some(imgref), Some(reference)) = (compare_to_ref, &compare_to) {
+            let reuse = compute_layer_reuse(&image, reference);
+            let percent = if reuse.size > 0 {
+                reuse.reused_size as f64 * 100.0 / reuse.size as f64
+            } else {
+                0.0
+            };
+            tracing::info!(
+                reference = %imgref,
+                layers = format!("{}/{}", reuse.reused_layers, reuse.layers),
+                size = format!(
+                    "{} of {} ({percent:.1}%)",
+                    utils::format_size(reuse.reused_size),
+                    utils::format_size(reuse.size)
+                ),
+                "layers reused"
+            );
+        }
+    }
+
+    if let Some(path) = &args.write_peak_mem_to {
+        let peak_rss = utils::get_peak_rss().context("getting peak memory usage")?;
+        std::fs::write(path, format!("{peak_rss}\n"))
+            .with_context(|| format!("writing peak memory to {path}"))?;
+    }
+
+    tracing::info!("build complete");
+    Ok(())
+}
+
+/// The architecture given with `--arch`, or else `config_arch` (that of the
+/// base config), or else the current one.
+fn target_arch<'a>(args: &'a BuildArgs, config_arch: Option<&'a str>) -> &'a str {
+    let architecture = args.arch.as_deref().or(config_arch);
+    // get the current arch if not provided, but even if provided, this
+    // normalizes the arch so that `--arch x86_64` also works
+    utils::get_goarch(architecture)
+}
+
+/// Load the base config from file, string, or use an empty default.
+fn load_config(args: &BuildArgs) -> Result<ParsedConfig> {
+    if let Some(path) = &args.config {
+        tracing::debug!(source = %path, "loading config from file");
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file: {}", path))?;
+        parse_config(&content).with_context(|| format!("failed to parse config file: {}", path))
+    } else if let Some(config_str) = &args.config_str {
+        tracing::debug!("loading config from string");
+        parse_config(config_str).context("failed to parse config string")
+    } else {
+        tracing::debug!("using default config");
+        Ok(ParsedConfig {
+            config: oci_image::Config::default(),
+            annotations: HashMap::new(),
+            architecture: None,
+            created: None,
+        })
+    }
+}
+
+/// Scan the rootfs and assign files to components.
+fn scan_components(
+    args: &BuildArgs,
+    rootfs: &Dir,
+    created_epoch: u64,
+    rewrite_rules: &[rewrite::RewriteRule],
+    claim_rules: Option<&mut ClaimRules>,
+    cancellation: &CancellationToken,
+) -> Result<HashMap<String, Component>> {
+    if !args.live && args.rootfs.canonicalize_utf8().is_ok_and(|p| p == "/") {
+        tracing::warn!("building from / without --live; runtime state will end up in the image");
+    }
+    let mut files = crate::scan::Scanner::new(rootfs)
+        .cancellation(cancellation.clone())
+        .threads(args.threads())
+        .preserve_ima(args.preserve_ima)
+        .skip_special_files(args.skip_special_files || args.live)
+        .one_file_system(args.live)
+        .scan_errors(args.scan_errors())
+        .prune(&args.prune_paths())?
         .scan()
         .with_context(|| format!("scanning {} for files", args.rootfs))?;
     let total_size: u64 = files.values().map(|f| f.size).sum();
     tracing::info!(files = files.len(), size = %utils::format_size(total_size), "scan complete");
 
     warn_ostree_sysroot(&files);
-
-    let repos =
-        ComponentsRepos::load(&rootfs, &files, created_epoch).context("loading components")?;
+    stubs::apply_special_dir_policies(&mut files, args.mount_points, args.opaque_dirs);
+
+    let repos = ReposLoader::new(rootfs, &files, created_epoch)
+        .docs_layer(args.docs_layer)
+        .pki_layer(args.pki_layer)
+        .volatile_patterns(args.volatile_patterns.clone())
+        .components_db(args.components_db.clone())
+        .load()
+        .context("loading components")?;
     if repos.is_empty() {
         anyhow::bail!("no supported component repo found in rootfs");
     }
 
-    let components = repos
-        .into_components(&rootfs, files)
+    let mut components = repos
+        .into_components_with_rules(rootfs, files, claim_rules)
         .context("assigning components")?;
     tracing::info!(components = components.len(), "components assigned");
 
+    rewrite::apply_rewrites(rootfs, rewrite_rules, &mut components).context("rewriting paths")?;
+    symlinks::apply_symlink_policy(rootfs, args.absolute_symlinks, &mut components)
+        .context("checking symlinks")?;
+    select_components(
+        &mut components,
+        &args.only_components,
+        &args.skip_components,
+    )?;
+    hardlinks::apply_hardlink_policy(&mut components, args.cross_component_hardlinks)
+        .context("checking hardlinks")?;
+    collisions::check_path_collisions(&components, args.path_collisions)?;
+    if let Some(max_depth) = args.max_path_depth {
+        check_path_depth(&components, max_depth)?;
+    }
+
+    if let Some(epoch) = args.clamp_mtime {
+        for component in components.values_mut() {
+            component.mtime_clamp = component.mtime_clamp.min(epoch);
+        }
+    }
+
     // write the component manifest before packing merges components
     if let Some(path) = &args.write_manifest_to {
         let file = std::fs::File::create(path)
@@ -259,64 +1109,384 @@ pub fn run(args: &BuildArgs) -> Result<()> {
         write_manifest(&components, file).with_context(|| format!("writing manifest to {path}"))?;
     }
 
-    // pack components down to max layers
-    let components = pack_components(args.max_layers, components).context("packing components")?;
-    tracing::info!(layers = components.len(), "packing complete");
+    Ok(components)
+}
 
-    // build the OCI image
-    let compression = if args.compressed {
-        Compression::Gzip(args.compression_level)
+/// Load the plan to seed packing with, from `--seed-plan` or the manifest of
+/// the `--previous` image for `architecture`.
+pub fn load_seed_plan(args: &BuildArgs, architecture: &str) -> Result<Option<Plan>> {
+    if let Some(path) = &args.seed_plan {
+        let json =
+            std::fs::read_to_string(path).with_context(|| format!("reading seed plan {path}"))?;
+        let plan = Plan::from_plan_or_manifest(&json)
+            .with_context(|| format!("parsing seed plan {path}"))?;
+        return Ok(Some(plan));
+    }
+    if let Some(imgref) = &args.previous {
+        let manifest = registry::fetch_manifest(imgref, architecture)
+            .with_context(|| format!("fetching manifest of {imgref}"))?;
+        let plan = Plan::from_manifest(&manifest)
+            .with_context(|| format!("reading layout of {imgref}"))?;
+        tracing::debug!(previous = %imgref, layers = plan.layers.len(), "loaded previous layout");
+        return Ok(Some(plan));
+    }
+    Ok(None)
+}
+
+/// Pack components down to `max_layers` layers, keeping together those in
+/// the same layer of `seed`.
+pub fn pack(
+    args: &BuildArgs,
+    max_layers: usize,
+    seed: Option<&Plan>,
+    components: HashMap<String, Component>,
+) -> Result<(Vec<(String, Component)>, Plan)> {
+    let deadline = args
+        .packing_timeout
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let budgets = SizeBudgets {
+        image: args.max_image_size,
+        layer: args.max_layer_size,
+    };
+    // packing merges components, so record their sizes for the budget report
+    let component_sizes: HashMap<String, u64> = if budgets.is_empty() {
+        HashMap::new()
     } else {
-        Compression::None
+        components
+            .iter()
+            .map(|(name, c)| (name.clone(), c.files.values().map(|f| f.size).sum()))
+            .collect()
     };
+    let (mut components, mut plan) =
+        pack_components(max_layers, seed, deadline, components).context("packing components")?;
+    stubs::place_opaque_markers(&mut components);
+    plan.mount_points = args.mount_points;
+    plan.opaque_dirs = args.opaque_dirs;
+    tracing::info!(max_layers, layers = components.len(), "packing complete");
+    check_layer_limits(components.len(), args.layer_limits)?;
+    budgets.check(&plan, &component_sizes)?;
+
+    Ok((components, plan))
+}
+
+/// Turn identical files within each layer into hardlinks.
+fn dedup_layers(
+    rootfs: &Dir,
+    components: &mut [(String, Component)],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let (mut linked, mut saved) = (0, 0);
+    for (name, component) in components {
+        let (n, size) = dedup::link_duplicates(rootfs, &mut component.files, cancellation)
+            .with_context(|| format!("deduplicating {name}"))?;
+        linked += n;
+        saved += size;
+    }
+    tracing::info!(files = linked, saved = %utils::format_size(saved), "deduplicated layers");
+    Ok(())
+}
 
-    let threads = NonZeroUsize::new(args.threads).unwrap_or_else(|| {
-        match std::thread::available_parallelism() {
-            Ok(n) => n,
-            Err(e) => {
-                tracing::warn!(err = %e, "failed to detect available parallelism, defaulting to 1");
-                NonZeroUsize::MIN
+/// Move the debug info and sources of `components`, i.e. what's under
+/// [`DEBUGINFO_DIRS`], out of them and into a single component. Components
+/// left empty are dropped.
+fn split_debuginfo(components: &mut HashMap<String, Component>) -> Component {
+    let mut debuginfo = Component {
+        mtime_clamp: 0,
+        stability: 0.0,
+        isolated: false,
+        files: FileMap::new(),
+    };
+    for component in components.values_mut() {
+        let is_debuginfo = |path: &Utf8Path| {
+            DEBUGINFO_DIRS
+                .iter()
+                .any(|dir| path.starts_with(dir) && path != *dir)
+        };
+        if !component.files.keys().any(|path| is_debuginfo(path)) {
+            continue;
+        }
+        // like when packing merges components, use the latest clamp
+        debuginfo.mtime_clamp = debuginfo.mtime_clamp.max(component.mtime_clamp);
+        for (path, info) in std::mem::take(&mut component.files) {
+            if is_debuginfo(&path) {
+                debuginfo.files.insert(path, info);
+            } else {
+                component.files.insert(path, info);
             }
         }
-    });
+    }
+    let (emptied, kept): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(components)
+        .into_iter()
+        .partition(|(_, component)| component.files.is_empty());
+    for name in emptied.keys() {
+        tracing::debug!(component = %name, "component only had debuginfo");
+    }
+    *components = kept;
+    let size: u64 = debuginfo.files.values().map(|f| f.size).sum();
+    tracing::info!(files = debuginfo.files.len(), size = %utils::format_size(size), "split out debuginfo");
+    debuginfo
+}
 
-    let mut builder = Builder::new(&rootfs, components)
-        .context("creating builder")?
-        .compression(compression)
-        .threads(threads)
-        .annotations(annotations)
-        .config(image_config);
-    if let Some(tag) = &args.tag {
-        builder = builder.tag(tag.clone());
+/// Drop the components not matching any of the `only` globs (if any), then
+/// those matching any of the `skip` globs.
+fn select_components(
+    components: &mut HashMap<String, Component>,
+    only: &[String],
+    skip: &[String],
+) -> Result<()> {
+    if only.is_empty() && skip.is_empty() {
+        return Ok(());
+    }
+    for pattern in only.iter().chain(skip) {
+        if !components
+            .keys()
+            .any(|name| utils::glob_match(pattern, name))
+        {
+            tracing::warn!(pattern = %pattern, "component pattern matches nothing");
+        }
     }
 
-    match output_target {
-        OutputTarget::OciDir(ref path) => {
-            // no logging needed here; build_to_oci_dir already logs
-            builder.build_to_oci_dir(path)?;
+    let before = components.len();
+    components.retain(|name, _| {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| utils::glob_match(p, name));
+        let keep = (only.is_empty() || matches(only)) && !matches(skip);
+        if !keep {
+            tracing::debug!(component = %name, "component left out");
         }
-        OutputTarget::OciArchive(ref path) => {
-            tracing::info!(output = %path, "writing to file");
-            let mut file = std::fs::File::create(path)
-                .with_context(|| format!("creating output file {}", path))?;
-            builder.build_to_oci_archive(&mut file)?;
+        keep
+    });
+    anyhow::ensure!(
+        !components.is_empty(),
+        "no components left after applying --only-components and --skip-components"
+    );
+    tracing::info!(
+        kept = components.len(),
+        dropped = before - components.len(),
+        "components selected"
+    );
+    Ok(())
+}
+
+/// Check the number of layers of the image against [`LAYER_LIMITS`].
+fn check_layer_limits(layers: usize, policy: LayerLimitPolicy) -> Result<()> {
+    for &(limit, consequence) in LAYER_LIMITS {
+        if layers <= limit {
+            continue;
         }
-        OutputTarget::Stdout => {
-            tracing::info!("writing to stdout");
-            builder.build_to_oci_archive(&mut std::io::stdout().lock())?;
+        match policy {
+            LayerLimitPolicy::Ignore => return Ok(()),
+            LayerLimitPolicy::Warn => {
+                tracing::warn!(
+                    layers,
+                    limit,
+                    "image has too many layers for some runtimes: {consequence}; use --max-layers={limit} or lower to support them"
+                );
+            }
+            LayerLimitPolicy::Fail => anyhow::bail!(
+                "image has {layers} layers, more than {limit}: {consequence}; use --max-layers={limit} or lower, or --layer-limits=warn"
+            ),
         }
     }
+    Ok(())
+}
 
-    if let Some(path) = &args.write_peak_mem_to {
-        let peak_rss = utils::get_peak_rss().context("getting peak memory usage")?;
-        std::fs::write(path, format!("{peak_rss}\n"))
-            .with_context(|| format!("writing peak memory to {path}"))?;
+/// Fail if any path of `components` is nested more than `max_depth` levels
+/// deep, reporting the deepest one.
+fn check_path_depth(components: &HashMap<String, Component>, max_depth: usize) -> Result<()> {
+    let deepest = components
+        .iter()
+        .flat_map(|(name, component)| component.files.keys().map(move |path| (path, name)))
+        .map(|(path, name)| (path_depth(path), path, name))
+        .filter(|(depth, _, _)| *depth > max_depth)
+        // the first path among the deepest, for a stable message
+        .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)));
+    if let Some((depth, path, name)) = deepest {
+        anyhow::bail!(
+            "path {path} in component {name} is nested {depth} levels deep, more than --max-path-depth={max_depth}"
+        );
     }
-
-    tracing::info!("build complete");
     Ok(())
 }
 
+/// Number of levels `path` is nested, e.g. 1 for `/usr`.
+fn path_depth(path: &Utf8Path) -> usize {
+    path.components()
+        .filter(|c| matches!(c, Utf8Component::Normal(_)))
+        .count()
+}
+
+/// A packing plan, along with what the image would be built from.
+pub struct PlannedBuild {
+    pub rootfs: Dir,
+    /// The packed components, in layer order.
+    pub components: Vec<(String, Component)>,
+    pub plan: Plan,
+}
+
+/// Compute the packing plan for `args` without building the image.
+pub fn plan(args: &BuildArgs, cancellation: &CancellationToken) -> Result<PlannedBuild> {
+    tracing::info!(rootfs = %args.rootfs, "planning build");
+    let seed = load_seed_plan(args, &args.arch()?)?;
+    let (rootfs, mut components) = scan(args, cancellation)?;
+    if args.debuginfo_image.is_some() {
+        split_debuginfo(&mut components);
+    }
+    let (components, plan) = pack(args, args.max_layers(0), seed.as_ref(), components)?;
+    Ok(PlannedBuild {
+        rootfs,
+        components,
+        plan,
+    })
+}
+
+/// Open the rootfs, scan it and assign files to components, without packing.
+pub fn scan(
+    args: &BuildArgs,
+    cancellation: &CancellationToken,
+) -> Result<(Dir, HashMap<String, Component>)> {
+    scan_with_rules(args, None, cancellation)
+}
+
+/// Like [`scan`], but leaving out what goes into the `--debuginfo-image`, i.e.
+/// the components of the image itself.
+#[cfg(feature = "merged-view")]
+pub fn scan_image(
+    args: &BuildArgs,
+    cancellation: &CancellationToken,
+) -> Result<(Dir, HashMap<String, Component>)> {
+    let (rootfs, mut components) = scan(args, cancellation)?;
+    if args.debuginfo_image.is_some() {
+        split_debuginfo(&mut components);
+    }
+    Ok((rootfs, components))
+}
+
+/// Like [`scan`], but also record the rule which assigned each path to its
+/// component in `claim_rules`. Paths relocated by rewrite rules are recorded
+/// under their original path.
+pub fn scan_with_rules(
+    args: &BuildArgs,
+    claim_rules: Option<&mut ClaimRules>,
+    cancellation: &CancellationToken,
+) -> Result<(Dir, HashMap<String, Component>)> {
+    let parsed = load_config(args)?;
+    let created_epoch = resolve_created_epoch(args.source_date_epoch, &parsed)?;
+    let rewrite_rules = rewrite::parse_rewrite_rules(&args.rewrites)?;
+    let rootfs = Dir::open_ambient_dir(args.rootfs.as_std_path(), ambient_authority())
+        .with_context(|| format!("opening rootfs {}", args.rootfs))?;
+    let components = scan_components(
+        args,
+        &rootfs,
+        created_epoch,
+        &rewrite_rules,
+        claim_rules,
+        cancellation,
+    )?;
+    Ok((rootfs, components))
+}
+
+/// Layers of a built image that are identical to layers of a reference image.
+#[derive(Debug, PartialEq)]
+struct LayerReuse {
+    layers: usize,
+    reused_layers: usize,
+    /// Total size of the layers, as stored (i.e. possibly compressed).
+    size: u64,
+    reused_size: u64,
+}
+
+/// Compare the layers of `image` against those of `reference` by diff ID.
+fn compute_layer_reuse(
+    image: &BuiltImage,
+    reference: &oci_image::ImageConfiguration,
+) -> LayerReuse {
+    let reference_diff_ids: HashSet<&String> = reference.rootfs().diff_ids().iter().collect();
+    let mut reuse = LayerReuse {
+        layers: image.manifest.layers().len(),
+        reused_layers: 0,
+        size: 0,
+        reused_size: 0,
+    };
+    for (layer, diff_id) in image
+        .manifest
+        .layers()
+        .iter()
+        .zip(image.config.rootfs().diff_ids())
+    {
+        let reused = reference_diff_ids.contains(diff_id);
+        let component = layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get("org.chunkah.component"))
+            .map_or("", String::as_str);
+        tracing::debug!(component = %component, reused, "layer reuse");
+        reuse.size += layer.size();
+        if reused {
+            reuse.reused_layers += 1;
+            reuse.reused_size += layer.size();
+        }
+    }
+    reuse
+}
+
+/// Restrict filesystem access to what the rest of the build needs: reading
+/// the rootfs (and components database), and writing next to the outputs, to
+/// the temporary directory and to the staging directory.
+fn apply_sandbox(args: &BuildArgs, output_targets: &[OutputTarget]) -> Result<()> {
+    let outputs = output_targets.iter().filter_map(|target| match target {
+        OutputTarget::Stdout => None,
+        OutputTarget::OciArchive(path)
+        | OutputTarget::OciDir(path)
+        | OutputTarget::Blobs(path)
+        | OutputTarget::ChunkMap(path) => Some(path),
+    });
+    let written = outputs
+        .chain(&args.write_plan_to)
+        .chain(&args.security_report)
+        .chain(&args.write_peak_mem_to)
+        .chain(&args.write_manifest_to);
+
+    let tmpdir = Utf8PathBuf::try_from(std::env::temp_dir()).context("temporary directory")?;
+    let mut sandbox = Sandbox::new().allow_read(&args.rootfs).allow_write(&tmpdir);
+    if let Some(path) = &args.staging_dir {
+        sandbox = sandbox.allow_write(path);
+    }
+    if let Some(path) = &args.components_db {
+        sandbox = sandbox.allow_read(path);
+    }
+    for path in written {
+        // the parent, since outputs are created (and removed on failure)
+        let parent = match path.parent() {
+            Some(p) if !p.as_str().is_empty() => p,
+            _ => Utf8Path::new("."),
+        };
+        sandbox = sandbox.allow_write(parent);
+    }
+    sandbox.apply()
+}
+
+/// Parse the `--output` values into [`OutputTarget`]s, defaulting to stdout.
+fn parse_output_targets(outputs: &[Utf8PathBuf]) -> Result<Vec<OutputTarget>> {
+    if outputs.is_empty() {
+        return Ok(vec![OutputTarget::Stdout]);
+    }
+    let mut seen = HashSet::new();
+    outputs
+        .iter()
+        .map(|output| {
+            let target = parse_output_target(Some(output))?;
+            if let OutputTarget::OciArchive(path)
+            | OutputTarget::OciDir(path)
+            | OutputTarget::Blobs(path)
+            | OutputTarget::ChunkMap(path) = &target
+            {
+                anyhow::ensure!(seen.insert(path.clone()), "duplicate output path: {path}");
+            }
+            Ok(target)
+        })
+        .collect()
+}
+
 /// Parse the `--output` value into an [`OutputTarget`].
 fn parse_output_target(output: Option<&Utf8Path>) -> Result<OutputTarget> {
     match output.map(|o| o.as_str()) {
@@ -332,6 +1502,18 @@ fn parse_output_target(output: Option<&Utf8Path>) -> Result<OutputTarget> {
                 anyhow::ensure!(!path.exists(), "output path already exists: {path}");
                 Ok(OutputTarget::OciDir(path))
             }
+            Some(("blobs", path)) => {
+                anyhow::ensure!(!path.is_empty(), "output path cannot be empty");
+                let path = Utf8PathBuf::from(path);
+                anyhow::ensure!(!path.exists(), "output path already exists: {path}");
+                Ok(OutputTarget::Blobs(path))
+            }
+            Some(("chunkmap", path)) => {
+                anyhow::ensure!(!path.is_empty(), "output path cannot be empty");
+                let path = Utf8PathBuf::from(path);
+                anyhow::ensure!(!path.exists(), "output path already exists: {path}");
+                Ok(OutputTarget::ChunkMap(path))
+            }
             Some((transport, _)) => {
                 // technically breaks paths with literal ':'... let's see if anyone complains; they
                 // can always just redirect from stdout instead
@@ -538,8 +1720,10 @@ fn parse_key_value_pairs(
 /// Packs components into layers according to max_layers constraint.
 fn pack_components(
     max_layers: usize,
+    seed: Option<&Plan>,
+    optimize_until: Option<Instant>,
     components: HashMap<String, Component>,
-) -> Result<Vec<(String, Component)>> {
+) -> Result<(Vec<(String, Component)>, Plan)> {
     let mut entries: Vec<Option<(String, Component)>> = components.into_iter().map(Some).collect();
     // sort by component name for deterministic inputs to the packing algorithm
     entries.sort_by(|a, b| a.as_ref().unwrap().0.cmp(&b.as_ref().unwrap().0));
@@ -555,28 +1739,79 @@ fn pack_components(
                 name: name.clone(),
                 size,
                 stability: comp.stability,
+                isolate: comp.isolated,
             }
         })
         .collect();
 
-    let packed_groups = calculate_packing(&items, max_layers);
+    let mut packed_groups = match seed {
+        Some(seed) => {
+            let index: HashMap<&str, usize> = items
+                .iter()
+                .enumerate()
+                .map(|(idx, item)| (item.name.as_str(), idx))
+                .collect();
+            let seed_groups: Vec<Vec<usize>> = seed
+                .layers
+                .iter()
+                .map(|layer| {
+                    layer
+                        .components
+                        .iter()
+                        .filter_map(|name| index.get(name.as_str()).copied())
+                        .collect()
+                })
+                .collect();
+            let seeded: usize = seed_groups.iter().map(Vec::len).sum();
+            tracing::info!(
+                components = seeded,
+                layers = seed.layers.len(),
+                "seeding packing from plan"
+            );
+            calculate_seeded_packing(&items, max_layers, &seed_groups)
+        }
+        None => calculate_packing(&items, max_layers),
+    };
+    if let Some(deadline) = optimize_until {
+        packed_groups = optimize_packing(&items, packed_groups, deadline);
+    }
 
     let mut result = Vec::with_capacity(packed_groups.len());
+    let mut plan = Plan {
+        naming_scheme: NAMING_SCHEME,
+        ..Default::default()
+    };
 
     for group in packed_groups {
         if group.indices.len() == 1 {
             // single component group
             let idx = group.indices[0];
             let (name, component) = entries[idx].take().expect("packing returned invalid index");
+            plan.layers.push(PlanLayer {
+                id: name.clone(),
+                components: vec![name.clone()],
+                size: group.size,
+                stability: group.stability,
+                content_class: ContentClass::from_stability(group.stability),
+                estimated_compressed_size: None,
+                digest: None,
+            });
             result.push((name, component));
         } else {
             // merged group - combine components
             let mut names = Vec::with_capacity(group.indices.len());
             let mut merged_files = FileMap::new();
             let mut max_mtime_clamp = 0u64;
+            // the largest component names the layer; ties go to the first name
+            let mut dominant = (0u64, String::new());
 
             for &idx in &group.indices {
                 let (name, comp) = entries[idx].take().expect("packing returned invalid index");
+                let size: u64 = comp.files.values().map(|f| f.size).sum();
+                let (max, max_name) = &dominant;
+                if max_name.is_empty() || size > *max || (size == *max && name < *max_name) {
+                    dominant = (size, name.clone());
+                }
                 names.push(name);
                 // Move "up" the clamp. We're still guaranteed that it's (1)
                 // a reproducible timestamp for this particular group, and
@@ -589,18 +1824,28 @@ fn pack_components(
             // this becomes history/annotation values; sort for reproducibility
             names.sort();
             let merged_name = names.join(" ");
+            plan.layers.push(PlanLayer {
+                id: dominant.1,
+                components: names,
+                size: group.size,
+                stability: group.stability,
+                content_class: ContentClass::from_stability(group.stability),
+                estimated_compressed_size: None,
+                digest: None,
+            });
             result.push((
                 merged_name,
                 Component {
                     mtime_clamp: max_mtime_clamp,
                     stability: group.stability,
+                    isolated: false,
                     files: merged_files,
                 },
             ));
         }
     }
 
-    Ok(result)
+    Ok((result, plan))
 }
 
 #[cfg(test)]
@@ -661,6 +1906,7 @@ mod tests {
             Component {
                 mtime_clamp: 1,
                 stability: 0.0,
+                isolated: false,
                 files: Default::default(),
             },
         )];
@@ -875,6 +2121,158 @@ mod tests {
         assert_eq!(labels.get("new-label"), Some(&"second".to_string()));
     }
 
+    #[test]
+    fn test_arch() {
+        // the base config's architecture is used unless overridden
+        let args = BuildArgs {
+            config_str: Some(r#"{"Architecture": "arm64", "Config": {}}"#.into()),
+            ..Default::default()
+        };
+        assert_eq!(args.arch().unwrap(), "arm64");
+        let args = BuildArgs {
+            arch: Some("x86_64".into()),
+            ..args
+        };
+        assert_eq!(args.arch().unwrap(), "amd64");
+    }
+
+    #[test]
+    fn test_live_prune_paths() {
+        use camino::Utf8Path;
+        use cap_std_ext::cap_tempfile;
+
+        let td = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        td.create_dir_all("etc/ssh").unwrap();
+        td.write("etc/machine-id", "0123456789abcdef\n").unwrap();
+        td.write("etc/ssh/sshd_config", "").unwrap();
+        td.write("etc/ssh/ssh_host_ed25519_key", "secret").unwrap();
+        td.create_dir_all("var/lib/NetworkManager").unwrap();
+        td.write("var/lib/NetworkManager/secret_key", "secret")
+            .unwrap();
+
+        let scan = |live| {
+            let args = BuildArgs {
+                live,
+                ..Default::default()
+            };
+            crate::scan::Scanner::new(&td)
+                .prune(&args.prune_paths())
+                .unwrap()
+                .scan()
+                .unwrap()
+        };
+        let host_files = [
+            "/etc/machine-id",
+            "/etc/ssh/ssh_host_ed25519_key",
+            "/var/lib/NetworkManager/secret_key",
+        ];
+
+        let files = scan(true);
+        for path in host_files {
+            assert!(!files.contains_key(Utf8Path::new(path)), "{path}");
+        }
+        assert!(files.contains_key(Utf8Path::new("/etc/ssh/sshd_config")));
+        assert!(files.contains_key(Utf8Path::new("/var/lib/NetworkManager")));
+
+        let files = scan(false);
+        for path in host_files {
+            assert!(files.contains_key(Utf8Path::new(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_strip_prune_paths() {
+        use camino::Utf8Path;
+        use cap_std_ext::cap_tempfile;
+
+        let td = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        td.create_dir_all("usr/share/doc/bash").unwrap();
+        td.write("usr/share/doc/bash/README", "docs").unwrap();
+        td.create_dir_all("usr/share/locale/fr/LC_MESSAGES")
+            .unwrap();
+        td.write("usr/share/locale/fr/LC_MESSAGES/bash.mo", "fr")
+            .unwrap();
+        td.create_dir_all("usr/lib/debug/usr/bin").unwrap();
+        td.write("usr/lib/debug/usr/bin/bash.debug", "debug")
+            .unwrap();
+        td.create_dir_all("usr/share/man/man1").unwrap();
+        td.write("usr/share/man/man1/bash.1.gz", "man").unwrap();
+
+        let args = BuildArgs {
+            prune: vec![Utf8PathBuf::from("/usr/share/man")],
+            strip: vec![StripKind::Docs, StripKind::Locales, StripKind::Debuginfo],
+            ..Default::default()
+        };
+        let files = crate::scan::Scanner::new(&td)
+            .prune(&args.prune_paths())
+            .unwrap()
+            .scan()
+            .unwrap();
+
+        // stripped directories are kept, but not their contents
+        for dir in ["/usr/share/doc", "/usr/share/locale", "/usr/lib/debug"] {
+            assert!(
+                files.contains_key(Utf8Path::new(dir)),
+                "{dir} should be kept"
+            );
+        }
+        assert!(!files.contains_key(Utf8Path::new("/usr/share/doc/bash")));
+        assert!(!files.contains_key(Utf8Path::new("/usr/share/locale/fr")));
+        assert!(!files.contains_key(Utf8Path::new("/usr/lib/debug/usr")));
+
+        // regular --prune still applies alongside
+        assert!(!files.contains_key(Utf8Path::new("/usr/share/man")));
+    }
+
+    #[test]
+    fn test_pack_components_layer_ids() {
+        use crate::components::{FileInfo, FileType};
+
+        let component = |path: &str, size: u64| {
+            let mut info = FileInfo::dummy(FileType::File);
+            info.size = size;
+            Component {
+                mtime_clamp: 0,
+                stability: 0.5,
+                isolated: false,
+                files: FileMap::from([(Utf8PathBuf::from(path), info)]),
+            }
+        };
+        let components = HashMap::from([
+            ("rpm/a".to_string(), component("/a", 10)),
+            ("rpm/mesa".to_string(), component("/mesa", 100)),
+            ("rpm/z".to_string(), component("/z", 100)),
+        ]);
+
+        let (packed, plan) = pack_components(2, None, None, components.clone()).unwrap();
+        assert_eq!(packed.len(), plan.layers.len());
+        for layer in &plan.layers {
+            assert!(layer.components.contains(&layer.id));
+        }
+
+        // the largest component names a merged layer, ties broken by name
+        let (_, plan) = pack_components(1, None, None, components.clone()).unwrap();
+        assert_eq!(plan.layers[0].id, "rpm/mesa");
+
+        // a seed keeps its layout; unknown components are ignored
+        let seed = Plan {
+            layers: vec![PlanLayer {
+                id: "rpm/a".into(),
+                components: vec!["rpm/a".into(), "rpm/z".into(), "rpm/gone".into()],
+                size: 0,
+                stability: 0.0,
+                content_class: ContentClass::Hot,
+                estimated_compressed_size: None,
+                digest: None,
+            }],
+            ..Default::default()
+        };
+        let (_, plan) = pack_components(2, Some(&seed), None, components).unwrap();
+        let layers: Vec<_> = plan.layers.iter().map(|l| &l.components).collect();
+        assert!(layers.contains(&&vec!["rpm/a".to_string(), "rpm/z".to_string()]));
+        assert!(layers.contains(&&vec!["rpm/mesa".to_string()]));
+    }
+
     #[test]
     fn test_packing_with_xattrs() {
         use camino::Utf8Path;
@@ -891,7 +2289,7 @@ mod tests {
             let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
 
             let add_component = |name: &str, size_mb: usize, interval: &str| {
-                rootfs.write(name, &vec![0u8; size_mb * MB]).unwrap();
+                rootfs.write(name, vec![0u8; size_mb * MB]).unwrap();
                 rootfs
                     .setxattr(name, XATTR_COMPONENT, name.as_bytes())
                     .unwrap();
@@ -905,9 +2303,9 @@ mod tests {
             add_component("small", 1, small_interval);
 
             let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
-            let repos = ComponentsRepos::load(&rootfs, &files, 0).unwrap();
+            let repos = ReposLoader::new(&rootfs, &files, 0).load().unwrap();
             let components = repos.into_components(&rootfs, files).unwrap();
-            pack_components(2, components).unwrap()
+            pack_components(2, None, None, components).unwrap().0
         };
 
         // Helper to find which packed layer contains a given file.
@@ -951,4 +2349,219 @@ mod tests {
             "unstable large should be separate from stable medium"
         );
     }
+
+    #[test]
+    fn test_compute_layer_reuse() {
+        let layer = |hex: &str, size: u64| {
+            oci_image::DescriptorBuilder::default()
+                .media_type(oci_image::MediaType::ImageLayerGzip)
+                .digest(
+                    format!("sha256:{}", hex.repeat(32))
+                        .parse::<oci_image::Digest>()
+                        .unwrap(),
+                )
+                .size(size)
+                .build()
+                .unwrap()
+        };
+        let config = |diff_ids: &[&str]| {
+            oci_image::ImageConfigurationBuilder::default()
+                .rootfs(
+                    oci_image::RootFsBuilder::default()
+                        .typ("layers")
+                        .diff_ids(
+                            diff_ids
+                                .iter()
+                                .map(|hex| format!("sha256:{}", hex.repeat(32)))
+                                .collect::<Vec<_>>(),
+                        )
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap()
+        };
+
+        let image = BuiltImage {
+            manifest: oci_image::ImageManifestBuilder::default()
+                .schema_version(oci_image::SCHEMA_VERSION)
+                .config(layer("c0", 10))
+                .layers(vec![layer("a0", 100), layer("b0", 200), layer("d0", 300)])
+                .build()
+                .unwrap(),
+            config: config(&["a1", "b1", "d1"]),
+        };
+
+        // matching is on diff IDs, not on the (compressed) layer digests
+        let reference = config(&["d1", "a1", "e1"]);
+        assert_eq!(
+            compute_layer_reuse(&image, &reference),
+            LayerReuse {
+                layers: 3,
+                reused_layers: 2,
+                size: 600,
+                reused_size: 400,
+            }
+        );
+        assert_eq!(
+            compute_layer_reuse(&image, &config(&["a0", "b0", "d0"])).reused_layers,
+            0
+        );
+    }
+
+    #[test]
+    fn test_check_layer_limits() {
+        check_layer_limits(64, LayerLimitPolicy::Fail).unwrap();
+        check_layer_limits(127, LayerLimitPolicy::Fail).unwrap();
+        check_layer_limits(200, LayerLimitPolicy::Warn).unwrap();
+        check_layer_limits(1000, LayerLimitPolicy::Ignore).unwrap();
+
+        let err = check_layer_limits(128, LayerLimitPolicy::Fail).unwrap_err();
+        assert!(err.to_string().contains("--max-layers=127"), "{err}");
+        // the lowest limit exceeded is reported
+        let err = check_layer_limits(501, LayerLimitPolicy::Fail).unwrap_err();
+        assert!(err.to_string().contains("more than 127"), "{err}");
+    }
+
+    #[test]
+    fn test_check_path_depth() {
+        use crate::components::{FileInfo, FileType};
+
+        let components = HashMap::from([(
+            "rpm/foo".to_string(),
+            Component {
+                mtime_clamp: 0,
+                stability: 0.0,
+                isolated: false,
+                files: FileMap::from([
+                    ("/usr".into(), FileInfo::dummy(FileType::Directory)),
+                    ("/usr/bin".into(), FileInfo::dummy(FileType::Directory)),
+                    ("/usr/bin/ls".into(), FileInfo::dummy(FileType::File)),
+                ]),
+            },
+        )]);
+        check_path_depth(&components, 3).unwrap();
+        let err = check_path_depth(&components, 2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "path /usr/bin/ls in component rpm/foo is nested 3 levels deep, more than --max-path-depth=2"
+        );
+    }
+
+    #[test]
+    fn test_split_debuginfo() {
+        use crate::components::{FileInfo, FileType};
+
+        let component = |paths: &[&str]| Component {
+            mtime_clamp: paths.len() as u64,
+            stability: 0.0,
+            isolated: false,
+            files: paths
+                .iter()
+                .map(|p| (Utf8PathBuf::from(*p), FileInfo::dummy(FileType::File)))
+                .collect(),
+        };
+        let mut components = HashMap::from([
+            (
+                "rpm/bash".to_string(),
+                component(&["/usr/bin/bash", "/usr/lib/debug", "/usr/lib/debugger"]),
+            ),
+            (
+                "rpm/bash-debuginfo".to_string(),
+                component(&["/usr/lib/debug/usr/bin/bash.debug"]),
+            ),
+            (
+                "rpm/bash-debugsource".to_string(),
+                component(&["/usr/src/debug/bash/main.c", "/usr/src/debug/bash/shell.c"]),
+            ),
+        ]);
+
+        let debuginfo = split_debuginfo(&mut components);
+        let paths: Vec<&str> = debuginfo.files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/usr/lib/debug/usr/bin/bash.debug",
+                "/usr/src/debug/bash/main.c",
+                "/usr/src/debug/bash/shell.c"
+            ]
+        );
+        assert_eq!(debuginfo.mtime_clamp, 2);
+        // the directories themselves stay with their owners
+        assert_eq!(components.len(), 1);
+        assert_eq!(components["rpm/bash"].files.len(), 3);
+    }
+
+    #[test]
+    fn test_select_components() {
+        let component = || Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: FileMap::new(),
+        };
+        let names = |components: &HashMap<String, Component>| {
+            let mut names: Vec<_> = components.keys().cloned().collect();
+            names.sort();
+            names
+        };
+        let all = || {
+            HashMap::from([
+                ("rpm/bash".to_string(), component()),
+                ("rpm/kernel".to_string(), component()),
+                ("docs/bash".to_string(), component()),
+                ("chunkah/unclaimed".to_string(), component()),
+            ])
+        };
+
+        let mut components = all();
+        select_components(&mut components, &[], &[]).unwrap();
+        assert_eq!(components.len(), 4);
+
+        let mut components = all();
+        select_components(&mut components, &[], &["docs/*".into()]).unwrap();
+        assert_eq!(
+            names(&components),
+            ["chunkah/unclaimed", "rpm/bash", "rpm/kernel"]
+        );
+
+        let mut components = all();
+        select_components(
+            &mut components,
+            &["rpm/*".into(), "chunkah/*".into()],
+            &["*kernel".into()],
+        )
+        .unwrap();
+        assert_eq!(names(&components), ["chunkah/unclaimed", "rpm/bash"]);
+
+        let mut components = all();
+        let err = select_components(&mut components, &["nope/*".into()], &[]).unwrap_err();
+        assert!(err.to_string().contains("no components left"), "{err}");
+    }
+
+    #[test]
+    fn test_multiple_outputs() {
+        let targets = parse_output_targets(&[]).unwrap();
+        assert!(matches!(targets.as_slice(), [OutputTarget::Stdout]));
+
+        let targets =
+            parse_output_targets(&["a.ociarchive".into(), "oci-archive:b.ociarchive".into()])
+                .unwrap();
+        assert!(matches!(
+            targets.as_slice(),
+            [OutputTarget::OciArchive(_), OutputTarget::OciArchive(_)]
+        ));
+
+        let err = parse_output_targets(&["a.ociarchive".into(), "oci-archive:a.ociarchive".into()])
+            .unwrap_err();
+        assert!(err.to_string().contains("duplicate output path"), "{err}");
+
+        let mut args = BuildArgs::default();
+        assert_eq!(args.max_layers(0), DEFAULT_MAX_LAYERS);
+        args.max_layers = vec![32];
+        assert_eq!(args.max_layers(0), 32);
+        assert_eq!(args.max_layers(1), 32);
+        args.max_layers = vec![32, 128];
+        assert_eq!(args.max_layers(1), 128);
+    }
 }
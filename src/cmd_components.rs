@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use serde::Serialize;
+
+use crate::cancel::CancellationToken;
+use crate::cmd_build::{self, BuildArgs};
+use crate::components::{ClaimRules, Component};
+use crate::utils;
+
+#[derive(Parser)]
+pub struct ComponentsArgs {
+    #[command(flatten)]
+    build: BuildArgs,
+
+    /// List every file with its component and the rule that assigned it
+    ///
+    /// The rule is the repo and the strength of its claim (e.g. `rpm:strong`
+    /// for the rpmdb, or `bigfiles:weak`), `build-id-link` or `unclaimed`.
+    /// Output as CSV, or JSON with --json.
+    #[arg(long)]
+    audit: bool,
+
+    /// Output as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Summary of a component.
+#[derive(Debug, Serialize, PartialEq)]
+struct ComponentSummary {
+    name: String,
+    files: usize,
+    size: u64,
+    stability: f64,
+}
+
+/// The attribution of a single file.
+#[derive(Debug, Serialize, PartialEq)]
+struct AuditEntry {
+    path: Utf8PathBuf,
+    component: String,
+    rule: String,
+    size: u64,
+}
+
+pub fn run(args: &ComponentsArgs, cancellation: &CancellationToken) -> Result<()> {
+    let mut rules = ClaimRules::new();
+    let rules_arg = args.audit.then_some(&mut rules);
+    let (_, components) = cmd_build::scan_with_rules(&args.build, rules_arg, cancellation)?;
+    let mut stdout = std::io::stdout().lock();
+
+    if args.audit {
+        let entries = audit_entries(&components, &rules);
+        if args.json {
+            serde_json::to_writer_pretty(&mut stdout, &entries).context("writing audit")?;
+            writeln!(stdout)?;
+        } else {
+            writeln!(stdout, "path,component,rule,size")?;
+            for entry in &entries {
+                writeln!(
+                    stdout,
+                    "{},{},{},{}",
+                    csv_field(entry.path.as_str()),
+                    csv_field(&entry.component),
+                    csv_field(&entry.rule),
+                    entry.size
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut summaries: Vec<ComponentSummary> = components
+        .iter()
+        .map(|(name, component)| ComponentSummary {
+            name: name.clone(),
+            files: component.files.len(),
+            size: component.files.values().map(|f| f.size).sum(),
+            stability: component.stability,
+        })
+        .collect();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    if args.json {
+        serde_json::to_writer_pretty(&mut stdout, &summaries).context("writing components")?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    writeln!(
+        stdout,
+        "{:>8}  {:>10}  {:>9}  COMPONENT",
+        "FILES", "SIZE", "STABILITY"
+    )?;
+    for summary in &summaries {
+        writeln!(
+            stdout,
+            "{:>8}  {:>10}  {:>9.3}  {}",
+            summary.files,
+            utils::format_size(summary.size),
+            summary.stability,
+            summary.name
+        )?;
+    }
+    Ok(())
+}
+
+/// The attribution of every file of `components`, sorted by path then
+/// component (directories can belong to several components).
+fn audit_entries(components: &HashMap<String, Component>, rules: &ClaimRules) -> Vec<AuditEntry> {
+    let mut entries: Vec<AuditEntry> = components
+        .iter()
+        .flat_map(|(name, component)| {
+            component.files.iter().map(move |(path, info)| {
+                // relocated files were claimed under their original path
+                let claimed_path = info.source.as_deref().unwrap_or(path);
+                let rule = rules
+                    .get(claimed_path)
+                    .map_or_else(|| "unknown".to_string(), |r| r.to_string());
+                AuditEntry {
+                    path: path.clone(),
+                    component: name.clone(),
+                    rule,
+                    size: info.size,
+                }
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.path, &a.component).cmp(&(&b.path, &b.component)));
+    entries
+}
+
+/// Quote `value` for CSV if needed, per RFC 4180.
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{ClaimRule, FileInfo, FileMap, FileType};
+
+    #[test]
+    fn test_audit_entries() {
+        let component = |paths: &[(&str, Option<&str>)]| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: paths
+                .iter()
+                .map(|(path, source)| {
+                    let mut info = FileInfo::dummy(FileType::File);
+                    info.source = source.map(Utf8PathBuf::from);
+                    (Utf8PathBuf::from(*path), info)
+                })
+                .collect::<FileMap>(),
+        };
+        let components = HashMap::from([
+            (
+                "rpm/a".to_string(),
+                component(&[("/usr", None), ("/usr/a", None)]),
+            ),
+            (
+                "rpm/b".to_string(),
+                component(&[("/usr", None), ("/opt/b", Some("/usr/b"))]),
+            ),
+        ]);
+        let rules = ClaimRules::from([
+            ("/usr".into(), ClaimRule::Strong("rpm")),
+            ("/usr/a".into(), ClaimRule::Strong("rpm")),
+            ("/usr/b".into(), ClaimRule::Weak("rpm")),
+        ]);
+
+        let entries = audit_entries(&components, &rules);
+        let rows: Vec<(&str, &str, &str)> = entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.component.as_str(), e.rule.as_str()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("/opt/b", "rpm/b", "rpm:weak"),
+                ("/usr", "rpm/a", "rpm:strong"),
+                ("/usr", "rpm/b", "rpm:strong"),
+                ("/usr/a", "rpm/a", "rpm:strong"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("/usr/bin/ls"), "/usr/bin/ls");
+        assert_eq!(csv_field("/a,b"), "\"/a,b\"");
+        assert_eq!(csv_field("/a\"b"), "\"/a\"\"b\"");
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use ocidir::oci_spec::image as oci_image;
+use serde::Serialize;
+
+use crate::{registry, utils};
+
+#[derive(Parser)]
+pub struct DiffArgs {
+    /// Reference to the old image (e.g. docker://quay.io/org/img:stable)
+    old: String,
+
+    /// Reference to the new image
+    new: String,
+
+    /// Architecture to compare when references point to manifest lists
+    ///
+    /// Defaults to the current architecture.
+    #[arg(long)]
+    arch: Option<String>,
+
+    /// Output the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Size of an update from one image to another, based on blob sizes.
+#[derive(Debug, PartialEq, Serialize)]
+struct DiffReport {
+    /// Number of layers in the new image.
+    layers: usize,
+    /// Number of layers in the new image not present in the old image.
+    changed_layers: usize,
+    /// Total size of the new image's blobs.
+    size: u64,
+    /// Size of the new image's blobs not present in the old image, i.e.
+    /// what a client with the old image needs to download.
+    download_size: u64,
+}
+
+pub fn run(args: &DiffArgs) -> Result<()> {
+    let arch = utils::get_goarch(args.arch.as_deref());
+    let old = registry::fetch_manifest(&args.old, arch)
+        .with_context(|| format!("fetching manifest of {}", args.old))?;
+    let new = registry::fetch_manifest(&args.new, arch)
+        .with_context(|| format!("fetching manifest of {}", args.new))?;
+
+    let report = compute_diff(&old, &new);
+    if args.json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &report)
+            .context("writing report")?;
+        println!();
+    } else {
+        let percent = if report.size > 0 {
+            report.download_size as f64 * 100.0 / report.size as f64
+        } else {
+            0.0
+        };
+        println!(
+            "layers changed: {}/{}",
+            report.changed_layers, report.layers
+        );
+        println!(
+            "download size: {} of {} ({percent:.1}%)",
+            utils::format_size(report.download_size),
+            utils::format_size(report.size)
+        );
+    }
+    Ok(())
+}
+
+/// Compute the update size from `old` to `new`. Blobs are matched by digest,
+/// so this is only meaningful when both images use the same compression.
+fn compute_diff(old: &oci_image::ImageManifest, new: &oci_image::ImageManifest) -> DiffReport {
+    let old_blobs: HashSet<String> = std::iter::once(old.config())
+        .chain(old.layers())
+        .map(|d| d.digest().to_string())
+        .collect();
+    let is_new = |desc: &oci_image::Descriptor| !old_blobs.contains(&desc.digest().to_string());
+
+    // a blob repeated in the new image is only downloaded once
+    let mut seen = HashSet::new();
+    let mut report = DiffReport {
+        layers: new.layers().len(),
+        changed_layers: 0,
+        size: 0,
+        download_size: 0,
+    };
+    for (i, desc) in std::iter::once(new.config())
+        .chain(new.layers())
+        .enumerate()
+    {
+        let is_layer = i > 0;
+        if is_layer && is_new(desc) {
+            report.changed_layers += 1;
+        }
+        if !seen.insert(desc.digest().to_string()) {
+            continue;
+        }
+        report.size += desc.size();
+        if is_new(desc) {
+            report.download_size += desc.size();
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(config: &str, layers: &[(&str, u64)]) -> oci_image::ImageManifest {
+        let desc = |hex: &str, size: u64| {
+            let digest = format!("sha256:{}", hex.repeat(64 / hex.len()));
+            oci_image::DescriptorBuilder::default()
+                .media_type(oci_image::MediaType::ImageLayerGzip)
+                .digest(oci_image::Digest::try_from(digest).unwrap())
+                .size(size)
+                .build()
+                .unwrap()
+        };
+        oci_image::ImageManifestBuilder::default()
+            .schema_version(oci_image::SCHEMA_VERSION)
+            .config(desc(config, 10))
+            .layers(
+                layers
+                    .iter()
+                    .map(|(hex, size)| desc(hex, *size))
+                    .collect::<Vec<_>>(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compute_diff() {
+        let old = manifest("c0", &[("a0", 100), ("b0", 200), ("d0", 300)]);
+        let new = manifest("c1", &[("a0", 100), ("b1", 250), ("d0", 300)]);
+        assert_eq!(
+            compute_diff(&old, &new),
+            DiffReport {
+                layers: 3,
+                changed_layers: 1,
+                size: 660,
+                download_size: 260,
+            }
+        );
+
+        // identical images need nothing
+        assert_eq!(compute_diff(&old, &old).download_size, 0);
+
+        // repeated blobs are downloaded once
+        let new = manifest("c0", &[("e0", 50), ("e0", 50)]);
+        assert_eq!(
+            compute_diff(&old, &new),
+            DiffReport {
+                layers: 2,
+                changed_layers: 2,
+                size: 60,
+                download_size: 50,
+            }
+        );
+    }
+}
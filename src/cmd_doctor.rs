@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+use serde::Serialize;
+
+use crate::cancel::{self, CancellationToken};
+use crate::cmd_build::{self, BuildArgs};
+use crate::components::{COMPONENT_XATTRS, ClaimRule, ClaimRules, Component, UNCLAIMED_COMPONENT};
+use crate::{hardlinks, scan, tar, utils};
+
+/// Number of affected paths or components listed for each finding.
+const MAX_EXAMPLES: usize = 5;
+
+/// Share of the image (in percent) unclaimed files must reach to be reported.
+const UNCLAIMED_THRESHOLD_PERCENT: u64 = 10;
+
+/// Depth of the directories unclaimed files are grouped under, e.g.
+/// `/usr/lib/foo`.
+const UNCLAIMED_TREE_DEPTH: usize = 3;
+
+/// Hints for known scan failures, by a substring of the error.
+const SCAN_FAILURE_HINTS: &[(&str, &str)] = &[
+    (
+        "special file type not supported",
+        "use --skip-special-files to leave sockets, FIFOs and device nodes out",
+    ),
+    (
+        "no supported component repo found",
+        "keep the package database (e.g. the rpmdb) in the rootfs, or label files with the user.component xattr",
+    ),
+    (
+        "non-UTF8 xattr key",
+        "remove the xattr in the step producing the rootfs",
+    ),
+    (
+        "Permission denied",
+        "run as a user who can read the whole rootfs, or use --scan-errors=warn to leave unreadable paths out",
+    ),
+    (
+        "hardlink group(s) span components",
+        "use --cross-component-hardlinks=merge to keep each group in a single layer",
+    ),
+];
+
+#[derive(Parser)]
+pub struct DoctorArgs {
+    #[command(flatten)]
+    build: BuildArgs,
+
+    /// Output the findings as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    /// The rootfs can't be built as is.
+    Error,
+    /// Chunking is likely to be poor.
+    Warning,
+    /// Worth knowing, but may be intended.
+    Info,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// Something about the rootfs that prevents building it, or degrades how
+/// well it's chunked.
+#[derive(Debug, Serialize, PartialEq)]
+struct Finding {
+    severity: Severity,
+    /// Identifier of the check, e.g. `unclaimed`.
+    check: &'static str,
+    summary: String,
+    /// Number of affected paths or components.
+    count: usize,
+    /// The first few of them.
+    examples: Vec<String>,
+    /// How to address it.
+    hint: String,
+}
+
+impl Finding {
+    fn new(
+        severity: Severity,
+        check: &'static str,
+        summary: String,
+        mut items: Vec<String>,
+        hint: &str,
+    ) -> Self {
+        let count = items.len();
+        items.truncate(MAX_EXAMPLES);
+        Self {
+            severity,
+            check,
+            summary,
+            count,
+            examples: items,
+            hint: hint.to_string(),
+        }
+    }
+}
+
+pub fn run(args: &DoctorArgs, cancellation: &CancellationToken) -> Result<()> {
+    let findings = diagnose(&args.build, cancellation)?;
+    let mut stdout = std::io::stdout().lock();
+
+    if args.json {
+        serde_json::to_writer_pretty(&mut stdout, &findings).context("writing findings")?;
+        writeln!(stdout)?;
+    } else if findings.is_empty() {
+        writeln!(stdout, "no problems found")?;
+    } else {
+        for finding in &findings {
+            writeln!(stdout, "{}: {}", finding.severity, finding.summary)?;
+            for example in &finding.examples {
+                writeln!(stdout, "    {example}")?;
+            }
+            if finding.count > finding.examples.len() {
+                writeln!(
+                    stdout,
+                    "    ... and {} more",
+                    finding.count - finding.examples.len()
+                )?;
+            }
+            writeln!(stdout, "  hint: {}", finding.hint)?;
+        }
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        anyhow::bail!("{errors} problem(s) prevent building the rootfs");
+    }
+    Ok(())
+}
+
+/// Run all the checks against the rootfs as it would be built with `args`,
+/// returning the findings, most severe first.
+fn diagnose(args: &BuildArgs, cancellation: &CancellationToken) -> Result<Vec<Finding>> {
+    let rootfs = Dir::open_ambient_dir(args.rootfs().as_std_path(), ambient_authority())
+        .with_context(|| format!("opening rootfs {}", args.rootfs()))?;
+
+    // the scan fails on the first of these, so find them all upfront
+    let non_utf8 = scan::find_non_utf8_paths(&rootfs)?;
+    if !non_utf8.is_empty() {
+        return Ok(vec![Finding::new(
+            Severity::Error,
+            "non-utf8-names",
+            format!("{} path(s) aren't valid UTF-8", non_utf8.len()),
+            non_utf8,
+            "rename them (e.g. with convmv) in the step producing the rootfs; the other checks need them fixed",
+        )]);
+    }
+
+    let mut rules = ClaimRules::new();
+    let components = match cmd_build::scan_with_rules(args, Some(&mut rules), cancellation) {
+        Ok((_, components)) => components,
+        Err(e) if cancel::is_cancelled(&e) => return Err(e),
+        Err(e) => return Ok(vec![scan_failure(&e)]),
+    };
+
+    let mut findings = Vec::new();
+    findings.extend(check_package_db(&rules));
+    findings.extend(check_unclaimed(&components));
+    findings.extend(check_hardlinks(&components));
+    findings.extend(check_xattrs(
+        &components,
+        args.preserve_ima(),
+        args.drop_user_xattrs(),
+    ));
+    findings.sort_by_key(|f| f.severity);
+    Ok(findings)
+}
+
+/// The finding for a scan which failed with `err`, with a hint if it's a
+/// known failure.
+fn scan_failure(err: &anyhow::Error) -> Finding {
+    let message = format!("{err:#}");
+    let hint = SCAN_FAILURE_HINTS
+        .iter()
+        .find(|(needle, _)| message.contains(needle))
+        .map_or("the other checks need the scan to succeed", |(_, hint)| {
+            *hint
+        });
+    Finding::new(
+        Severity::Error,
+        "scan",
+        format!("scanning the rootfs failed: {message}"),
+        Vec::new(),
+        hint,
+    )
+}
+
+/// Check that files are claimed from a package database, without which
+/// components are only derived from xattrs and heuristics.
+fn check_package_db(rules: &ClaimRules) -> Option<Finding> {
+    if rules
+        .values()
+        .any(|rule| matches!(rule, ClaimRule::Strong("rpm" | "alpm")))
+    {
+        return None;
+    }
+    // fine if the rootfs is labeled with xattrs instead
+    let labeled = rules
+        .values()
+        .any(|rule| *rule == ClaimRule::Strong("xattr"));
+    Some(Finding::new(
+        if labeled {
+            Severity::Info
+        } else {
+            Severity::Warning
+        },
+        "package-db",
+        "no package database found".to_string(),
+        Vec::new(),
+        "keep the package database (e.g. the rpmdb) in the rootfs, or label files with the user.component xattr",
+    ))
+}
+
+/// Check for a large share of unclaimed files, which all end up packed
+/// together and change with any of them.
+fn check_unclaimed(components: &HashMap<String, Component>) -> Option<Finding> {
+    let unclaimed = components.get(UNCLAIMED_COMPONENT)?;
+    let total: u64 = components
+        .values()
+        .flat_map(|c| c.files.values())
+        .map(|f| f.size)
+        .sum();
+    let size: u64 = unclaimed.files.values().map(|f| f.size).sum();
+    if total == 0 || size * 100 < total * UNCLAIMED_THRESHOLD_PERCENT {
+        return None;
+    }
+
+    let mut trees: BTreeMap<&str, u64> = BTreeMap::new();
+    for (path, info) in &unclaimed.files {
+        *trees.entry(unclaimed_tree(path)).or_default() += info.size;
+    }
+    let mut trees: Vec<(&str, u64)> = trees.into_iter().collect();
+    // largest first, then by path for a stable report
+    trees.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    Some(Finding::new(
+        Severity::Warning,
+        "unclaimed",
+        format!(
+            "{} of {} ({}%) isn't claimed by any component",
+            utils::format_size(size),
+            utils::format_size(total),
+            size * 100 / total
+        ),
+        trees
+            .iter()
+            .map(|(tree, size)| format!("{:>10}  {tree}", utils::format_size(*size)))
+            .collect(),
+        "label the largest trees with the user.component xattr so they get their own components, or leave them out with --prune if they aren't needed",
+    ))
+}
+
+/// The directory `path` is grouped under when reporting unclaimed files: its
+/// parent, or its ancestor at depth [`UNCLAIMED_TREE_DEPTH`] if deeper.
+fn unclaimed_tree(path: &Utf8Path) -> &str {
+    let parent = path.parent().unwrap_or(path);
+    parent
+        .ancestors()
+        .find(|a| a.components().count() <= UNCLAIMED_TREE_DEPTH + 1)
+        .map_or("/", Utf8Path::as_str)
+}
+
+/// Check for hardlink groups split across components, whose content is then
+/// duplicated across layers.
+fn check_hardlinks(components: &HashMap<String, Component>) -> Option<Finding> {
+    let groups = hardlinks::find_split_hardlinks(components);
+    if groups.is_empty() {
+        return None;
+    }
+    Some(Finding::new(
+        Severity::Warning,
+        "split-hardlinks",
+        format!("{} hardlink group(s) span components", groups.len()),
+        groups.iter().map(|g| g.describe()).collect(),
+        "their content is written in each layer with links of the group; use --cross-component-hardlinks=merge to keep each group in a single layer",
+    ))
+}
+
+/// Check for xattrs which won't come out as likely intended.
+fn check_xattrs(
+    components: &HashMap<String, Component>,
+    preserve_ima: bool,
+    drop_user_xattrs: bool,
+) -> Vec<Finding> {
+    // directories can be in several components
+    let mut files: BTreeMap<&Utf8Path, &[(String, Vec<u8>)]> = BTreeMap::new();
+    for component in components.values() {
+        for (path, info) in &component.files {
+            files.insert(path.as_path(), info.xattrs.as_slice());
+        }
+    }
+
+    let mut findings = Vec::new();
+    if !preserve_ima {
+        let signed: Vec<String> = files
+            .iter()
+            .filter(|(_, xattrs)| tar::has_evm(xattrs))
+            .map(|(path, _)| path.to_string())
+            .collect();
+        if !signed.is_empty() {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "evm",
+                format!("{} file(s) have EVM signatures", signed.len()),
+                signed,
+                "use --preserve-ima so that their metadata and SELinux label still match the signatures",
+            ));
+        }
+    }
+
+    if !drop_user_xattrs {
+        let user: Vec<String> = files
+            .iter()
+            .flat_map(|(path, xattrs)| {
+                xattrs
+                    .iter()
+                    .filter(|(key, _)| {
+                        key.starts_with("user.") && !COMPONENT_XATTRS.contains(&key.as_str())
+                    })
+                    .map(move |(key, _)| format!("{path} ({key})"))
+            })
+            .collect();
+        if !user.is_empty() {
+            findings.push(Finding::new(
+                Severity::Info,
+                "user-xattrs",
+                format!(
+                    "{} user xattr(s) will be carried into the layers",
+                    user.len()
+                ),
+                user,
+                "use --drop-user-xattrs if they're leftovers of the build",
+            ));
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    use super::*;
+    use crate::components::{FileInfo, FileMap, FileType};
+
+    fn component(files: &[(&str, u64)]) -> Component {
+        Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: files
+                .iter()
+                .map(|(path, size)| {
+                    let info = FileInfo {
+                        size: *size,
+                        ..FileInfo::dummy(FileType::File)
+                    };
+                    (Utf8PathBuf::from(*path), info)
+                })
+                .collect::<FileMap>(),
+        }
+    }
+
+    #[test]
+    fn test_check_unclaimed() {
+        assert_eq!(unclaimed_tree(Utf8Path::new("/foo")), "/");
+        assert_eq!(
+            unclaimed_tree(Utf8Path::new("/usr/lib/foo/bar")),
+            "/usr/lib/foo"
+        );
+        assert_eq!(
+            unclaimed_tree(Utf8Path::new("/usr/lib/foo/sub/dir/bar")),
+            "/usr/lib/foo"
+        );
+
+        let mut components = HashMap::from([
+            ("rpm/bash".to_string(), component(&[("/usr/bin/bash", 850)])),
+            (
+                UNCLAIMED_COMPONENT.to_string(),
+                component(&[
+                    ("/opt/app/lib/a/libfoo.so", 50),
+                    ("/opt/app/lib/b/libbar.so", 50),
+                    ("/usr/local/bin/tool", 20),
+                    ("/etc/foo.conf", 30),
+                ]),
+            ),
+        ]);
+
+        let finding = check_unclaimed(&components).unwrap();
+        assert_eq!(
+            finding.summary,
+            "150 B of 1000 B (15%) isn't claimed by any component"
+        );
+        assert_eq!(finding.count, 3);
+        assert_eq!(
+            finding.examples,
+            vec![
+                "     100 B  /opt/app/lib",
+                "      30 B  /etc",
+                "      20 B  /usr/local/bin",
+            ]
+        );
+
+        // below the threshold
+        components.insert("rpm/big".to_string(), component(&[("/big", 1000)]));
+        assert!(check_unclaimed(&components).is_none());
+    }
+
+    #[test]
+    fn test_scan_failure() {
+        let err = anyhow::anyhow!("special file type not supported: /run/sock")
+            .context("scanning rootfs");
+        let finding = scan_failure(&err);
+        assert_eq!(finding.severity, Severity::Error);
+        assert_eq!(
+            finding.summary,
+            "scanning the rootfs failed: scanning rootfs: special file type not supported: /run/sock"
+        );
+        assert!(finding.hint.contains("--skip-special-files"));
+
+        let finding = scan_failure(&anyhow::anyhow!("something else"));
+        assert_eq!(finding.hint, "the other checks need the scan to succeed");
+    }
+}
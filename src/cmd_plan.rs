@@ -0,0 +1,244 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+
+use crate::cancel::CancellationToken;
+use crate::cmd_build::{self, BuildArgs};
+use crate::components::{FileMap, FileType};
+use crate::plan::{Plan, PlanDiff};
+use crate::utils;
+
+#[derive(Parser)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    build: BuildArgs,
+
+    /// Fraction of each file to compress when estimating compressed sizes
+    ///
+    /// Small files are always compressed whole. Higher values give more
+    /// accurate estimates at the cost of reading more of the rootfs. Use 0 to
+    /// skip estimating.
+    #[arg(long, value_name = "RATIO", default_value_t = 0.05)]
+    sample_ratio: f64,
+
+    /// Compare against a previous plan and report the differences
+    ///
+    /// Components which moved to another layer, were added or removed, and
+    /// the layers which changed as a result. With --json, outputs the
+    /// differences instead of the plan.
+    #[arg(long, value_name = "PATH")]
+    against: Option<Utf8PathBuf>,
+
+    /// Output the plan as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Files smaller than this are sampled whole.
+const MIN_SAMPLE_BYTES: u64 = 4096;
+
+/// Estimated compressed size of a tar header and padding. Headers are mostly
+/// zeros and compress to almost nothing.
+const ENTRY_OVERHEAD: u64 = 32;
+
+pub fn run(args: &PlanArgs, cancellation: &CancellationToken) -> Result<()> {
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&args.sample_ratio),
+        "--sample-ratio must be between 0 and 1"
+    );
+
+    // read this upfront so that a bad path fails before scanning
+    let previous = args
+        .against
+        .as_deref()
+        .map(|path| {
+            let json =
+                std::fs::read_to_string(path).with_context(|| format!("reading plan {path}"))?;
+            Plan::from_plan_or_manifest(&json).with_context(|| format!("parsing plan {path}"))
+        })
+        .transpose()?;
+
+    let cmd_build::PlannedBuild {
+        rootfs,
+        components,
+        mut plan,
+    } = cmd_build::plan(&args.build, cancellation)?;
+
+    if args.sample_ratio > 0.0 {
+        let level = args.build.compression_level();
+        for (layer, (name, component)) in plan.layers.iter_mut().zip(&components) {
+            cancellation.check()?;
+            let estimate =
+                estimate_compressed_size(&rootfs, &component.files, level, args.sample_ratio)
+                    .with_context(|| format!("estimating compressed size of {name}"))?;
+            layer.estimated_compressed_size = Some(estimate);
+        }
+    }
+
+    let diff = previous
+        .as_ref()
+        .map(|previous| PlanDiff::compute(previous, &plan));
+
+    if args.json {
+        let stdout = std::io::stdout().lock();
+        match &diff {
+            Some(diff) => {
+                serde_json::to_writer_pretty(stdout, diff).context("writing plan diff")?
+            }
+            None => serde_json::to_writer_pretty(stdout, &plan).context("writing plan")?,
+        }
+        println!();
+        return Ok(());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    writeln!(
+        stdout,
+        "{:>5}  {:>10}  {:>10}  {:<5}  COMPONENTS",
+        "LAYER", "SIZE", "COMPRESSED", "CLASS"
+    )?;
+    for (i, layer) in plan.layers.iter().enumerate() {
+        let first = layer.components.first().map_or("", String::as_str);
+        let components = match layer.components.len() {
+            0 | 1 => first.to_string(),
+            n => format!("{first} (+{} more)", n - 1),
+        };
+        writeln!(
+            stdout,
+            "{:>5}  {:>10}  {:>10}  {:<5}  {components}",
+            i + 1,
+            utils::format_size(layer.size),
+            layer
+                .estimated_compressed_size
+                .map_or_else(|| "-".to_string(), utils::format_size),
+            layer.content_class.as_str(),
+        )?;
+    }
+    let size: u64 = plan.layers.iter().map(|l| l.size).sum();
+    write!(stdout, "total: {}", utils::format_size(size))?;
+    if args.sample_ratio > 0.0 {
+        let estimate: u64 = plan
+            .layers
+            .iter()
+            .filter_map(|l| l.estimated_compressed_size)
+            .sum();
+        write!(stdout, " ({} compressed)", utils::format_size(estimate))?;
+    }
+    writeln!(stdout)?;
+
+    if let Some(diff) = diff {
+        writeln!(stdout)?;
+        for moved in &diff.moved {
+            writeln!(
+                stdout,
+                "moved: {} ({} -> {})",
+                moved.name, moved.from, moved.to
+            )?;
+        }
+        for name in &diff.added {
+            writeln!(stdout, "added: {name}")?;
+        }
+        for name in &diff.removed {
+            writeln!(stdout, "removed: {name}")?;
+        }
+        writeln!(
+            stdout,
+            "changed layers: {} of {} ({})",
+            diff.changed_layers.len(),
+            plan.layers.len(),
+            utils::format_size(diff.changed_size)
+        )?;
+    }
+    Ok(())
+}
+
+/// Estimate the gzip-compressed size of a layer holding `files` by
+/// compressing a sample of each file's content at `level` and extrapolating
+/// the resulting ratio.
+fn estimate_compressed_size(
+    rootfs: &Dir,
+    files: &FileMap,
+    level: u32,
+    sample_ratio: f64,
+) -> Result<u64> {
+    let mut encoder =
+        flate2::write::GzEncoder::new(CountingWriter::default(), flate2::Compression::new(level));
+    let mut sampled = 0u64;
+    let mut content_size = 0u64;
+    for (path, info) in files {
+        if info.file_type != FileType::File || info.size == 0 {
+            continue;
+        }
+        content_size += info.size;
+        let len = ((info.size as f64 * sample_ratio).ceil() as u64)
+            .max(MIN_SAMPLE_BYTES)
+            .min(info.size);
+        let source = info.source.as_deref().unwrap_or(path);
+        let file = rootfs
+            .open(crate::tar::strip_root_prefix(source))
+            .with_context(|| format!("opening {source}"))?;
+        sampled += std::io::copy(&mut file.take(len), &mut encoder)
+            .with_context(|| format!("reading {source}"))?;
+    }
+    let compressed = encoder.finish().context("compressing sample")?.0;
+
+    let ratio = if sampled > 0 {
+        compressed as f64 / sampled as f64
+    } else {
+        0.0
+    };
+    let entries = files.len() as u64;
+    Ok((content_size as f64 * ratio) as u64 + entries * ENTRY_OVERHEAD)
+}
+
+/// Writer which discards its input, only counting the bytes.
+#[derive(Default)]
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::FileInfo;
+
+    #[test]
+    fn test_estimate_compressed_size() {
+        let td = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(td.path(), ambient_authority()).unwrap();
+        // highly compressible
+        rootfs.write("zeros", vec![0u8; 1 << 20]).unwrap();
+        // not compressible at all
+        let mut random = vec![0u8; 1 << 20];
+        openssl::rand::rand_bytes(&mut random).unwrap();
+        rootfs.write("random", &random).unwrap();
+
+        let files = |name: &str| {
+            let mut info = FileInfo::dummy(FileType::File);
+            info.size = 1 << 20;
+            FileMap::from([(format!("/{name}").into(), info)])
+        };
+        let zeros = estimate_compressed_size(&rootfs, &files("zeros"), 6, 0.05).unwrap();
+        let random = estimate_compressed_size(&rootfs, &files("random"), 6, 0.05).unwrap();
+        assert!(zeros < 64 << 10, "{zeros}");
+        assert!(random > 1 << 20, "{random}");
+
+        // level 0 stores everything
+        let stored = estimate_compressed_size(&rootfs, &files("zeros"), 0, 0.05).unwrap();
+        assert!(stored >= 1 << 20, "{stored}");
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use serde::Serialize;
+
+use crate::cancel::CancellationToken;
+use crate::cmd_build::{self, BuildArgs};
+use crate::components::FileMap;
+use crate::dedup::{self, DuplicateGroup};
+use crate::plan::{Plan, PlanLayer};
+use crate::utils;
+
+#[derive(Parser)]
+// the rootfs isn't needed to report on the history; the default is never used
+#[command(mut_arg("rootfs", |arg| arg
+    .required(false)
+    .required_unless_present("history")
+    .default_value("")))]
+pub struct StatsArgs {
+    #[command(flatten)]
+    build: BuildArgs,
+
+    /// Number of duplicate groups to list, largest first
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+
+    /// Report how often each layer changed over previous builds instead
+    ///
+    /// DIR holds the plans (as written by `--write-plan-to`) or image
+    /// manifests (e.g. from `skopeo inspect --raw`) of previous builds, one
+    /// per `.json` file, in build order when sorted by name. A layer changed
+    /// between two builds if its digest did, or for plans (which don't record
+    /// digests) if its components or size did. Layers are matched by their
+    /// identifier. The rootfs isn't scanned.
+    #[arg(long, value_name = "DIR")]
+    history: Option<Utf8PathBuf>,
+
+    /// Only consider the last N builds of the history
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "history")]
+    builds: usize,
+
+    /// Output the stats as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Statistics about the content of an image as it would be built.
+#[derive(Debug, Serialize, PartialEq)]
+struct Stats {
+    files: usize,
+    size: u64,
+    components: usize,
+    layers: usize,
+    duplicates: DuplicateStats,
+}
+
+/// Bytes shipped more than once, i.e. in files with identical content.
+#[derive(Debug, Default, Serialize, PartialEq)]
+struct DuplicateStats {
+    /// Number of files which are a copy of another file.
+    files: usize,
+    /// Total size of those copies.
+    size: u64,
+    /// Part of `size` where the copies are in the same layer, and so can be
+    /// reclaimed with `--dedup-hardlinks` (if their metadata also matches).
+    within_layers: u64,
+    /// Part of `size` where the copies are in different layers.
+    across_layers: u64,
+    /// The largest groups of copies.
+    top: Vec<DuplicateReport>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct DuplicateReport {
+    /// Size of each copy.
+    size: u64,
+    /// Components owning the copies, sorted.
+    components: Vec<String>,
+    paths: Vec<Utf8PathBuf>,
+}
+
+/// How stable the layers of an image were over previous builds.
+#[derive(Debug, Serialize, PartialEq)]
+struct HistoryStats {
+    /// Number of builds considered.
+    builds: usize,
+    /// The layers, least stable first.
+    layers: Vec<LayerHistory>,
+}
+
+/// How a layer changed over previous builds.
+#[derive(Debug, Serialize, PartialEq)]
+struct LayerHistory {
+    /// Identifier of the layer.
+    id: String,
+    /// Number of builds with the layer.
+    builds: usize,
+    /// Number of builds in which the layer changed since the previous one.
+    changes: usize,
+    /// Fraction of builds in which the layer stayed the same since the previous
+    /// one, if it was in more than one build.
+    observed_stability: Option<f64>,
+    /// Stability predicted for the layer in the last build with it.
+    predicted_stability: f64,
+}
+
+pub fn run(args: &StatsArgs, cancellation: &CancellationToken) -> Result<()> {
+    if let Some(dir) = &args.history {
+        return run_history(args, dir);
+    }
+
+    let seed = cmd_build::load_seed_plan(&args.build, &args.build.arch()?)?;
+    let (rootfs, components) = cmd_build::scan(&args.build, cancellation)?;
+
+    // all the files, and which component they're in
+    let mut files = FileMap::new();
+    let mut owners: HashMap<Utf8PathBuf, String> = HashMap::new();
+    for (name, component) in &components {
+        for (path, info) in &component.files {
+            files.insert(path.clone(), info.clone());
+            owners.insert(path.clone(), name.clone());
+        }
+    }
+    let component_count = components.len();
+
+    let (_, plan) = cmd_build::pack(
+        &args.build,
+        args.build.max_layers(0),
+        seed.as_ref(),
+        components,
+    )?;
+    let layers: HashMap<&str, usize> = plan
+        .layers
+        .iter()
+        .enumerate()
+        .flat_map(|(i, layer)| layer.components.iter().map(move |c| (c.as_str(), i)))
+        .collect();
+
+    let groups =
+        dedup::find_duplicates(&rootfs, &files, cancellation).context("finding duplicate files")?;
+    let stats = Stats {
+        files: files.len(),
+        size: files.values().map(|f| f.size).sum(),
+        components: component_count,
+        layers: plan.layers.len(),
+        duplicates: summarize_duplicates(&groups, &owners, &layers, args.top),
+    };
+
+    if args.json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &stats).context("writing stats")?;
+        println!();
+        return Ok(());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let dups = &stats.duplicates;
+    writeln!(
+        stdout,
+        "{} files, {} in {} components packed into {} layers",
+        stats.files,
+        utils::format_size(stats.size),
+        stats.components,
+        stats.layers,
+    )?;
+    writeln!(
+        stdout,
+        "duplicates: {} files, {} ({} within layers, {} across layers)",
+        dups.files,
+        utils::format_size(dups.size),
+        utils::format_size(dups.within_layers),
+        utils::format_size(dups.across_layers),
+    )?;
+    if dups.top.is_empty() {
+        return Ok(());
+    }
+    writeln!(stdout)?;
+    writeln!(stdout, "{:>10}  {:>6}  COMPONENTS", "SIZE", "COPIES")?;
+    for group in &dups.top {
+        writeln!(
+            stdout,
+            "{:>10}  {:>6}  {}",
+            utils::format_size(group.size),
+            group.paths.len(),
+            group.components.join(", "),
+        )?;
+        for path in &group.paths {
+            writeln!(stdout, "{:>20}{path}", "")?;
+        }
+    }
+    Ok(())
+}
+
+fn run_history(args: &StatsArgs, dir: &Utf8Path) -> Result<()> {
+    let plans = load_history(dir, args.builds)?;
+    let stats = HistoryStats {
+        builds: plans.len(),
+        layers: layer_history(&plans),
+    };
+
+    if args.json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &stats)
+            .context("writing history stats")?;
+        writeln!(std::io::stdout())?;
+        return Ok(());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    writeln!(
+        stdout,
+        "{} builds, {} layers",
+        stats.builds,
+        stats.layers.len()
+    )?;
+    writeln!(stdout)?;
+    writeln!(
+        stdout,
+        "{:>6}  {:>7}  {:>8}  {:>9}  LAYER",
+        "BUILDS", "CHANGES", "OBSERVED", "PREDICTED"
+    )?;
+    for layer in &stats.layers {
+        let observed = layer
+            .observed_stability
+            .map_or_else(|| "-".to_string(), |s| format!("{s:.3}"));
+        writeln!(
+            stdout,
+            "{:>6}  {:>7}  {:>8}  {:>9.3}  {}",
+            layer.builds, layer.changes, observed, layer.predicted_stability, layer.id
+        )?;
+    }
+    Ok(())
+}
+
+/// Load the plans of the last `builds` builds in `dir`, oldest first.
+fn load_history(dir: &Utf8Path, builds: usize) -> Result<Vec<Plan>> {
+    let mut paths = Vec::new();
+    for entry in dir
+        .read_dir_utf8()
+        .with_context(|| format!("reading {dir}"))?
+    {
+        let path = entry.with_context(|| format!("reading {dir}"))?.into_path();
+        if path.extension() == Some("json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let paths = &paths[paths.len().saturating_sub(builds)..];
+    anyhow::ensure!(
+        paths.len() >= 2,
+        "need at least 2 builds in {dir}, found {}",
+        paths.len()
+    );
+
+    paths
+        .iter()
+        .map(|path| {
+            let json = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+            Plan::from_plan_or_manifest(&json).with_context(|| format!("parsing {path}"))
+        })
+        .collect()
+}
+
+/// Whether `new` differs from `old`, the same layer in the previous build.
+fn layer_changed(old: &PlanLayer, new: &PlanLayer) -> bool {
+    match (&old.digest, &new.digest) {
+        (Some(old), Some(new)) => old != new,
+        // no digest on one side; go by what both record, treating a zero size
+        // (manifests don't carry uncompressed sizes) as unknown
+        _ => {
+            let mut old_components = old.components.clone();
+            let mut new_components = new.components.clone();
+            old_components.sort();
+            new_components.sort();
+            old_components != new_components
+                || (old.size != 0 && new.size != 0 && old.size != new.size)
+        }
+    }
+}
+
+/// How often each layer of `plans` (oldest first) changed from one build to
+/// the next, least stable first.
+fn layer_history(plans: &[Plan]) -> Vec<LayerHistory> {
+    // with the number of builds which also had the layer in the previous one
+    let mut layers: BTreeMap<&str, (LayerHistory, usize)> = BTreeMap::new();
+    let mut previous: HashMap<&str, &PlanLayer> = HashMap::new();
+    for plan in plans {
+        let mut current = HashMap::new();
+        for layer in &plan.layers {
+            let (history, transitions) = layers.entry(layer.id.as_str()).or_insert_with(|| {
+                let history = LayerHistory {
+                    id: layer.id.clone(),
+                    builds: 0,
+                    changes: 0,
+                    observed_stability: None,
+                    predicted_stability: 0.0,
+                };
+                (history, 0)
+            });
+            history.builds += 1;
+            history.predicted_stability = layer.stability;
+            if let Some(old) = previous.get(layer.id.as_str()) {
+                *transitions += 1;
+                if layer_changed(old, layer) {
+                    history.changes += 1;
+                }
+            }
+            current.insert(layer.id.as_str(), layer);
+        }
+        previous = current;
+    }
+
+    let mut layers: Vec<LayerHistory> = layers
+        .into_values()
+        .map(|(mut history, transitions)| {
+            history.observed_stability =
+                (transitions > 0).then(|| 1.0 - history.changes as f64 / transitions as f64);
+            history
+        })
+        .collect();
+    // least stable first, then by identifier since the map was sorted
+    layers.sort_by(|a, b| {
+        let stability = |l: &LayerHistory| l.observed_stability.unwrap_or(1.0);
+        stability(a).total_cmp(&stability(b))
+    });
+    layers
+}
+
+/// Summarize duplicate `groups`, given the component owning each path and the
+/// layer each component is packed in.
+fn summarize_duplicates(
+    groups: &[DuplicateGroup],
+    owners: &HashMap<Utf8PathBuf, String>,
+    layers: &HashMap<&str, usize>,
+    top: usize,
+) -> DuplicateStats {
+    let owner = |path: &Utf8Path| owners.get(path).map_or("", String::as_str);
+
+    let mut stats = DuplicateStats::default();
+    for group in groups {
+        stats.files += group.paths.len() - 1;
+        stats.size += group.wasted();
+
+        let mut per_layer: HashMap<Option<usize>, u64> = HashMap::new();
+        for path in &group.paths {
+            *per_layer
+                .entry(layers.get(owner(path)).copied())
+                .or_default() += 1;
+        }
+        // all but one copy per layer can be linked
+        let within: u64 = per_layer.values().map(|n| (n - 1) * group.size).sum();
+        stats.within_layers += within;
+        stats.across_layers += group.wasted() - within;
+    }
+
+    // groups are sorted by wasted bytes already
+    stats.top = groups
+        .iter()
+        .take(top)
+        .map(|group| DuplicateReport {
+            size: group.size,
+            components: group
+                .paths
+                .iter()
+                .map(|path| owner(path).to_string())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            paths: group.paths.clone(),
+        })
+        .collect();
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_duplicates() {
+        let groups = vec![
+            DuplicateGroup {
+                size: 100,
+                paths: vec!["/a1".into(), "/a2".into(), "/b1".into()],
+            },
+            DuplicateGroup {
+                size: 10,
+                paths: vec!["/a3".into(), "/c1".into()],
+            },
+        ];
+        let owners: HashMap<Utf8PathBuf, String> = [
+            ("/a1", "rpm/a"),
+            ("/a2", "rpm/a"),
+            ("/a3", "rpm/a"),
+            ("/b1", "rpm/b"),
+            ("/c1", "rpm/c"),
+        ]
+        .into_iter()
+        .map(|(p, c)| (p.into(), c.to_string()))
+        .collect();
+        // a and c share a layer
+        let layers = HashMap::from([("rpm/a", 0), ("rpm/b", 1), ("rpm/c", 0)]);
+
+        let stats = summarize_duplicates(&groups, &owners, &layers, 1);
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.size, 210);
+        assert_eq!(stats.within_layers, 110);
+        assert_eq!(stats.across_layers, 100);
+        assert_eq!(
+            stats.top,
+            vec![DuplicateReport {
+                size: 100,
+                components: vec!["rpm/a".into(), "rpm/b".into()],
+                paths: groups[0].paths.clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_layer_history() {
+        use crate::plan::ContentClass;
+
+        let layer = |id: &str, size, digest: Option<&str>| PlanLayer {
+            id: id.to_string(),
+            components: vec![id.to_string()],
+            size,
+            stability: 0.5,
+            content_class: ContentClass::Hot,
+            estimated_compressed_size: None,
+            digest: digest.map(str::to_string),
+        };
+        let plan = |layers| Plan {
+            layers,
+            ..Default::default()
+        };
+        let plans = vec![
+            plan(vec![
+                layer("rpm/glibc", 10, None),
+                layer("rpm/bash", 5, None),
+            ]),
+            // bash changed size
+            plan(vec![
+                layer("rpm/glibc", 10, None),
+                layer("rpm/bash", 6, None),
+            ]),
+            // digests are compared when known, over sizes
+            plan(vec![
+                layer("rpm/glibc", 10, Some("sha256:aa")),
+                layer("rpm/bash", 6, Some("sha256:bb")),
+                layer("rpm/new", 1, Some("sha256:cc")),
+            ]),
+            plan(vec![
+                layer("rpm/glibc", 11, Some("sha256:aa")),
+                layer("rpm/bash", 6, Some("sha256:b2")),
+                layer("rpm/new", 1, Some("sha256:cc")),
+            ]),
+        ];
+
+        let history = layer_history(&plans);
+        let summary: Vec<(&str, usize, usize, Option<f64>)> = history
+            .iter()
+            .map(|l| (l.id.as_str(), l.builds, l.changes, l.observed_stability))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("rpm/bash", 4, 2, Some(1.0 - 2.0 / 3.0)),
+                ("rpm/glibc", 4, 0, Some(1.0)),
+                ("rpm/new", 2, 0, Some(1.0)),
+            ]
+        );
+        assert_eq!(history[0].predicted_stability, 0.5);
+    }
+}
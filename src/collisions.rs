@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use camino::Utf8Path;
+use clap::ValueEnum;
+
+use crate::components::Component;
+
+/// What to do with paths that differ only by case or unicode normalization.
+/// These can't coexist on case-insensitive or normalizing filesystems (e.g.
+/// macOS defaults, or Windows hosts), where extracting the image silently
+/// merges or overwrites them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PathCollisionPolicy {
+    /// Don't check
+    Ignore,
+    /// Warn about each collision
+    #[default]
+    Warn,
+    /// Fail the build
+    Fail,
+}
+
+/// Paths which would be the same on a case-insensitive or normalizing
+/// filesystem, with their owning components.
+#[derive(Debug, PartialEq)]
+pub struct PathCollision<'a> {
+    pub paths: Vec<(&'a Utf8Path, &'a str)>,
+}
+
+/// Find the paths in `components` which differ only by case or unicode
+/// normalization. Only the topmost collisions are reported, i.e. not those
+/// of the files under colliding directories. Collisions are sorted by path.
+pub fn find_path_collisions(components: &HashMap<String, Component>) -> Vec<PathCollision<'_>> {
+    // entries under colliding directories have different parents; the
+    // collision of the directories themselves is reported instead
+    let mut by_key: HashMap<_, Vec<(&Utf8Path, &str)>> = HashMap::new();
+    for (name, component) in components {
+        for path in component.files.keys() {
+            by_key
+                .entry((fold_path(path.as_str()), path.parent()))
+                .or_default()
+                .push((path, name));
+        }
+    }
+
+    let mut collisions: Vec<PathCollision> = by_key
+        .into_values()
+        .filter_map(|mut paths| {
+            paths.sort();
+            // directories can be shared by several components
+            let mut distinct = paths.iter().map(|(p, _)| p).collect::<Vec<_>>();
+            distinct.dedup();
+            (distinct.len() > 1).then_some(PathCollision { paths })
+        })
+        .collect();
+    collisions.sort_by(|a, b| a.paths.cmp(&b.paths));
+    collisions
+}
+
+/// Check `components` for path collisions according to `policy`.
+pub fn check_path_collisions(
+    components: &HashMap<String, Component>,
+    policy: PathCollisionPolicy,
+) -> Result<()> {
+    let fail = match policy {
+        PathCollisionPolicy::Ignore => return Ok(()),
+        PathCollisionPolicy::Warn => false,
+        PathCollisionPolicy::Fail => true,
+    };
+    let collisions = find_path_collisions(components);
+    if collisions.is_empty() {
+        return Ok(());
+    }
+
+    let describe = |collision: &PathCollision| {
+        collision
+            .paths
+            .iter()
+            .map(|(path, component)| format!("{path} ({component})"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if fail {
+        let list: Vec<String> = collisions.iter().map(describe).collect();
+        anyhow::bail!(
+            "{} path collision(s) on case-insensitive or normalizing filesystems:\n  {}",
+            collisions.len(),
+            list.join("\n  ")
+        );
+    }
+    for collision in &collisions {
+        tracing::warn!(
+            "paths differ only by case or unicode normalization: {}",
+            describe(collision)
+        );
+    }
+    Ok(())
+}
+
+/// Fold `path` so that paths which differ only by case or normalization fold
+/// to the same string: precomposed Latin-1 letters are decomposed into their
+/// base letter and combining mark (as in NFD), then everything is lowercased.
+///
+/// This doesn't implement full unicode normalization, but covers the common
+/// case of accented Latin letters.
+fn fold_path(path: &str) -> String {
+    let mut folded = String::with_capacity(path.len());
+    for c in path.chars() {
+        match decompose_latin1(c) {
+            Some((base, mark)) => {
+                folded.extend(base.to_lowercase());
+                folded.push(mark);
+            }
+            None => folded.extend(c.to_lowercase()),
+        }
+    }
+    folded
+}
+
+/// Canonical decomposition of the precomposed letters of the Latin-1
+/// Supplement block.
+fn decompose_latin1(c: char) -> Option<(char, char)> {
+    const GRAVE: char = '\u{300}';
+    const ACUTE: char = '\u{301}';
+    const CIRCUMFLEX: char = '\u{302}';
+    const TILDE: char = '\u{303}';
+    const DIAERESIS: char = '\u{308}';
+    const RING: char = '\u{30A}';
+    const CEDILLA: char = '\u{327}';
+
+    // the lowercase letters are 0x20 above the uppercase ones
+    let (upper, lower) = if ('\u{E0}'..='\u{FF}').contains(&c) {
+        (char::from_u32(c as u32 - 0x20)?, true)
+    } else {
+        (c, false)
+    };
+    let (base, mark) = match upper {
+        'À' => ('A', GRAVE),
+        'Á' => ('A', ACUTE),
+        'Â' => ('A', CIRCUMFLEX),
+        'Ã' => ('A', TILDE),
+        'Ä' => ('A', DIAERESIS),
+        'Å' => ('A', RING),
+        'Ç' => ('C', CEDILLA),
+        'È' => ('E', GRAVE),
+        'É' => ('E', ACUTE),
+        'Ê' => ('E', CIRCUMFLEX),
+        'Ë' => ('E', DIAERESIS),
+        'Ì' => ('I', GRAVE),
+        'Í' => ('I', ACUTE),
+        'Î' => ('I', CIRCUMFLEX),
+        'Ï' => ('I', DIAERESIS),
+        'Ñ' => ('N', TILDE),
+        'Ò' => ('O', GRAVE),
+        'Ó' => ('O', ACUTE),
+        'Ô' => ('O', CIRCUMFLEX),
+        'Õ' => ('O', TILDE),
+        'Ö' => ('O', DIAERESIS),
+        'Ù' => ('U', GRAVE),
+        'Ú' => ('U', ACUTE),
+        'Û' => ('U', CIRCUMFLEX),
+        'Ü' => ('U', DIAERESIS),
+        'Ý' => ('Y', ACUTE),
+        // ÿ is the only one whose uppercase isn't in the block
+        '\u{DF}' if lower => ('Y', DIAERESIS),
+        _ => return None,
+    };
+    Some((
+        if lower {
+            base.to_ascii_lowercase()
+        } else {
+            base
+        },
+        mark,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    use super::*;
+    use crate::components::{FileInfo, FileMap, FileType};
+
+    #[test]
+    fn test_fold_path() {
+        assert_eq!(fold_path("/usr/Share/README"), "/usr/share/readme");
+        // precomposed and decomposed forms fold the same
+        assert_eq!(fold_path("/caf\u{E9}"), fold_path("/cafe\u{301}"));
+        assert_eq!(fold_path("/CAF\u{C9}"), fold_path("/cafe\u{301}"));
+        assert_eq!(fold_path("/\u{FF}"), fold_path("/y\u{308}"));
+        // but accents aren't dropped
+        assert_ne!(fold_path("/caf\u{E9}"), fold_path("/cafe"));
+        // ß (0xDF) and ÷ (0xF7) aren't decomposable
+        assert_eq!(fold_path("/\u{DF}\u{F7}"), "/\u{DF}\u{F7}");
+    }
+
+    #[test]
+    fn test_find_path_collisions() {
+        let component = |paths: &[&str]| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: paths
+                .iter()
+                .map(|p| (Utf8PathBuf::from(*p), FileInfo::dummy(FileType::File)))
+                .collect::<FileMap>(),
+        };
+        let components = HashMap::from([
+            (
+                "rpm/a".to_string(),
+                component(&["/etc/Foo", "/dir/X", "/dir/X/f", "/caf\u{E9}"]),
+            ),
+            (
+                "rpm/b".to_string(),
+                component(&["/etc/foo", "/dir/x", "/dir/x/f", "/cafe\u{301}", "/bar"]),
+            ),
+        ]);
+
+        let collisions = find_path_collisions(&components);
+        let paths: Vec<Vec<(&str, &str)>> = collisions
+            .iter()
+            .map(|c| c.paths.iter().map(|(p, c)| (p.as_str(), *c)).collect())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec![("/cafe\u{301}", "rpm/b"), ("/caf\u{E9}", "rpm/a")],
+                vec![("/dir/X", "rpm/a"), ("/dir/x", "rpm/b")],
+                vec![("/etc/Foo", "rpm/a"), ("/etc/foo", "rpm/b")],
+            ]
+        );
+
+        assert!(check_path_collisions(&components, PathCollisionPolicy::Warn).is_ok());
+        assert!(check_path_collisions(&components, PathCollisionPolicy::Ignore).is_ok());
+        let err = check_path_collisions(&components, PathCollisionPolicy::Fail).unwrap_err();
+        assert!(err.to_string().contains("/etc/foo (rpm/b)"), "{err}");
+
+        // directories shared by components don't collide with themselves
+        let components = HashMap::from([
+            ("rpm/a".to_string(), component(&["/usr/bin", "/usr/bin/a"])),
+            ("rpm/b".to_string(), component(&["/usr/bin", "/usr/bin/b"])),
+        ]);
+        assert!(find_path_collisions(&components).is_empty());
+        assert!(check_path_collisions(&components, PathCollisionPolicy::Fail).is_ok());
+
+        // a collision under a colliding directory is still found when other
+        // paths with the same fold are under the other directory
+        let components = HashMap::from([
+            (
+                "rpm/a".to_string(),
+                component(&["/dir/X", "/dir/X/f", "/dir/X/F"]),
+            ),
+            ("rpm/b".to_string(), component(&["/dir/x", "/dir/x/f"])),
+        ]);
+        let collisions = find_path_collisions(&components);
+        let paths: Vec<Vec<&str>> = collisions
+            .iter()
+            .map(|c| c.paths.iter().map(|(p, _)| p.as_str()).collect())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![vec!["/dir/X", "/dir/x"], vec!["/dir/X/F", "/dir/X/f"]]
+        );
+    }
+}
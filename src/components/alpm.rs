@@ -782,8 +782,8 @@ mod tests {
 
         // Multiple packages in base have gcc as their basename, so in terms of component names, they will be seen multiple times
         let mut expected_components = IMPORTANT_ARCH_LINUX_PACKAGES_IN_BASE
-            .into_iter()
-            .map(|pkg| *pkg)
+            .iter()
+            .copied()
             .collect::<BTreeSet<_>>();
         let component_info = claims.iter().map(|claim| alpm.component_info(*claim));
         for component in component_info {
@@ -937,16 +937,13 @@ etc/services	b80b33810d79289b09bac307a99b4b54
         ];
         let mut expected_paths_set = EXPECTED_PATHS
             .iter()
-            .map(|path| Utf8Path::new(path))
+            .map(Utf8Path::new)
             .collect::<BTreeSet<_>>();
 
         let parsed_files = FILES_CONTENT.parse::<LocalAlpmDbFile>().unwrap();
 
         // Test that the generic parser can parse other sections, such as BACKUP
-        let mut other_section = parsed_files
-            .get_multi_line_value("BACKUP")
-            .unwrap()
-            .into_iter();
+        let mut other_section = parsed_files.get_multi_line_value("BACKUP").unwrap().iter();
         assert_eq!(
             other_section.next().unwrap(),
             "etc/protocols\tb9833a5373ef2f5df416f4f71ccb42eb"
@@ -0,0 +1,81 @@
+use super::FileType;
+use super::pathlist::PathList;
+
+/// Caches which are regenerated from the content of other packages, usually by
+/// scriptlets or file triggers, whenever any of those packages change. Only
+/// files are claimed.
+///
+/// Any package update touching icons, libraries, modules or fonts regenerates
+/// some of these, so they're treated like a daily-updated component.
+pub const CACHES: PathList = PathList {
+    repo: "caches",
+    component: "regenerated",
+    patterns: &[
+        // gtk-update-icon-cache
+        "/usr/share/icons/*/icon-theme.cache",
+        // ldconfig
+        "/etc/ld.so.cache",
+        "/var/cache/ldconfig/aux-cache",
+        // gtk-query-immodules-*
+        "/usr/lib*/gtk-*/*/immodules.cache",
+        // gdk-pixbuf-query-loaders
+        "/usr/lib*/gdk-pixbuf-2.0/*/loaders.cache",
+        // gio-querymodules
+        "/usr/lib*/gio/modules/giomodule.cache",
+        // glib-compile-schemas
+        "/usr/share/glib-2.0/schemas/gschemas.compiled",
+        // fc-cache
+        "/usr/lib/fontconfig/cache/*",
+        "/var/cache/fontconfig/*",
+    ],
+    file_types: &[FileType::File],
+    update_interval_days: 1,
+};
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::*;
+    use crate::components::pathlist::PathListRepo;
+    use crate::components::{ComponentsRepo, FileInfo, FileMap};
+
+    #[test]
+    fn test_caches_claims() {
+        let mut files = FileMap::new();
+        files.insert(
+            "/usr/share/icons/hicolor/icon-theme.cache".into(),
+            FileInfo::dummy(FileType::File),
+        );
+        let repo = PathListRepo::load(&CACHES, &files, &[], 0).unwrap();
+
+        let claimed = |path: &str, file_type| {
+            !repo
+                .strong_claims_for_path(Utf8Path::new(path), &FileInfo::dummy(file_type))
+                .is_empty()
+        };
+        assert!(claimed(
+            "/usr/share/icons/hicolor/icon-theme.cache",
+            FileType::File
+        ));
+        assert!(claimed("/etc/ld.so.cache", FileType::File));
+        assert!(claimed(
+            "/usr/lib64/gtk-3.0/3.0.0/immodules.cache",
+            FileType::File
+        ));
+        assert!(claimed(
+            "/usr/lib/fontconfig/cache/abcd-le64.cache-9",
+            FileType::File
+        ));
+        // only files; directories stay with their owners
+        assert!(!claimed("/usr/lib/fontconfig/cache", FileType::Directory));
+        assert!(!claimed(
+            "/usr/share/icons/hicolor/index.theme",
+            FileType::File
+        ));
+        assert!(!claimed("/etc/ld.so.conf", FileType::File));
+
+        // nothing to claim
+        assert!(PathListRepo::load(&CACHES, &FileMap::new(), &[], 0).is_none());
+    }
+}
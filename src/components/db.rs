@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use crate::utils::interval_to_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "db";
+
+/// Update interval assumed for components without a stability, as for xattr
+/// components.
+const UPDATE_INTERVAL_DEFAULT: u64 = 7;
+
+/// A components database, as produced by an earlier pipeline stage or
+/// another tool which already knows which component owns each file.
+///
+/// This is a superset of the manifest written by `--write-manifest-to`, so
+/// that one can be fed back as is.
+#[derive(Debug, Deserialize)]
+struct ComponentsDb {
+    components: BTreeMap<String, DbComponent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DbComponent {
+    /// Absolute paths of the files of the component.
+    files: Vec<Utf8PathBuf>,
+    /// Probability that the component doesn't change over a week.
+    #[serde(default)]
+    stability: Option<f64>,
+    /// Whether the component must get a layer of its own.
+    #[serde(default)]
+    isolated: bool,
+    /// Clamp for the mtimes of the files of the component.
+    #[serde(default)]
+    mtime_clamp: Option<u64>,
+}
+
+/// Components repo loaded from a components database file.
+///
+/// When given, this replaces detection entirely: paths are claimed exactly as
+/// listed, and anything not listed ends up unclaimed. Directories may be
+/// listed by several components; other files may not.
+pub struct DbRepo {
+    /// Component names, indexed by ComponentId.
+    names: Vec<String>,
+    /// Per-component info, indexed by ComponentId.
+    components: Vec<DbComponent>,
+    /// Mapping from path to the components listing it.
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+    default_mtime_clamp: u64,
+}
+
+impl DbRepo {
+    /// Load the components database at `path`, checking it against the paths
+    /// in `files`.
+    pub fn load(path: &Utf8Path, files: &FileMap, default_mtime_clamp: u64) -> Result<Self> {
+        let json = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::from_json(&json, files, default_mtime_clamp)
+            .with_context(|| format!("parsing {path}"))
+    }
+
+    fn from_json(json: &str, files: &FileMap, default_mtime_clamp: u64) -> Result<Self> {
+        let db: ComponentsDb = serde_json::from_str(json)?;
+        let mut names = Vec::with_capacity(db.components.len());
+        let mut components = Vec::with_capacity(db.components.len());
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>> = HashMap::new();
+        let mut missing = 0usize;
+
+        for (idx, (name, component)) in db.components.into_iter().enumerate() {
+            if let Some(stability) = component.stability {
+                anyhow::ensure!(
+                    (0.0..=1.0).contains(&stability),
+                    "invalid stability for component {name}: {stability} is not between 0 and 1"
+                );
+            }
+            for file in &component.files {
+                anyhow::ensure!(
+                    file.is_absolute(),
+                    "invalid path for component {name}: {file} is not absolute"
+                );
+                let Some(file_info) = files.get(file) else {
+                    tracing::trace!(path = %file, component = %name, "db path not in rootfs");
+                    missing += 1;
+                    continue;
+                };
+                let ids = path_to_components.entry(file.clone()).or_default();
+                if ids.contains(&ComponentId(idx)) {
+                    continue;
+                }
+                if let Some(other) = ids.first()
+                    && file_info.file_type != FileType::Directory
+                {
+                    anyhow::bail!(
+                        "{file} is listed by both components {} and {name}",
+                        names[other.0]
+                    );
+                }
+                ids.push(ComponentId(idx));
+            }
+            names.push(name);
+            components.push(component);
+        }
+
+        if missing > 0 {
+            tracing::warn!(
+                paths = missing,
+                "paths in components db not found in rootfs"
+            );
+        }
+        tracing::debug!(
+            components = names.len(),
+            paths = path_to_components.len(),
+            "loaded components db"
+        );
+
+        Ok(Self {
+            names,
+            components,
+            path_to_components,
+            default_mtime_clamp,
+        })
+    }
+}
+
+impl ComponentsRepo for DbRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // it's the only repo when loaded
+        0
+    }
+
+    fn strong_claims_for_path(
+        &self,
+        path: &Utf8Path,
+        _file_info: &super::FileInfo,
+    ) -> Vec<ComponentId> {
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let component = &self.components[id.0];
+        ComponentInfo {
+            name: &self.names[id.0],
+            mtime_clamp: component.mtime_clamp.unwrap_or(self.default_mtime_clamp),
+            stability: component
+                .stability
+                .unwrap_or_else(|| interval_to_stability(UPDATE_INTERVAL_DEFAULT)),
+        }
+    }
+
+    fn is_isolated(&self, id: ComponentId) -> bool {
+        self.components[id.0].isolated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::FileInfo;
+
+    fn files() -> FileMap {
+        FileMap::from([
+            ("/usr".into(), FileInfo::dummy(FileType::Directory)),
+            ("/usr/bin".into(), FileInfo::dummy(FileType::Directory)),
+            ("/usr/bin/app".into(), FileInfo::dummy(FileType::File)),
+            ("/usr/bin/tool".into(), FileInfo::dummy(FileType::File)),
+        ])
+    }
+
+    fn claims<'a>(repo: &'a DbRepo, path: &str) -> Vec<&'a str> {
+        repo.strong_claims_for_path(Utf8Path::new(path), &FileInfo::dummy(FileType::File))
+            .into_iter()
+            .map(|id| repo.component_info(id).name)
+            .collect()
+    }
+
+    #[test]
+    fn test_db_claims() {
+        // as written by --write-manifest-to, with extra fields
+        let json = r#"{
+            "components": {
+                "app": {
+                    "file_count": 3,
+                    "files": ["/usr/bin", "/usr/bin/app", "/usr/bin/gone"],
+                    "stability": 0.5,
+                    "isolated": true
+                },
+                "tool": {"files": ["/usr/bin", "/usr/bin/tool"], "mtime_clamp": 10}
+            }
+        }"#;
+        let repo = DbRepo::from_json(json, &files(), 100).unwrap();
+        assert_eq!(claims(&repo, "/usr/bin/app"), ["app"]);
+        assert_eq!(claims(&repo, "/usr/bin"), ["app", "tool"]);
+        assert!(claims(&repo, "/usr").is_empty());
+
+        let app = repo.strong_claims_for_path(
+            Utf8Path::new("/usr/bin/app"),
+            &FileInfo::dummy(FileType::File),
+        )[0];
+        let info = repo.component_info(app);
+        assert_eq!((info.mtime_clamp, info.stability), (100, 0.5));
+        assert!(repo.is_isolated(app));
+        let tool = ComponentId(1);
+        assert_eq!(repo.component_info(tool).mtime_clamp, 10);
+        assert!(!repo.is_isolated(tool));
+    }
+
+    #[test]
+    fn test_db_invalid() {
+        let err = |json: &str| {
+            DbRepo::from_json(json, &files(), 0)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err(
+                r#"{"components": {"a": {"files": ["/usr/bin/app"]}, "b": {"files": ["/usr/bin/app"]}}}"#
+            ),
+            "/usr/bin/app is listed by both components a and b"
+        );
+        assert_eq!(
+            err(r#"{"components": {"a": {"files": ["usr/bin/app"]}}}"#),
+            "invalid path for component a: usr/bin/app is not absolute"
+        );
+        assert_eq!(
+            err(r#"{"components": {"a": {"files": [], "stability": 2.0}}}"#),
+            "invalid stability for component a: 2 is not between 0 and 1"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::ops::Bound;
+
+use camino::Utf8Path;
+
+use crate::utils::interval_to_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap};
+
+const REPO_NAME: &str = "docs";
+
+/// Documentation trees and the name of the component their content goes to.
+const DOCS_DIRS: &[(&str, &str)] = &[
+    ("/usr/share/doc", "doc"),
+    ("/usr/share/info", "info"),
+    ("/usr/share/man", "man"),
+];
+
+/// Docs change whenever any package shipping them is updated, so there's no
+/// single update cadence to derive. Nothing depends on them being current
+/// though, so treat them like a quarterly-updated component; this classifies
+/// them as cold.
+const UPDATE_INTERVAL_DAYS: u64 = 90;
+
+/// Documentation components repo implementation.
+///
+/// Claims everything under the well-known documentation trees into one
+/// component per tree, regardless of which package owns the files. Docs are
+/// "cold" content: they rarely matter at runtime yet they pad package
+/// components, meaning a layer gets invalidated for e.g. a changelog update.
+/// Splitting them out keeps package layers focused on what actually runs.
+///
+/// The directories themselves are left to their owners; only their contents
+/// are claimed.
+pub struct DocsRepo {
+    /// Indices into DOCS_DIRS of the trees present in the rootfs.
+    present: Vec<usize>,
+    default_mtime_clamp: u64,
+}
+
+impl DocsRepo {
+    /// Load the docs repo if any documentation tree has content in `files`.
+    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+        let present: Vec<usize> = DOCS_DIRS
+            .iter()
+            .enumerate()
+            .filter(|(_, (dir, _))| {
+                files
+                    .range::<Utf8Path, _>((Bound::Excluded(Utf8Path::new(dir)), Bound::Unbounded))
+                    .next()
+                    .is_some_and(|(path, _)| path.starts_with(dir))
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+
+        if present.is_empty() {
+            return None;
+        }
+
+        tracing::debug!(trees = present.len(), "loaded docs components");
+
+        Some(Self {
+            present,
+            default_mtime_clamp,
+        })
+    }
+}
+
+impl ComponentsRepo for DocsRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Above package repos, since the whole point is to take files away
+        // from them, but below xattrs, which are explicit user intent.
+        5
+    }
+
+    fn strong_claims_for_path(
+        &self,
+        path: &Utf8Path,
+        _file_info: &super::FileInfo,
+    ) -> Vec<ComponentId> {
+        self.present
+            .iter()
+            .find(|&&idx| {
+                let dir = DOCS_DIRS[idx].0;
+                path.starts_with(dir) && path.as_str() != dir
+            })
+            .map(|&idx| vec![ComponentId(idx)])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        ComponentInfo {
+            name: DOCS_DIRS[id.0].1,
+            mtime_clamp: self.default_mtime_clamp,
+            stability: interval_to_stability(UPDATE_INTERVAL_DAYS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::cap_std::fs::Dir;
+
+    use super::*;
+    use crate::components::{FileInfo, FileType};
+
+    #[test]
+    fn test_docs_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/share/doc/bash").unwrap();
+        rootfs.write("usr/share/doc/bash/README", "docs").unwrap();
+        rootfs.create_dir_all("usr/share/man/man1").unwrap();
+        rootfs.write("usr/share/man/man1/bash.1.gz", "man").unwrap();
+        rootfs.create_dir_all("usr/share/info").unwrap();
+        rootfs.create_dir_all("usr/share/docs-not-really").unwrap();
+        rootfs.write("usr/share/docs-not-really/file", "x").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = DocsRepo::load(&files, 0).unwrap();
+
+        let claim = |path: &str| -> Option<&str> {
+            let claims =
+                repo.strong_claims_for_path(Utf8Path::new(path), &FileInfo::dummy(FileType::File));
+            assert!(claims.len() <= 1, "{path} should have at most one claim");
+            claims.first().map(|id| repo.component_info(*id).name)
+        };
+
+        assert_eq!(claim("/usr/share/doc/bash"), Some("doc"));
+        assert_eq!(claim("/usr/share/doc/bash/README"), Some("doc"));
+        assert_eq!(claim("/usr/share/man/man1/bash.1.gz"), Some("man"));
+        let stability = repo.component_info(ComponentId(0)).stability;
+        assert_eq!(
+            crate::plan::ContentClass::from_stability(stability),
+            crate::plan::ContentClass::Cold
+        );
+
+        // the trees themselves stay with their owners
+        assert_eq!(claim("/usr/share/doc"), None);
+        assert_eq!(claim("/usr/share/man"), None);
+
+        // empty tree isn't loaded
+        assert_eq!(claim("/usr/share/info/foo.info.gz"), None);
+
+        // not a docs tree, despite the prefix
+        assert_eq!(claim("/usr/share/docs-not-really/file"), None);
+    }
+
+    #[test]
+    fn test_docs_no_trees() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/share/doc").unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.write("usr/bin/bash", "bash").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        assert!(DocsRepo::load(&files, 0).is_none());
+    }
+}
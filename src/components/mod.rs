@@ -1,13 +1,26 @@
 mod alpm;
 mod bigfiles;
+mod caches;
+mod db;
+mod docs;
+mod pathlist;
+mod pkgdb;
+mod pki;
 mod rpm;
+mod volatile;
 mod xattr;
 
+pub use xattr::COMPONENT_XATTRS;
+
 use std::collections::{BTreeMap, HashMap};
 
 /// The name of the component for files not claimed by any repo.
 pub const UNCLAIMED_COMPONENT: &str = "chunkah/unclaimed";
 
+/// Version of the scheme used to derive component names, recorded in plans
+/// so that consumers can tell how to interpret them. See [`canonical_name`].
+pub const NAMING_SCHEME: u32 = 1;
+
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::{Dir, FileType as CapFileType, Metadata, MetadataExt};
@@ -30,6 +43,33 @@ pub struct ComponentsRepos {
     default_mtime_clamp: u64,
 }
 
+/// The rule which assigned a path to its component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimRule {
+    /// Claimed by the authoritative database of a repo (e.g. the rpmdb).
+    Strong(&'static str),
+    /// Claimed by the heuristics of a repo.
+    Weak(&'static str),
+    /// A build-id link, assigned to the component of the file it points to.
+    BuildIdLink,
+    /// Not claimed by anything.
+    Unclaimed,
+}
+
+impl std::fmt::Display for ClaimRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClaimRule::Strong(repo) => write!(f, "{repo}:strong"),
+            ClaimRule::Weak(repo) => write!(f, "{repo}:weak"),
+            ClaimRule::BuildIdLink => write!(f, "build-id-link"),
+            ClaimRule::Unclaimed => write!(f, "unclaimed"),
+        }
+    }
+}
+
+/// The rule which assigned each path to its component.
+pub type ClaimRules = HashMap<Utf8PathBuf, ClaimRule>;
+
 /// Files belonging to a component.
 #[derive(Debug, Clone)]
 pub struct Component {
@@ -39,6 +79,9 @@ pub struct Component {
     /// Probability that the component doesn't change over STABILITY_PERIOD_DAYS.
     /// Used by the packing algorithm.
     pub stability: f64,
+    /// Whether the component must get a layer of its own rather than being
+    /// merged with others during packing.
+    pub isolated: bool,
     /// The files belonging to this component, with their metadata.
     pub files: FileMap,
 }
@@ -58,6 +101,14 @@ pub struct FileInfo {
     pub ino: u64,
     pub nlink: u64,
     pub xattrs: Vec<(String, Vec<u8>)>,
+    /// Where to read the file from in the rootfs, if not at its own path
+    /// (e.g. because it was relocated by a rewrite rule).
+    pub source: Option<Utf8PathBuf>,
+    /// Symlink target to write instead of the one on disk.
+    pub link_target: Option<Utf8PathBuf>,
+    /// Whether to mark the directory opaque in its layer, hiding what lower
+    /// layers have in it.
+    pub opaque: bool,
 }
 
 /// File type for entries in the rootfs.
@@ -102,20 +153,98 @@ impl FileInfo {
             ino: metadata.ino(),
             nlink: metadata.nlink(),
             xattrs,
+            source: None,
+            link_target: None,
+            opaque: false,
         }
     }
 }
 
-impl ComponentsRepos {
-    /// Detect and load all component repos present in the given rootfs.
+/// Builder for detecting and loading the component repos of a rootfs.
+pub struct ReposLoader<'a> {
+    rootfs: &'a Dir,
+    files: &'a FileMap,
+    default_mtime_clamp: u64,
+    docs_layer: bool,
+    pki_layer: bool,
+    volatile_patterns: Vec<String>,
+    components_db: Option<Utf8PathBuf>,
+}
+
+impl<'a> ReposLoader<'a> {
+    /// Create a new loader for the given rootfs.
     ///
     /// The `files` map is the set of paths in the rootfs. This avoids the xattr
     /// repo having to walk the rootfs again. The `default_mtime_clamp` will be
     /// used as the mtime clamp for components that don't have a reproducible
     /// clamp (e.g. xattr-claimed files, unclaimed files).
-    pub fn load(rootfs: &Dir, files: &FileMap, default_mtime_clamp: u64) -> Result<Self> {
+    pub fn new(rootfs: &'a Dir, files: &'a FileMap, default_mtime_clamp: u64) -> Self {
+        Self {
+            rootfs,
+            files,
+            default_mtime_clamp,
+            docs_layer: false,
+            pki_layer: false,
+            volatile_patterns: Vec::new(),
+            components_db: None,
+        }
+    }
+
+    /// Split documentation trees into their own components.
+    ///
+    /// By default, docs stay with the packages that own them.
+    pub fn docs_layer(mut self, enabled: bool) -> Self {
+        self.docs_layer = enabled;
+        self
+    }
+
+    /// Group CA certificates and trust stores into their own component.
+    ///
+    /// By default, they stay with the packages that own them (or unclaimed, for
+    /// generated bundles).
+    pub fn pki_layer(mut self, enabled: bool) -> Self {
+        self.pki_layer = enabled;
+        self
+    }
+
+    /// Also treat paths matching these patterns as volatile state, in addition
+    /// to the built-in ones.
+    pub fn volatile_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.volatile_patterns = patterns;
+        self
+    }
+
+    /// Take components from the components database at `path` instead of
+    /// detecting them. See [`db::DbRepo`].
+    pub fn components_db(mut self, path: Option<Utf8PathBuf>) -> Self {
+        self.components_db = path;
+        self
+    }
+
+    /// Detect and load all component repos present in the rootfs.
+    pub fn load(self) -> Result<ComponentsRepos> {
+        let Self {
+            rootfs,
+            files,
+            default_mtime_clamp,
+            docs_layer,
+            pki_layer,
+            volatile_patterns,
+            components_db,
+        } = self;
         let mut repos: Vec<Box<dyn ComponentsRepo>> = Vec::new();
 
+        if let Some(path) = components_db {
+            let repo = db::DbRepo::load(&path, files, default_mtime_clamp)
+                .context("loading components db")?;
+            tracing::info!(repo = "db", "loaded repo");
+            repos.push(Box::new(repo));
+            return Ok(ComponentsRepos {
+                repos,
+                default_mtime_clamp,
+            });
+        }
+
         if let Some(repo) =
             xattr::XattrRepo::load(files, default_mtime_clamp).context("loading xattrs")?
         {
@@ -123,6 +252,38 @@ impl ComponentsRepos {
             repos.push(Box::new(repo));
         }
 
+        if docs_layer && let Some(repo) = docs::DocsRepo::load(files, default_mtime_clamp) {
+            tracing::info!(repo = "docs", "loaded repo");
+            repos.push(Box::new(repo));
+        }
+
+        if pki_layer && let Some(repo) = pki::PkiRepo::load(files, default_mtime_clamp) {
+            tracing::info!(repo = "pki", "loaded repo");
+            repos.push(Box::new(repo));
+        }
+
+        if let Some(repo) =
+            pathlist::PathListRepo::load(&caches::CACHES, files, &[], default_mtime_clamp)
+        {
+            tracing::info!(repo = "caches", "loaded repo");
+            repos.push(Box::new(repo));
+        }
+
+        if let Some(repo) = pathlist::PathListRepo::load(
+            &volatile::VOLATILE,
+            files,
+            &volatile_patterns,
+            default_mtime_clamp,
+        ) {
+            tracing::info!(repo = "volatile", "loaded repo");
+            repos.push(Box::new(repo));
+        }
+
+        if let Some(repo) = pkgdb::PkgdbRepo::load(files, default_mtime_clamp) {
+            tracing::info!(repo = "pkgdb", "loaded repo");
+            repos.push(Box::new(repo));
+        }
+
         if let Some(repo) =
             rpm::RpmRepo::load(rootfs, files, default_mtime_clamp).context("loading rpmdb")?
         {
@@ -144,26 +305,40 @@ impl ComponentsRepos {
 
         // Other backends (e.g. deb, apk, pip, etc.) would go here...
 
-        Ok(Self {
+        Ok(ComponentsRepos {
             repos,
             default_mtime_clamp,
         })
     }
+}
 
+impl ComponentsRepos {
     /// Returns true if no repos were loaded.
     pub fn is_empty(&self) -> bool {
         self.repos.is_empty()
     }
 
     /// Claim files from repos and return the mapping of component names to files.
+    #[cfg(test)]
+    pub fn into_components(
+        self,
+        rootfs: &Dir,
+        files: FileMap,
+    ) -> Result<HashMap<String, Component>> {
+        self.into_components_with_rules(rootfs, files, None)
+    }
+
+    /// Claim files from repos and return the mapping of component names to
+    /// files, also recording the rule which assigned each path in `rules`.
     ///
     /// Repos are sorted by priority (lower values first) before processing.
     /// Higher priority repos "win" - if they claim a path, lower priority repos
     /// are not consulted for that path. All unclaimed paths go into a catch-all.
-    pub fn into_components(
+    pub fn into_components_with_rules(
         mut self,
         rootfs: &Dir,
         files: FileMap,
+        mut rules: Option<&mut ClaimRules>,
     ) -> Result<HashMap<String, Component>> {
         let mut claims: HashMap<(usize, ComponentId), FileMap> = HashMap::new();
 
@@ -183,6 +358,7 @@ impl ComponentsRepos {
             unclaimed,
             &mut claims,
             ClaimStrength::Strong,
+            rules.as_deref_mut(),
         )
         .context("strong claims pass")?;
         let unclaimed = claim_pass(
@@ -191,8 +367,11 @@ impl ComponentsRepos {
             unclaimed,
             &mut claims,
             ClaimStrength::Weak,
+            rules.as_deref_mut(),
         )
         .context("weak claims pass")?;
+        let unclaimed = claim_build_id_links(rootfs, unclaimed, &mut claims, rules.as_deref_mut())
+            .context("build-id links pass")?;
 
         #[derive(Default)]
         struct RepoStats {
@@ -200,13 +379,26 @@ impl ComponentsRepos {
             total_size: u64,
         }
 
-        // build final components map, tracking per-repo stats
+        // build final components map, tracking per-repo stats; go in repo and
+        // component order so that disambiguated names are deterministic
+        let mut claims: Vec<_> = claims.into_iter().collect();
+        claims.sort_by_key(|(key, _)| *key);
         let mut repo_stats: BTreeMap<usize, RepoStats> = BTreeMap::new();
         let mut components = HashMap::new();
         for ((repo_idx, comp_id), files) in claims {
             let repo = &self.repos[repo_idx];
             let info = repo.component_info(comp_id);
-            let full_name = format!("{}/{}", repo.name(), info.name);
+            let mut full_name = canonical_name(repo.name(), info.name);
+            if components.contains_key(&full_name) {
+                let mut n = 2;
+                let mut unique = format!("{full_name}~{n}");
+                while components.contains_key(&unique) {
+                    n += 1;
+                    unique = format!("{full_name}~{n}");
+                }
+                tracing::warn!(component = %full_name, renamed = %unique, "duplicate component name");
+                full_name = unique;
+            }
             let stats = repo_stats.entry(repo_idx).or_default();
             stats.components += 1;
             stats.total_size += files.values().map(|f| f.size).sum::<u64>();
@@ -215,6 +407,7 @@ impl ComponentsRepos {
                 Component {
                     mtime_clamp: info.mtime_clamp,
                     stability: info.stability,
+                    isolated: repo.is_isolated(comp_id),
                     files,
                 },
             );
@@ -228,6 +421,9 @@ impl ComponentsRepos {
 
         // and the catch-all component for anything still unclaimed
         if !unclaimed.is_empty() {
+            if let Some(rules) = rules {
+                rules.extend(unclaimed.keys().map(|p| (p.clone(), ClaimRule::Unclaimed)));
+            }
             let size: u64 = unclaimed.values().map(|f| f.size).sum();
             tracing::info!(files = unclaimed.len(), size = %utils::format_size(size), "unclaimed files");
             components.insert(
@@ -235,6 +431,7 @@ impl ComponentsRepos {
                 Component {
                     mtime_clamp: self.default_mtime_clamp,
                     stability: 0.0,
+                    isolated: false,
                     files: unclaimed,
                 },
             );
@@ -244,6 +441,29 @@ impl ComponentsRepos {
     }
 }
 
+/// Build the canonical name of component `name` of repo `repo`, i.e.
+/// `repo/name` with any byte of `name` outside of ASCII alphanumerics and
+/// `._+-@:/` percent-encoded.
+///
+/// This keeps names free of whitespace (which separates merged component names
+/// in layer annotations), glob wildcards and unicode, while staying injective
+/// so that distinct names never collide. `/` is kept since some repos (e.g.
+/// bigfiles) use paths as names; it's unambiguous because repo names never
+/// contain one. `~` is reserved for disambiguating duplicate names.
+pub fn canonical_name(repo: &str, name: &str) -> String {
+    let mut canonical = String::with_capacity(repo.len() + 1 + name.len());
+    canonical.push_str(repo);
+    canonical.push('/');
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"._+-@:/".contains(&byte) {
+            canonical.push(byte as char);
+        } else {
+            canonical.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    canonical
+}
+
 /// Whether to use strong or weak claims in a claiming pass.
 #[derive(Debug)]
 enum ClaimStrength {
@@ -259,6 +479,7 @@ fn claim_pass(
     files: FileMap,
     claims: &mut HashMap<(usize, ComponentId), FileMap>,
     strength: ClaimStrength,
+    mut rules: Option<&mut ClaimRules>,
 ) -> Result<FileMap> {
     let mut unclaimed = FileMap::new();
     // This is O(files x repos), though really the number of active
@@ -273,6 +494,13 @@ fn claim_pass(
             .with_context(|| format!("claiming {path}"))?;
             if !ids.is_empty() {
                 tracing::trace!(path = %path, repo_idx, ids = ?ids, ?strength, "path claimed");
+                if let Some(rules) = rules.as_deref_mut() {
+                    let rule = match strength {
+                        ClaimStrength::Strong => ClaimRule::Strong(repo.name()),
+                        ClaimStrength::Weak => ClaimRule::Weak(repo.name()),
+                    };
+                    rules.insert(path.clone(), rule);
+                }
                 for id in ids {
                     claims
                         .entry((repo_idx, id))
@@ -291,6 +519,104 @@ fn claim_pass(
     Ok(unclaimed)
 }
 
+/// Directories holding build-id symlinks, which point to the binary (or
+/// debuginfo file) with that build ID.
+const BUILD_ID_DIRS: &[&str] = &["/usr/lib/.build-id/", "/usr/lib/debug/.build-id/"];
+
+/// Attach unclaimed build-id symlinks to the component owning the file they
+/// point to, so that they change along with it rather than bloating the
+/// unclaimed component. Returns the files that are still unclaimed.
+fn claim_build_id_links(
+    rootfs: &Dir,
+    files: FileMap,
+    claims: &mut HashMap<(usize, ComponentId), FileMap>,
+    mut rules: Option<&mut ClaimRules>,
+) -> Result<FileMap> {
+    let is_build_id_link = |path: &Utf8Path, file_info: &FileInfo| {
+        file_info.file_type == FileType::Symlink
+            && BUILD_ID_DIRS.iter().any(|dir| path.starts_with(dir))
+    };
+
+    // resolve the targets of all the candidate links first
+    let mut targets: HashMap<Utf8PathBuf, Utf8PathBuf> = HashMap::new();
+    for (path, file_info) in &files {
+        if is_build_id_link(path, file_info)
+            && let Some(target) = resolve_build_id_link(rootfs, &files, path)?
+        {
+            targets.insert(path.clone(), target);
+        }
+    }
+    if targets.is_empty() {
+        return Ok(files);
+    }
+
+    // find the owners of the targets; if shared, pick the first for determinism
+    let mut owners: HashMap<&Utf8Path, (usize, ComponentId)> = HashMap::new();
+    let mut keys: Vec<_> = claims.keys().copied().collect();
+    keys.sort();
+    for key in keys {
+        for target in targets.values() {
+            if claims[&key].contains_key(target) {
+                owners.entry(target.as_path()).or_insert(key);
+            }
+        }
+    }
+
+    let mut unclaimed = FileMap::new();
+    let mut claimed = 0usize;
+    for (path, file_info) in files {
+        match targets.get(&path).and_then(|t| owners.get(t.as_path())) {
+            Some(key) => {
+                tracing::trace!(path = %path, "build-id link claimed");
+                if let Some(rules) = rules.as_deref_mut() {
+                    rules.insert(path.clone(), ClaimRule::BuildIdLink);
+                }
+                claims.entry(*key).or_default().insert(path, file_info);
+                claimed += 1;
+            }
+            None => {
+                unclaimed.insert(path, file_info);
+            }
+        }
+    }
+    tracing::debug!(links = claimed, "claimed build-id links");
+    Ok(unclaimed)
+}
+
+/// Resolve a build-id symlink to the file it points to, following links
+/// within the build-id trees (e.g. `.debug` links pointing to sibling links).
+/// Returns `None` if the target isn't in the rootfs.
+fn resolve_build_id_link(
+    rootfs: &Dir,
+    files: &FileMap,
+    path: &Utf8Path,
+) -> Result<Option<Utf8PathBuf>> {
+    let mut current = path.to_owned();
+    // bound the hops so that a loop doesn't hang us
+    for _ in 0..8 {
+        let target = rootfs
+            .read_link_contents(current.strip_prefix("/").unwrap_or(&current).as_str())
+            .with_context(|| format!("reading symlink {current}"))?;
+        let Some(target) = Utf8Path::from_path(&target) else {
+            return Ok(None);
+        };
+        let Some(parent) = current.parent() else {
+            return Ok(None);
+        };
+        let resolved = utils::normalize_path(&parent.join(target))?;
+        match files.get(&resolved) {
+            Some(info)
+                if info.file_type == FileType::Symlink
+                    && BUILD_ID_DIRS.iter().any(|dir| resolved.starts_with(dir)) =>
+            {
+                current = resolved;
+            }
+            _ => return Ok(Some(resolved)),
+        }
+    }
+    Ok(None)
+}
+
 /// Opaque identifier for a component within a repo.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct ComponentId(usize);
@@ -351,12 +677,21 @@ trait ComponentsRepo {
 
     /// Get info about a component by ID.
     fn component_info(&self, id: ComponentId) -> ComponentInfo<'_>;
+
+    /// Whether a component must always get its own layer.
+    ///
+    /// This is for small components which change far more often than their
+    /// size suggests, and would otherwise invalidate whatever layer they're
+    /// merged into. Default implementation returns false.
+    fn is_isolated(&self, _id: ComponentId) -> bool {
+        false
+    }
 }
 
 #[cfg(test)]
 impl FileInfo {
     /// Create a dummy FileInfo with the given file type for tests.
-    fn dummy(file_type: FileType) -> Self {
+    pub fn dummy(file_type: FileType) -> Self {
         Self {
             file_type,
             mode: 0,
@@ -367,6 +702,9 @@ impl FileInfo {
             ino: 0,
             nlink: 1,
             xattrs: Vec::new(),
+            source: None,
+            link_target: None,
+            opaque: false,
         }
     }
 }
@@ -420,13 +758,33 @@ mod tests {
             .as_secs();
         let rpm_repo = rpm::RpmRepo::load_from_packages(packages, now).unwrap();
 
-        let repos: Vec<Box<dyn ComponentsRepo>> = vec![Box::new(rpm_repo), Box::new(xattr_repo)];
+        let pkgdb_repo = pkgdb::PkgdbRepo::load(&files, 0).unwrap();
+        let repos: Vec<Box<dyn ComponentsRepo>> = vec![
+            Box::new(rpm_repo),
+            Box::new(xattr_repo),
+            Box::new(pkgdb_repo),
+        ];
         let loaded = ComponentsRepos {
             repos,
             default_mtime_clamp: 0,
         };
 
-        let components = loaded.into_components(&rootfs, files).unwrap();
+        let mut rules = ClaimRules::new();
+        let components = loaded
+            .into_components_with_rules(&rootfs, files, Some(&mut rules))
+            .unwrap();
+
+        // every path has a rule
+        assert!(
+            components
+                .values()
+                .flat_map(|c| c.files.keys())
+                .all(|path| rules.contains_key(path))
+        );
+        let rule = |path: &str| rules[Utf8Path::new(path)].to_string();
+        assert_eq!(rule("/usr/bin/bash"), "xattr:strong");
+        assert_eq!(rule("/usr/lib64/libc.so.6"), "rpm:strong");
+        assert_eq!(rule("/opt/myapp/config"), "unclaimed");
 
         // example xattr overrides rpm entry
         assert!(
@@ -460,12 +818,114 @@ mod tests {
             "/opt/myapp/config should be unclaimed"
         );
 
-        // rpmdb paths should be unclaimed
+        // rpmdb paths go to their own isolated component
+        let pkgdb = &components["pkgdb/rpm"];
         assert!(
-            components[UNCLAIMED_COMPONENT]
+            pkgdb
                 .files
                 .contains_key(Utf8Path::new("/usr/lib/sysimage/rpm/rpmdb.sqlite")),
-            "/usr/lib/sysimage/rpm/rpmdb.sqlite should be unclaimed"
+            "/usr/lib/sysimage/rpm/rpmdb.sqlite should belong to pkgdb/rpm"
+        );
+        assert!(pkgdb.isolated);
+        assert!(!components["rpm/glibc"].isolated);
+    }
+
+    #[test]
+    fn test_canonical_name() {
+        assert_eq!(canonical_name("rpm", "glibc"), "rpm/glibc");
+        assert_eq!(canonical_name("rpm", "libstdc++"), "rpm/libstdc++");
+        assert_eq!(
+            canonical_name("bigfiles", "usr/share/game/assets.pak"),
+            "bigfiles/usr/share/game/assets.pak"
+        );
+        assert_eq!(canonical_name("xattr", "my app"), "xattr/my%20app");
+        assert_eq!(canonical_name("xattr", "café"), "xattr/caf%C3%A9");
+        assert_eq!(canonical_name("xattr", "a*b~1"), "xattr/a%2Ab%7E1");
+        // the escape character is escaped too, so names can't collide
+        assert_eq!(canonical_name("xattr", "my%20app"), "xattr/my%2520app");
+    }
+
+    #[test]
+    fn test_into_components_docs_layer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.create_dir_all("usr/share/doc/bash").unwrap();
+        rootfs.write("usr/bin/bash", "fake bash").unwrap();
+        rootfs.write("usr/share/doc/bash/FAQ", "fake faq").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let docs_repo = docs::DocsRepo::load(&files, 0).unwrap();
+        let packages = rpm_qa::load_from_str(RPM_FIXTURE).unwrap();
+        let rpm_repo = rpm::RpmRepo::load_from_packages(packages, 0).unwrap();
+
+        let repos: Vec<Box<dyn ComponentsRepo>> = vec![Box::new(rpm_repo), Box::new(docs_repo)];
+        let loaded = ComponentsRepos {
+            repos,
+            default_mtime_clamp: 0,
+        };
+
+        let components = loaded.into_components(&rootfs, files).unwrap();
+
+        // docs win over the package owning them
+        assert!(
+            components["docs/doc"]
+                .files
+                .contains_key(Utf8Path::new("/usr/share/doc/bash/FAQ"))
+        );
+        assert!(
+            !components["rpm/bash"]
+                .files
+                .contains_key(Utf8Path::new("/usr/share/doc/bash/FAQ"))
+        );
+        // but binaries stay put
+        assert!(
+            components["rpm/bash"]
+                .files
+                .contains_key(Utf8Path::new("/usr/bin/bash"))
+        );
+    }
+
+    #[test]
+    fn test_into_components_build_id_links() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.create_dir_all("usr/lib/.build-id/ab").unwrap();
+        rootfs.write("usr/bin/foo", "fake foo").unwrap();
+        rootfs.setxattr("usr/bin/foo", XATTR_NAME, b"foo").unwrap();
+        rootfs
+            .symlink("../../../../usr/bin/foo", "usr/lib/.build-id/ab/cdef")
+            .unwrap();
+        rootfs
+            .symlink("cdef", "usr/lib/.build-id/ab/cdef.1")
+            .unwrap();
+        rootfs
+            .symlink("../../../../usr/bin/missing", "usr/lib/.build-id/ab/0123")
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let xattr_repo = xattr::XattrRepo::load(&files, 0).unwrap().unwrap();
+        let repos: Vec<Box<dyn ComponentsRepo>> = vec![Box::new(xattr_repo)];
+        let loaded = ComponentsRepos {
+            repos,
+            default_mtime_clamp: 0,
+        };
+
+        let components = loaded.into_components(&rootfs, files).unwrap();
+
+        let foo = &components["xattr/foo"].files;
+        assert!(foo.contains_key(Utf8Path::new("/usr/lib/.build-id/ab/cdef")));
+        assert!(foo.contains_key(Utf8Path::new("/usr/lib/.build-id/ab/cdef.1")));
+        // dangling links stay unclaimed
+        assert!(
+            components[UNCLAIMED_COMPONENT]
+                .files
+                .contains_key(Utf8Path::new("/usr/lib/.build-id/ab/0123"))
         );
     }
 
@@ -0,0 +1,97 @@
+use camino::Utf8Path;
+
+use crate::utils::{glob_match, interval_to_stability};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileInfo, FileMap, FileType};
+
+/// A list of well-known paths which change on nearly every build, and go to a
+/// single isolated component of their own. Patterns use the same `*` and `?`
+/// wildcards as `--layer-compression`, where `*` also matches `/`.
+pub struct PathList {
+    pub repo: &'static str,
+    pub component: &'static str,
+    pub patterns: &'static [&'static str],
+    /// File types claimed; directories always stay with their owners.
+    pub file_types: &'static [FileType],
+    pub update_interval_days: u64,
+}
+
+/// Components repo claiming the paths of a [`PathList`] into its component.
+///
+/// The paths are small, but change in nearly every build and with them
+/// whichever layer they'd land in. So the component is isolated into its own
+/// layer, keeping the layers of the packages which (nominally) own them
+/// stable.
+pub struct PathListRepo {
+    list: &'static PathList,
+    /// Patterns beyond the list's own.
+    extra_patterns: Vec<String>,
+    default_mtime_clamp: u64,
+}
+
+impl PathListRepo {
+    /// Load the repo for `list` if any path in `files` matches its patterns or
+    /// `extra_patterns`.
+    pub fn load(
+        list: &'static PathList,
+        files: &FileMap,
+        extra_patterns: &[String],
+        default_mtime_clamp: u64,
+    ) -> Option<Self> {
+        let repo = Self {
+            list,
+            extra_patterns: extra_patterns.to_vec(),
+            default_mtime_clamp,
+        };
+        if !files
+            .iter()
+            .any(|(path, file_info)| repo.is_listed(path, file_info))
+        {
+            return None;
+        }
+        Some(repo)
+    }
+
+    fn is_listed(&self, path: &Utf8Path, file_info: &FileInfo) -> bool {
+        self.list.file_types.contains(&file_info.file_type)
+            && self
+                .list
+                .patterns
+                .iter()
+                .copied()
+                .chain(self.extra_patterns.iter().map(String::as_str))
+                .any(|pattern| glob_match(pattern, path.as_str()))
+    }
+}
+
+impl ComponentsRepo for PathListRepo {
+    fn name(&self) -> &'static str {
+        self.list.repo
+    }
+
+    fn default_priority(&self) -> usize {
+        // Like docs: above package repos (which often own these as ghosts),
+        // but below xattrs.
+        5
+    }
+
+    fn strong_claims_for_path(&self, path: &Utf8Path, file_info: &FileInfo) -> Vec<ComponentId> {
+        if self.is_listed(path, file_info) {
+            vec![ComponentId(0)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn component_info(&self, _id: ComponentId) -> ComponentInfo<'_> {
+        ComponentInfo {
+            name: self.list.component,
+            mtime_clamp: self.default_mtime_clamp,
+            stability: interval_to_stability(self.list.update_interval_days),
+        }
+    }
+
+    fn is_isolated(&self, _id: ComponentId) -> bool {
+        true
+    }
+}
@@ -0,0 +1,125 @@
+use std::ops::Bound;
+
+use camino::Utf8Path;
+
+use crate::utils::interval_to_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap};
+
+const REPO_NAME: &str = "pkgdb";
+
+/// The single component all package manager state goes to.
+const COMPONENT_NAME: &str = "rpm";
+
+/// Trees holding the rpmdb and the dnf state (history, swdb, etc...).
+const PKGDB_DIRS: &[&str] = &[
+    "/usr/lib/sysimage/rpm",
+    "/usr/share/rpm",
+    "/var/lib/rpm",
+    "/usr/lib/sysimage/dnf",
+    "/usr/lib/sysimage/libdnf5",
+    "/var/lib/dnf",
+];
+
+/// The rpmdb changes with every package operation, so with every build that
+/// updates anything at all.
+const UPDATE_INTERVAL_DAYS: u64 = 1;
+
+/// Package database components repo implementation.
+///
+/// Claims the rpmdb and dnf state into a single component. Since it changes
+/// with every package operation, the component is isolated into its own layer
+/// rather than invalidating whichever (often the largest) layer it would
+/// otherwise be packed into.
+pub struct PkgdbRepo {
+    default_mtime_clamp: u64,
+}
+
+impl PkgdbRepo {
+    /// Load the package database repo if any of its trees exist in `files`.
+    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+        let present = PKGDB_DIRS.iter().any(|dir| {
+            files
+                .range::<Utf8Path, _>((Bound::Included(Utf8Path::new(dir)), Bound::Unbounded))
+                .next()
+                .is_some_and(|(path, _)| path.starts_with(dir))
+        });
+        if !present {
+            return None;
+        }
+
+        Some(Self {
+            default_mtime_clamp,
+        })
+    }
+}
+
+impl ComponentsRepo for PkgdbRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // above package repos, which may own (parts of) the trees, but below
+        // xattrs
+        5
+    }
+
+    fn strong_claims_for_path(
+        &self,
+        path: &Utf8Path,
+        _file_info: &super::FileInfo,
+    ) -> Vec<ComponentId> {
+        if PKGDB_DIRS.iter().any(|dir| path.starts_with(dir)) {
+            vec![ComponentId(0)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn component_info(&self, _id: ComponentId) -> ComponentInfo<'_> {
+        ComponentInfo {
+            name: COMPONENT_NAME,
+            mtime_clamp: self.default_mtime_clamp,
+            stability: interval_to_stability(UPDATE_INTERVAL_DAYS),
+        }
+    }
+
+    fn is_isolated(&self, _id: ComponentId) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, FileType};
+
+    #[test]
+    fn test_pkgdb_claims() {
+        let mut files = FileMap::new();
+        files.insert(
+            "/usr/lib/sysimage/rpm".into(),
+            FileInfo::dummy(FileType::Directory),
+        );
+        let repo = PkgdbRepo::load(&files, 0).unwrap();
+
+        let claimed = |path: &str| {
+            !repo
+                .strong_claims_for_path(Utf8Path::new(path), &FileInfo::dummy(FileType::File))
+                .is_empty()
+        };
+        assert!(claimed("/usr/lib/sysimage/rpm"));
+        assert!(claimed("/usr/lib/sysimage/rpm/rpmdb.sqlite"));
+        assert!(claimed(
+            "/usr/lib/sysimage/libdnf5/transaction_history.sqlite"
+        ));
+        assert!(claimed("/var/lib/dnf/history.sqlite"));
+        assert!(!claimed(
+            "/usr/lib/sysimage/rpm-ostree-base-db/rpmdb.sqlite"
+        ));
+        assert!(!claimed("/usr/bin/rpm"));
+
+        assert!(PkgdbRepo::load(&FileMap::new(), 0).is_none());
+    }
+}
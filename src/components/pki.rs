@@ -0,0 +1,135 @@
+use std::ops::Bound;
+
+use camino::Utf8Path;
+
+use crate::utils::interval_to_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap};
+
+const REPO_NAME: &str = "pki";
+
+/// The single component all trust-store content goes to.
+const COMPONENT_NAME: &str = "trust";
+
+/// Trees holding CA certificates, trust anchors and the bundles generated from
+/// them (e.g. by `update-ca-trust` or `update-ca-certificates`).
+const PKI_DIRS: &[&str] = &[
+    "/etc/pki",
+    "/etc/ssl",
+    "/etc/ca-certificates",
+    "/usr/share/pki",
+    "/usr/share/ca-certificates",
+];
+
+/// Trust stores are regenerated whenever any CA package is updated, and CA
+/// updates are frequent. Treat them like a weekly-updated component.
+const UPDATE_INTERVAL_DAYS: u64 = 7;
+
+/// Trust-store components repo implementation.
+///
+/// Claims everything under the well-known PKI trees into a single component,
+/// regardless of which package owns the files or whether they were generated
+/// at build time. This keeps trust-store churn away from stable package layers
+/// and puts all the security-sensitive content in one place for auditing.
+///
+/// The directories themselves are left to their owners; only their contents
+/// are claimed.
+pub struct PkiRepo {
+    default_mtime_clamp: u64,
+}
+
+impl PkiRepo {
+    /// Load the PKI repo if any PKI tree has content in `files`.
+    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+        let present = PKI_DIRS.iter().any(|dir| {
+            files
+                .range::<Utf8Path, _>((Bound::Excluded(Utf8Path::new(dir)), Bound::Unbounded))
+                .next()
+                .is_some_and(|(path, _)| path.starts_with(dir))
+        });
+        if !present {
+            return None;
+        }
+
+        Some(Self {
+            default_mtime_clamp,
+        })
+    }
+}
+
+impl ComponentsRepo for PkiRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Like docs: above package repos, but below xattrs.
+        5
+    }
+
+    fn strong_claims_for_path(
+        &self,
+        path: &Utf8Path,
+        _file_info: &super::FileInfo,
+    ) -> Vec<ComponentId> {
+        if PKI_DIRS
+            .iter()
+            .any(|dir| path.starts_with(dir) && path.as_str() != *dir)
+        {
+            vec![ComponentId(0)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn component_info(&self, _id: ComponentId) -> ComponentInfo<'_> {
+        ComponentInfo {
+            name: COMPONENT_NAME,
+            mtime_clamp: self.default_mtime_clamp,
+            stability: interval_to_stability(UPDATE_INTERVAL_DAYS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::cap_std::fs::Dir;
+
+    use super::*;
+    use crate::components::{FileInfo, FileType};
+
+    #[test]
+    fn test_pki_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs
+            .create_dir_all("etc/pki/ca-trust/extracted/pem")
+            .unwrap();
+        rootfs
+            .write("etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem", "certs")
+            .unwrap();
+        rootfs.create_dir_all("etc/pkitool").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = PkiRepo::load(&files, 0).unwrap();
+
+        let claimed = |path: &str| {
+            !repo
+                .strong_claims_for_path(Utf8Path::new(path), &FileInfo::dummy(FileType::File))
+                .is_empty()
+        };
+        assert!(claimed("/etc/pki/ca-trust"));
+        assert!(claimed("/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem"));
+        assert!(claimed("/usr/share/pki/ca-trust-source/anchors/my.crt"));
+        // the trees themselves stay with their owners
+        assert!(!claimed("/etc/pki"));
+        // not a PKI tree, despite the prefix
+        assert!(!claimed("/etc/pkitool"));
+        assert!(!claimed("/etc/passwd"));
+
+        // nothing to claim
+        let files = FileMap::new();
+        assert!(PkiRepo::load(&files, 0).is_none());
+    }
+}
@@ -4,10 +4,9 @@ use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use indexmap::IndexMap;
-use openssl::hash::{Hasher, MessageDigest};
 use rpm_qa::FileInfo;
 
-use crate::utils::{calculate_stability, canonicalize_parent_path};
+use crate::utils::{calculate_stability, canonicalize_parent_path, compute_sha256};
 
 use super::{ComponentId, ComponentInfo, ComponentsRepo, FileType};
 
@@ -169,7 +168,7 @@ impl ComponentsRepo for RpmRepo {
         path: &Utf8Path,
         file_info: &super::FileInfo,
     ) -> Vec<ComponentId> {
-        // Don't claim RPM database paths - let them fall into chunkah/unclaimed
+        // Don't claim RPM database paths - they're left to the pkgdb repo
         if let Ok(rel_path) = path.strip_prefix("/")
             && RPMDB_PATHS.iter().any(|p| rel_path.starts_with(p))
         {
@@ -343,20 +342,6 @@ fn build_orphan_digest_index(repo: &mut RpmRepo, files: &super::FileMap) {
     repo.orphan_sizes = orphan_sizes;
 }
 
-/// Compute the SHA-256 digest of a file in the rootfs.
-fn compute_sha256(rootfs: &Dir, path: &Utf8Path) -> Result<String> {
-    let rel_path = path.strip_prefix("/").unwrap_or(path.as_ref());
-    let mut file = rootfs
-        .open(rel_path.as_str())
-        .with_context(|| format!("opening {path} for hashing"))?;
-
-    let mut hasher = Hasher::new(MessageDigest::sha256()).context("creating SHA-256 hasher")?;
-    std::io::copy(&mut file, &mut hasher).with_context(|| format!("hashing {path}"))?;
-
-    let digest = hasher.finish().context("finalizing SHA-256 hash")?;
-    Ok(hex::encode(digest))
-}
-
 /// Parse the SRPM name from a full SRPM filename.
 ///
 /// e.g., "bash-5.2.15-5.fc40.src.rpm" -> "bash"
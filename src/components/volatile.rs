@@ -0,0 +1,68 @@
+use super::FileType;
+use super::pathlist::PathList;
+
+/// Databases and state which are rewritten whenever any package touching them
+/// is installed, even if their content doesn't meaningfully change. Files and
+/// symlinks are claimed, along with any paths matching `--volatile` patterns.
+/// Regenerated caches (e.g. the ldconfig ones) are handled by the caches repo
+/// instead, and the package database by the pkgdb repo.
+///
+/// These change with most composes, so they're treated like a daily-updated
+/// component.
+pub const VOLATILE: PathList = PathList {
+    repo: "volatile",
+    component: "state",
+    patterns: &[
+        // systemd-hwdb update
+        "/etc/udev/hwdb.bin",
+        "/usr/lib/udev/hwdb.bin",
+        // alternatives: its state, and the links it manages
+        "/var/lib/alternatives/*",
+        "/etc/alternatives/*",
+        // journalctl --update-catalog
+        "/var/lib/systemd/catalog/database",
+        // mandb
+        "/var/cache/man/*",
+    ],
+    file_types: &[FileType::File, FileType::Symlink],
+    update_interval_days: 1,
+};
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::*;
+    use crate::components::pathlist::PathListRepo;
+    use crate::components::{ComponentsRepo, FileInfo, FileMap};
+
+    #[test]
+    fn test_volatile_claims() {
+        let mut files = FileMap::new();
+        files.insert("/etc/udev/hwdb.bin".into(), FileInfo::dummy(FileType::File));
+        let repo =
+            PathListRepo::load(&VOLATILE, &files, &["/var/lib/myapp/*.db".into()], 0).unwrap();
+
+        let claimed = |path: &str, file_type| {
+            !repo
+                .strong_claims_for_path(Utf8Path::new(path), &FileInfo::dummy(file_type))
+                .is_empty()
+        };
+        assert!(claimed("/etc/udev/hwdb.bin", FileType::File));
+        assert!(claimed("/etc/alternatives/java", FileType::Symlink));
+        assert!(claimed("/var/lib/alternatives/java", FileType::File));
+        assert!(claimed("/var/lib/myapp/state.db", FileType::File));
+        // only files and symlinks; directories stay with their owners
+        assert!(!claimed("/etc/alternatives", FileType::Directory));
+        assert!(!claimed("/var/cache/man", FileType::Directory));
+        assert!(!claimed("/etc/udev/rules.d/99-foo.rules", FileType::File));
+
+        // nothing to claim, unless the extra patterns match
+        let files = FileMap::from([(
+            "/var/lib/myapp/state.db".into(),
+            FileInfo::dummy(FileType::File),
+        )]);
+        assert!(PathListRepo::load(&VOLATILE, &files, &[], 0).is_none());
+        assert!(PathListRepo::load(&VOLATILE, &files, &["/var/lib/myapp/*".into()], 0).is_some());
+    }
+}
@@ -4,15 +4,18 @@ use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use indexmap::IndexSet;
 
-use super::{
-    ComponentId, ComponentInfo, ComponentsRepo, FileInfo, FileMap, FileType, STABILITY_PERIOD_DAYS,
-};
+use crate::utils::interval_to_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileInfo, FileMap, FileType};
 
 const XATTR_NAME: &str = "user.component";
 const UPDATE_INTERVAL_XATTR_NAME: &str = "user.update-interval";
 const UPDATE_INTERVAL_DEFAULT: u64 = 7; // i.e. weekly
 const REPO_NAME: &str = "xattr";
 
+/// The xattrs read by this repo.
+pub const COMPONENT_XATTRS: &[&str] = &[XATTR_NAME, UPDATE_INTERVAL_XATTR_NAME];
+
 /// Xattr-based components repo implementation.
 ///
 /// Uses the `user.component` extended attribute to determine file ownership.
@@ -207,11 +210,6 @@ fn parse_update_interval_xattr(value: &str) -> Result<u64> {
     Ok(interval)
 }
 
-/// Convert a stability interval in days to a probability using the Poisson model.
-fn interval_to_stability(interval_days: u64) -> f64 {
-    (-STABILITY_PERIOD_DAYS / interval_days as f64).exp()
-}
-
 impl ComponentsRepo for XattrRepo {
     fn name(&self) -> &'static str {
         REPO_NAME
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+
+use crate::cancel::CancellationToken;
+use crate::components::{FileInfo, FileMap, FileType};
+use crate::utils;
+
+/// Regular files with identical content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Size of each copy.
+    pub size: u64,
+    /// Paths of the copies, sorted.
+    pub paths: Vec<Utf8PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// Bytes that would be saved by keeping only one copy.
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// Find the non-empty regular files in `files` with identical content.
+///
+/// Only files sharing their size with another file are hashed, which in
+/// practice is a small fraction of them. Returns groups sorted by wasted bytes
+/// descending.
+pub fn find_duplicates(
+    rootfs: &Dir,
+    files: &FileMap,
+    cancellation: &CancellationToken,
+) -> Result<Vec<DuplicateGroup>> {
+    let mut by_size: BTreeMap<u64, Vec<(&Utf8PathBuf, &FileInfo)>> = BTreeMap::new();
+    for (path, info) in files {
+        if info.file_type == FileType::File && info.size > 0 {
+            by_size.entry(info.size).or_default().push((path, info));
+        }
+    }
+
+    let mut groups = Vec::new();
+    for (size, candidates) in by_size {
+        if candidates.len() < 2 {
+            continue;
+        }
+        cancellation.check()?;
+        let mut by_digest: BTreeMap<String, Vec<Utf8PathBuf>> = BTreeMap::new();
+        for (path, info) in candidates {
+            let source = info.source.as_deref().unwrap_or(path);
+            let digest = utils::compute_sha256(rootfs, source)
+                .with_context(|| format!("hashing {path} for deduplication"))?;
+            by_digest.entry(digest).or_default().push(path.clone());
+        }
+        groups.extend(
+            by_digest
+                .into_values()
+                .filter(|paths| paths.len() > 1)
+                .map(|paths| DuplicateGroup { size, paths }),
+        );
+    }
+
+    groups.sort_by(|a, b| b.wasted().cmp(&a.wasted()).then(a.paths.cmp(&b.paths)));
+    tracing::debug!(groups = groups.len(), "found duplicate files");
+    Ok(groups)
+}
+
+/// Turn files in `files` with identical content and metadata into hardlinks to
+/// the first of them, so that their content is only stored once in the layer.
+///
+/// This works by giving them the same inode number, which is what the tar
+/// writer uses to detect hardlinks. Returns the number of files turned into
+/// hardlinks and the bytes saved.
+pub fn link_duplicates(
+    rootfs: &Dir,
+    files: &mut FileMap,
+    cancellation: &CancellationToken,
+) -> Result<(usize, u64)> {
+    let groups = find_duplicates(rootfs, files, cancellation)?;
+
+    let (mut linked, mut saved) = (0usize, 0u64);
+    for group in groups {
+        // hardlinks share all their metadata, so only link files that
+        // already agree on it (the mtime is clamped anyway)
+        let mut by_metadata: BTreeMap<_, Vec<&Utf8Path>> = BTreeMap::new();
+        for path in &group.paths {
+            let Some(info) = files.get(path) else {
+                continue;
+            };
+            by_metadata
+                .entry((info.mode, info.uid, info.gid, info.xattrs.clone()))
+                .or_default()
+                .push(path);
+        }
+        for paths in by_metadata.into_values() {
+            // paths are sorted, so the first is also the first written
+            let Some((first, rest)) = paths.split_first() else {
+                continue;
+            };
+            if rest.is_empty() {
+                continue;
+            }
+            let Some(first_info) = files.get_mut(*first) else {
+                continue;
+            };
+            first_info.nlink = first_info.nlink.max(2);
+            let ino = first_info.ino;
+            for path in rest {
+                let Some(info) = files.get_mut(*path) else {
+                    continue;
+                };
+                tracing::trace!(path = %path, target = %first, "linking duplicate");
+                info.ino = ino;
+                info.nlink = info.nlink.max(2);
+                linked += 1;
+                saved += group.size;
+            }
+        }
+    }
+    Ok((linked, saved))
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+
+    #[test]
+    fn test_duplicates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("a", "same content").unwrap();
+        rootfs.write("b", "same content").unwrap();
+        rootfs.write("c", "same content").unwrap();
+        rootfs.write("d", "diff content").unwrap();
+        rootfs.write("e", "").unwrap();
+        rootfs.write("f", "").unwrap();
+
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        // different metadata; can't be linked
+        files.get_mut(Utf8Path::new("/c")).unwrap().mode ^= 0o111;
+        let token = CancellationToken::new();
+
+        let groups = find_duplicates(&rootfs, &files, &token).unwrap();
+        assert_eq!(
+            groups,
+            vec![DuplicateGroup {
+                size: 12,
+                paths: vec!["/a".into(), "/b".into(), "/c".into()],
+            }]
+        );
+        assert_eq!(groups[0].wasted(), 24);
+
+        assert_eq!(
+            link_duplicates(&rootfs, &mut files, &token).unwrap(),
+            (1, 12)
+        );
+        let info = |path: &str| &files[Utf8Path::new(path)];
+        assert_eq!(info("/a").ino, info("/b").ino);
+        assert!(info("/a").nlink > 1 && info("/b").nlink > 1);
+        assert_ne!(info("/a").ino, info("/c").ino);
+
+        // the layer stores the content once
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        crate::tar::write_files_to_tar(
+            &mut tar_builder,
+            &rootfs,
+            &files,
+            0,
+            crate::tar::Normalization::default(),
+            &token,
+        )
+        .unwrap();
+        let data = tar_builder.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        let entry = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap())
+            .find(|e| e.path().unwrap().to_str() == Some("b"))
+            .unwrap();
+        assert_eq!(entry.header().entry_type(), tar::EntryType::Link);
+    }
+}
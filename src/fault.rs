@@ -0,0 +1,53 @@
+//! Failure injection, to test how interrupted builds are handled.
+//!
+//! A fault is armed for a named point in the code and a target (e.g. a
+//! component name), and makes the next matching [`inject`] call fail. This is
+//! only compiled in tests and with the `fault-injection` feature, where faults
+//! can also be armed for the whole process with
+//! `CHUNKAH_INJECT_FAULT=POINT:TARGET`. Otherwise, [`inject`] is a no-op.
+
+use anyhow::Result;
+
+/// Point at which a layer blob has been fully written, but not yet completed.
+pub const WRITE_LAYER: &str = "write-layer";
+
+#[cfg(any(test, feature = "fault-injection"))]
+static ARMED: std::sync::Mutex<Vec<(String, String)>> = std::sync::Mutex::new(Vec::new());
+
+/// Lock the armed faults. A panic while holding the lock can't leave the list
+/// half-updated, so poisoning is ignored.
+#[cfg(any(test, feature = "fault-injection"))]
+fn armed() -> std::sync::MutexGuard<'static, Vec<(String, String)>> {
+    ARMED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Make the next [`inject`] call for `point` and `target` fail.
+#[cfg(test)]
+pub fn arm(point: &str, target: &str) {
+    armed().push((point.to_string(), target.to_string()));
+}
+
+/// Fail if a fault is armed for `point` and `target`.
+#[cfg(any(test, feature = "fault-injection"))]
+pub fn inject(point: &str, target: &str) -> Result<()> {
+    let mut armed = armed();
+    if let Some(i) = armed.iter().position(|(p, t)| p == point && t == target) {
+        armed.remove(i);
+        anyhow::bail!("injected fault at {point} for {target}");
+    }
+    #[cfg(feature = "fault-injection")]
+    if std::env::var("CHUNKAH_INJECT_FAULT")
+        .is_ok_and(|spec| spec.split_once(':') == Some((point, target)))
+    {
+        anyhow::bail!("injected fault at {point} for {target}");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+#[inline(always)]
+pub fn inject(_point: &str, _target: &str) -> Result<()> {
+    Ok(())
+}
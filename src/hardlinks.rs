@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+
+use crate::components::{Component, FileType};
+use crate::utils;
+
+/// What to do with hardlink groups whose links are claimed by different
+/// components (e.g. multi-call binaries whose links come from several
+/// packages). Hardlinks can only be kept within a layer, so such groups
+/// otherwise end up split across layers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HardlinkPolicy {
+    /// Write the content once in each layer with links of the group, so that
+    /// links in different layers are separate files
+    #[default]
+    Duplicate,
+    /// Move all links of the group into the component of its first path, so
+    /// that the group stays intact in a single layer
+    Merge,
+    /// Fail the build
+    Fail,
+}
+
+/// A hardlink group whose links are claimed by more than one component.
+#[derive(Debug, PartialEq)]
+pub struct SplitHardlinkGroup {
+    /// The links of the group with their owning components, sorted by path.
+    pub paths: Vec<(Utf8PathBuf, String)>,
+    /// Size of the content.
+    pub size: u64,
+}
+
+impl SplitHardlinkGroup {
+    fn components(&self) -> usize {
+        let mut names: Vec<&str> = self.paths.iter().map(|(_, c)| c.as_str()).collect();
+        names.sort();
+        names.dedup();
+        names.len()
+    }
+
+    /// The links of the group with their components, for reporting.
+    pub fn describe(&self) -> String {
+        self.paths
+            .iter()
+            .map(|(path, component)| format!("{path} ({component})"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Find the hardlink groups in `components` whose links are claimed by more
+/// than one component. Groups are sorted by their first path.
+pub fn find_split_hardlinks(components: &HashMap<String, Component>) -> Vec<SplitHardlinkGroup> {
+    // BTreeMap so that groups are built in a deterministic order
+    let mut by_ino: BTreeMap<u64, SplitHardlinkGroup> = BTreeMap::new();
+    for (name, component) in components {
+        for (path, file_info) in &component.files {
+            if file_info.file_type != FileType::File || file_info.nlink <= 1 {
+                continue;
+            }
+            by_ino
+                .entry(file_info.ino)
+                .or_insert_with(|| SplitHardlinkGroup {
+                    paths: Vec::new(),
+                    size: file_info.size,
+                })
+                .paths
+                .push((path.clone(), name.clone()));
+        }
+    }
+
+    let mut groups: Vec<SplitHardlinkGroup> = by_ino
+        .into_values()
+        .filter(|group| group.components() > 1)
+        .map(|mut group| {
+            group.paths.sort();
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+    groups
+}
+
+/// Apply `policy` to the hardlink groups in `components` which span more
+/// than one component, reporting them.
+pub fn apply_hardlink_policy(
+    components: &mut HashMap<String, Component>,
+    policy: HardlinkPolicy,
+) -> Result<()> {
+    let groups = find_split_hardlinks(components);
+    if groups.is_empty() {
+        return Ok(());
+    }
+
+    match policy {
+        HardlinkPolicy::Duplicate => {
+            // each extra component holding links of the group is (at most)
+            // an extra copy of the content
+            let duplicated: u64 = groups
+                .iter()
+                .map(|group| group.size * (group.components() as u64 - 1))
+                .sum();
+            for group in &groups {
+                tracing::debug!("hardlink group spans components: {}", group.describe());
+            }
+            tracing::warn!(
+                groups = groups.len(),
+                duplicated = %utils::format_size(duplicated),
+                "hardlink groups span components; their content is duplicated in each layer"
+            );
+        }
+        HardlinkPolicy::Merge => {
+            for group in &groups {
+                let (_, target) = &group.paths[0];
+                tracing::info!(
+                    component = %target,
+                    "keeping hardlink group together: {}",
+                    group.describe()
+                );
+                for (path, name) in &group.paths[1..] {
+                    if name == target {
+                        continue;
+                    }
+                    let file_info = components
+                        .get_mut(name)
+                        .and_then(|c| c.files.remove(path))
+                        .with_context(|| format!("finding {path} in component {name}"))?;
+                    components
+                        .get_mut(target)
+                        .with_context(|| format!("finding component {target}"))?
+                        .files
+                        .insert(path.clone(), file_info);
+                }
+            }
+            components.retain(|name, component| {
+                if component.files.is_empty() {
+                    tracing::debug!(component = %name, "dropping component emptied by hardlinks");
+                    return false;
+                }
+                true
+            });
+        }
+        HardlinkPolicy::Fail => {
+            let list: Vec<String> = groups.iter().map(SplitHardlinkGroup::describe).collect();
+            anyhow::bail!(
+                "{} hardlink group(s) span components:\n  {}",
+                groups.len(),
+                list.join("\n  ")
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, FileMap};
+
+    fn link(ino: u64, nlink: u64) -> FileInfo {
+        FileInfo {
+            ino,
+            nlink,
+            size: 100,
+            ..FileInfo::dummy(FileType::File)
+        }
+    }
+
+    fn components() -> HashMap<String, Component> {
+        let component = |files: Vec<(&str, FileInfo)>| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: files
+                .into_iter()
+                .map(|(p, f)| (Utf8PathBuf::from(p), f))
+                .collect::<FileMap>(),
+        };
+        HashMap::from([
+            (
+                "rpm/coreutils".to_string(),
+                component(vec![
+                    ("/usr/bin/coreutils", link(1, 3)),
+                    ("/usr/bin/ls", link(1, 3)),
+                    ("/usr/bin/a", link(2, 2)),
+                    ("/usr/bin/b", link(2, 2)),
+                ]),
+            ),
+            (
+                "rpm/coreutils-single".to_string(),
+                component(vec![("/usr/bin/cp", link(1, 3))]),
+            ),
+            (
+                "rpm/other".to_string(),
+                component(vec![("/usr/bin/other", link(3, 1))]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_find_split_hardlinks() {
+        let components = components();
+        let groups = find_split_hardlinks(&components);
+        // only the group spanning components, not the one within a component
+        // or the unlinked file
+        assert_eq!(
+            groups,
+            vec![SplitHardlinkGroup {
+                paths: vec![
+                    ("/usr/bin/coreutils".into(), "rpm/coreutils".into()),
+                    ("/usr/bin/cp".into(), "rpm/coreutils-single".into()),
+                    ("/usr/bin/ls".into(), "rpm/coreutils".into()),
+                ],
+                size: 100,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_apply_hardlink_policy() {
+        let mut components = components();
+        // duplicating leaves the components as they are
+        apply_hardlink_policy(&mut components, HardlinkPolicy::Duplicate).unwrap();
+        assert_eq!(components.len(), 3);
+        assert_eq!(find_split_hardlinks(&components).len(), 1);
+
+        let err = apply_hardlink_policy(&mut components, HardlinkPolicy::Fail)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "1 hardlink group(s) span components:\n  /usr/bin/coreutils (rpm/coreutils), /usr/bin/cp (rpm/coreutils-single)"
+            ),
+            "{err}"
+        );
+
+        // the link moves to the component of the first path, and the
+        // emptied component is dropped
+        apply_hardlink_policy(&mut components, HardlinkPolicy::Merge).unwrap();
+        assert!(!components.contains_key("rpm/coreutils-single"));
+        assert!(
+            components["rpm/coreutils"]
+                .files
+                .contains_key(camino::Utf8Path::new("/usr/bin/cp"))
+        );
+        assert!(find_split_hardlinks(&components).is_empty());
+    }
+}
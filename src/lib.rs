@@ -0,0 +1,46 @@
+//! Library interface of chunkah.
+//!
+//! Only the packing algorithm is exposed by default, so that packing
+//! strategies can be simulated and iterated on independently of the rest of
+//! the build. With the `merged-view` feature, [`merged`] also exposes the
+//! content of the image a build would produce, for verification tooling.
+//!
+//! The rest of the build pipeline is private; the `chunkah` binary is a thin
+//! wrapper around its hidden `main` entry point, so that the pipeline is
+//! only compiled once.
+
+pub mod packing;
+
+#[cfg(feature = "merged-view")]
+pub mod merged;
+
+mod budget;
+mod cancel;
+mod cli;
+mod cmd_build;
+mod cmd_components;
+mod cmd_diff;
+mod cmd_doctor;
+mod cmd_plan;
+mod cmd_stats;
+mod collisions;
+mod components;
+mod dedup;
+mod fault;
+mod hardlinks;
+mod ocibuilder;
+mod owners;
+mod plan;
+mod registry;
+mod resume;
+mod rewrite;
+mod sandbox;
+mod scan;
+mod security;
+mod stubs;
+mod symlinks;
+mod tar;
+mod utils;
+
+#[doc(hidden)]
+pub use cli::main;
@@ -0,0 +1,303 @@
+//! Read-only merged view of the content of a planned image.
+//!
+//! This presents the files the image built with a given set of `chunkah
+//! build` options would ship (i.e. after rewrites, pruning and component
+//! selection, and with metadata as normalized in the layers) as a virtual
+//! tree, without building any layers. This lets test suites and policy
+//! scanners validate the exact content of an image ahead of building it.
+//!
+//! ```no_run
+//! use chunkah::merged::MergedView;
+//!
+//! let view = MergedView::from_build_args(["--rootfs", "/path/to/rootfs"])?;
+//! for (path, entry) in view.iter() {
+//!     if entry.mode & 0o002 != 0 {
+//!         println!("world-writable: {path}");
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::collections::{BTreeMap, btree_map};
+use std::ffi::OsString;
+use std::ops::Bound;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, File};
+use clap::Parser;
+
+use crate::cancel::CancellationToken;
+use crate::cmd_build::{self, BuildArgs};
+use crate::components::FileInfo;
+use crate::tar::{self, Normalization};
+
+pub use crate::components::FileType;
+
+/// An entry of the merged view, with its metadata as written to the image.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub file_type: FileType,
+    /// Full mode, including the file type bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Modification time, after clamping.
+    pub mtime: u64,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// The components shipping the entry, sorted. Directories can be in
+    /// several; parent directories written only to hold the content of
+    /// components are in none.
+    pub components: Vec<String>,
+    /// Modification time in the rootfs.
+    unclamped_mtime: u64,
+    /// Where the content is in the rootfs.
+    source: Utf8PathBuf,
+    link_target: Option<Utf8PathBuf>,
+}
+
+impl Entry {
+    fn new(
+        path: &Utf8Path,
+        file_info: &FileInfo,
+        mtime_clamp: u64,
+        normalization: &Normalization,
+    ) -> Self {
+        Entry {
+            file_type: file_info.file_type,
+            mode: tar::normalized_mode(file_info, normalization),
+            uid: file_info.uid,
+            gid: file_info.gid,
+            size: file_info.size,
+            mtime: file_info.mtime.min(mtime_clamp),
+            unclamped_mtime: file_info.mtime,
+            xattrs: file_info
+                .xattrs
+                .iter()
+                .filter(|(k, _)| tar::keeps_xattr(k, normalization))
+                .cloned()
+                .collect(),
+            components: Vec::new(),
+            source: file_info.source.clone().unwrap_or_else(|| path.to_owned()),
+            link_target: file_info.link_target.clone(),
+        }
+    }
+
+    /// Account for the entry being written with `mtime_clamp` too.
+    fn clamp_mtime(&mut self, mtime_clamp: u64) {
+        self.mtime = self.mtime.max(self.unclamped_mtime.min(mtime_clamp));
+    }
+}
+
+/// The merged content of all the layers of a planned image.
+pub struct MergedView {
+    rootfs: Dir,
+    entries: BTreeMap<Utf8PathBuf, Entry>,
+}
+
+impl MergedView {
+    /// Plan the image from the same options as `chunkah build` (e.g.
+    /// `["--rootfs", "/path", "--prune", "/var/cache"]`). Output options are
+    /// ignored; nothing is written.
+    pub fn from_build_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args = BuildArgs::try_parse_from(
+            std::iter::once(OsString::from("build")).chain(args.into_iter().map(Into::into)),
+        )
+        .context("parsing build options")?;
+        Self::new(&args, &CancellationToken::new())
+    }
+
+    fn new(args: &BuildArgs, cancellation: &CancellationToken) -> Result<Self> {
+        let (rootfs, components) = cmd_build::scan_image(args, cancellation)?;
+        let normalization = args.normalization(&rootfs)?;
+
+        // directories are written in each layer with content in them, and
+        // the last one wins; the order of layers depends on packing, so go
+        // with the latest mtime
+        let mut entries: BTreeMap<Utf8PathBuf, Entry> = BTreeMap::new();
+        for (name, component) in &components {
+            for (path, file_info) in &component.files {
+                let entry = entries.entry(path.clone()).or_insert_with(|| {
+                    Entry::new(path, file_info, component.mtime_clamp, &normalization)
+                });
+                entry.clamp_mtime(component.mtime_clamp);
+                entry.components.push(name.clone());
+            }
+        }
+
+        // and the parents which layers write to hold their content, with the
+        // highest mtime clamp of these layers
+        let mut parents: BTreeMap<&Utf8Path, u64> = BTreeMap::new();
+        for component in components.values() {
+            for path in component.files.keys() {
+                for parent in path.ancestors().skip(1) {
+                    if parent == "/" || component.files.contains_key(parent) {
+                        continue;
+                    }
+                    let mtime_clamp = parents.entry(parent).or_default();
+                    *mtime_clamp = (*mtime_clamp).max(component.mtime_clamp);
+                }
+            }
+        }
+        for (path, mtime_clamp) in parents {
+            let entry = match entries.entry(path.to_owned()) {
+                btree_map::Entry::Occupied(entry) => entry.into_mut(),
+                btree_map::Entry::Vacant(entry) => {
+                    let file_info = tar::parent_dir_info(&rootfs, path)?;
+                    entry.insert(Entry::new(path, &file_info, mtime_clamp, &normalization))
+                }
+            };
+            entry.clamp_mtime(mtime_clamp);
+        }
+
+        for entry in entries.values_mut() {
+            entry.components.sort();
+        }
+        Ok(MergedView { rootfs, entries })
+    }
+
+    /// The entry at the absolute `path`, if the image has it.
+    pub fn get(&self, path: &Utf8Path) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+
+    /// All the entries of the image, sorted by path. The root directory isn't
+    /// included.
+    pub fn iter(&self) -> impl Iterator<Item = (&Utf8Path, &Entry)> {
+        self.entries.iter().map(|(p, e)| (p.as_path(), e))
+    }
+
+    /// The entries directly in the directory at `path`, sorted by path.
+    pub fn read_dir(&self, path: &Utf8Path) -> Result<impl Iterator<Item = (&Utf8Path, &Entry)>> {
+        if path != "/" {
+            let entry = self
+                .entries
+                .get(path)
+                .with_context(|| format!("{path} not found"))?;
+            anyhow::ensure!(
+                entry.file_type == FileType::Directory,
+                "{path} is not a directory"
+            );
+        }
+        Ok(self
+            .entries
+            .range::<Utf8Path, _>((Bound::Excluded(path), Bound::Unbounded))
+            .take_while(move |(p, _)| p.starts_with(path))
+            .filter(move |(p, _)| p.parent() == Some(path))
+            .map(|(p, e)| (p.as_path(), e)))
+    }
+
+    /// Open the regular file at `path` to read its content.
+    pub fn open(&self, path: &Utf8Path) -> Result<File> {
+        let entry = self
+            .entries
+            .get(path)
+            .with_context(|| format!("{path} not found"))?;
+        anyhow::ensure!(
+            entry.file_type == FileType::File,
+            "{path} is not a regular file"
+        );
+        self.rootfs
+            .open(tar::strip_root_prefix(&entry.source))
+            .with_context(|| format!("opening {}", entry.source))
+    }
+
+    /// The target of the symlink at `path`.
+    pub fn read_link(&self, path: &Utf8Path) -> Result<Utf8PathBuf> {
+        let entry = self
+            .entries
+            .get(path)
+            .with_context(|| format!("{path} not found"))?;
+        anyhow::ensure!(
+            entry.file_type == FileType::Symlink,
+            "{path} is not a symlink"
+        );
+        if let Some(target) = &entry.link_target {
+            return Ok(target.clone());
+        }
+        let target = self
+            .rootfs
+            .read_link_contents(tar::strip_root_prefix(&entry.source))
+            .with_context(|| format!("reading symlink {}", entry.source))?;
+        Utf8PathBuf::from_path_buf(target)
+            .map_err(|p| anyhow::anyhow!("non-UTF-8 symlink target {}", p.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::dirext::CapStdExtDirExt;
+
+    use super::*;
+
+    #[test]
+    fn test_merged_view() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.create_dir_all("usr/lib/old").unwrap();
+        rootfs.create_dir_all("var/cache").unwrap();
+        rootfs.write("usr/bin/app", "hello").unwrap();
+        rootfs.symlink("app", "usr/bin/sh").unwrap();
+        rootfs.write("usr/lib/old/data", "data").unwrap();
+        rootfs.write("var/cache/junk", "junk").unwrap();
+        rootfs.setxattr("usr", "user.component", b"app").unwrap();
+
+        let view = MergedView::from_build_args([
+            "--rootfs",
+            tmp.path().to_str().unwrap(),
+            "--source-date-epoch",
+            "1000",
+            "--prune",
+            "/var/cache/",
+            "--rewrite",
+            "/usr/lib/old=/usr/lib/new",
+            "--drop-user-xattrs",
+        ])
+        .unwrap();
+
+        let app = view.get(Utf8Path::new("/usr/bin/app")).unwrap();
+        assert_eq!(app.components, ["xattr/app"]);
+        assert_eq!(app.mtime, 1000);
+        let mut content = String::new();
+        view.open(Utf8Path::new("/usr/bin/app"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "hello");
+        assert_eq!(view.read_link(Utf8Path::new("/usr/bin/sh")).unwrap(), "app");
+        assert!(view.open(Utf8Path::new("/usr/bin/sh")).is_err());
+        // normalized as in the layers
+        assert!(view.get(Utf8Path::new("/usr")).unwrap().xattrs.is_empty());
+
+        // post-transform and post-exclusion
+        assert!(view.get(Utf8Path::new("/usr/lib/old/data")).is_none());
+        let mut content = String::new();
+        view.open(Utf8Path::new("/usr/lib/new/data"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "data");
+        assert!(view.get(Utf8Path::new("/var/cache")).is_some());
+        assert!(view.get(Utf8Path::new("/var/cache/junk")).is_none());
+
+        let names = |path: &str| -> Vec<String> {
+            view.read_dir(Utf8Path::new(path))
+                .unwrap()
+                .map(|(p, _)| p.to_string())
+                .collect()
+        };
+        assert_eq!(names("/"), ["/usr", "/var"]);
+        assert_eq!(names("/usr"), ["/usr/bin", "/usr/lib"]);
+        assert_eq!(names("/usr/lib"), ["/usr/lib/new"]);
+        assert!(view.read_dir(Utf8Path::new("/usr/bin/app")).is_err());
+    }
+}
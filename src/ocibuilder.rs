@@ -4,15 +4,22 @@ use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::{Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::cap_tempfile;
+use ocidir::OciRead;
 use ocidir::oci_spec::image as oci_image;
+use serde::Serialize;
 
+use crate::cancel::CancellationToken;
 use crate::components::Component;
+use crate::plan::{ContentClass, PLAN_MEDIA_TYPE, Plan};
+use crate::resume::{self, BlobStaging, ResumeJournal};
+use crate::tar::Normalization;
+use crate::utils;
 
 /// Compression settings for the OCI image.
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum Compression {
     /// No compression.
     #[default]
@@ -21,6 +28,58 @@ pub enum Compression {
     Gzip(u32),
 }
 
+impl Compression {
+    /// Parse a codec spec: `none`, `gzip` (at `default_level`) or `gzip-LEVEL`.
+    pub fn parse(spec: &str, default_level: u32) -> Result<Self> {
+        match spec {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip(default_level)),
+            _ => {
+                let level = spec
+                    .strip_prefix("gzip-")
+                    .with_context(|| format!("unknown compression codec: {spec}"))?;
+                let level: u32 = level
+                    .parse()
+                    .with_context(|| format!("invalid gzip level: {level}"))?;
+                anyhow::ensure!(level <= 9, "gzip level must be between 0 and 9: {level}");
+                Ok(Compression::Gzip(level))
+            }
+        }
+    }
+}
+
+/// Compression override for the layers of components matching a glob.
+#[derive(Clone, Debug)]
+pub struct CompressionRule {
+    pub pattern: String,
+    pub compression: Compression,
+}
+
+/// Parse `PATTERN=CODEC` compression rules. See [`Compression::parse`] for
+/// the codec syntax.
+pub fn parse_compression_rules(
+    rules: &[String],
+    default_level: u32,
+) -> Result<Vec<CompressionRule>> {
+    rules
+        .iter()
+        .map(|rule| {
+            let (pattern, codec) = rule.split_once('=').with_context(|| {
+                format!("invalid compression rule (expected PATTERN=CODEC): {rule}")
+            })?;
+            anyhow::ensure!(
+                !pattern.is_empty(),
+                "empty pattern in compression rule: {rule}"
+            );
+            Ok(CompressionRule {
+                pattern: pattern.to_string(),
+                compression: Compression::parse(codec, default_level)
+                    .with_context(|| format!("parsing compression rule {rule}"))?,
+            })
+        })
+        .collect()
+}
+
 /// Builder for creating OCI images from components.
 pub struct Builder {
     /// The rootfs to build from.
@@ -29,19 +88,165 @@ pub struct Builder {
     components: Vec<(String, Component)>,
     /// Compression settings for layers and archive.
     compression: Compression,
+    /// Per-component overrides of the layer compression.
+    compression_rules: Vec<CompressionRule>,
+    /// Stable identifiers of the layers, in the same order as the components.
+    layer_ids: Vec<String>,
     /// Number of threads for parallel layer writing.
     threads: NonZeroUsize,
+    /// Metadata normalization applied to layer entries.
+    normalization: Normalization,
     /// Annotations to add to the image manifest.
     annotations: Option<HashMap<String, String>>,
     /// Tag to set on the manifest descriptor in index.json.
     tag: Option<String>,
     /// The image configuration.
     config: Option<oci_image::ImageConfiguration>,
+    /// Packing plan to attach as an artifact referring to the image.
+    plan: Option<Plan>,
+    /// Whether to write layers to the archive as soon as they're ready.
+    stream_layers: bool,
+    /// Tag of an additional single-layer variant of the image.
+    squashed_tag: Option<String>,
+    /// Tag and content of a companion image with the debug info and sources.
+    debuginfo: Option<(String, Component)>,
+    /// Whether to date layer history entries by their newest content.
+    history_from_content: bool,
+    /// Whether to end gzip layers with their metadata.
+    layer_metadata: bool,
+    /// Whether to keep partial output on failure, and resume from it.
+    resume: bool,
+    /// Layers completed in the partial output being resumed.
+    journal: Option<ResumeJournal>,
+    /// Directory layer blobs are staged in across builds.
+    blob_staging: Option<BlobStaging>,
+    /// Token used to cancel the build.
+    cancellation: CancellationToken,
+}
+
+/// The manifest and config of a built image.
+#[derive(Debug)]
+pub struct BuiltImage {
+    pub manifest: oci_image::ImageManifest,
+    pub config: oci_image::ImageConfiguration,
+}
+
+/// Directory an OCI layout is built in before being moved to the output.
+enum StagingDir {
+    /// Removed on drop.
+    Temp(tempfile::TempDir),
+    /// Kept until explicitly removed, so that a failed build can be resumed.
+    Partial(Utf8PathBuf),
+}
+
+impl StagingDir {
+    fn path(&self) -> &std::path::Path {
+        match self {
+            StagingDir::Temp(dir) => dir.path(),
+            StagingDir::Partial(path) => path.as_std_path(),
+        }
+    }
+
+    /// Don't remove the directory, e.g. because it was renamed into place.
+    fn keep(self) {
+        if let StagingDir::Temp(mut dir) = self {
+            // TempDir needs a .persist()...
+            dir.disable_cleanup(true);
+        }
+    }
+
+    /// Remove the directory now that it isn't needed anymore.
+    fn remove(self) -> Result<()> {
+        match self {
+            StagingDir::Temp(dir) => dir.close().context("removing temp directory"),
+            StagingDir::Partial(path) => std::fs::remove_dir_all(&path)
+                .with_context(|| format!("removing partial output {path}")),
+        }
+    }
+}
+
+/// Directory to create the staging directory of `output` in: the same as the
+/// output, so that it can be renamed into place.
+fn staging_parent(output: &Utf8Path) -> &Utf8Path {
+    output
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."))
+}
+
+/// A layer of a chunk map: its digests, and the files of the components in it.
+#[derive(Serialize)]
+struct ChunkMapLayer<'a> {
+    digest: String,
+    diff_id: &'a String,
+    size: u64,
+    component: &'a str,
+    files: Vec<&'a Utf8Path>,
+}
+
+/// Write `value` as pretty-printed JSON to a new file at `path`.
+fn write_json_file<T: Serialize>(path: &std::path::Path, value: &T) -> Result<()> {
+    let file =
+        std::fs::File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, value)
+        .with_context(|| format!("writing {}", path.display()))?;
+    writer
+        .flush()
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Component name recorded for the layer of the squashed image variant.
+const SQUASHED_COMPONENT: &str = "chunkah/squashed";
+
+/// Component name recorded for the debug layer of the debuginfo image.
+const DEBUGINFO_COMPONENT: &str = "chunkah/debuginfo";
+
+/// Callback invoked with each layer once written.
+type LayerCallback<'a> = &'a mut dyn FnMut(&LayerBlob) -> Result<()>;
+
+/// A layer blob in the OCI directory, either just written or reused from a
+/// previous build.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerBlob {
+    /// Descriptor of the blob, as listed in the manifest.
+    pub descriptor: oci_image::Descriptor,
+    /// Digest of the uncompressed tar, as listed in the config.
+    pub diff_id: String,
+}
+
+impl LayerBlob {
+    pub fn new(layer: &ocidir::Layer) -> Result<Self> {
+        Ok(LayerBlob {
+            descriptor: layer
+                .descriptor()
+                .build()
+                .context("building layer descriptor")?,
+            diff_id: layer.uncompressed_sha256_as_digest().to_string(),
+        })
+    }
+
+    /// Add the layer to the top of the image of `manifest` and `config`.
+    fn push(
+        self,
+        manifest: &mut oci_image::ImageManifest,
+        config: &mut oci_image::ImageConfiguration,
+        annotations: HashMap<String, String>,
+        history: oci_image::History,
+    ) {
+        let mut descriptor = self.descriptor;
+        descriptor.set_annotations(Some(annotations));
+        manifest.layers_mut().push(descriptor);
+        let mut rootfs = config.rootfs().clone();
+        rootfs.diff_ids_mut().push(self.diff_id);
+        config.set_rootfs(rootfs);
+        config.history_mut().get_or_insert_default().push(history);
+    }
 }
 
 /// Result of writing a single component's tar layer.
 struct ComponentLayer {
-    layer: ocidir::Layer,
+    layer: LayerBlob,
     annotations: HashMap<String, String>,
     history: oci_image::History,
 }
@@ -53,10 +258,23 @@ impl Builder {
             rootfs: rootfs.try_clone().context("cloning rootfs")?,
             components,
             compression: Compression::default(),
+            compression_rules: Vec::new(),
+            layer_ids: Vec::new(),
             threads: NonZeroUsize::MIN,
+            normalization: Normalization::default(),
             annotations: None,
             tag: None,
             config: None,
+            plan: None,
+            stream_layers: false,
+            squashed_tag: None,
+            debuginfo: None,
+            history_from_content: false,
+            layer_metadata: false,
+            resume: false,
+            journal: None,
+            blob_staging: None,
+            cancellation: CancellationToken::new(),
         })
     }
 
@@ -66,12 +284,35 @@ impl Builder {
         self
     }
 
+    /// Override the layer compression for components matching the rules.
+    ///
+    /// The first rule matching any of the components in a layer applies. The
+    /// compression of the OCI archive itself is unaffected.
+    pub fn compression_rules(mut self, rules: Vec<CompressionRule>) -> Self {
+        self.compression_rules = rules;
+        self
+    }
+
+    /// Set stable identifiers for the layers, in the same order as the
+    /// components. These are recorded in the `org.chunkah.layer-id` layer
+    /// annotation.
+    pub fn layer_ids(mut self, ids: Vec<String>) -> Self {
+        self.layer_ids = ids;
+        self
+    }
+
     /// Set the number of threads for parallel layer writing.
     pub fn threads(mut self, threads: NonZeroUsize) -> Self {
         self.threads = threads;
         self
     }
 
+    /// Set the metadata normalization applied to layer entries.
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
     /// Set annotations to add to the image manifest.
     pub fn annotations(mut self, annotations: HashMap<String, String>) -> Self {
         self.annotations = Some(annotations);
@@ -94,58 +335,296 @@ impl Builder {
         self
     }
 
+    /// Attach the packing plan as an OCI artifact referring to the image.
+    pub fn plan(mut self, plan: Plan) -> Self {
+        self.plan = Some(plan);
+        self
+    }
+
+    /// Set the token used to cancel the build.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Write layers to the OCI archive as soon as each one is ready.
+    ///
+    /// Layers then appear in the archive in completion order rather than image
+    /// order, which is not reproducible across builds. The image itself (i.e.
+    /// the manifest, config and blob digests) is unaffected.
+    pub fn stream_layers(mut self, enabled: bool) -> Self {
+        self.stream_layers = enabled;
+        self
+    }
+
+    /// Also add a variant of the image with all files in a single layer,
+    /// tagged `tag`, as a second manifest in the output.
+    pub fn squashed(mut self, tag: String) -> Self {
+        self.squashed_tag = Some(tag);
+        self
+    }
+
+    /// Also add a companion image tagged `tag`, with the layers of the image
+    /// plus one with the files of `debuginfo`, as another manifest in the
+    /// output. The companion shares all but its last layer with the image.
+    pub fn debuginfo(mut self, tag: String, debuginfo: Component) -> Self {
+        self.debuginfo = Some((tag, debuginfo));
+        self
+    }
+
+    /// Date the history entry of each layer by the newest mtime of its files
+    /// (after clamping) rather than by the component's mtime clamp.
+    ///
+    /// The clamp is e.g. the package build time, or the build time of the
+    /// image for unclaimed files, whereas this reflects when the content last
+    /// changed. Both are reproducible.
+    pub fn history_from_content(mut self, enabled: bool) -> Self {
+        self.history_from_content = enabled;
+        self
+    }
+
+    /// End each gzip layer with a [`crate::tar::LayerMetadata`] listing its
+    /// components and uncompressed size, as an extra (empty) gzip member.
+    ///
+    /// This doesn't change the uncompressed content of the layers, and so
+    /// their diff IDs. Uncompressed layers are unaffected.
+    pub fn layer_metadata(mut self, enabled: bool) -> Self {
+        self.layer_metadata = enabled;
+        self
+    }
+
+    /// Keep the partial output of a failed build, and resume from it.
+    ///
+    /// This applies to OCI directory and blobs output. The image is built in a
+    /// `.NAME.partial` directory next to the output, which is kept if the build
+    /// fails. A later build with the same output then reuses the layers
+    /// completed in it, as long as their files and settings are unchanged.
+    pub fn resume(mut self, enabled: bool) -> Self {
+        self.resume = enabled;
+        self
+    }
+
+    /// Stage layer blobs in `staging`, and reuse those already staged there
+    /// for layers whose files and settings are unchanged.
+    ///
+    /// Unlike [`Builder::resume`], this applies to all outputs, and the
+    /// staging directory can be shared across outputs and concurrent builds.
+    pub fn blob_staging(mut self, staging: BlobStaging) -> Self {
+        self.blob_staging = Some(staging);
+        self
+    }
+
     /// Build the OCI image and write it as an OCI archive to the given output.
-    pub fn build_to_oci_archive<W: Write>(self, output: &mut W) -> Result<()> {
+    pub fn build_to_oci_archive<W: Write>(self, output: &mut W) -> Result<BuiltImage> {
         let oci_dir = cap_tempfile::tempdir(cap_std_ext::cap_std::ambient_authority())
             .context("creating temp directory")?;
-        self.build_oci_dir(&oci_dir)
-            .context("building OCI directory")?;
-
         let compressed = !matches!(self.compression, Compression::None);
-        tracing::info!(compressed = compressed, "writing OCI archive");
-
         let compression = match self.compression {
             Compression::None => crate::tar::ArchiveCompression::None,
             Compression::Gzip(level) => {
                 crate::tar::ArchiveCompression::Gzip(flate2::Compression::new(level))
             }
         };
+        let mut archive = crate::tar::OciArchiveWriter::new(&mut *output, compression);
+
+        let image = if self.stream_layers {
+            tracing::info!(compressed = compressed, "streaming layers to OCI archive");
+            let mut on_layer = |layer: &LayerBlob| -> Result<()> {
+                archive.append_blob(&oci_dir, layer.descriptor.digest().as_ref())
+            };
+            self.build_oci_dir(&oci_dir, Some(&mut on_layer))
+                .context("building OCI directory")?
+        } else {
+            let image = self
+                .build_oci_dir(&oci_dir, None)
+                .context("building OCI directory")?;
+            tracing::info!(compressed = compressed, "writing OCI archive");
+            image
+        };
 
-        crate::tar::write_oci_archive(&oci_dir, &mut *output, compression)
-            .context("writing OCI archive")?;
-
-        output.flush().context("flushing output")
+        archive.finish(&oci_dir).context("writing OCI archive")?;
+        output.flush().context("flushing output")?;
+        Ok(image)
     }
 
     /// Build the OCI image and write it as an OCI directory layout.
-    pub fn build_to_oci_dir(self, output: &Utf8Path) -> Result<()> {
-        // Allocate the tempdir in the same dir as the target for rename().
-        let parent = output
-            .parent()
-            .filter(|p| !p.as_str().is_empty())
-            .unwrap_or(Utf8Path::new("."));
-        // Notice here we use `tempfile::TempDir` rather than `cap_tempfile::TempDir` because we
-        // need an actually addressable path for rename(). `cap_tempfile` intentionally doesn't
-        // expose paths.
-        let mut temp_dir = tempfile::TempDir::with_prefix_in("chunkah-", parent.as_std_path())
-            .context("creating temp directory")?;
+    pub fn build_to_oci_dir(mut self, output: &Utf8Path) -> Result<BuiltImage> {
+        let staging = self.staging_dir(output)?;
         let oci_dir =
-            Dir::open_ambient_dir(temp_dir.path(), cap_std_ext::cap_std::ambient_authority())
+            Dir::open_ambient_dir(staging.path(), cap_std_ext::cap_std::ambient_authority())
                 .context("opening temp directory")?;
-        self.build_oci_dir(&oci_dir)
+        let image = self
+            .build_oci_dir(&oci_dir, None)
             .context("building OCI directory")?;
+        if self.resume {
+            resume::prune_unreferenced_blobs(&oci_dir).context("pruning partial output")?;
+            oci_dir
+                .remove_file(resume::JOURNAL_FILE)
+                .context("removing resume journal")?;
+        }
 
         tracing::info!(output = %output, "writing OCI directory");
-        std::fs::rename(temp_dir.path(), output.as_std_path())
+        std::fs::rename(staging.path(), output.as_std_path())
             .with_context(|| format!("renaming temp directory to {output}"))?;
-        // Disarm cleanup now that the rename succeeded. TempDir needs a .persist()...
-        temp_dir.disable_cleanup(true);
-        Ok(())
+        staging.keep();
+        Ok(image)
+    }
+
+    /// Build the OCI image and write its blobs as individual files.
+    ///
+    /// The output directory contains the image manifest as `manifest.json` and
+    /// each blob it references (config and layers) in a file named after the
+    /// hex of its digest. There is no index or OCI layout metadata; this is for
+    /// consumers that upload blobs or assemble indexes themselves.
+    pub fn build_to_blobs_dir(mut self, output: &Utf8Path) -> Result<BuiltImage> {
+        let parent = staging_parent(output);
+        let oci_temp_dir = self.staging_dir(output)?;
+        let oci_dir = Dir::open_ambient_dir(
+            oci_temp_dir.path(),
+            cap_std_ext::cap_std::ambient_authority(),
+        )
+        .context("opening temp directory")?;
+        let image = self
+            .build_oci_dir(&oci_dir, None)
+            .context("building OCI directory")?;
+
+        let mut blobs_temp_dir =
+            tempfile::TempDir::with_prefix_in("chunkah-", parent.as_std_path())
+                .context("creating temp directory")?;
+        let oci_dir = ocidir::OciDir::open(oci_dir).context("opening OCI directory")?;
+        let index = oci_dir.read_index().context("reading index")?;
+        // the image is always the first manifest; others are artifacts referring to it
+        let manifest_desc = index.manifests().first().context("no manifest in index")?;
+        let manifest = &image.manifest;
+
+        let blob_path = |desc: &oci_image::Descriptor| -> Result<(std::path::PathBuf, String)> {
+            let digest = desc.digest().to_string();
+            let (algorithm, hex) = digest
+                .split_once(':')
+                .with_context(|| format!("invalid digest {digest}"))?;
+            let path = oci_temp_dir.path().join("blobs").join(algorithm).join(hex);
+            Ok((path, hex.to_string()))
+        };
+
+        tracing::info!(output = %output, "writing blobs");
+        for desc in std::iter::once(manifest.config()).chain(manifest.layers()) {
+            let (src, hex) = blob_path(desc)?;
+            let dest = blobs_temp_dir.path().join(hex);
+            // identical layers share a blob
+            if dest.exists() {
+                continue;
+            }
+            std::fs::rename(&src, &dest)
+                .with_context(|| format!("moving blob {}", desc.digest()))?;
+        }
+        let (src, _) = blob_path(manifest_desc)?;
+        std::fs::rename(&src, blobs_temp_dir.path().join("manifest.json"))
+            .context("moving manifest")?;
+
+        std::fs::rename(blobs_temp_dir.path(), output.as_std_path())
+            .with_context(|| format!("renaming temp directory to {output}"))?;
+        blobs_temp_dir.disable_cleanup(true);
+        oci_temp_dir.remove()?;
+        Ok(image)
+    }
+
+    /// Build the OCI image but only write its metadata, as a "chunk map".
+    ///
+    /// The output directory contains the image manifest as `manifest.json`,
+    /// the config as `config.json`, and the digests and files of each layer
+    /// as `layers.json`. The layer blobs are written to compute their digests,
+    /// but then discarded. This is for recording or auditing what an image
+    /// consists of, or for consumers regenerating the blobs from the rootfs.
+    pub fn build_to_chunk_map(mut self, output: &Utf8Path) -> Result<BuiltImage> {
+        let parent = staging_parent(output);
+        let oci_temp_dir = self.staging_dir(output)?;
+        let oci_dir = Dir::open_ambient_dir(
+            oci_temp_dir.path(),
+            cap_std_ext::cap_std::ambient_authority(),
+        )
+        .context("opening temp directory")?;
+        let image = self
+            .build_oci_dir(&oci_dir, None)
+            .context("building OCI directory")?;
+
+        // empty components have no layer
+        let components = self.components.iter().filter(|(_, c)| !c.files.is_empty());
+        let layers: Vec<ChunkMapLayer> = image
+            .manifest
+            .layers()
+            .iter()
+            .zip(image.config.rootfs().diff_ids())
+            .zip(components)
+            .map(|((desc, diff_id), (name, component))| ChunkMapLayer {
+                digest: desc.digest().to_string(),
+                diff_id,
+                size: desc.size(),
+                component: name,
+                files: component.files.keys().map(|p| p.as_path()).collect(),
+            })
+            .collect();
+
+        let mut map_temp_dir = tempfile::TempDir::with_prefix_in("chunkah-", parent.as_std_path())
+            .context("creating temp directory")?;
+        tracing::info!(output = %output, "writing chunk map");
+        let dir = map_temp_dir.path();
+        write_json_file(&dir.join("manifest.json"), &image.manifest)?;
+        write_json_file(&dir.join("config.json"), &image.config)?;
+        write_json_file(&dir.join("layers.json"), &layers)?;
+
+        std::fs::rename(map_temp_dir.path(), output.as_std_path())
+            .with_context(|| format!("renaming temp directory to {output}"))?;
+        map_temp_dir.disable_cleanup(true);
+        oci_temp_dir.remove()?;
+        Ok(image)
+    }
+
+    /// Create the directory to build the OCI layout of `output` in.
+    ///
+    /// Normally, this is a temporary directory removed on drop. When resuming,
+    /// it's a fixed path kept on failure, and the layers already completed in
+    /// it are reused.
+    fn staging_dir(&mut self, output: &Utf8Path) -> Result<StagingDir> {
+        let parent = staging_parent(output);
+        if !self.resume {
+            // Notice here we use `tempfile::TempDir` rather than `cap_tempfile::TempDir` because
+            // we need an actually addressable path for rename(). `cap_tempfile` intentionally
+            // doesn't expose paths.
+            let temp_dir = tempfile::TempDir::with_prefix_in("chunkah-", parent.as_std_path())
+                .context("creating temp directory")?;
+            return Ok(StagingDir::Temp(temp_dir));
+        }
+
+        let name = output
+            .file_name()
+            .with_context(|| format!("output path has no file name: {output}"))?;
+        let path = parent.join(format!(".{name}.partial"));
+        std::fs::create_dir_all(&path).with_context(|| format!("creating {path}"))?;
+        let dir = Dir::open_ambient_dir(&path, cap_std_ext::cap_std::ambient_authority())
+            .with_context(|| format!("opening {path}"))?;
+        // manifests are written last, so any are from a failed attempt at
+        // the very end; they're rewritten anyway
+        match dir.remove_file("index.json") {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).context("removing stale index");
+            }
+            _ => {}
+        }
+        let journal = ResumeJournal::open(&dir)?;
+        let layers = journal.len()?;
+        if layers > 0 {
+            tracing::info!(path = %path, layers, "resuming from partial output");
+        }
+        self.journal = Some(journal);
+        Ok(StagingDir::Partial(path))
     }
 
     /// The underlying function called by build_to_oci_archive() and build_to_oci_dir() that does
     /// all the heavy-lifting to actually build the image.
-    fn build_oci_dir(&self, dir: &Dir) -> Result<()> {
+    ///
+    /// If provided, `on_layer` is called for each layer as soon as it's written,
+    /// in completion order.
+    fn build_oci_dir(&self, dir: &Dir, on_layer: Option<LayerCallback<'_>>) -> Result<BuiltImage> {
         let oci_dir = ocidir::OciDir::ensure(dir.try_clone().context("cloning temp directory")?)
             .context("creating OCI directory")?;
 
@@ -159,7 +638,7 @@ impl Builder {
         let mut config = self.config.clone().unwrap_or_default();
 
         // this is the important bit: we add all the layers
-        self.add_components(dir, &mut manifest, &mut config)
+        self.add_components(dir, &mut manifest, &mut config, on_layer)
             .context("adding layers to OCI directory")?;
 
         if let Some(annotations) = &self.annotations {
@@ -173,10 +652,113 @@ impl Builder {
             .build()
             .context("building platform")?;
 
-        oci_dir
-            .insert_manifest_and_config(manifest, config, self.tag.as_deref(), platform)
+        let manifest_desc = oci_dir
+            .insert_manifest_and_config(manifest, config, self.tag.as_deref(), platform.clone())
             .context("inserting manifest and config")?;
 
+        if let Some(plan) = &self.plan {
+            attach_plan(&oci_dir, plan, &manifest_desc, platform.clone())
+                .context("attaching plan")?;
+        }
+
+        if let Some((tag, debuginfo)) = &self.debuginfo {
+            self.add_debuginfo(
+                dir,
+                &oci_dir,
+                &manifest_desc,
+                tag,
+                debuginfo,
+                platform.clone(),
+            )
+            .context("adding debuginfo image")?;
+        }
+
+        if let Some(tag) = &self.squashed_tag {
+            self.add_squashed(dir, &oci_dir, tag, platform)
+                .context("adding squashed image")?;
+        }
+
+        // read back what was written, now that the config descriptor is filled in
+        let manifest: oci_image::ImageManifest = oci_dir
+            .read_json_blob(&manifest_desc)
+            .context("reading manifest")?;
+        let config = oci_dir
+            .read_json_blob(manifest.config())
+            .context("reading config")?;
+        Ok(BuiltImage { manifest, config })
+    }
+
+    /// Add a variant of the image with all components in a single layer.
+    fn add_squashed(
+        &self,
+        dir: &Dir,
+        oci_dir: &ocidir::OciDir,
+        tag: &str,
+        platform: oci_image::Platform,
+    ) -> Result<()> {
+        // like when packing merges components, use the latest clamp
+        let mut files = crate::components::FileMap::new();
+        let mut mtime_clamp = 0u64;
+        for (_, component) in &self.components {
+            mtime_clamp = mtime_clamp.max(component.mtime_clamp);
+            files.extend(component.files.iter().map(|(p, f)| (p.clone(), f.clone())));
+        }
+        let squashed = Component {
+            mtime_clamp,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+
+        tracing::info!(tag = %tag, "writing squashed layer");
+        let cl = self
+            .write_component_layer(dir, SQUASHED_COMPONENT, &squashed, None)
+            .context("writing squashed layer")?;
+
+        let mut manifest = oci_dir
+            .new_empty_manifest()
+            .context("creating empty manifest")?
+            .build()
+            .context("building manifest")?;
+        let mut config = self.config.clone().unwrap_or_default();
+        cl.layer
+            .push(&mut manifest, &mut config, cl.annotations, cl.history);
+        if let Some(annotations) = &self.annotations {
+            manifest.set_annotations(Some(annotations.clone()));
+        }
+        oci_dir
+            .insert_manifest_and_config(manifest, config, Some(tag), platform)
+            .context("inserting squashed manifest and config")?;
+        Ok(())
+    }
+
+    /// Add a companion image with the layers of the image of `manifest_desc`
+    /// plus one with the files of `debuginfo`.
+    fn add_debuginfo(
+        &self,
+        dir: &Dir,
+        oci_dir: &ocidir::OciDir,
+        manifest_desc: &oci_image::Descriptor,
+        tag: &str,
+        debuginfo: &Component,
+        platform: oci_image::Platform,
+    ) -> Result<()> {
+        tracing::info!(tag = %tag, files = debuginfo.files.len(), "writing debuginfo layer");
+        let cl = self
+            .write_component_layer(dir, DEBUGINFO_COMPONENT, debuginfo, None)
+            .context("writing debuginfo layer")?;
+
+        let mut manifest: oci_image::ImageManifest = oci_dir
+            .read_json_blob(manifest_desc)
+            .context("reading manifest")?;
+        let mut config: oci_image::ImageConfiguration = oci_dir
+            .read_json_blob(manifest.config())
+            .context("reading config")?;
+        cl.layer
+            .push(&mut manifest, &mut config, cl.annotations, cl.history);
+        oci_dir
+            .insert_manifest_and_config(manifest, config, Some(tag), platform)
+            .context("inserting debuginfo manifest and config")?;
         Ok(())
     }
 
@@ -186,12 +768,19 @@ impl Builder {
         oci_dir: &Dir,
         manifest: &mut oci_image::ImageManifest,
         config: &mut oci_image::ImageConfiguration,
+        mut on_layer: Option<LayerCallback<'_>>,
     ) -> Result<()> {
         // filter out empty components
         let components: Vec<_> = self
             .components
             .iter()
-            .filter(|(name, component)| {
+            .zip(
+                self.layer_ids
+                    .iter()
+                    .map(Some)
+                    .chain(std::iter::repeat(None)),
+            )
+            .filter(|((name, component), _)| {
                 if component.files.is_empty() {
                     tracing::debug!(component = %name, "skipping empty component");
                     false
@@ -210,56 +799,78 @@ impl Builder {
         );
 
         // farm out to worker threads; they each keep picking the next component
-        // (by index) to work on until there are none and return a Vec of the results
+        // (by index) to work on until there are none and send back the results
+        // as they complete
         let next_i = AtomicUsize::new(0);
+        let (tx, rx) = std::sync::mpsc::channel();
         let mut results: Vec<(usize, Result<ComponentLayer>)> = std::thread::scope(|s| {
-            (0..num_workers)
-                .map(|_| {
-                    s.spawn(|| {
-                        let mut results = Vec::new();
-                        loop {
-                            let i = next_i.fetch_add(1, Ordering::Relaxed);
-                            if i >= components.len() {
-                                break;
-                            }
-                            let (name, component) = components[i];
-                            let result = self
-                                .write_component_layer(oci_dir, name, component)
-                                .with_context(|| format!("adding component {name}"));
-                            results.push((i, result));
+            for _ in 0..num_workers {
+                let tx = tx.clone();
+                let next_i = &next_i;
+                let components = &components;
+                // NB: a worker panic propagates when the scope ends
+                s.spawn(move || {
+                    loop {
+                        let i = next_i.fetch_add(1, Ordering::Relaxed);
+                        if i >= components.len() {
+                            break;
                         }
-                        results
-                    })
-                })
-                // force map() above to evaluate and thus spawn the threads
-                .collect::<Vec<_>>()
-                .into_iter()
-                // join threads and flatten their results into a single Vec
-                // SAFETY: a thread panic here would be a bug somewhere in our code (...or a dep)
-                .flat_map(|h| h.join().expect("worker thread panicked"))
-                .collect()
+                        let ((name, component), id) = components[i];
+                        let result = self
+                            .write_component_layer(oci_dir, name, component, id)
+                            .with_context(|| format!("adding component {name}"));
+                        // the receiver only hangs up early on failure
+                        if tx.send((i, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            // drop our own sender so that the loop below ends with the workers
+            drop(tx);
+
+            let mut results = Vec::with_capacity(components.len());
+            for (i, result) in rx {
+                let result = match (result, on_layer.as_deref_mut()) {
+                    (Ok(cl), Some(on_layer)) => on_layer(&cl.layer)
+                        .map(|()| cl)
+                        .with_context(|| format!("streaming component {}", components[i].0.0)),
+                    (result, _) => result,
+                };
+                // when streaming, stop at the first failure rather than
+                // finishing all the remaining layers first
+                let stop = on_layer.is_some() && result.is_err();
+                results.push((i, result));
+                if stop {
+                    break;
+                }
+            }
+            results
         });
 
         // sort back based on index for reproducible builds
         results.sort_by_key(|(idx, _)| *idx);
 
-        let oci_dir = ocidir::OciDir::open(oci_dir.try_clone().context("cloning oci_dir")?)
-            .context("opening OCI directory")?;
-
         for (_, result) in results {
             let cl = result?; // NB: this already has 'adding component {name}' context
-            oci_dir.push_layer_with_history_annotated(
-                manifest,
-                config,
-                cl.layer,
-                Some(cl.annotations),
-                Some(cl.history),
-            );
+            cl.layer.push(manifest, config, cl.annotations, cl.history);
         }
 
         Ok(())
     }
 
+    /// Compression to use for the layer `name`, which may consist of several
+    /// space-separated components if they were merged.
+    fn layer_compression(&self, name: &str) -> Compression {
+        self.compression_rules
+            .iter()
+            .find(|rule| {
+                name.split(' ')
+                    .any(|component| utils::glob_match(&rule.pattern, component))
+            })
+            .map_or(self.compression, |rule| rule.compression)
+    }
+
     /// Write a single component as a tar layer. Returns the layer metadata for
     /// later assembly into the manifest and config.
     fn write_component_layer(
@@ -267,27 +878,42 @@ impl Builder {
         oci_dir: &Dir,
         name: &str,
         component: &Component,
+        id: Option<&String>,
     ) -> Result<ComponentLayer> {
-        let oci_dir = ocidir::OciDir::open(oci_dir.try_clone().context("cloning oci_dir")?)
-            .context("opening OCI directory")?;
-        tracing::debug!(component = name, "creating tar layer");
-        let mut tar_builder =
-            crate::tar::create_layer(&oci_dir, self.compression).context("creating layer")?;
-
-        crate::tar::write_files_to_tar(
-            &mut tar_builder,
-            &self.rootfs,
-            &component.files,
-            component.mtime_clamp,
-        )
-        .context("building tar layer")?;
-
-        tar_builder.finish().context("finishing layer tar")?;
-        let layer = tar_builder
-            .into_inner()
-            .context("getting layer writer")?
-            .complete()
-            .context("completing layer")?;
+        let compression = self.layer_compression(name);
+        let key = (self.journal.is_some() || self.blob_staging.is_some())
+            .then(|| {
+                resume::layer_key(
+                    &self.rootfs,
+                    name,
+                    component,
+                    compression,
+                    self.layer_metadata,
+                    &self.normalization,
+                )
+            })
+            .transpose()
+            .context("computing layer key")?;
+        let layer = match self.reusable_layer(oci_dir, name, key.as_deref())? {
+            Some(layer) => layer,
+            None => {
+                let layer = self.write_layer(oci_dir, name, component, compression)?;
+                if let (Some(journal), Some(key)) = (&self.journal, &key) {
+                    journal.record(key, &layer).context("recording layer")?;
+                }
+                if let (Some(staging), Some(key)) = (&self.blob_staging, &key) {
+                    // staging is only an optimization for later builds
+                    if let Err(e) = staging.stage(key, oci_dir, &layer) {
+                        tracing::warn!(
+                            component = name,
+                            err = format!("{e:#}"),
+                            "failed to stage layer"
+                        );
+                    }
+                }
+                layer
+            }
+        };
 
         let annotations = {
             let mut hm = HashMap::new();
@@ -296,14 +922,23 @@ impl Builder {
                 "org.chunkah.stability".to_string(),
                 format!("{:.3}", component.stability),
             );
+            hm.insert(
+                "org.chunkah.content-class".to_string(),
+                ContentClass::from_stability(component.stability)
+                    .as_str()
+                    .to_string(),
+            );
+            if let Some(id) = id {
+                hm.insert("org.chunkah.layer-id".to_string(), id.clone());
+            }
             hm
         };
 
-        let mtime_i64 =
-            i64::try_from(component.mtime_clamp).context("mtime_clamp overflows i64")?;
+        let created = self.history_created(component);
+        let created_i64 = i64::try_from(created).context("history timestamp overflows i64")?;
 
-        let created = chrono::DateTime::from_timestamp(mtime_i64, 0)
-            .with_context(|| format!("invalid mtime_clamp: {}", component.mtime_clamp))?
+        let created = chrono::DateTime::from_timestamp(created_i64, 0)
+            .with_context(|| format!("invalid history timestamp: {created}"))?
             .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
 
         let history = oci_image::HistoryBuilder::default()
@@ -320,6 +955,144 @@ impl Builder {
             history,
         })
     }
+
+    /// A layer completed by a previous build for `key`, from the partial output
+    /// being resumed or the staging directory.
+    fn reusable_layer(
+        &self,
+        oci_dir: &Dir,
+        name: &str,
+        key: Option<&str>,
+    ) -> Result<Option<LayerBlob>> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        if let Some(layer) = self
+            .journal
+            .as_ref()
+            .map(|j| j.get(key))
+            .transpose()?
+            .flatten()
+        {
+            tracing::debug!(component = name, "reusing layer from partial output");
+            return Ok(Some(layer));
+        }
+        let Some(staging) = &self.blob_staging else {
+            return Ok(None);
+        };
+        match staging.get(key, oci_dir) {
+            Ok(Some(layer)) => {
+                tracing::debug!(component = name, "reusing staged layer");
+                Ok(Some(layer))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                tracing::warn!(
+                    component = name,
+                    err = format!("{e:#}"),
+                    "failed to reuse staged layer"
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Write the tar layer of a single component.
+    fn write_layer(
+        &self,
+        oci_dir: &Dir,
+        name: &str,
+        component: &Component,
+        compression: Compression,
+    ) -> Result<LayerBlob> {
+        let oci_dir = ocidir::OciDir::open(oci_dir.try_clone().context("cloning oci_dir")?)
+            .context("opening OCI directory")?;
+        tracing::debug!(component = name, ?compression, "creating tar layer");
+        // merged components are named by their names separated by spaces
+        let metadata_components = self
+            .layer_metadata
+            .then(|| name.split_whitespace().map(String::from).collect());
+        let mut tar_builder = crate::tar::create_layer(
+            &oci_dir,
+            compression,
+            metadata_components,
+            crate::tar::batch_size(&component.files),
+        )
+        .context("creating layer")?;
+
+        crate::tar::write_files_to_tar(
+            &mut tar_builder,
+            &self.rootfs,
+            &component.files,
+            component.mtime_clamp,
+            self.normalization.clone(),
+            &self.cancellation,
+        )
+        .context("building tar layer")?;
+
+        let writer = crate::tar::finish_layer(tar_builder)?;
+        crate::fault::inject(crate::fault::WRITE_LAYER, name)?;
+        let layer = writer.complete().context("completing layer")?;
+        LayerBlob::new(&layer)
+    }
+
+    /// The timestamp of the history entry for the layer of `component`.
+    fn history_created(&self, component: &Component) -> u64 {
+        if !self.history_from_content {
+            return component.mtime_clamp;
+        }
+        component
+            .files
+            .values()
+            .map(|f| f.mtime.min(component.mtime_clamp))
+            .max()
+            .unwrap_or(component.mtime_clamp)
+    }
+}
+
+/// Write the plan as an artifact manifest whose subject is the image manifest.
+///
+/// This follows the OCI guidance for artifacts: an empty config, the plan as
+/// the sole layer, and the artifact type set on the manifest.
+fn attach_plan(
+    oci_dir: &ocidir::OciDir,
+    plan: &Plan,
+    image_desc: &oci_image::Descriptor,
+    platform: oci_image::Platform,
+) -> Result<()> {
+    let media_type = oci_image::MediaType::Other(PLAN_MEDIA_TYPE.to_string());
+    let config = oci_dir
+        .write_json_blob(&serde_json::json!({}), oci_image::MediaType::EmptyJSON)
+        .context("writing empty config")?
+        .build()
+        .context("building config descriptor")?;
+    let layer = oci_dir
+        .write_json_blob(plan, media_type.clone())
+        .context("writing plan blob")?
+        .build()
+        .context("building plan descriptor")?;
+    // the subject is a plain reference; drop the index-specific platform and
+    // annotations from the image descriptor
+    let subject = oci_image::DescriptorBuilder::default()
+        .media_type(image_desc.media_type().clone())
+        .digest(image_desc.digest().clone())
+        .size(image_desc.size())
+        .build()
+        .context("building subject descriptor")?;
+    let manifest = oci_image::ImageManifestBuilder::default()
+        .schema_version(oci_image::SCHEMA_VERSION)
+        .media_type(oci_image::MediaType::ImageManifest)
+        .artifact_type(media_type)
+        .config(config)
+        .layers(vec![layer])
+        .subject(subject)
+        .build()
+        .context("building plan manifest")?;
+    oci_dir
+        .insert_manifest(manifest, None, platform)
+        .context("inserting plan manifest")?;
+    tracing::debug!(layers = plan.layers.len(), "attached packing plan");
+    Ok(())
 }
 
 #[cfg(test)]
@@ -332,6 +1105,7 @@ mod tests {
     use maplit::btreeset;
     use ocidir::OciRead;
     use std::collections::BTreeSet;
+    use std::io::Read;
 
     use crate::components::FileMap;
 
@@ -404,6 +1178,7 @@ mod tests {
                     Component {
                         mtime_clamp,
                         stability: 0.0,
+                        isolated: false,
                         files,
                     },
                 )
@@ -728,4 +1503,661 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_attach_plan() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let components = vec![(
+            "test".to_string(),
+            Component {
+                mtime_clamp: 0,
+                stability: 0.5,
+                isolated: false,
+                files,
+            },
+        )];
+        let plan = Plan {
+            naming_scheme: crate::components::NAMING_SCHEME,
+            layers: vec![crate::plan::PlanLayer {
+                id: "test".to_string(),
+                components: vec!["test".to_string()],
+                size: 7,
+                stability: 0.5,
+                content_class: ContentClass::Hot,
+                estimated_compressed_size: None,
+                digest: None,
+            }],
+            ..Default::default()
+        };
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("oci")).unwrap();
+        Builder::new(&rootfs, components)
+            .unwrap()
+            .tag("test".to_string())
+            .plan(plan.clone())
+            .build_to_oci_dir(&output)
+            .unwrap();
+
+        let oci_dir_cap = Dir::open_ambient_dir(&output, ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::open(oci_dir_cap).unwrap();
+        let index = oci_dir.read_index().unwrap();
+        assert_eq!(index.manifests().len(), 2);
+        let image_desc = &index.manifests()[0];
+        let plan_manifest: oci_image::ImageManifest =
+            oci_dir.read_json_blob(&index.manifests()[1]).unwrap();
+
+        assert_eq!(
+            plan_manifest.artifact_type(),
+            &Some(oci_image::MediaType::Other(PLAN_MEDIA_TYPE.to_string()))
+        );
+        let subject = plan_manifest.subject().as_ref().unwrap();
+        assert_eq!(subject.digest(), image_desc.digest());
+        assert_eq!(
+            plan_manifest.config().media_type(),
+            &oci_image::MediaType::EmptyJSON
+        );
+        let read_plan: Plan = oci_dir.read_json_blob(&plan_manifest.layers()[0]).unwrap();
+        assert_eq!(read_plan, plan);
+
+        // the layers are annotated like the plan
+        let image_manifest: oci_image::ImageManifest = oci_dir.read_json_blob(image_desc).unwrap();
+        assert_eq!(
+            image_manifest.layers()[0]
+                .annotations()
+                .as_ref()
+                .unwrap()
+                .get("org.chunkah.content-class")
+                .map(String::as_str),
+            Some(plan.layers[0].content_class.as_str())
+        );
+    }
+
+    #[test]
+    fn test_squashed() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file_a", "content a").unwrap();
+        rootfs.write("file_b", "content b").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let components = files
+            .into_iter()
+            .filter(|(path, _)| path != "/")
+            .map(|(path, info)| {
+                (
+                    path.to_string(),
+                    Component {
+                        mtime_clamp: 0,
+                        stability: 0.0,
+                        isolated: false,
+                        files: FileMap::from([(path, info)]),
+                    },
+                )
+            })
+            .collect();
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("oci")).unwrap();
+        let image = Builder::new(&rootfs, components)
+            .unwrap()
+            .tag("chunked".to_string())
+            .squashed("squashed".to_string())
+            .build_to_oci_dir(&output)
+            .unwrap();
+        assert_eq!(image.manifest.layers().len(), 2);
+
+        let oci_dir_cap = Dir::open_ambient_dir(&output, ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::open(oci_dir_cap).unwrap();
+        let index = oci_dir.read_index().unwrap();
+        assert_eq!(index.manifests().len(), 2);
+        let desc = &index.manifests()[1];
+        assert_eq!(
+            desc.annotations()
+                .as_ref()
+                .unwrap()
+                .get("org.opencontainers.image.ref.name")
+                .map(String::as_str),
+            Some("squashed")
+        );
+        let manifest: oci_image::ImageManifest = oci_dir.read_json_blob(desc).unwrap();
+        assert_eq!(manifest.layers().len(), 1);
+        let config: oci_image::ImageConfiguration =
+            oci_dir.read_json_blob(manifest.config()).unwrap();
+        assert_eq!(config.rootfs().diff_ids().len(), 1);
+
+        let blob = oci_dir.read_blob(&manifest.layers()[0]).unwrap();
+        let mut archive = tar::Archive::new(blob);
+        let mut paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["file_a", "file_b"]);
+    }
+
+    #[test]
+    fn test_layer_metadata() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file_a", "content a").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let component = Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("oci")).unwrap();
+        // merged components, as named by packing
+        let image = Builder::new(&rootfs, vec![("rpm/a rpm/b".to_string(), component)])
+            .unwrap()
+            .compression(Compression::Gzip(6))
+            .layer_metadata(true)
+            .build_to_oci_dir(&output)
+            .unwrap();
+
+        let oci_dir_cap = Dir::open_ambient_dir(&output, ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::open(oci_dir_cap).unwrap();
+        let mut blob = Vec::new();
+        oci_dir
+            .read_blob(&image.manifest.layers()[0])
+            .unwrap()
+            .read_to_end(&mut blob)
+            .unwrap();
+
+        // the metadata member doesn't change the content
+        let mut tar = Vec::new();
+        flate2::read::MultiGzDecoder::new(blob.as_slice())
+            .read_to_end(&mut tar)
+            .unwrap();
+        assert_eq!(
+            format!("sha256:{}", hex::encode(openssl::sha::sha256(&tar))),
+            image.config.rootfs().diff_ids()[0]
+        );
+
+        let member = crate::tar::layer_metadata_member(&crate::tar::LayerMetadata {
+            components: vec!["rpm/a".to_string(), "rpm/b".to_string()],
+            uncompressed_size: tar.len() as u64,
+        })
+        .unwrap();
+        assert!(blob.ends_with(&member));
+    }
+
+    #[test]
+    fn test_many_small_files() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("icons").unwrap();
+        for i in 0..2000 {
+            rootfs
+                .write(format!("icons/{i:04}.png"), [i as u8; 3])
+                .unwrap();
+        }
+        // and one file bigger than a batch
+        rootfs.write("big", vec![0xaa; 2 * 1024 * 1024]).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let component = Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("oci")).unwrap();
+        let image = Builder::new(&rootfs, vec![("icons".to_string(), component)])
+            .unwrap()
+            .compression(Compression::Gzip(1))
+            .build_to_oci_dir(&output)
+            .unwrap();
+
+        let oci_dir_cap = Dir::open_ambient_dir(&output, ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::open(oci_dir_cap).unwrap();
+        let mut tar = Vec::new();
+        flate2::read::GzDecoder::new(oci_dir.read_blob(&image.manifest.layers()[0]).unwrap())
+            .read_to_end(&mut tar)
+            .unwrap();
+        assert_eq!(
+            format!("sha256:{}", hex::encode(openssl::sha::sha256(&tar))),
+            image.config.rootfs().diff_ids()[0]
+        );
+
+        // entries are in order, with their content intact
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let mut paths = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            if let Some(i) = path
+                .strip_prefix("icons/")
+                .and_then(|p| p.strip_suffix(".png"))
+            {
+                assert_eq!(content, [i.parse::<u16>().unwrap() as u8; 3]);
+            } else if path == "big" {
+                assert_eq!(content.len(), 2 * 1024 * 1024);
+            }
+            paths.push(path);
+        }
+        assert_eq!(paths.len(), 2002);
+        assert_eq!(paths[0], "big");
+        assert_eq!(paths[1], "icons/");
+        assert_eq!(paths[2], "icons/0000.png");
+        assert_eq!(paths[2001], "icons/1999.png");
+    }
+
+    #[test]
+    fn test_debuginfo() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/lib/debug").unwrap();
+        rootfs.write("usr/lib/debug/foo.debug", "debug").unwrap();
+        rootfs.write("file_a", "content a").unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let component = |files: FileMap| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+        let debug_path = Utf8Path::new("/usr/lib/debug/foo.debug");
+        let debuginfo = FileMap::from([files.remove_entry(debug_path).unwrap()]);
+        let main = FileMap::from([files.remove_entry(Utf8Path::new("/file_a")).unwrap()]);
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("oci")).unwrap();
+        let image = Builder::new(&rootfs, vec![("a".to_string(), component(main))])
+            .unwrap()
+            .tag("image".to_string())
+            .debuginfo("image-debug".to_string(), component(debuginfo))
+            .build_to_oci_dir(&output)
+            .unwrap();
+        assert_eq!(image.manifest.layers().len(), 1);
+
+        let oci_dir_cap = Dir::open_ambient_dir(&output, ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::open(oci_dir_cap).unwrap();
+        let index = oci_dir.read_index().unwrap();
+        assert_eq!(index.manifests().len(), 2);
+        let desc = &index.manifests()[1];
+        assert_eq!(
+            desc.annotations()
+                .as_ref()
+                .unwrap()
+                .get("org.opencontainers.image.ref.name")
+                .map(String::as_str),
+            Some("image-debug")
+        );
+        // the layers of the image, plus the debug one
+        let manifest: oci_image::ImageManifest = oci_dir.read_json_blob(desc).unwrap();
+        assert_eq!(manifest.layers().len(), 2);
+        assert_eq!(manifest.layers()[0], image.manifest.layers()[0]);
+        let config: oci_image::ImageConfiguration =
+            oci_dir.read_json_blob(manifest.config()).unwrap();
+        assert_eq!(config.rootfs().diff_ids().len(), 2);
+        assert_eq!(config.history().as_ref().unwrap().len(), 2);
+
+        let blob = oci_dir.read_blob(&manifest.layers()[1]).unwrap();
+        let mut archive = tar::Archive::new(blob);
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "usr/",
+                "usr/lib/",
+                "usr/lib/debug/",
+                "usr/lib/debug/foo.debug"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_to_blobs_dir() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file_a", "content a").unwrap();
+        rootfs.write("file_b", "content b").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let components = files
+            .into_iter()
+            .map(|(path, info)| {
+                (
+                    path.to_string(),
+                    Component {
+                        mtime_clamp: 0,
+                        stability: 0.0,
+                        isolated: false,
+                        files: FileMap::from([(path, info)]),
+                    },
+                )
+            })
+            .collect();
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("blobs")).unwrap();
+        Builder::new(&rootfs, components)
+            .unwrap()
+            .compression(Compression::Gzip(6))
+            .build_to_blobs_dir(&output)
+            .unwrap();
+
+        let manifest_bytes = std::fs::read(output.join("manifest.json")).unwrap();
+        let manifest: oci_image::ImageManifest = serde_json::from_slice(&manifest_bytes).unwrap();
+        assert_eq!(manifest.layers().len(), 2);
+
+        let mut expected = vec!["manifest.json".to_string()];
+        for desc in std::iter::once(manifest.config()).chain(manifest.layers()) {
+            let hex = desc
+                .digest()
+                .to_string()
+                .split_once(':')
+                .unwrap()
+                .1
+                .to_string();
+            let blob = std::fs::read(output.join(&hex)).unwrap();
+            assert_eq!(hex::encode(openssl::sha::sha256(&blob)), hex);
+            assert_eq!(blob.len() as u64, desc.size());
+            expected.push(hex);
+        }
+        expected.sort();
+
+        let mut entries: Vec<String> = std::fs::read_dir(&output)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_build_to_chunk_map() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("dir").unwrap();
+        rootfs.write("dir/file_a", "content a").unwrap();
+        rootfs.write("file_b", "content b").unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let file_b = files.split_off(Utf8Path::new("/file_b"));
+        let component = |files| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+        let components = vec![
+            ("a".to_string(), component(files)),
+            ("empty".to_string(), component(FileMap::new())),
+            ("b".to_string(), component(file_b)),
+        ];
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("map")).unwrap();
+        let image = Builder::new(&rootfs, components)
+            .unwrap()
+            .build_to_chunk_map(&output)
+            .unwrap();
+
+        let mut entries: Vec<String> = std::fs::read_dir(&output)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["config.json", "layers.json", "manifest.json"]);
+
+        let manifest: oci_image::ImageManifest =
+            serde_json::from_slice(&std::fs::read(output.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest, image.manifest);
+        let layers: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(output.join("layers.json")).unwrap()).unwrap();
+        let layers = layers.as_array().unwrap();
+        // the empty component has no layer
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0]["component"], "a");
+        assert_eq!(
+            layers[0]["files"],
+            serde_json::json!(["/dir", "/dir/file_a"])
+        );
+        assert_eq!(layers[1]["component"], "b");
+        assert_eq!(
+            layers[1]["digest"],
+            manifest.layers()[1].digest().to_string()
+        );
+        assert_eq!(layers[1]["diff_id"], image.config.rootfs().diff_ids()[1]);
+    }
+
+    #[test]
+    fn test_resume() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file_a", "content a").unwrap();
+        rootfs.write("file_b", "content b").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        // component names are unique to this test, since faults are global
+        let components: Vec<(String, Component)> = files
+            .into_iter()
+            .map(|(path, info)| {
+                (
+                    format!("resume{path}"),
+                    Component {
+                        mtime_clamp: 0,
+                        stability: 0.0,
+                        isolated: false,
+                        files: FileMap::from([(path, info)]),
+                    },
+                )
+            })
+            .collect();
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output = Utf8PathBuf::from_path_buf(output_dir.path().join("oci")).unwrap();
+        let partial = output_dir.path().join(".oci.partial");
+        let build = || {
+            Builder::new(&rootfs, components.clone())
+                .unwrap()
+                .compression(Compression::Gzip(6))
+                .resume(true)
+                .build_to_oci_dir(&output)
+        };
+
+        // the second layer fails; the first one is kept
+        crate::fault::arm(crate::fault::WRITE_LAYER, "resume/file_b");
+        let err = build().unwrap_err();
+        assert!(format!("{err:#}").contains("injected fault"), "{err:#}");
+        assert!(!output.exists());
+        let dir = Dir::open_ambient_dir(&partial, ambient_authority()).unwrap();
+        assert_eq!(ResumeJournal::open(&dir).unwrap().len().unwrap(), 1);
+
+        // resuming doesn't write the first layer again
+        crate::fault::arm(crate::fault::WRITE_LAYER, "resume/file_a");
+        let image = build().unwrap();
+        assert!(crate::fault::inject(crate::fault::WRITE_LAYER, "resume/file_a").is_err());
+        assert!(!partial.exists());
+        assert!(!output.join(resume::JOURNAL_FILE).exists());
+
+        // and the result is the same as building in one go
+        let fresh_output = Utf8PathBuf::from_path_buf(output_dir.path().join("fresh")).unwrap();
+        let fresh = Builder::new(&rootfs, components.clone())
+            .unwrap()
+            .compression(Compression::Gzip(6))
+            .build_to_oci_dir(&fresh_output)
+            .unwrap();
+        assert_eq!(image.manifest, fresh.manifest);
+        // resuming drops the blobs no manifest references, e.g. the empty
+        // config of a new manifest
+        let fresh_dir = Dir::open_ambient_dir(&fresh_output, ambient_authority()).unwrap();
+        resume::prune_unreferenced_blobs(&fresh_dir).unwrap();
+        let blobs = |path: &Utf8Path| {
+            let mut names: Vec<_> = std::fs::read_dir(path.join("blobs/sha256"))
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(blobs(&output), blobs(&fresh_output));
+    }
+
+    #[test]
+    fn test_blob_staging() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        // the component name is unique to this test, since faults are global
+        let components = vec![(
+            "staging/file".to_string(),
+            Component {
+                mtime_clamp: 0,
+                stability: 0.0,
+                isolated: false,
+                files,
+            },
+        )];
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let staging_path = Utf8PathBuf::from_path_buf(output_dir.path().join("staging")).unwrap();
+        let build = || {
+            let mut archive = Vec::new();
+            Builder::new(&rootfs, components.clone())
+                .unwrap()
+                .compression(Compression::Gzip(6))
+                .blob_staging(BlobStaging::open(&staging_path).unwrap())
+                .build_to_oci_archive(&mut archive)
+                .unwrap()
+        };
+
+        let first = build();
+        // the second build doesn't write the layer again
+        crate::fault::arm(crate::fault::WRITE_LAYER, "staging/file");
+        let second = build();
+        assert!(crate::fault::inject(crate::fault::WRITE_LAYER, "staging/file").is_err());
+        assert_eq!(first.manifest, second.manifest);
+    }
+
+    #[test]
+    fn test_compression_rules() {
+        let rules = parse_compression_rules(
+            &[
+                "bigfiles/*=none".into(),
+                "rpm/kernel*=gzip-9".into(),
+                "*=gzip".into(),
+            ],
+            1,
+        )
+        .unwrap();
+        assert_eq!(rules[2].compression, Compression::Gzip(1));
+        assert!(parse_compression_rules(&["rpm/foo".into()], 6).is_err());
+        assert!(parse_compression_rules(&["rpm/foo=zstd".into()], 6).is_err());
+        assert!(parse_compression_rules(&["rpm/foo=gzip-10".into()], 6).is_err());
+
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        let builder = Builder::new(&rootfs, vec![])
+            .unwrap()
+            .compression(Compression::Gzip(6))
+            .compression_rules(rules[..2].to_vec());
+        assert_eq!(
+            builder.layer_compression("bigfiles/usr/share/game/assets.pak"),
+            Compression::None
+        );
+        assert_eq!(
+            builder.layer_compression("rpm/bash rpm/kernel-core"),
+            Compression::Gzip(9)
+        );
+        assert_eq!(builder.layer_compression("rpm/bash"), Compression::Gzip(6));
+    }
+
+    #[test]
+    fn test_stream_layers() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            rootfs.write(name, name).unwrap();
+        }
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let build = |stream: bool| -> oci_image::Descriptor {
+            let components = files
+                .iter()
+                .map(|(path, info)| {
+                    (
+                        path.to_string(),
+                        Component {
+                            mtime_clamp: 0,
+                            stability: 0.0,
+                            isolated: false,
+                            files: FileMap::from([(path.clone(), info.clone())]),
+                        },
+                    )
+                })
+                .collect();
+            let mut output = Vec::new();
+            Builder::new(&rootfs, components)
+                .unwrap()
+                .threads(NonZeroUsize::new(4).unwrap())
+                .stream_layers(stream)
+                .build_to_oci_archive(&mut output)
+                .unwrap();
+
+            let oci_tempdir = tempfile::tempdir().unwrap();
+            tar::Archive::new(output.as_slice())
+                .unpack(oci_tempdir.path())
+                .unwrap();
+            let oci_dir_cap =
+                Dir::open_ambient_dir(oci_tempdir.path(), ambient_authority()).unwrap();
+            let oci_dir = ocidir::OciDir::open(oci_dir_cap).unwrap();
+            let index = oci_dir.read_index().unwrap();
+            let manifest_desc = index.manifests()[0].clone();
+            // all layers made it into the archive
+            let manifest: oci_image::ImageManifest =
+                oci_dir.read_json_blob(&manifest_desc).unwrap();
+            assert_eq!(manifest.layers().len(), 4);
+            for layer in manifest.layers() {
+                oci_dir.read_blob(layer).unwrap();
+            }
+            manifest_desc
+        };
+
+        // streaming only changes the order of entries in the archive
+        assert_eq!(build(true).digest(), build(false).digest());
+    }
+
+    #[test]
+    fn test_history_from_content() {
+        use crate::components::{FileInfo, FileType};
+
+        let file = |mtime| FileInfo {
+            mtime,
+            ..FileInfo::dummy(FileType::File)
+        };
+        let component = Component {
+            mtime_clamp: 1000,
+            stability: 0.0,
+            isolated: false,
+            files: FileMap::from([("/a".into(), file(100)), ("/b".into(), file(500))]),
+        };
+
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        let builder = Builder::new(&rootfs, vec![]).unwrap();
+        assert_eq!(builder.history_created(&component), 1000);
+
+        let builder = builder.history_from_content(true);
+        assert_eq!(builder.history_created(&component), 500);
+
+        // content newer than the clamp is clamped
+        let mut component = component;
+        component.files.insert("/c".into(), file(2000));
+        assert_eq!(builder.history_created(&component), 1000);
+    }
 }
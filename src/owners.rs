@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+
+/// Databases of user names, in lookup order. `/usr/lib` is used by
+/// nss-altfiles, e.g. on ostree-based systems.
+const PASSWD_FILES: &[&str] = &["etc/passwd", "usr/lib/passwd"];
+
+/// Databases of group names, in lookup order.
+const GROUP_FILES: &[&str] = &["etc/group", "usr/lib/group"];
+
+/// Size of the user and group name fields of tar headers.
+const MAX_NAME_LEN: usize = 32;
+
+/// User and group names of the rootfs, keyed by id.
+#[derive(Debug, Default)]
+pub struct OwnerNames {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl OwnerNames {
+    /// Load the user and group names from the passwd and group files of
+    /// `rootfs` (never the host's). Missing files are skipped. If several
+    /// entries have the same id, the first one wins. Names too long for tar
+    /// headers are skipped with a warning, so their owners are only recorded
+    /// by id.
+    pub fn load(rootfs: &Dir) -> Result<Self> {
+        let names = Self {
+            users: load_ids(rootfs, PASSWD_FILES)?,
+            groups: load_ids(rootfs, GROUP_FILES)?,
+        };
+        tracing::debug!(
+            users = names.users.len(),
+            groups = names.groups.len(),
+            "loaded owner names"
+        );
+        Ok(names)
+    }
+
+    pub fn user(&self, uid: u32) -> Option<&str> {
+        self.users.get(&uid).map(String::as_str)
+    }
+
+    pub fn group(&self, gid: u32) -> Option<&str> {
+        self.groups.get(&gid).map(String::as_str)
+    }
+}
+
+/// Read `name:password:id:...` entries from each of `paths` that exists.
+fn load_ids(rootfs: &Dir, paths: &[&str]) -> Result<HashMap<u32, String>> {
+    let mut ids = HashMap::new();
+    for path in paths {
+        let content = match rootfs.read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("reading /{path}")),
+        };
+        for (name, id) in content.lines().filter_map(parse_entry) {
+            if name.len() > MAX_NAME_LEN {
+                tracing::warn!(
+                    file = *path,
+                    name,
+                    id,
+                    "name too long for tar headers; recording id only"
+                );
+                continue;
+            }
+            ids.entry(id).or_insert_with(|| name.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+fn parse_entry(line: &str) -> Option<(&str, u32)> {
+    let mut fields = line.split(':');
+    let name = fields
+        .next()
+        .filter(|n| !n.is_empty() && !n.starts_with('#'))?;
+    let id = fields.nth(1)?.parse().ok()?;
+    Some((name, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+
+    #[test]
+    fn test_owner_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("etc").unwrap();
+        rootfs.create_dir_all("usr/lib").unwrap();
+        rootfs
+            .write(
+                "etc/passwd",
+                "root:x:0:0:root:/root:/bin/bash\n# comment\n\nbroken\ncore:x:1000:1000::/var/home/core:/bin/bash\n",
+            )
+            .unwrap();
+        rootfs
+            .write(
+                "usr/lib/passwd",
+                "toor:x:0:0::/:/sbin/nologin\nbin:x:1:1:bin:/bin:/sbin/nologin\n",
+            )
+            .unwrap();
+        rootfs
+            .write(
+                "etc/group",
+                format!("wheel:x:10:core\n{}:x:11:\n", "g".repeat(33)),
+            )
+            .unwrap();
+
+        let names = OwnerNames::load(&rootfs).unwrap();
+        // /etc takes precedence
+        assert_eq!(names.user(0), Some("root"));
+        assert_eq!(names.user(1), Some("bin"));
+        assert_eq!(names.user(1000), Some("core"));
+        assert_eq!(names.user(42), None);
+        assert_eq!(names.group(10), Some("wheel"));
+        assert_eq!(names.group(0), None);
+        // too long to write
+        assert_eq!(names.group(11), None);
+
+        // nothing to load
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let names = OwnerNames::load(&rootfs).unwrap();
+        assert_eq!(names.user(0), None);
+    }
+}
@@ -12,7 +12,9 @@
 //!
 //! ## Algorithm
 //!
-//! The algorithm has two phases:
+//! The algorithm has two phases. Before those, components marked as isolated
+//! (small but volatile ones, like regenerated caches) are each given their own
+//! layer and left out of the rest of the process.
 //!
 //! ### Phase 1: Size-Based Statistical Classification
 //!
@@ -32,9 +34,57 @@
 //! components are assigned to bins deterministically using a hash of
 //! their component name. This ensures stable bin membership across
 //! builds without needing to track prior build state.
+//!
+//! ### Optimization (optional)
+//!
+//! The phases above are cheap and keep bin membership stable across builds,
+//! but are blind to how much each bin would actually cost to update. The
+//! result can optionally be refined by [`optimize_packing`], a local search
+//! which moves items between groups as long as it lowers the expected update
+//! cost (i.e. the sum over groups of size * (1 - stability)). Each move is an
+//! improvement, so the search can be cut off at any time and still return the
+//! best packing found so far.
+//!
+//! ## Usage
+//!
+//! This module is pure computation: it knows nothing about files or images,
+//! only about named items with a size and a stability. This makes it possible
+//! to simulate packing strategies on synthetic or recorded inputs (e.g. from a
+//! `chunkah plan --json` output) without going through the IO pipeline.
+//!
+//! ```
+//! use chunkah::packing::{PackItem, calculate_packing};
+//!
+//! let item = |name: &str, size, stability| PackItem {
+//!     name: name.into(),
+//!     size,
+//!     stability,
+//!     isolate: false,
+//! };
+//! let items = vec![
+//!     item("rpm/glibc", 10 << 20, 0.9),
+//!     item("rpm/bash", 2 << 20, 0.95),
+//!     item("rpm/kernel", 100 << 20, 0.2),
+//! ];
+//! let groups = calculate_packing(&items, 2);
+//! assert_eq!(groups.len(), 2);
+//! // the kernel is large enough to get its own group
+//! assert!(groups.iter().any(|g| g.indices == [2]));
+//! ```
+//!
+//! [`calculate_packing`] guarantees that:
+//! - every input item ends up in exactly one group,
+//! - there are at most `max_groups` groups, and none are empty,
+//! - groups are sorted by stability descending,
+//! - the result only depends on the input, i.e. it's deterministic,
+//! - isolated items get a group of their own, as long as the budget allows.
+//!
+//! [`optimize_packing`] upholds the same guarantees, except that it's only
+//! deterministic if it's given enough time to finish.
 
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
+use std::time::Instant;
 
 use fnv::FnvHasher;
 
@@ -44,6 +94,11 @@ const SIZE_OUTLIER_THRESHOLD: f64 = 1.5;
 /// Max fraction of layer budget for high-size singletons.
 const HIGH_SIZE_CAP: f64 = 0.8;
 
+/// Minimum decrease in expected update cost (in bytes) for a move to be worth
+/// it during optimization. This also guarantees the search terminates despite
+/// float rounding.
+const MIN_OPTIMIZATION_GAIN: f64 = 1.0;
+
 /// Input item for packing
 #[derive(Debug, Clone)]
 pub struct PackItem {
@@ -53,6 +108,8 @@ pub struct PackItem {
     pub size: u64,
     /// Probability the component doesn't change between updates (0.0 to 1.0)
     pub stability: f64,
+    /// Always give this item a group of its own
+    pub isolate: bool,
 }
 
 /// Output group from packing
@@ -96,6 +153,12 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
         return result;
     }
 
+    // isolated items are set aside as singletons before anything else
+    let (isolated, others): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| items[i].isolate);
+    if !isolated.is_empty() {
+        return pack_isolated(items, &isolated, &others, max_groups);
+    }
+
     // Phase 1: isolate size outliers as singletons
     let (mut result_groups, remaining_indices) = isolate_size_outliers(items, max_groups);
 
@@ -112,6 +175,218 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
     result_groups
 }
 
+/// Like [`calculate_packing`], but first keeps the items of each `seed` group
+/// (indices into `items`, e.g. the layers of a previous build) together in a
+/// group of their own, packing only the other items. This allows carrying
+/// over an existing layout rather than reshuffling all layers.
+///
+/// Isolated items are left out of seed groups. If there are too many seed
+/// groups for `max_groups`, the smallest ones are dropped and their items
+/// packed along with the others.
+pub fn calculate_seeded_packing(
+    items: &[PackItem],
+    max_groups: usize,
+    seed: &[Vec<usize>],
+) -> Vec<PackGroup> {
+    if items.is_empty() || max_groups == 0 {
+        return Vec::new();
+    }
+
+    let mut seeded = vec![false; items.len()];
+    let mut seed_groups: Vec<PackGroup> = Vec::new();
+    for indices in seed {
+        let indices: Vec<usize> = indices
+            .iter()
+            .copied()
+            .filter(|&idx| !items[idx].isolate && !std::mem::replace(&mut seeded[idx], true))
+            .collect();
+        if !indices.is_empty() {
+            seed_groups.push(make_group(items, indices));
+        }
+    }
+
+    // keep room for the items not in any seed group
+    let unseeded = seeded.iter().filter(|&&s| !s).count();
+    let limit = max_groups - usize::from(unseeded > 0);
+    if seed_groups.len() > limit {
+        tracing::warn!(
+            seed_layers = seed_groups.len(),
+            limit,
+            "too many seed layers for max_layers; packing smallest ones"
+        );
+        // stable sort, so ties keep seed order
+        seed_groups.sort_by_key(|g| std::cmp::Reverse(g.size));
+        for group in seed_groups.drain(limit..) {
+            for idx in group.indices {
+                seeded[idx] = false;
+            }
+        }
+    }
+    tracing::debug!(seed_layers = seed_groups.len(), "seeded packing");
+
+    let rest: Vec<usize> = (0..items.len()).filter(|&idx| !seeded[idx]).collect();
+    let rest_items: Vec<PackItem> = rest.iter().map(|&idx| items[idx].clone()).collect();
+    let mut result_groups = seed_groups;
+    for mut group in calculate_packing(&rest_items, max_groups - result_groups.len()) {
+        for idx in &mut group.indices {
+            *idx = rest[*idx];
+        }
+        result_groups.push(group);
+    }
+
+    sort_by_stability_desc(&mut result_groups);
+    result_groups
+}
+
+/// Gives each isolated item its own group and packs the others into the
+/// remaining budget. If there are too many isolated items for the budget, the
+/// excess ones are packed along with the others.
+fn pack_isolated(
+    items: &[PackItem],
+    isolated: &[usize],
+    others: &[usize],
+    max_groups: usize,
+) -> Vec<PackGroup> {
+    // we're only called if there are more items than groups, so there's
+    // always a rest to pack
+    let limit = max_groups - 1;
+    let (singletons, excess) = isolated.split_at(isolated.len().min(limit));
+    if !excess.is_empty() {
+        tracing::warn!(
+            isolated = isolated.len(),
+            limit,
+            "too many isolated components for max_layers; packing excess"
+        );
+    }
+    tracing::debug!(isolated = singletons.len(), "isolated components");
+
+    let mut result_groups: Vec<PackGroup> = singletons
+        .iter()
+        .map(|&idx| make_singleton(items, idx))
+        .collect();
+
+    // pack the rest as if the isolated items didn't exist, then map back
+    let rest: Vec<usize> = excess.iter().chain(others).copied().collect();
+    let rest_items: Vec<PackItem> = rest
+        .iter()
+        .map(|&idx| PackItem {
+            isolate: false,
+            ..items[idx].clone()
+        })
+        .collect();
+    for mut group in calculate_packing(&rest_items, max_groups - singletons.len()) {
+        for idx in &mut group.indices {
+            *idx = rest[*idx];
+        }
+        result_groups.push(group);
+    }
+
+    sort_by_stability_desc(&mut result_groups);
+    result_groups
+}
+
+/// Improves `groups`, as returned by [`calculate_packing`] for `items`, by
+/// moving items between groups while that lowers the expected update cost,
+/// until no move does or `deadline` is reached. See module docstring for
+/// details.
+///
+/// The result upholds the same guarantees as [`calculate_packing`]: the number
+/// of groups doesn't change, no group becomes empty and isolated items stay
+/// alone. It's deterministic unless the deadline is hit, in which case it
+/// depends on how far the search got.
+pub fn optimize_packing(
+    items: &[PackItem],
+    mut groups: Vec<PackGroup>,
+    deadline: Instant,
+) -> Vec<PackGroup> {
+    let cost = |size: u64, stability: f64| size as f64 * (1.0 - stability);
+    let is_isolated =
+        |group: &PackGroup| group.indices.len() == 1 && items[group.indices[0]].isolate;
+    let initial_cost: f64 = groups.iter().map(|g| cost(g.size, g.stability)).sum();
+
+    let (mut passes, mut moves, mut timed_out) = (0, 0, false);
+    'passes: loop {
+        passes += 1;
+        let mut improved = false;
+        for from in 0..groups.len() {
+            let mut pos = 0;
+            // never empty a group
+            while pos < groups[from].indices.len() && groups[from].indices.len() > 1 {
+                if Instant::now() >= deadline {
+                    timed_out = true;
+                    break 'passes;
+                }
+                let idx = groups[from].indices[pos];
+                let item = &items[idx];
+                if item.isolate {
+                    pos += 1;
+                    continue;
+                }
+
+                let source = &groups[from];
+                let source_size = source.size - item.size;
+                let source_stability: f64 = source
+                    .indices
+                    .iter()
+                    .filter(|&&i| i != idx)
+                    .map(|&i| items[i].stability)
+                    .product();
+                let removal_gain =
+                    cost(source.size, source.stability) - cost(source_size, source_stability);
+
+                // the best destination, with ties going to the first group
+                let mut best: Option<(usize, f64)> = None;
+                for (to, group) in groups.iter().enumerate() {
+                    if to == from || is_isolated(group) {
+                        continue;
+                    }
+                    let added_cost = cost(group.size + item.size, group.stability * item.stability)
+                        - cost(group.size, group.stability);
+                    let gain = removal_gain - added_cost;
+                    if gain > MIN_OPTIMIZATION_GAIN && best.is_none_or(|(_, g)| gain > g) {
+                        best = Some((to, gain));
+                    }
+                }
+
+                if let Some((to, _)) = best {
+                    // NB: don't advance pos; the next item shifted into it
+                    let source = &mut groups[from];
+                    source.indices.remove(pos);
+                    source.size = source_size;
+                    source.stability = source_stability;
+                    let dest = &mut groups[to];
+                    dest.indices.push(idx);
+                    dest.size += item.size;
+                    dest.stability *= item.stability;
+                    moves += 1;
+                    improved = true;
+                } else {
+                    pos += 1;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    let final_cost: f64 = groups.iter().map(|g| cost(g.size, g.stability)).sum();
+    tracing::debug!(
+        passes,
+        moves,
+        timed_out,
+        initial_cost,
+        final_cost,
+        "optimized packing"
+    );
+
+    for group in &mut groups {
+        group.indices.sort();
+    }
+    sort_by_stability_desc(&mut groups);
+    groups
+}
+
 fn sort_by_stability_desc(groups: &mut [PackGroup]) {
     groups.sort_by(|a, b| {
         b.stability
@@ -437,6 +712,7 @@ mod tests {
             name: name.to_string(),
             size,
             stability,
+            isolate: false,
         }
     }
 
@@ -594,4 +870,170 @@ mod tests {
         let huge_group = result.iter().find(|g| g.indices.contains(&0)).unwrap();
         assert_eq!(huge_group.indices.len(), 1);
     }
+
+    #[test]
+    fn test_isolated_items_get_singletons() {
+        let mut items: Vec<PackItem> = (0..20)
+            .map(|i| make_item(&format!("pkg{i}"), 1000, 0.9))
+            .collect();
+        items.push(PackItem {
+            isolate: true,
+            ..make_item("caches", 10, 0.1)
+        });
+        let result = calculate_packing(&items, 4);
+        verify_packing_result(&items, &result, 4);
+        let group = result.iter().find(|g| g.indices.contains(&20)).unwrap();
+        assert_eq!(group.indices, vec![20]);
+
+        // too many isolated items; the rest still get packed
+        for item in &mut items[..5] {
+            item.isolate = true;
+        }
+        let result = calculate_packing(&items, 4);
+        verify_packing_result(&items, &result, 4);
+        let result = calculate_packing(&items, 1);
+        verify_packing_result(&items, &result, 1);
+    }
+
+    #[test]
+    fn test_optimize_packing() {
+        let cost = |groups: &[PackGroup]| -> f64 {
+            groups
+                .iter()
+                .map(|g| g.size as f64 * (1.0 - g.stability))
+                .sum()
+        };
+
+        // a volatile item hashed in with stable ones gets moved out
+        let items = vec![
+            make_item("stable1", 1000, 0.99),
+            make_item("stable2", 1000, 0.99),
+            make_item("volatile1", 1000, 0.1),
+            make_item("volatile2", 1000, 0.1),
+        ];
+        let groups = vec![
+            make_group(&items, vec![0, 2]),
+            make_group(&items, vec![1, 3]),
+        ];
+        let far = Instant::now() + std::time::Duration::from_secs(3600);
+        let result = optimize_packing(&items, groups.clone(), far);
+        verify_packing_result(&items, &result, 2);
+        let indices: Vec<Vec<usize>> = result.iter().map(|g| g.indices.clone()).collect();
+        assert_eq!(indices, vec![vec![0, 1], vec![2, 3]]);
+        assert!(cost(&result) < cost(&groups));
+
+        // an expired deadline returns the packing as is
+        let result = optimize_packing(&items, groups.clone(), Instant::now());
+        let indices: Vec<Vec<usize>> = result.iter().map(|g| g.indices.clone()).collect();
+        assert_eq!(indices, vec![vec![0, 2], vec![1, 3]]);
+    }
+
+    #[test]
+    fn test_seeded_packing() {
+        let mut items: Vec<PackItem> = (0..20)
+            .map(|i| make_item(&format!("pkg{i}"), 1000, 0.9))
+            .collect();
+        items[19].isolate = true;
+
+        // seed groups are kept as is, minus isolated items
+        let seed = vec![vec![0, 5, 10], vec![1, 19], vec![]];
+        let result = calculate_seeded_packing(&items, 4, &seed);
+        verify_packing_result(&items, &result, 4);
+        let has_group =
+            |result: &[PackGroup], indices: &[usize]| result.iter().any(|g| g.indices == indices);
+        assert!(has_group(&result, &[0, 5, 10]));
+        assert!(has_group(&result, &[1]));
+        assert!(has_group(&result, &[19]));
+
+        // too many seed groups; the largest ones are kept
+        let seed = vec![vec![0], vec![1, 2, 3], vec![4, 5]];
+        let result = calculate_seeded_packing(&items, 3, &seed);
+        verify_packing_result(&items, &result, 3);
+        assert!(has_group(&result, &[1, 2, 3]));
+        assert!(has_group(&result, &[4, 5]));
+
+        // everything seeded
+        let seed = vec![(0..10).collect(), (10..20).collect()];
+        let result = calculate_seeded_packing(&items, 3, &seed);
+        verify_packing_result(&items, &result, 3);
+
+        // no seed is the same as unseeded
+        let indices = |groups: &[PackGroup]| -> Vec<Vec<usize>> {
+            groups.iter().map(|g| g.indices.clone()).collect()
+        };
+        assert_eq!(
+            indices(&calculate_seeded_packing(&items, 4, &[])),
+            indices(&calculate_packing(&items, 4))
+        );
+    }
+
+    /// Minimal xorshift PRNG so that the property tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    #[test]
+    fn test_packing_properties() {
+        let mut rng = Rng(0x5eed);
+        for _ in 0..500 {
+            let n = rng.below(200) as usize;
+            let max_groups = 1 + rng.below(80) as usize;
+            let items: Vec<PackItem> = (0..n)
+                .map(|i| {
+                    // sizes spanning several orders of magnitude, like real rootfses
+                    let magnitude = rng.below(32) + 1;
+                    let size = rng.below(1 << magnitude);
+                    let stability = rng.below(1001) as f64 / 1000.0;
+                    PackItem {
+                        isolate: rng.below(20) == 0,
+                        ..make_item(&format!("comp{i}"), size, stability)
+                    }
+                })
+                .collect();
+
+            let result = calculate_packing(&items, max_groups);
+            verify_packing_result(&items, &result, max_groups);
+
+            // deterministic
+            let again = calculate_packing(&items, max_groups);
+            let indices = |groups: &[PackGroup]| -> Vec<Vec<usize>> {
+                groups.iter().map(|g| g.indices.clone()).collect()
+            };
+            assert_eq!(indices(&result), indices(&again));
+
+            // optimizing keeps the invariants and never makes things worse
+            let far = Instant::now() + std::time::Duration::from_secs(3600);
+            let optimized = optimize_packing(&items, result.clone(), far);
+            verify_packing_result(&items, &optimized, max_groups);
+            assert_eq!(optimized.len(), result.len());
+            let cost = |groups: &[PackGroup]| -> f64 {
+                groups
+                    .iter()
+                    .map(|g| g.size as f64 * (1.0 - g.stability))
+                    .sum()
+            };
+            assert!(cost(&optimized) <= cost(&result) * (1.0 + 1e-9));
+
+            // isolated items get singletons if the budget allows
+            let isolated = items.iter().filter(|i| i.isolate).count();
+            if n <= max_groups || isolated < max_groups {
+                for group in result.iter().chain(&optimized) {
+                    if group.indices.iter().any(|&i| items[i].isolate) {
+                        assert_eq!(group.indices.len(), 1, "isolated item not alone");
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use ocidir::oci_spec::image as oci_image;
+use serde::{Deserialize, Serialize};
+
+use crate::components::canonical_name;
+use crate::stubs::SpecialDirPolicy;
+
+/// Media type of the plan blob, also used as the artifact type of the
+/// manifest carrying it.
+pub const PLAN_MEDIA_TYPE: &str = "application/vnd.coreos.chunkah.plan.v1+json";
+
+/// The outcome of packing: which components ended up in which layer.
+///
+/// The plan is what a subsequent build of the same image needs to know to
+/// keep layers stable, so it can be exported alongside the image.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    /// Version of the scheme component names were derived with, or 0 if
+    /// unknown (i.e. the plan predates versioning).
+    #[serde(default)]
+    pub naming_scheme: u32,
+    /// How empty mount-point directories were handled.
+    #[serde(default)]
+    pub mount_points: SpecialDirPolicy,
+    /// How directories marked opaque by overlayfs were handled.
+    #[serde(default)]
+    pub opaque_dirs: SpecialDirPolicy,
+    /// Layers in image order.
+    pub layers: Vec<PlanLayer>,
+}
+
+/// Annotation set by rpm-ostree on each layer of the images it chunks, listing
+/// the (comma-separated) packages in the layer.
+const RPM_OSTREE_COMPONENTS_ANNOTATION: &str = "ostree.components";
+
+/// Annotations set by chunkah on each layer: the (space-separated) components
+/// in the layer, its stability, and its stable identifier.
+const COMPONENT_ANNOTATION: &str = "org.chunkah.component";
+const STABILITY_ANNOTATION: &str = "org.chunkah.stability";
+const LAYER_ID_ANNOTATION: &str = "org.chunkah.layer-id";
+
+impl Plan {
+    /// Parse `json` as either a plan (e.g. as written by `--write-plan-to`) or
+    /// the manifest of an image built by chunkah or chunked by rpm-ostree.
+    pub fn from_plan_or_manifest(json: &str) -> Result<Self> {
+        if let Ok(plan) = serde_json::from_str::<Plan>(json) {
+            return Ok(plan);
+        }
+        let manifest: oci_image::ImageManifest =
+            serde_json::from_str(json).context("parsing as plan or image manifest")?;
+        Self::from_manifest(&manifest)
+    }
+
+    /// Build a plan from the layer annotations of an image built by chunkah or
+    /// chunked by rpm-ostree.
+    pub fn from_manifest(manifest: &oci_image::ImageManifest) -> Result<Self> {
+        let plan = Self::from_chunkah_manifest(manifest);
+        if !plan.layers.is_empty() {
+            return Ok(plan);
+        }
+        let plan = Self::from_rpm_ostree_manifest(manifest);
+        anyhow::ensure!(
+            !plan.layers.is_empty(),
+            "image manifest has no layers with {COMPONENT_ANNOTATION} or {RPM_OSTREE_COMPONENTS_ANNOTATION} annotations"
+        );
+        Ok(plan)
+    }
+
+    /// Record the digests of the layers built for this plan in the image of
+    /// `manifest`. Layers are matched by their components.
+    pub fn record_digests(&mut self, manifest: &oci_image::ImageManifest) {
+        let built = Self::from_chunkah_manifest(manifest);
+        let digests: HashMap<&[String], &Option<String>> = built
+            .layers
+            .iter()
+            .map(|l| (l.components.as_slice(), &l.digest))
+            .collect();
+        for layer in &mut self.layers {
+            let mut components = layer.components.clone();
+            components.sort();
+            layer.digest = digests
+                .get(components.as_slice())
+                .and_then(|d| (*d).clone());
+        }
+    }
+
+    /// Build a plan from the layer annotations of an image built by chunkah.
+    /// Layers without components (e.g. from `--also-squashed`) are skipped.
+    /// Only compressed sizes are known, so sizes are zero.
+    pub fn from_chunkah_manifest(manifest: &oci_image::ImageManifest) -> Self {
+        let layers = manifest
+            .layers()
+            .iter()
+            .filter_map(|layer| {
+                let annotations = layer.annotations().as_ref()?;
+                let mut components: Vec<String> = annotations
+                    .get(COMPONENT_ANNOTATION)?
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect();
+                components.sort();
+                let id = annotations
+                    .get(LAYER_ID_ANNOTATION)
+                    .or(components.first())?
+                    .clone();
+                let stability = annotations
+                    .get(STABILITY_ANNOTATION)
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0.0);
+                Some(PlanLayer {
+                    id,
+                    components,
+                    size: 0,
+                    stability,
+                    content_class: ContentClass::from_stability(stability),
+                    estimated_compressed_size: None,
+                    digest: Some(layer.digest().to_string()),
+                })
+            })
+            .collect();
+        // the scheme isn't recorded in the image; assume it's the current one
+        Plan {
+            naming_scheme: crate::components::NAMING_SCHEME,
+            layers,
+            ..Default::default()
+        }
+    }
+
+    /// Build a plan from the layer annotations of an image chunked by
+    /// rpm-ostree. Packages are mapped to components of the `rpm` repo of the
+    /// same name. Layers without components (e.g. the ostree commit layer) are
+    /// skipped. Only the layout is known, so sizes and stabilities are zero.
+    pub fn from_rpm_ostree_manifest(manifest: &oci_image::ImageManifest) -> Self {
+        let layers = manifest
+            .layers()
+            .iter()
+            .filter_map(|layer| {
+                let value = layer
+                    .annotations()
+                    .as_ref()?
+                    .get(RPM_OSTREE_COMPONENTS_ANNOTATION)?;
+                let mut components: Vec<String> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| canonical_name("rpm", name))
+                    .collect();
+                components.sort();
+                components.dedup();
+                let id = components.first()?.clone();
+                Some(PlanLayer {
+                    id,
+                    components,
+                    size: 0,
+                    stability: 0.0,
+                    content_class: ContentClass::default(),
+                    estimated_compressed_size: None,
+                    digest: Some(layer.digest().to_string()),
+                })
+            })
+            .collect();
+        Plan {
+            naming_scheme: crate::components::NAMING_SCHEME,
+            layers,
+            ..Default::default()
+        }
+    }
+}
+
+/// A single layer in the plan.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanLayer {
+    /// Stable identifier of the layer: the name of its largest component. This
+    /// allows tracking a layer across builds even as its digest changes.
+    #[serde(default)]
+    pub id: String,
+    /// Names of the components packed in this layer, sorted.
+    pub components: Vec<String>,
+    /// Total size of the files in the layer.
+    pub size: u64,
+    /// Combined stability of the components in the layer.
+    pub stability: f64,
+    /// Whether the layer is expected to change between builds. Also recorded
+    /// in the `org.chunkah.content-class` layer annotation.
+    #[serde(default)]
+    pub content_class: ContentClass,
+    /// Estimated size of the layer once gzip-compressed, if computed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_compressed_size: Option<u64>,
+    /// Digest of the layer blob, if known (i.e. for plans read from an image
+    /// manifest).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+/// Stability from which a layer is classified as cold.
+const COLD_STABILITY: f64 = 0.9;
+
+/// Whether the content of a layer is expected to change between builds.
+///
+/// This is a hint for pullers and provisioning tools: cold layers are likely
+/// already present from a previous pull of the image, so e.g. hot layers can
+/// be fetched first, or cold layers prefetched ahead of an update.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentClass {
+    /// Content which rarely changes, e.g. long-stable packages.
+    Cold,
+    /// Content which is likely to change, or whose stability is unknown.
+    #[default]
+    Hot,
+}
+
+impl ContentClass {
+    /// Classify content of the given stability.
+    pub fn from_stability(stability: f64) -> Self {
+        if stability >= COLD_STABILITY {
+            ContentClass::Cold
+        } else {
+            ContentClass::Hot
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentClass::Cold => "cold",
+            ContentClass::Hot => "hot",
+        }
+    }
+}
+
+/// Differences in layout between two plans of the same image.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct PlanDiff {
+    /// Components in both plans, but in layers with different identifiers.
+    pub moved: Vec<MovedComponent>,
+    /// Components only in the new plan.
+    pub added: Vec<String>,
+    /// Components only in the old plan.
+    pub removed: Vec<String>,
+    /// Identifiers of the layers of the new plan which don't have the same
+    /// components as in the old plan, and so will need to be redownloaded.
+    pub changed_layers: Vec<String>,
+    /// Total size of the changed layers.
+    pub changed_size: u64,
+}
+
+/// A component which moved to another layer.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct MovedComponent {
+    pub name: String,
+    /// Identifier of its layer in the old plan.
+    pub from: String,
+    /// Identifier of its layer in the new plan.
+    pub to: String,
+}
+
+impl PlanDiff {
+    /// Compare the layout of `new` against `old`. Layers are identified by
+    /// their components rather than their identifier, which can change with
+    /// the size of their content. All lists are sorted.
+    pub fn compute(old: &Plan, new: &Plan) -> Self {
+        fn layer_of(plan: &Plan) -> BTreeMap<String, &PlanLayer> {
+            plan.layers
+                .iter()
+                .flat_map(|l| l.components.iter().map(move |c| (c.clone(), l)))
+                .collect()
+        }
+        let (old_layers, new_layers) = (layer_of(old), layer_of(new));
+
+        let mut diff = PlanDiff::default();
+        // a component moved if the components it shares its layer with
+        // changed; added and removed ones don't count
+        let mates = |layer: &PlanLayer| -> BTreeSet<String> {
+            layer
+                .components
+                .iter()
+                .filter(|c| old_layers.contains_key(*c) && new_layers.contains_key(*c))
+                .cloned()
+                .collect()
+        };
+        for (name, to) in &new_layers {
+            match old_layers.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(from) if mates(from) != mates(to) => diff.moved.push(MovedComponent {
+                    name: name.clone(),
+                    from: from.id.clone(),
+                    to: to.id.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old_layers
+            .keys()
+            .filter(|name| !new_layers.contains_key(*name))
+            .cloned()
+            .collect();
+
+        let components =
+            |layer: &PlanLayer| -> BTreeSet<String> { layer.components.iter().cloned().collect() };
+        let old_sets: HashSet<BTreeSet<String>> = old.layers.iter().map(components).collect();
+        for layer in &new.layers {
+            if !old_sets.contains(&components(layer)) {
+                diff.changed_layers.push(layer.id.clone());
+                diff.changed_size += layer.size;
+            }
+        }
+        diff.changed_layers.sort();
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_plan_or_manifest() {
+        let plan = Plan::from_plan_or_manifest(
+            r#"{"layers": [{"components": ["rpm/bash"], "size": 1, "stability": 0.5}]}"#,
+        )
+        .unwrap();
+        assert_eq!(plan.layers[0].components, ["rpm/bash"]);
+        assert_eq!(plan.naming_scheme, 0);
+
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+                "size": 1
+            },
+            "layers": [
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "size": 1
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "size": 1,
+                    "annotations": {"ostree.components": "kernel,linux-firmware"}
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+                    "size": 1,
+                    "annotations": {"ostree.components": "glibc"}
+                }
+            ]
+        }"#;
+        let plan = Plan::from_plan_or_manifest(manifest).unwrap();
+        let layers: Vec<_> = plan.layers.iter().map(|l| &l.components).collect();
+        assert_eq!(
+            layers,
+            [
+                &vec!["rpm/kernel".to_string(), "rpm/linux-firmware".to_string()],
+                &vec!["rpm/glibc".to_string()],
+            ]
+        );
+        assert_eq!(plan.layers[0].id, "rpm/kernel");
+        assert_eq!(
+            plan.layers[1].digest.as_deref(),
+            Some("sha256:3333333333333333333333333333333333333333333333333333333333333333")
+        );
+
+        assert!(Plan::from_plan_or_manifest("{}").is_err());
+    }
+
+    #[test]
+    fn test_from_chunkah_manifest() {
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+                "size": 1
+            },
+            "layers": [
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "size": 1,
+                    "annotations": {
+                        "org.chunkah.component": "rpm/glibc rpm/bash",
+                        "org.chunkah.layer-id": "rpm/glibc",
+                        "org.chunkah.stability": "0.950"
+                    }
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "size": 1,
+                    "annotations": {"org.chunkah.component": "chunkah/unclaimed"}
+                }
+            ]
+        }"#;
+        let plan = Plan::from_plan_or_manifest(manifest).unwrap();
+        assert_eq!(plan.layers.len(), 2);
+        assert_eq!(plan.layers[0].components, ["rpm/bash", "rpm/glibc"]);
+        assert_eq!(plan.layers[0].id, "rpm/glibc");
+        assert_eq!(plan.layers[0].content_class, ContentClass::Cold);
+        assert_eq!(plan.layers[1].id, "chunkah/unclaimed");
+        assert_eq!(plan.layers[1].stability, 0.0);
+    }
+
+    #[test]
+    fn test_record_digests() {
+        let manifest: oci_image::ImageManifest = serde_json::from_str(
+            r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+                "size": 1
+            },
+            "layers": [
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "size": 1,
+                    "annotations": {"org.chunkah.component": "rpm/glibc rpm/bash"}
+                }
+            ]
+        }"#,
+        )
+        .unwrap();
+        let layer = |components: &[&str]| PlanLayer {
+            id: components[0].into(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+            size: 1,
+            stability: 0.0,
+            content_class: ContentClass::Hot,
+            estimated_compressed_size: None,
+            digest: None,
+        };
+        let mut plan = Plan {
+            layers: vec![layer(&["rpm/glibc", "rpm/bash"]), layer(&["rpm/vim"])],
+            ..Default::default()
+        };
+        plan.record_digests(&manifest);
+        assert_eq!(
+            plan.layers[0].digest.as_deref(),
+            Some("sha256:1111111111111111111111111111111111111111111111111111111111111111")
+        );
+        assert_eq!(plan.layers[1].digest, None);
+    }
+
+    #[test]
+    fn test_content_class() {
+        assert_eq!(ContentClass::from_stability(0.0), ContentClass::Hot);
+        assert_eq!(ContentClass::from_stability(0.5), ContentClass::Hot);
+        assert_eq!(ContentClass::from_stability(0.9), ContentClass::Cold);
+        assert_eq!(ContentClass::from_stability(1.0), ContentClass::Cold);
+
+        // plans predating the classification parse as hot
+        let plan: Plan = serde_json::from_str(
+            r#"{"layers": [{"id": "a", "components": ["a"], "size": 1, "stability": 1.0}]}"#,
+        )
+        .unwrap();
+        assert_eq!(plan.layers[0].content_class, ContentClass::Hot);
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["layers"][0]["content_class"], "hot");
+    }
+
+    #[test]
+    fn test_plan_diff() {
+        let layer = |id: &str, components: &[&str], size| PlanLayer {
+            id: id.into(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+            size,
+            stability: 0.5,
+            content_class: ContentClass::Hot,
+            estimated_compressed_size: None,
+            digest: None,
+        };
+        let plan = |layers| Plan {
+            layers,
+            ..Default::default()
+        };
+        let old = plan(vec![
+            layer("rpm/kernel", &["rpm/kernel"], 100),
+            layer("rpm/glibc", &["rpm/bash", "rpm/glibc"], 50),
+            layer("rpm/vim", &["rpm/nano", "rpm/vim"], 20),
+        ]);
+        let new = plan(vec![
+            layer("rpm/kernel", &["rpm/kernel"], 100),
+            layer("rpm/glibc", &["rpm/glibc"], 40),
+            layer("rpm/vim", &["rpm/bash", "rpm/emacs", "rpm/vim"], 60),
+        ]);
+
+        let diff = PlanDiff::compute(&old, &new);
+        let moved = |name: &str, from: &str, to: &str| MovedComponent {
+            name: name.into(),
+            from: from.into(),
+            to: to.into(),
+        };
+        assert_eq!(
+            diff.moved,
+            [
+                moved("rpm/bash", "rpm/glibc", "rpm/vim"),
+                moved("rpm/glibc", "rpm/glibc", "rpm/glibc"),
+                moved("rpm/vim", "rpm/vim", "rpm/vim"),
+            ]
+        );
+        assert_eq!(diff.added, ["rpm/emacs"]);
+        assert_eq!(diff.removed, ["rpm/nano"]);
+        assert_eq!(diff.changed_layers, ["rpm/glibc", "rpm/vim"]);
+        assert_eq!(diff.changed_size, 100);
+
+        assert_eq!(PlanDiff::compute(&new, &new), PlanDiff::default());
+
+        // content changes which rename layers aren't moves
+        let renamed = plan(vec![
+            layer("rpm/kernel", &["rpm/kernel"], 100),
+            layer("rpm/glibc", &["rpm/glibc"], 40),
+            layer("rpm/emacs", &["rpm/bash", "rpm/emacs", "rpm/vim"], 80),
+        ]);
+        assert_eq!(PlanDiff::compute(&new, &renamed), PlanDiff::default());
+    }
+}
@@ -0,0 +1,115 @@
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use ocidir::oci_spec::image as oci_image;
+
+/// Fetch the image manifest of `imgref`, a containers-transports reference
+/// such as `docker://quay.io/org/img:tag` or `oci:/path/to/dir`.
+///
+/// This only fetches metadata; no blobs are downloaded. Manifest lists are
+/// resolved to the image for `arch`.
+pub fn fetch_manifest(imgref: &str, arch: &str) -> Result<oci_image::ImageManifest> {
+    let raw = skopeo_inspect_raw(imgref)?;
+    let value: serde_json::Value =
+        serde_json::from_slice(&raw).with_context(|| format!("parsing manifest of {imgref}"))?;
+    if value.get("manifests").is_none() {
+        return serde_json::from_value(value)
+            .with_context(|| format!("parsing manifest of {imgref}"));
+    }
+
+    let index: oci_image::ImageIndex =
+        serde_json::from_value(value).with_context(|| format!("parsing index of {imgref}"))?;
+    let desc = index
+        .manifests()
+        .iter()
+        .find(|m| {
+            m.platform()
+                .as_ref()
+                .is_some_and(|p| p.architecture().to_string() == arch)
+        })
+        .with_context(|| format!("no image for architecture {arch} in {imgref}"))?;
+    let repo = imgref
+        .strip_prefix("docker://")
+        .map(docker_repo)
+        .with_context(|| {
+            format!("resolving manifest lists is only supported for docker:// references: {imgref}")
+        })?;
+
+    let imgref = format!("docker://{repo}@{}", desc.digest());
+    tracing::debug!(imgref = %imgref, "resolved manifest list entry");
+    let raw = skopeo_inspect_raw(&imgref)?;
+    serde_json::from_slice(&raw).with_context(|| format!("parsing manifest of {imgref}"))
+}
+
+/// Fetch the image config of `imgref`, resolving manifest lists to the image
+/// for `arch`.
+pub fn fetch_config(imgref: &str, arch: &str) -> Result<oci_image::ImageConfiguration> {
+    tracing::debug!(imgref = %imgref, "fetching config");
+    let output = Command::new("skopeo")
+        .args([
+            "--override-arch",
+            arch,
+            "inspect",
+            "--config",
+            "--raw",
+            imgref,
+        ])
+        .output()
+        .context("running skopeo")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "skopeo inspect --config {imgref} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout).with_context(|| format!("parsing config of {imgref}"))
+}
+
+/// Return the repository part of a docker reference, i.e. without the tag
+/// or digest.
+fn docker_repo(reference: &str) -> &str {
+    let reference = reference
+        .split_once('@')
+        .map_or(reference, |(repo, _)| repo);
+    // a ':' after the last '/' starts the tag; one before it is a registry port
+    match reference.rfind(':') {
+        Some(i) if !reference[i..].contains('/') => &reference[..i],
+        _ => reference,
+    }
+}
+
+fn skopeo_inspect_raw(imgref: &str) -> Result<Vec<u8>> {
+    tracing::debug!(imgref = %imgref, "fetching manifest");
+    let output = Command::new("skopeo")
+        .args(["inspect", "--raw", imgref])
+        .output()
+        .context("running skopeo")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "skopeo inspect {imgref} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_repo() {
+        assert_eq!(docker_repo("quay.io/org/img:tag"), "quay.io/org/img");
+        assert_eq!(docker_repo("quay.io/org/img"), "quay.io/org/img");
+        assert_eq!(docker_repo("localhost:5000/img"), "localhost:5000/img");
+        assert_eq!(docker_repo("localhost:5000/img:tag"), "localhost:5000/img");
+        assert_eq!(
+            docker_repo("quay.io/org/img:tag@sha256:abcd"),
+            "quay.io/org/img"
+        );
+        assert_eq!(
+            docker_repo("quay.io/org/img@sha256:abcd"),
+            "quay.io/org/img"
+        );
+    }
+}
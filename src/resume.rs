@@ -0,0 +1,474 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use ocidir::OciRead;
+use ocidir::oci_spec::image as oci_image;
+use serde::{Deserialize, Serialize};
+
+use crate::components::{Component, FileType};
+use crate::ocibuilder::{Compression, LayerBlob};
+use crate::tar::Normalization;
+use crate::utils;
+
+/// File of a partial output recording the layers completed so far.
+pub const JOURNAL_FILE: &str = "chunkah-resume.json";
+
+/// The layers completed in a partial output directory, so that an interrupted
+/// build can reuse them rather than writing them again.
+///
+/// Layers are keyed by a fingerprint of everything their content is derived
+/// from (see [`layer_key`]), so a layer is only reused if it would come out
+/// the same.
+pub struct ResumeJournal {
+    dir: Dir,
+    layers: Mutex<HashMap<String, JournalLayer>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalLayer {
+    /// Hex SHA-256 digest of the blob.
+    digest: String,
+    size: u64,
+    /// Hex SHA-256 digest of the uncompressed tar.
+    diff_id: String,
+    media_type: oci_image::MediaType,
+}
+
+impl JournalLayer {
+    fn new(layer: &LayerBlob) -> Self {
+        let desc = &layer.descriptor;
+        JournalLayer {
+            digest: desc.digest().digest().to_string(),
+            size: desc.size(),
+            diff_id: layer
+                .diff_id
+                .strip_prefix("sha256:")
+                .unwrap_or(&layer.diff_id)
+                .to_string(),
+            media_type: desc.media_type().clone(),
+        }
+    }
+
+    fn to_layer(&self) -> Option<LayerBlob> {
+        let digest = oci_image::Sha256Digest::from_str(&self.digest).ok()?;
+        let diff_id = oci_image::Sha256Digest::from_str(&self.diff_id).ok()?;
+        let descriptor = oci_image::DescriptorBuilder::default()
+            .media_type(self.media_type.clone())
+            .digest(digest)
+            .size(self.size)
+            .build()
+            .ok()?;
+        Some(LayerBlob {
+            descriptor,
+            diff_id: oci_image::Digest::from(diff_id).to_string(),
+        })
+    }
+
+    fn blob_path(&self) -> String {
+        format!("blobs/sha256/{}", self.digest)
+    }
+}
+
+impl ResumeJournal {
+    /// Open the journal of the partial output `dir`. It's empty if the
+    /// directory is new, or if the journal can't be parsed.
+    pub fn open(dir: &Dir) -> Result<Self> {
+        let layers = match dir.read_to_string(JOURNAL_FILE) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                tracing::warn!(err = %e, "ignoring unparseable resume journal");
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).context("reading resume journal"),
+        };
+        Ok(Self {
+            dir: dir
+                .try_clone()
+                .context("cloning partial output directory")?,
+            layers: Mutex::new(layers),
+        })
+    }
+
+    /// Number of layers recorded.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.lock()?.len())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, JournalLayer>>> {
+        self.layers
+            .lock()
+            .map_err(|_| anyhow::anyhow!("resume journal lock poisoned"))
+    }
+
+    /// The layer recorded for `key`, if its blob is still complete.
+    pub fn get(&self, key: &str) -> Result<Option<LayerBlob>> {
+        let Some(entry) = self.lock()?.get(key).cloned() else {
+            return Ok(None);
+        };
+        // blobs are only renamed into place once complete, but may have been
+        // moved out since (by a blobs output failing halfway)
+        match self.dir.metadata(entry.blob_path()) {
+            Ok(meta) if meta.len() == entry.size => {}
+            _ => {
+                tracing::debug!(blob = %entry.digest, "recorded layer blob missing");
+                return Ok(None);
+            }
+        }
+        Ok(entry.to_layer())
+    }
+
+    /// Record `layer` as completed for `key`.
+    pub fn record(&self, key: &str, layer: &LayerBlob) -> Result<()> {
+        let mut layers = self.lock()?;
+        layers.insert(key.to_string(), JournalLayer::new(layer));
+        // still under the lock, so that writes are ordered
+        let content = serde_json::to_vec(&*layers).context("serializing resume journal")?;
+        self.dir
+            .atomic_write(JOURNAL_FILE, content)
+            .context("writing resume journal")
+    }
+}
+
+/// Directory layer blobs are staged in across builds, so that a build after
+/// one that crashed (or was simply run before) can skip writing the layers
+/// which were completed.
+///
+/// Blobs are stored under `blobs/sha256/`, named by their digest, and the
+/// layer written for each [`layer_key`] under `layers/`. Unlike a
+/// [`ResumeJournal`], this isn't tied to an output, and is safe to share
+/// between concurrent builds: entries are only renamed into place once
+/// complete, and blobs are checked against their digest before being reused.
+pub struct BlobStaging {
+    dir: Dir,
+}
+
+impl BlobStaging {
+    /// Open the staging directory at `path`, creating it if needed.
+    pub fn open(path: &Utf8Path) -> Result<Self> {
+        for subdir in ["blobs/sha256", "layers"] {
+            let path = path.join(subdir);
+            std::fs::create_dir_all(&path).with_context(|| format!("creating {path}"))?;
+        }
+        let dir = Dir::open_ambient_dir(path, cap_std_ext::cap_std::ambient_authority())
+            .with_context(|| format!("opening staging directory {path}"))?;
+        Ok(Self { dir })
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        let dir = self.dir.try_clone().context("cloning staging directory")?;
+        Ok(Self { dir })
+    }
+
+    /// The layer staged for `key`, if any, with its blob added to `oci_dir`.
+    pub fn get(&self, key: &str, oci_dir: &Dir) -> Result<Option<LayerBlob>> {
+        let entry: JournalLayer = match self.dir.read_to_string(format!("layers/{key}.json")) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("parsing staged layer {key}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading staged layer {key}")),
+        };
+        let Some(layer) = entry.to_layer() else {
+            return Ok(None);
+        };
+        let path = entry.blob_path();
+        if !self.dir.try_exists(&path).context("checking for blob")? {
+            tracing::debug!(blob = %entry.digest, "staged layer blob missing");
+            return Ok(None);
+        }
+        if !self.verify_blob(&path, &entry.digest)? {
+            tracing::warn!(blob = %entry.digest, "staged blob doesn't match its digest; discarding");
+            // so that it's staged again once rewritten
+            match self.dir.remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e).context("removing corrupted blob");
+                }
+                _ => {}
+            }
+            return Ok(None);
+        }
+        if !oci_dir.try_exists(&path).context("checking for blob")? {
+            link_or_copy(&self.dir, &path, oci_dir).context("adding staged blob")?;
+        }
+        Ok(Some(layer))
+    }
+
+    /// Stage the blob of `layer`, written to `oci_dir` for `key`.
+    pub fn stage(&self, key: &str, oci_dir: &Dir, layer: &LayerBlob) -> Result<()> {
+        let entry = JournalLayer::new(layer);
+        let path = entry.blob_path();
+        // a concurrent build may have staged the same blob; it's identical
+        if !self.dir.try_exists(&path).context("checking for blob")? {
+            link_or_copy(oci_dir, &path, &self.dir).context("staging blob")?;
+        }
+        let content = serde_json::to_vec(&entry).context("serializing staged layer")?;
+        self.dir
+            .atomic_write(format!("layers/{key}.json"), content)
+            .context("writing staged layer")
+    }
+
+    /// Whether the blob at `path` has the SHA-256 digest `digest`.
+    fn verify_blob(&self, path: &str, digest: &str) -> Result<bool> {
+        let mut file = self
+            .dir
+            .open(path)
+            .with_context(|| format!("opening {path}"))?;
+        let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())
+            .context("creating SHA-256 hasher")?;
+        std::io::copy(&mut file, &mut hasher).with_context(|| format!("reading {path}"))?;
+        let actual = hasher.finish().context("finalizing SHA-256 hash")?;
+        Ok(hex::encode(actual) == digest)
+    }
+}
+
+/// Hardlink `path` from `src` to the same path in `dest`, or copy it if they
+/// are on different filesystems. Either way, it only appears once complete.
+fn link_or_copy(src: &Dir, path: &str, dest: &Dir) -> Result<()> {
+    match src.hard_link(path, dest, path) {
+        Ok(()) => return Ok(()),
+        // someone else got there first
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => tracing::debug!(path, err = %e, "failed to hardlink; copying"),
+    }
+    let mut file = src.open(path).with_context(|| format!("opening {path}"))?;
+    dest.atomic_replace_with(path, |w| std::io::copy(&mut file, w))
+        .with_context(|| format!("copying {path}"))?;
+    Ok(())
+}
+
+/// Fingerprint of the inputs of the layer of component `name`: its files'
+/// metadata and content in `rootfs`, and the settings they're written with.
+/// Since keys select blobs staged by earlier builds, content is hashed rather
+/// than assumed unchanged along with the metadata, e.g. for files rewritten in
+/// place with the same size and mtime.
+pub fn layer_key(
+    rootfs: &Dir,
+    name: &str,
+    component: &Component,
+    compression: Compression,
+    layer_metadata: bool,
+    normalization: &Normalization,
+) -> Result<String> {
+    let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())
+        .context("creating SHA-256 hasher")?;
+    writeln!(
+        hasher,
+        "{name}\n{compression:?} {layer_metadata}\n{}\n{} {} {}",
+        component.mtime_clamp,
+        normalization.dir_perms,
+        normalization.drop_user_xattrs,
+        normalization.preserve_ima,
+    )?;
+    for (path, info) in &component.files {
+        let file_type = match info.file_type {
+            FileType::Directory => 'd',
+            FileType::File => 'f',
+            FileType::Symlink => 'l',
+        };
+        // everything the tar entry is written from; the inode only matters
+        // to find hardlinks
+        writeln!(
+            hasher,
+            "{path}\n{} {:o} {} {} {} {} {} {} {}",
+            file_type,
+            info.mode,
+            info.size,
+            info.uid,
+            info.gid,
+            info.mtime,
+            info.ino,
+            info.nlink,
+            info.opaque,
+        )?;
+        writeln!(
+            hasher,
+            "{}\n{}",
+            info.source.as_deref().map_or("", |p| p.as_str()),
+            info.link_target.as_deref().map_or("", |p| p.as_str()),
+        )?;
+        for (key, value) in &info.xattrs {
+            writeln!(hasher, "{key}={}", hex::encode(value))?;
+        }
+        if info.file_type == FileType::File {
+            let source = info.source.as_deref().unwrap_or(path);
+            writeln!(hasher, "{}", utils::compute_sha256(rootfs, source)?)?;
+        }
+        if let Some(names) = &normalization.owner_names {
+            writeln!(
+                hasher,
+                "{:?} {:?}",
+                names.user(info.uid),
+                names.group(info.gid)
+            )?;
+        }
+    }
+    let digest = hasher.finish().context("finalizing SHA-256 hash")?;
+    Ok(hex::encode(digest))
+}
+
+/// Remove the blobs of `dir` not referenced by any manifest of its index,
+/// e.g. left over from an interrupted build.
+pub fn prune_unreferenced_blobs(dir: &Dir) -> Result<()> {
+    let oci_dir = ocidir::OciDir::open(dir.try_clone().context("cloning directory")?)
+        .context("opening OCI directory")?;
+    let index = oci_dir.read_index().context("reading index")?;
+
+    let mut referenced = HashSet::new();
+    for manifest_desc in index.manifests() {
+        referenced.insert(manifest_desc.digest().to_string());
+        let manifest: oci_image::ImageManifest = oci_dir
+            .read_json_blob(manifest_desc)
+            .context("reading manifest")?;
+        referenced.insert(manifest.config().digest().to_string());
+        referenced.extend(manifest.layers().iter().map(|l| l.digest().to_string()));
+    }
+
+    let blobs = dir
+        .open_dir("blobs/sha256")
+        .context("opening blobs directory")?;
+    for entry in blobs.entries().context("listing blobs")? {
+        let entry = entry.context("reading blobs entry")?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !referenced.contains(&format!("sha256:{name}")) {
+            tracing::debug!(blob = name, "removing unreferenced blob");
+            blobs
+                .remove_file(name)
+                .with_context(|| format!("removing blob {name}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::{FileInfo, FileMap};
+
+    #[test]
+    fn test_layer_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        let component = |mtime| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: FileMap::from([(
+                "/file".into(),
+                FileInfo {
+                    mtime,
+                    ..FileInfo::dummy(FileType::File)
+                },
+            )]),
+        };
+        let key = |name, component: &Component, compression| {
+            layer_key(
+                &rootfs,
+                name,
+                component,
+                compression,
+                false,
+                &Normalization::default(),
+            )
+            .unwrap()
+        };
+
+        let base = key("a", &component(1), Compression::None);
+        assert_eq!(base, key("a", &component(1), Compression::None));
+        assert_ne!(base, key("b", &component(1), Compression::None));
+        assert_ne!(base, key("a", &component(2), Compression::None));
+        assert_ne!(base, key("a", &component(1), Compression::Gzip(6)));
+        let with_metadata = layer_key(
+            &rootfs,
+            "a",
+            &component(1),
+            Compression::None,
+            true,
+            &Normalization::default(),
+        )
+        .unwrap();
+        assert_ne!(base, with_metadata);
+
+        // content rewritten in place, with the same metadata
+        rootfs.write("file", "CONTENT").unwrap();
+        assert_ne!(base, key("a", &component(1), Compression::None));
+    }
+
+    #[test]
+    fn test_journal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::ensure(dir.try_clone().unwrap()).unwrap();
+        let mut writer = oci_dir.create_uncompressed_layer().unwrap();
+        writer.write_all(b"not really a tar").unwrap();
+        let layer = LayerBlob::new(&writer.complete().unwrap()).unwrap();
+
+        let journal = ResumeJournal::open(&dir).unwrap();
+        assert_eq!(journal.len().unwrap(), 0);
+        assert!(journal.get("key").unwrap().is_none());
+        journal.record("key", &layer).unwrap();
+
+        // reopening sees the recorded layer
+        let journal = ResumeJournal::open(&dir).unwrap();
+        assert_eq!(journal.len().unwrap(), 1);
+        assert_eq!(journal.get("key").unwrap().unwrap(), layer);
+
+        // but not once its blob is gone
+        dir.remove_file(format!(
+            "blobs/sha256/{}",
+            layer.descriptor.digest().digest()
+        ))
+        .unwrap();
+        assert!(journal.get("key").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_blob_staging() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = camino::Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let oci_dir_at = |name: &str| {
+            let path = root.join(name);
+            std::fs::create_dir(&path).unwrap();
+            let dir = Dir::open_ambient_dir(&path, ambient_authority()).unwrap();
+            ocidir::OciDir::ensure(dir.try_clone().unwrap()).unwrap();
+            dir
+        };
+        let staging = BlobStaging::open(&root.join("staging")).unwrap();
+
+        let first = oci_dir_at("first");
+        let first_oci = ocidir::OciDir::open(first.try_clone().unwrap()).unwrap();
+        let mut writer = first_oci.create_uncompressed_layer().unwrap();
+        writer.write_all(b"not really a tar").unwrap();
+        let layer = LayerBlob::new(&writer.complete().unwrap()).unwrap();
+        assert!(staging.get("key", &first).unwrap().is_none());
+        staging.stage("key", &first, &layer).unwrap();
+        // staging the same blob again is fine
+        staging.stage("key", &first, &layer).unwrap();
+
+        // another build gets the layer with its blob
+        let second = oci_dir_at("second");
+        let blob = format!("blobs/sha256/{}", layer.descriptor.digest().digest());
+        assert_eq!(staging.get("key", &second).unwrap().unwrap(), layer);
+        assert_eq!(second.read(&blob).unwrap(), b"not really a tar");
+
+        // but not if the staged blob is corrupted
+        let third = oci_dir_at("third");
+        std::fs::remove_file(root.join("staging").join(&blob)).unwrap();
+        std::fs::write(root.join("staging").join(&blob), b"not really a tar!").unwrap();
+        assert!(staging.get("key", &third).unwrap().is_none());
+        assert!(!third.try_exists(&blob).unwrap());
+        // and it's staged again from the rewritten layer
+        staging.stage("key", &first, &layer).unwrap();
+        assert!(staging.get("key", &third).unwrap().is_some());
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+
+use crate::components::{Component, FileInfo, FileMap, FileType};
+
+/// A rule relocating a directory tree to another location in the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewriteRule {
+    from: Utf8PathBuf,
+    to: Utf8PathBuf,
+}
+
+impl std::fmt::Display for RewriteRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.from, self.to)
+    }
+}
+
+/// Parse `FROM=TO` rewrite rules.
+///
+/// Both sides must be absolute paths other than `/`. Rules must not overlap
+/// one another, so that every path is rewritten by at most one rule and no
+/// two rules write to the same tree.
+pub fn parse_rewrite_rules(rules: &[String]) -> Result<Vec<RewriteRule>> {
+    let rules = rules
+        .iter()
+        .map(|rule| parse_rewrite_rule(rule))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (i, a) in rules.iter().enumerate() {
+        for b in &rules[i + 1..] {
+            anyhow::ensure!(
+                !overlaps(&a.from, &b.from) && !overlaps(&a.to, &b.to),
+                "overlapping rewrite rules: {a} and {b}"
+            );
+        }
+    }
+
+    Ok(rules)
+}
+
+fn parse_rewrite_rule(rule: &str) -> Result<RewriteRule> {
+    let (from, to) = rule
+        .split_once('=')
+        .with_context(|| format!("invalid rewrite rule '{rule}': expected FROM=TO"))?;
+    let from = parse_rewrite_path(from).with_context(|| format!("parsing rewrite rule {rule}"))?;
+    let to = parse_rewrite_path(to).with_context(|| format!("parsing rewrite rule {rule}"))?;
+    anyhow::ensure!(
+        !overlaps(&from, &to),
+        "rewrite source and destination overlap: {rule}"
+    );
+    Ok(RewriteRule { from, to })
+}
+
+fn parse_rewrite_path(path: &str) -> Result<Utf8PathBuf> {
+    let path = Utf8PathBuf::from(path.trim_end_matches('/'));
+    anyhow::ensure!(
+        path.is_absolute(),
+        "path must be absolute and not the root: '{path}'"
+    );
+    anyhow::ensure!(
+        !path
+            .components()
+            .any(|c| matches!(c, Utf8Component::ParentDir | Utf8Component::CurDir)),
+        "path must be normalized: {path}"
+    );
+    Ok(path)
+}
+
+fn overlaps(a: &Utf8Path, b: &Utf8Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+/// Return the rewritten form of `path`, if any rule applies to it.
+fn rewrite_path(rules: &[RewriteRule], path: &Utf8Path) -> Option<Utf8PathBuf> {
+    rules.iter().find_map(|rule| {
+        let rest = path.strip_prefix(&rule.from).ok()?;
+        if rest.as_str().is_empty() {
+            Some(rule.to.clone())
+        } else {
+            Some(rule.to.join(rest))
+        }
+    })
+}
+
+/// Apply rewrite rules to the files of all components.
+///
+/// This runs after component assignment so that claims are still matched
+/// against the paths the package databases know about. Relocated files keep
+/// their original path as their source to read from in the rootfs. Absolute
+/// symlink targets pointing into a relocated tree are rewritten as well.
+pub fn apply_rewrites(
+    rootfs: &Dir,
+    rules: &[RewriteRule],
+    components: &mut HashMap<String, Component>,
+) -> Result<()> {
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    validate_destinations(rules, components)?;
+
+    // Relocated directories by their new path. Components may need these as
+    // ancestors, which unlike other ancestors can't be looked up in the
+    // rootfs by path when writing the layer.
+    let mut rewritten_dirs = FileMap::new();
+    let mut n_rewritten = 0usize;
+    for (name, component) in components.iter_mut() {
+        let files = std::mem::take(&mut component.files);
+        for (path, mut info) in files {
+            if info.file_type == FileType::Symlink {
+                rewrite_link_target(rootfs, rules, &path, &mut info)
+                    .with_context(|| format!("rewriting symlink {path}"))?;
+            }
+            let path = match rewrite_path(rules, &path) {
+                Some(new_path) => {
+                    tracing::trace!(path = %path, new_path = %new_path, component = %name, "path rewritten");
+                    n_rewritten += 1;
+                    info.source = Some(path);
+                    if info.file_type == FileType::Directory {
+                        rewritten_dirs.insert(new_path.clone(), info.clone());
+                    }
+                    new_path
+                }
+                None => path,
+            };
+            component.files.insert(path, info);
+        }
+    }
+
+    if !rewritten_dirs.is_empty() {
+        for component in components.values_mut() {
+            let missing: FileMap = component
+                .files
+                .keys()
+                .flat_map(|path| path.ancestors().skip(1))
+                .filter(|ancestor| !component.files.contains_key(*ancestor))
+                .filter_map(|ancestor| rewritten_dirs.get_key_value(ancestor))
+                .map(|(path, info)| (path.clone(), info.clone()))
+                .collect();
+            component.files.extend(missing);
+        }
+    }
+
+    tracing::info!(files = n_rewritten, rules = rules.len(), "paths rewritten");
+    Ok(())
+}
+
+/// Check that each destination is free and has an existing parent.
+fn validate_destinations(
+    rules: &[RewriteRule],
+    components: &HashMap<String, Component>,
+) -> Result<()> {
+    let existing: HashSet<&Utf8Path> = components
+        .values()
+        .flat_map(|c| c.files.keys().map(|p| p.as_path()))
+        .collect();
+
+    for rule in rules {
+        anyhow::ensure!(
+            !existing.contains(rule.to.as_path()),
+            "rewrite destination {} already exists in rootfs",
+            rule.to
+        );
+        if let Some(parent) = rule.to.parent() {
+            anyhow::ensure!(
+                parent.as_str() == "/" || existing.contains(parent),
+                "parent of rewrite destination {} does not exist in rootfs",
+                rule.to
+            );
+        }
+        if !existing.contains(rule.from.as_path()) {
+            tracing::warn!(from = %rule.from, "rewrite source not found in rootfs");
+        }
+    }
+    Ok(())
+}
+
+/// Point an absolute symlink into a relocated tree to the new location.
+fn rewrite_link_target(
+    rootfs: &Dir,
+    rules: &[RewriteRule],
+    path: &Utf8Path,
+    info: &mut FileInfo,
+) -> Result<()> {
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    let target = rootfs.read_link_contents(rel_path)?;
+    // non-UTF-8 targets can't match any rule
+    let Ok(target) = Utf8PathBuf::try_from(target) else {
+        return Ok(());
+    };
+    if target.is_absolute()
+        && let Some(new_target) = rewrite_path(rules, &target)
+    {
+        tracing::trace!(path = %path, target = %target, new_target = %new_target, "symlink target rewritten");
+        info.link_target = Some(new_target);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rewrite_rules() {
+        let rules = parse_rewrite_rules(&["/opt/vendor/=/usr/lib/vendor".into(), "/a=/b/c".into()])
+            .unwrap();
+        assert_eq!(
+            rules[0],
+            RewriteRule {
+                from: "/opt/vendor".into(),
+                to: "/usr/lib/vendor".into(),
+            }
+        );
+        assert_eq!(rules[1].to_string(), "/a=/b/c");
+
+        for bad in [
+            "/opt/vendor",
+            "opt/vendor=/usr/lib/vendor",
+            "/opt/vendor=/",
+            "/=/usr",
+            "/opt/vendor=/opt/vendor/sub",
+            "/opt/vendor=/usr/../lib",
+        ] {
+            assert!(
+                parse_rewrite_rules(&[bad.into()]).is_err(),
+                "{bad} should be rejected"
+            );
+        }
+
+        // overlapping sources or destinations
+        assert!(parse_rewrite_rules(&["/a=/x".into(), "/a/b=/y".into()]).is_err());
+        assert!(parse_rewrite_rules(&["/a=/x".into(), "/b=/x/y".into()]).is_err());
+    }
+
+    #[test]
+    fn test_apply_rewrites() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/vendor/bin").unwrap();
+        rootfs.write("opt/vendor/bin/tool", "tool").unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.create_dir_all("usr/lib").unwrap();
+        rootfs
+            .symlink_contents("/opt/vendor/bin/tool", "usr/bin/tool")
+            .unwrap();
+        rootfs
+            .symlink_contents("/etc/other", "usr/bin/other")
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let (vendor, rest): (FileMap, FileMap) = files.into_iter().partition(|(path, _)| {
+            path.as_str() != "/opt/vendor" && path.starts_with("/opt/vendor")
+        });
+        let component = |files| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+        let mut components = HashMap::from([
+            ("vendor".to_string(), component(vendor)),
+            ("rest".to_string(), component(rest)),
+        ]);
+
+        let rules = parse_rewrite_rules(&["/opt/vendor=/usr/lib/vendor".into()]).unwrap();
+        apply_rewrites(&rootfs, &rules, &mut components).unwrap();
+
+        let vendor = &components["vendor"].files;
+        let tool = &vendor[Utf8Path::new("/usr/lib/vendor/bin/tool")];
+        assert_eq!(
+            tool.source.as_deref(),
+            Some(Utf8Path::new("/opt/vendor/bin/tool"))
+        );
+        assert!(!vendor.contains_key(Utf8Path::new("/opt/vendor/bin/tool")));
+        // the relocated root was owned by the other component; it's pulled in
+        // as an ancestor since it can't be found on disk at its new path
+        assert!(vendor.contains_key(Utf8Path::new("/usr/lib/vendor")));
+
+        let rest = &components["rest"].files;
+        assert!(rest.contains_key(Utf8Path::new("/usr/lib/vendor")));
+        assert!(!rest.contains_key(Utf8Path::new("/opt/vendor")));
+        assert_eq!(
+            rest[Utf8Path::new("/usr/bin/tool")].link_target.as_deref(),
+            Some(Utf8Path::new("/usr/lib/vendor/bin/tool"))
+        );
+        assert!(rest[Utf8Path::new("/usr/bin/other")].link_target.is_none());
+
+        // the layer reads relocated content from its original location
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        crate::tar::write_files_to_tar(
+            &mut tar_builder,
+            &rootfs,
+            vendor,
+            0,
+            Default::default(),
+            &Default::default(),
+        )
+        .unwrap();
+        let data = tar_builder.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        let mut found = false;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            assert!(!path.starts_with("opt/"), "unexpected entry {path}");
+            if path == "usr/lib/vendor/bin/tool" {
+                let mut content = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+                assert_eq!(content, "tool");
+                found = true;
+            }
+        }
+        assert!(found);
+    }
+
+    #[test]
+    fn test_apply_rewrites_destination_exists() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/vendor").unwrap();
+        rootfs.create_dir_all("usr/lib/vendor").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let mut components = HashMap::from([(
+            "all".to_string(),
+            Component {
+                mtime_clamp: 0,
+                stability: 0.0,
+                isolated: false,
+                files,
+            },
+        )]);
+
+        let rules = parse_rewrite_rules(&["/opt/vendor=/usr/lib/vendor".into()]).unwrap();
+        let err = apply_rewrites(&rootfs, &rules, &mut components).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+
+        let rules = parse_rewrite_rules(&["/opt/vendor=/srv/x/vendor".into()]).unwrap();
+        let err = apply_rewrites(&rootfs, &rules, &mut components).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{err}");
+    }
+}
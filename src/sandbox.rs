@@ -0,0 +1,237 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+// Landlock UAPI definitions; see linux/landlock.h.
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+// ABI 2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+
+/// Everything we restrict, i.e. all rights up to and including REFER. ABI 2 is required so that files can be moved
+/// between directories at all (e.g. when writing blobs output); ABI 1 always
+/// denies that.
+const ACCESS_ALL: u64 = (1 << 14) - 1;
+
+/// Rights which apply to regular files, as opposed to directories.
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+
+const ACCESS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+const ACCESS_WRITE: u64 = ACCESS_READ
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SYM
+    | ACCESS_FS_REFER;
+
+/// Host directories needed to execute helpers (e.g. `rpm` to read the rpmdb).
+const SYSTEM_DIRS: &[&str] = &["/usr", "/lib", "/lib64", "/bin", "/sbin", "/etc"];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Builder for a Landlock sandbox restricting filesystem access of the
+/// current thread and the threads and processes it spawns afterwards.
+///
+/// Anything not explicitly allowed is denied. Host system directories are
+/// always readable (and executable) so that helpers like `rpm` keep working.
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    read_paths: Vec<Utf8PathBuf>,
+    write_paths: Vec<Utf8PathBuf>,
+}
+
+impl Sandbox {
+    /// Create a new sandbox builder with nothing allowed yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow reading everything under `path`.
+    pub fn allow_read(mut self, path: &Utf8Path) -> Self {
+        self.read_paths.push(path.to_owned());
+        self
+    }
+
+    /// Allow reading, creating, writing and removing everything under `path`.
+    pub fn allow_write(mut self, path: &Utf8Path) -> Self {
+        self.write_paths.push(path.to_owned());
+        self
+    }
+
+    /// Apply the sandbox. This can't be undone.
+    pub fn apply(self) -> Result<()> {
+        // SAFETY: querying the ABI version takes no pointers
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Landlock is not supported by this kernel");
+        }
+        anyhow::ensure!(
+            abi >= 2,
+            "Landlock ABI version 2 or later is required, but the kernel only supports {abi}"
+        );
+
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_ALL,
+        };
+        // SAFETY: attr is a valid landlock_ruleset_attr of the given size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("creating Landlock ruleset");
+        }
+        // SAFETY: the syscall returned a new fd which nothing else owns
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+
+        for dir in SYSTEM_DIRS {
+            add_rule(
+                &ruleset,
+                Utf8Path::new(dir),
+                ACCESS_READ | ACCESS_FS_EXECUTE,
+                true,
+            )?;
+        }
+        // for reading e.g. peak memory usage
+        add_rule(&ruleset, Utf8Path::new("/proc"), ACCESS_READ, true)?;
+        // spawning helpers opens it for their stdin
+        add_rule(
+            &ruleset,
+            Utf8Path::new("/dev/null"),
+            ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE,
+            true,
+        )?;
+        for path in &self.read_paths {
+            add_rule(&ruleset, path, ACCESS_READ, false)?;
+        }
+        for path in &self.write_paths {
+            add_rule(&ruleset, path, ACCESS_WRITE, false)?;
+        }
+
+        // SAFETY: plain prctl call without pointers
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("setting no_new_privs");
+        }
+        // SAFETY: ruleset is a valid Landlock ruleset fd
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("applying Landlock ruleset");
+        }
+
+        tracing::debug!(
+            read = ?self.read_paths,
+            write = ?self.write_paths,
+            abi,
+            "sandbox applied"
+        );
+        Ok(())
+    }
+}
+
+/// Allow `access` beneath `path`. If `optional`, a missing path is ignored.
+fn add_rule(ruleset: &OwnedFd, path: &Utf8Path, access: u64, optional: bool) -> Result<()> {
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("opening {path} for sandbox rule")),
+    };
+    let is_dir = file
+        .metadata()
+        .with_context(|| format!("getting metadata for {path}"))?
+        .is_dir();
+
+    let attr = PathBeneathAttr {
+        // directory-only rights are invalid on files
+        allowed_access: if is_dir { access } else { access & ACCESS_FILE },
+        parent_fd: file.as_raw_fd(),
+    };
+    // SAFETY: attr is a valid landlock_path_beneath_attr and both fds are open
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("adding sandbox rule for {path}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox() {
+        let allowed = tempfile::tempdir().unwrap();
+        let denied = tempfile::tempdir().unwrap();
+        std::fs::write(denied.path().join("file"), "secret").unwrap();
+        let allowed_path = Utf8Path::from_path(allowed.path()).unwrap().to_owned();
+        let denied_path = Utf8Path::from_path(denied.path()).unwrap().to_owned();
+
+        // Landlock applies to the calling thread, so do this in a separate one
+        // to not affect the other tests.
+        std::thread::spawn(move || {
+            // Landlock isn't available everywhere (e.g. older kernels, or
+            // in some containers); there's nothing to test then
+            if Sandbox::new().allow_write(&allowed_path).apply().is_err() {
+                return;
+            }
+            std::fs::write(allowed_path.join("file"), "ok").unwrap();
+            assert_eq!(
+                std::fs::read_to_string(allowed_path.join("file")).unwrap(),
+                "ok"
+            );
+            let err = std::fs::read_to_string(denied_path.join("file")).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+            let err = std::fs::write(denied_path.join("new"), "x").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,19 +1,46 @@
 use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::num::NonZeroUsize;
 use std::ops::ControlFlow;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_primitives::fs::OpenOptionsExt;
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::dirext::{CapStdExtDirExt, WalkConfiguration};
+use clap::ValueEnum;
 
+use crate::cancel::CancellationToken;
 use crate::components::{FileInfo, FileMap, FileType};
 
+/// What to do with paths which can't be read during the scan because they
+/// vanished or we lack permissions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ScanErrorPolicy {
+    /// Fail the scan on the first error
+    #[default]
+    Abort,
+    /// Leave the paths out of the image, and warn about each one at the end
+    Warn,
+    /// Leave the paths out, then fail at the end of the scan listing all of them
+    Fail,
+}
+
 /// Builder for scanning a rootfs directory.
 pub struct Scanner<'a> {
     rootfs: &'a Dir,
     skip_special_files: bool,
+    one_file_system: bool,
     prune_paths: Vec<PrunePath>,
+    cancellation: CancellationToken,
+    threads: NonZeroUsize,
+    preserve_ima: bool,
+    scan_errors: ScanErrorPolicy,
 }
 
 impl<'a> Scanner<'a> {
@@ -22,7 +49,12 @@ impl<'a> Scanner<'a> {
         Self {
             rootfs,
             skip_special_files: false,
+            one_file_system: false,
             prune_paths: Vec::new(),
+            cancellation: CancellationToken::new(),
+            threads: NonZeroUsize::MIN,
+            preserve_ima: false,
+            scan_errors: ScanErrorPolicy::default(),
         }
     }
 
@@ -35,6 +67,14 @@ impl<'a> Scanner<'a> {
         self
     }
 
+    /// Don't descend into filesystems mounted below the rootfs.
+    ///
+    /// Mount points themselves are still scanned, as directories.
+    pub fn one_file_system(mut self, enabled: bool) -> Self {
+        self.one_file_system = enabled;
+        self
+    }
+
     /// Set paths to prune from the scan.
     ///
     /// Paths must be absolute. A trailing `/` means prune children only,
@@ -50,33 +90,75 @@ impl<'a> Scanner<'a> {
         Ok(self)
     }
 
+    /// Set the token used to cancel the scan.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Set the number of threads for reading xattrs.
+    pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Keep the SELinux label of files with an EVM signature.
+    ///
+    /// SELinux labels are normally dropped, but EVM signatures cover them, so
+    /// dropping them would make appraisal fail.
+    pub fn preserve_ima(mut self, enabled: bool) -> Self {
+        self.preserve_ima = enabled;
+        self
+    }
+
+    /// Set what to do with paths which vanish or can't be read during the scan.
+    ///
+    /// By default, the scan fails on the first one. Errors other than
+    /// `ENOENT`, `EACCES` and `EPERM` always fail the scan.
+    pub fn scan_errors(mut self, policy: ScanErrorPolicy) -> Self {
+        self.scan_errors = policy;
+        self
+    }
+
     /// Scan the rootfs and return a map of file paths to their metadata.
     ///
-    /// We use cap-std-ext's walk here, which doesn't follow symlinks.
+    /// We use cap-std-ext's walk here, which doesn't follow symlinks. Xattrs
+    /// are read afterwards, in parallel.
     pub fn scan(self) -> Result<FileMap> {
         let mut files = BTreeMap::new();
+        let skipped = Mutex::new(Vec::new());
 
-        let config = WalkConfiguration::default().path_base(Path::new("/"));
+        let mut config = WalkConfiguration::default().path_base(Path::new("/"));
+        if self.one_file_system {
+            config = config.noxdev();
+        }
 
         self.rootfs
             .walk(&config, |component| {
+                self.cancellation.check()?;
+
                 let path: &Utf8Path = component
                     .path
                     .try_into()
                     .map_err(|_| anyhow::anyhow!("path is not valid UTF-8"))?;
 
-                let rel_path = path.strip_prefix("/").unwrap_or(path);
-                let fs_path = if rel_path.as_str().is_empty() {
-                    "."
-                } else {
-                    rel_path.as_str()
+                let fs_path = fs_path(path);
+
+                let metadata = match self.rootfs.symlink_metadata(fs_path) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        let err =
+                            anyhow::Error::new(e).context(format!("getting metadata for {}", path));
+                        self.skip_path(&skipped, path, err)?;
+                        // don't recurse into a directory we couldn't stat
+                        return Ok(if component.file_type.is_dir() {
+                            ControlFlow::Break(())
+                        } else {
+                            ControlFlow::Continue(())
+                        });
+                    }
                 };
 
-                let metadata = self
-                    .rootfs
-                    .symlink_metadata(fs_path)
-                    .with_context(|| format!("getting metadata for {}", path))?;
-
                 // Check file type early, before reading xattrs
                 let file_type = match FileType::from_cap_std(&metadata.file_type()) {
                     Some(ft) => ft,
@@ -100,10 +182,19 @@ impl<'a> Scanner<'a> {
                     return Ok(ControlFlow::Continue(()));
                 }
 
-                let xattrs = read_xattrs(self.rootfs, fs_path)
-                    .with_context(|| format!("reading xattrs for {}", path))?;
+                // the walk fails on directories it can't read; check upfront
+                // if we're to skip them
+                if file_type == FileType::Directory
+                    && self.scan_errors != ScanErrorPolicy::Abort
+                    && let Err(e) = self.rootfs.open_dir(fs_path)
+                {
+                    let err = anyhow::Error::new(e).context(format!("opening directory {}", path));
+                    return self
+                        .skip_path(&skipped, path, err)
+                        .map(|()| ControlFlow::Break(()));
+                }
 
-                let file_info = FileInfo::from_metadata(&metadata, file_type, xattrs);
+                let file_info = FileInfo::from_metadata(&metadata, file_type, Vec::new());
 
                 tracing::trace!(path = %path, size = file_info.size, "scanned file");
                 files.insert(path.to_owned(), file_info);
@@ -118,46 +209,320 @@ impl<'a> Scanner<'a> {
             })
             .context("failed to walk rootfs")?;
 
+        self.read_all_xattrs(&mut files, &skipped)?;
+        let skipped = skipped
+            .into_inner()
+            .map_err(|_| anyhow::anyhow!("skipped paths lock poisoned"))?;
+        self.drop_skipped(&mut files, skipped)?;
+
         Ok(files)
     }
+
+    /// Remove the paths in `skipped` from `files`, reporting them, and fail
+    /// with [`ScanErrorPolicy::Fail`] if there are any.
+    fn drop_skipped(
+        &self,
+        files: &mut FileMap,
+        skipped: Vec<(Utf8PathBuf, anyhow::Error)>,
+    ) -> Result<()> {
+        if skipped.is_empty() {
+            return Ok(());
+        }
+        for (path, err) in &skipped {
+            files.remove(path);
+            tracing::warn!(path = %path, "skipped unreadable path: {err:#}");
+        }
+        tracing::warn!(paths = skipped.len(), "some paths could not be read");
+        if self.scan_errors == ScanErrorPolicy::Fail {
+            let list: Vec<String> = skipped
+                .iter()
+                .map(|(path, err)| format!("  {path}: {err:#}"))
+                .collect();
+            anyhow::bail!(
+                "failed to read {} path(s):\n{}",
+                skipped.len(),
+                list.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Handle a path that couldn't be read: with [`ScanErrorPolicy::Abort`],
+    /// or for errors other than vanished or inaccessible paths, return the
+    /// error. Otherwise, record it in `skipped`.
+    fn skip_path(
+        &self,
+        skipped: &Mutex<Vec<(Utf8PathBuf, anyhow::Error)>>,
+        path: &Utf8Path,
+        err: anyhow::Error,
+    ) -> Result<()> {
+        if self.scan_errors == ScanErrorPolicy::Abort || !is_unreadable(&err) {
+            return Err(err);
+        }
+        tracing::debug!(path = %path, "skipping unreadable path: {err:#}");
+        skipped
+            .lock()
+            .map_err(|_| anyhow::anyhow!("skipped paths lock poisoned"))?
+            .push((path.to_owned(), err));
+        Ok(())
+    }
+
+    /// Read the xattrs of all files, spreading the work over the configured
+    /// number of threads.
+    fn read_all_xattrs(
+        &self,
+        files: &mut FileMap,
+        skipped: &Mutex<Vec<(Utf8PathBuf, anyhow::Error)>>,
+    ) -> Result<()> {
+        // hand out work in batches to keep contention on the counter low
+        const BATCH: usize = 256;
+
+        let entries: Vec<(&Utf8PathBuf, FileType)> =
+            files.iter().map(|(p, f)| (p, f.file_type)).collect();
+        let num_workers = self.threads.get().min(entries.len().div_ceil(BATCH)).max(1);
+        tracing::debug!(
+            threads = num_workers,
+            files = entries.len(),
+            "reading xattrs"
+        );
+
+        let next = AtomicUsize::new(0);
+        let results: Vec<Vec<_>> = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..num_workers)
+                .map(|_| {
+                    s.spawn(|| -> Result<_> {
+                        let mut results = Vec::new();
+                        loop {
+                            let start = next.fetch_add(BATCH, Ordering::Relaxed);
+                            if start >= entries.len() {
+                                break;
+                            }
+                            self.cancellation.check()?;
+                            for (i, (path, file_type)) in
+                                entries.iter().enumerate().skip(start).take(BATCH)
+                            {
+                                match self.read_file_xattrs(path, *file_type) {
+                                    Ok(xattrs) if xattrs.is_empty() => {}
+                                    Ok(xattrs) => results.push((i, xattrs)),
+                                    Err(err) => self.skip_path(skipped, path, err)?,
+                                }
+                            }
+                        }
+                        Ok(results)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                // a worker panic propagates as is
+                .map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect::<Result<_>>()
+        })?;
+
+        // indices are in map order, so we can zip them back in
+        let mut results: Vec<_> = results.into_iter().flatten().collect();
+        results.sort_unstable_by_key(|(i, _)| *i);
+        let mut results = results.into_iter().peekable();
+        for (i, info) in files.values_mut().enumerate() {
+            if let Some((_, xattrs)) = results.next_if(|(j, _)| *j == i) {
+                info.xattrs = xattrs;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Scanner<'_> {
+    /// Read the xattrs to carry over for the file at `path`.
+    fn read_file_xattrs(
+        &self,
+        path: &Utf8Path,
+        file_type: FileType,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut xattrs = read_xattrs_for(self.rootfs, fs_path(path), file_type)
+            .with_context(|| format!("reading xattrs for {}", path))?;
+        if self.preserve_ima && crate::tar::has_evm(&xattrs) {
+            add_selinux_label(self.rootfs, fs_path(path), &mut xattrs)
+                .with_context(|| format!("reading SELinux label for {}", path))?;
+        }
+        Ok(xattrs)
+    }
+}
+
+/// Whether `err` was caused by a path that vanished or can't be accessed.
+fn is_unreadable(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+            )
+        })
+}
+
+/// Add the SELinux label of `fs_path`, if any, to `xattrs`.
+fn add_selinux_label(
+    rootfs: &Dir,
+    fs_path: &str,
+    xattrs: &mut Vec<(String, Vec<u8>)>,
+) -> Result<()> {
+    const SELINUX_XATTR: &str = "security.selinux";
+    if let Some(label) = rootfs.getxattr(fs_path, OsStr::new(SELINUX_XATTR))? {
+        xattrs.push((SELINUX_XATTR.to_string(), label));
+        xattrs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    }
+    Ok(())
+}
+
+/// Return the path relative to the rootfs to use in `Dir` operations.
+fn fs_path(path: &Utf8Path) -> &str {
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    if rel_path.as_str().is_empty() {
+        "."
+    } else {
+        rel_path.as_str()
+    }
+}
+
+/// Read all xattrs of the file at `fs_path`.
+///
+/// Regular files and directories are opened once and queried through the fd,
+/// which avoids resolving the path again for every attribute. (O_PATH fds
+/// would be cheaper to open, but older kernels reject them in f*xattr().)
+/// Symlinks can't be opened without following them, so they, and files we
+/// can't open, fall back to path-based lookups.
+fn read_xattrs_for(
+    rootfs: &Dir,
+    fs_path: &str,
+    file_type: FileType,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let file = match file_type {
+        FileType::Symlink => None,
+        FileType::Directory | FileType::File => {
+            let mut opts = cap_std_ext::cap_std::fs::OpenOptions::new();
+            opts.read(true)
+                .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY);
+            rootfs.open_with(fs_path, &opts).ok()
+        }
+    };
+    let Some(file) = file else {
+        return read_xattrs(rootfs, fs_path);
+    };
+
+    let fd = file.as_raw_fd();
+    let keys = fd_listxattr(fd).with_context(|| format!("listing xattrs for {}", fs_path))?;
+    let mut xattrs = Vec::new();
+    for key in keys {
+        let Some(key_str) = filter_xattr_key(&key, fs_path)? else {
+            continue;
+        };
+        if let Some(value) = fd_getxattr(fd, &key)
+            .with_context(|| format!("reading xattr {} for {}", key.display(), fs_path))?
+        {
+            xattrs.push((key_str.to_string(), value));
+        }
+    }
+    xattrs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(xattrs)
+}
+
+/// List the xattr keys of an fd.
+fn fd_listxattr(fd: RawFd) -> std::io::Result<Vec<OsString>> {
+    let buf = read_sized(|buf, len| {
+        // SAFETY: buf is valid for len bytes, or null with len 0
+        unsafe { libc::flistxattr(fd, buf as *mut libc::c_char, len) }
+    })?
+    .unwrap_or_default();
+    Ok(buf
+        .split(|&b| b == 0)
+        .filter(|key| !key.is_empty())
+        .map(|key| OsStr::from_bytes(key).to_owned())
+        .collect())
+}
+
+/// Read an xattr of an fd. Returns `None` if it doesn't exist (anymore).
+fn fd_getxattr(fd: RawFd, key: &OsStr) -> std::io::Result<Option<Vec<u8>>> {
+    let key = std::ffi::CString::new(key.as_bytes()).map_err(std::io::Error::other)?;
+    read_sized(|buf, len| {
+        // SAFETY: key is NUL-terminated and buf is valid for len bytes, or
+        // null with len 0
+        unsafe { libc::fgetxattr(fd, key.as_ptr(), buf as *mut libc::c_void, len) }
+    })
+}
+
+/// Call a size-querying syscall like getxattr(2) to first get the size of the
+/// value and then read it, retrying if it grew in between. ENODATA maps to
+/// `None`.
+fn read_sized(f: impl Fn(*mut u8, usize) -> isize) -> std::io::Result<Option<Vec<u8>>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let mut buf = vec![0u8; size as usize];
+        let n = f(buf.as_mut_ptr(), buf.len());
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ERANGE) => continue,
+                Some(libc::ENODATA) => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        buf.truncate(n as usize);
+        return Ok(Some(buf));
+    }
+}
+
+/// Check whether to keep the xattr `key`, returning it as a string if so.
+fn filter_xattr_key<'k>(key: &'k OsStr, fs_path: &str) -> Result<Option<&'k str>> {
+    // Skip selinux attributes for now. It would only bloat images since
+    // _every_ file has SELinux attributes but they come from the container
+    // runtime, not the tar layer, which is ignored. Bootable containers
+    // could use them, but don't currently. We can make it opt in once it's
+    // desirable.
+    if key == OsStr::new("security.selinux") {
+        return Ok(None);
+    }
+
+    // Technically, keeping the key as OsStr would be more correct,
+    // but we'll need UTF-8 to shove it in a PAX header anyway so might
+    // as well error now. Note libarchive and GNU tar differ here.
+    // libarchive does urlencoding, GNU tar just writes the key as is
+    // anyway. We'll cross that bridge when/if we get to it.
+    let key_str = key
+        .to_str()
+        .with_context(|| format!("non-UTF8 xattr key {} on {}", key.display(), fs_path))?;
+
+    // Skip all trusted.* xattrs. It's primarily used by overlayfs itself
+    // and so more of a runtime thing. And no container runtime preserves
+    // them. This also avoids capturing filesystem specific things like XFS'
+    // legacy ACL aliases (trusted.SGI_ACL_*).
+    // The exception is the overlayfs opaque marker of directories, which is
+    // handled according to `--opaque-dirs` after the scan.
+    if key_str.starts_with("trusted.") && !crate::stubs::is_opaque_xattr(key_str) {
+        return Ok(None);
+    }
+
+    Ok(Some(key_str))
 }
 
 /// Read all xattrs for a path.
 pub fn read_xattrs(rootfs: &Dir, fs_path: &str) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
-    use std::ffi::OsStr;
-
     let xattr_list = rootfs
         .listxattrs(fs_path)
         .with_context(|| format!("listing xattrs for {}", fs_path))?;
 
     let mut xattrs = Vec::new();
     for key in xattr_list.iter() {
-        // Skip selinux attributes for now. It would only bloat images since
-        // _every_ file has SELinux attributes but they come from the container
-        // runtime, not the tar layer, which is ignored. Bootable containers
-        // could use them, but don't currently. We can make it opt in once it's
-        // desirable.
-        if key == OsStr::new("security.selinux") {
+        let Some(key_str) = filter_xattr_key(key, fs_path)? else {
             continue;
-        }
-
-        // Technically, keeping the key as OsStr would be more correct,
-        // but we'll need UTF-8 to shove it in a PAX header anyway so might
-        // as well error now. Note libarchive and GNU tar differ here.
-        // libarchive does urlencoding, GNU tar just writes the key as is
-        // anyway. We'll cross that bridge when/if we get to it.
-        let key_str = key
-            .to_str()
-            .with_context(|| format!("non-UTF8 xattr key {} on {}", key.display(), fs_path))?;
-
-        // Skip all trusted.* xattrs. It's primarily used by overlayfs itself
-        // and so more of a runtime thing. And no container runtime preserves
-        // them. This also avoids capturing filesystem specific things like XFS'
-        // legacy ACL aliases (trusted.SGI_ACL_*).
-        if key_str.starts_with("trusted.") {
-            continue;
-        }
-
+        };
         if let Some(value) = rootfs
             .getxattr(fs_path, key)
             .with_context(|| format!("reading xattr {} for {}", key.display(), fs_path))?
@@ -174,6 +539,28 @@ pub fn read_xattrs(rootfs: &Dir, fs_path: &str) -> anyhow::Result<Vec<(String, V
     Ok(xattrs)
 }
 
+/// Find the paths of `rootfs` which aren't valid UTF-8, which the scan fails
+/// on. Paths are lossily converted for reporting, and directories with such
+/// names aren't descended into.
+pub fn find_non_utf8_paths(rootfs: &Dir) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let config = WalkConfiguration::default().path_base(Path::new("/"));
+    rootfs
+        .walk(&config, |component| {
+            if component.path.to_str().is_some() {
+                return Ok::<_, anyhow::Error>(ControlFlow::Continue(()));
+            }
+            paths.push(component.path.to_string_lossy().into_owned());
+            Ok(if component.file_type.is_dir() {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            })
+        })
+        .context("failed to walk rootfs")?;
+    Ok(paths)
+}
+
 /// Represents a path to prune during scanning.
 #[derive(Debug, Clone, PartialEq)]
 enum PrunePath {
@@ -270,6 +657,127 @@ mod tests {
         assert_eq!(get_file_type(&files, "/escape"), Some(FileType::Symlink));
     }
 
+    #[test]
+    fn test_scanner_xattrs_parallel() {
+        use cap_std_ext::dirext::CapStdExtDirExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("dir").unwrap();
+        rootfs.setxattr("dir", "user.dir", b"d").unwrap();
+        // enough files to span several batches
+        for i in 0..1000 {
+            let name = format!("dir/file{i:04}");
+            rootfs.write(&name, "content").unwrap();
+            if i % 7 == 0 {
+                rootfs
+                    .setxattr(&name, "user.index", i.to_string().as_bytes())
+                    .unwrap();
+            }
+        }
+
+        let files = Scanner::new(&rootfs)
+            .threads(NonZeroUsize::new(4).unwrap())
+            .scan()
+            .unwrap();
+
+        assert_eq!(
+            files[Utf8Path::new("/dir")].xattrs,
+            vec![("user.dir".to_string(), b"d".to_vec())]
+        );
+        for i in 0..1000 {
+            let info = &files[Utf8Path::new(&format!("/dir/file{i:04}"))];
+            if i % 7 == 0 {
+                assert_eq!(
+                    info.xattrs,
+                    vec![("user.index".to_string(), i.to_string().into_bytes())]
+                );
+            } else {
+                assert!(info.xattrs.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_scanner_scan_errors() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("locked/nested").unwrap();
+        rootfs.write("file", "content").unwrap();
+
+        // a path which vanished after being walked can't be read, even by root
+        let read_vanished = |policy| {
+            let scanner = Scanner::new(&rootfs).scan_errors(policy);
+            let mut files = FileMap::from([
+                ("/file".into(), FileInfo::dummy(FileType::File)),
+                ("/vanished".into(), FileInfo::dummy(FileType::File)),
+            ]);
+            let skipped = Mutex::new(Vec::new());
+            scanner.read_all_xattrs(&mut files, &skipped)?;
+            scanner.drop_skipped(&mut files, skipped.into_inner().unwrap())?;
+            anyhow::Ok(files)
+        };
+        let err = read_vanished(ScanErrorPolicy::Abort).unwrap_err();
+        assert!(is_unreadable(&err), "{err:#}");
+        let files = read_vanished(ScanErrorPolicy::Warn).unwrap();
+        assert!(files.contains_key(Utf8Path::new("/file")));
+        assert!(!files.contains_key(Utf8Path::new("/vanished")));
+        let err = read_vanished(ScanErrorPolicy::Fail).unwrap_err();
+        assert!(
+            err.to_string().contains("failed to read 1 path(s)"),
+            "{err:#}"
+        );
+        assert!(err.to_string().contains("/vanished"), "{err:#}");
+
+        // root can read the locked directory anyway
+        // SAFETY: plain libc call without pointers
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        struct Unlock<'a>(&'a Path);
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                let _ = std::fs::set_permissions(self.0, std::fs::Permissions::from_mode(0o755));
+            }
+        }
+        let locked = tmp.path().join("locked");
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        let _unlock = Unlock(&locked);
+
+        let err = Scanner::new(&rootfs).scan().unwrap_err();
+        assert!(is_unreadable(&err), "{err:#}");
+
+        let files = Scanner::new(&rootfs)
+            .scan_errors(ScanErrorPolicy::Warn)
+            .scan()
+            .unwrap();
+        assert!(files.contains_key(Utf8Path::new("/file")));
+        assert!(!files.contains_key(Utf8Path::new("/locked")));
+
+        let err = Scanner::new(&rootfs)
+            .scan_errors(ScanErrorPolicy::Fail)
+            .scan()
+            .unwrap_err();
+        assert!(err.to_string().contains("/locked"), "{err:#}");
+    }
+
+    #[test]
+    fn test_scanner_cancelled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = Scanner::new(&rootfs)
+            .cancellation(token)
+            .scan()
+            .unwrap_err();
+        assert!(crate::cancel::is_cancelled(&err), "{err:#}");
+    }
+
     #[test]
     fn test_scanner_empty() {
         let tmp = tempfile::tempdir().unwrap();
@@ -286,6 +794,27 @@ mod tests {
         assert_eq!(files.len(), 0);
     }
 
+    #[test]
+    fn test_find_non_utf8_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("ok/dir").unwrap();
+        rootfs.write("ok/file", "content").unwrap();
+        assert!(find_non_utf8_paths(&rootfs).unwrap().is_empty());
+
+        let bad = OsStr::from_bytes(b"bad\xff");
+        rootfs.create_dir(Path::new("ok").join(bad)).unwrap();
+        rootfs
+            .write(Path::new("ok").join(bad).join("file"), "content")
+            .unwrap();
+        // only the directory, not what's under it
+        assert_eq!(
+            find_non_utf8_paths(&rootfs).unwrap(),
+            vec!["/ok/bad\u{FFFD}"]
+        );
+        Scanner::new(&rootfs).scan().unwrap_err();
+    }
+
     #[test]
     fn test_scanner_nested_structure() {
         let tmp = tempfile::tempdir().unwrap();
@@ -333,7 +862,7 @@ mod tests {
         assert_eq!(get_file_type(&files, "/regular.txt"), Some(FileType::File));
 
         // Socket should be skipped (not in the map)
-        assert!(files.get(Utf8Path::new("/test.sock")).is_none());
+        assert!(!files.contains_key(Utf8Path::new("/test.sock")));
     }
 
     #[test]
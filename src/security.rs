@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, HashMap};
+
+use camino::Utf8PathBuf;
+use serde::Serialize;
+
+use crate::components::{Component, FileInfo, FileType};
+use crate::plan::Plan;
+
+/// Xattr holding the file capabilities of an executable.
+const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Security-relevant metadata of a file.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecurityFlag {
+    /// A file running as its owner.
+    Setuid,
+    /// A file running as its group.
+    Setgid,
+    /// Writable by anyone. Directories with the sticky bit (e.g. `/tmp`) are
+    /// fine, since only owners can remove or rename their files there.
+    WorldWritable,
+    /// A file granted capabilities when run.
+    Capabilities,
+}
+
+/// The security-relevant metadata of `info`.
+pub fn security_flags(info: &FileInfo) -> Vec<SecurityFlag> {
+    let mut flags = Vec::new();
+    match info.file_type {
+        FileType::File => {
+            if info.mode & 0o4000 != 0 {
+                flags.push(SecurityFlag::Setuid);
+            }
+            if info.mode & 0o2000 != 0 {
+                flags.push(SecurityFlag::Setgid);
+            }
+            if info.mode & 0o002 != 0 {
+                flags.push(SecurityFlag::WorldWritable);
+            }
+            if info.xattrs.iter().any(|(k, _)| k == CAPABILITY_XATTR) {
+                flags.push(SecurityFlag::Capabilities);
+            }
+        }
+        FileType::Directory => {
+            if info.mode & 0o002 != 0 && info.mode & 0o1000 == 0 {
+                flags.push(SecurityFlag::WorldWritable);
+            }
+        }
+        // symlink permissions are meaningless
+        FileType::Symlink => {}
+    }
+    flags
+}
+
+/// A file with security-relevant metadata.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FlaggedFile {
+    pub path: Utf8PathBuf,
+    /// Permission bits, in octal.
+    pub mode: String,
+    pub flags: Vec<SecurityFlag>,
+}
+
+/// The flagged files of each component, sorted by path. Components without
+/// any are left out.
+pub type FlaggedFiles = HashMap<String, Vec<FlaggedFile>>;
+
+/// Find the files of `components` with security-relevant metadata.
+pub fn flag_components(components: &HashMap<String, Component>) -> FlaggedFiles {
+    components
+        .iter()
+        .filter_map(|(name, component)| {
+            let files: Vec<FlaggedFile> = component
+                .files
+                .iter()
+                .filter_map(|(path, info)| {
+                    let flags = security_flags(info);
+                    (!flags.is_empty()).then(|| FlaggedFile {
+                        path: path.clone(),
+                        mode: format!("{:04o}", info.mode & 0o7777),
+                        flags,
+                    })
+                })
+                .collect();
+            (!files.is_empty()).then(|| (name.clone(), files))
+        })
+        .collect()
+}
+
+/// Files with security-relevant metadata, by layer and component, for
+/// security review of an image.
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct SecurityReport {
+    /// Number of files with each flag.
+    pub totals: BTreeMap<SecurityFlag, usize>,
+    /// Layers with flagged files, in image order.
+    pub layers: Vec<LayerReport>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LayerReport {
+    /// Identifier of the layer, as in the plan.
+    pub id: String,
+    pub components: Vec<ComponentReport>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ComponentReport {
+    pub name: String,
+    pub files: Vec<FlaggedFile>,
+}
+
+impl SecurityReport {
+    /// Group `flagged` by the layers of `plan`.
+    pub fn new(mut flagged: FlaggedFiles, plan: &Plan) -> Self {
+        let mut report = SecurityReport::default();
+        for layer in &plan.layers {
+            // plan components are sorted
+            let components: Vec<ComponentReport> = layer
+                .components
+                .iter()
+                .filter_map(|name| {
+                    let files = flagged.remove(name)?;
+                    Some(ComponentReport {
+                        name: name.clone(),
+                        files,
+                    })
+                })
+                .collect();
+            if components.is_empty() {
+                continue;
+            }
+            for file in components.iter().flat_map(|c| &c.files) {
+                for flag in &file.flags {
+                    *report.totals.entry(*flag).or_default() += 1;
+                }
+            }
+            report.layers.push(LayerReport {
+                id: layer.id.clone(),
+                components,
+            });
+        }
+        report
+    }
+
+    /// Log a summary of the report, warning about each world-writable file
+    /// since those are rarely intended.
+    pub fn log(&self) {
+        for layer in &self.layers {
+            for component in &layer.components {
+                for file in &component.files {
+                    if file.flags.contains(&SecurityFlag::WorldWritable) {
+                        tracing::warn!(
+                            path = %file.path,
+                            component = %component.name,
+                            mode = %file.mode,
+                            "world-writable file"
+                        );
+                    } else {
+                        tracing::debug!(path = %file.path, component = %component.name, mode = %file.mode, flags = ?file.flags, "security-relevant file");
+                    }
+                }
+            }
+        }
+        let count = |flag| self.totals.get(&flag).copied().unwrap_or(0);
+        tracing::info!(
+            setuid = count(SecurityFlag::Setuid),
+            setgid = count(SecurityFlag::Setgid),
+            world_writable = count(SecurityFlag::WorldWritable),
+            capabilities = count(SecurityFlag::Capabilities),
+            "security-relevant files"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::FileMap;
+    use crate::plan::{ContentClass, PlanLayer};
+
+    fn info(file_type: FileType, mode: u32) -> FileInfo {
+        FileInfo {
+            mode,
+            ..FileInfo::dummy(file_type)
+        }
+    }
+
+    #[test]
+    fn test_security_flags() {
+        use SecurityFlag::*;
+        assert!(security_flags(&info(FileType::File, 0o100755)).is_empty());
+        assert_eq!(
+            security_flags(&info(FileType::File, 0o104755)),
+            vec![Setuid]
+        );
+        assert_eq!(
+            security_flags(&info(FileType::File, 0o106777)),
+            vec![Setuid, Setgid, WorldWritable]
+        );
+        // sticky world-writable directories are fine, setgid ones too
+        assert!(security_flags(&info(FileType::Directory, 0o41777)).is_empty());
+        assert!(security_flags(&info(FileType::Directory, 0o42755)).is_empty());
+        assert_eq!(
+            security_flags(&info(FileType::Directory, 0o40777)),
+            vec![WorldWritable]
+        );
+        assert!(security_flags(&info(FileType::Symlink, 0o120777)).is_empty());
+
+        let mut ping = info(FileType::File, 0o100755);
+        ping.xattrs
+            .push((CAPABILITY_XATTR.to_string(), b"\x01\x00\x00\x02".to_vec()));
+        assert_eq!(security_flags(&ping), vec![Capabilities]);
+    }
+
+    #[test]
+    fn test_security_report() {
+        let component = |files: &[(&str, u32)]| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files: files
+                .iter()
+                .map(|(p, mode)| (Utf8PathBuf::from(*p), info(FileType::File, *mode)))
+                .collect::<FileMap>(),
+        };
+        let components = HashMap::from([
+            (
+                "rpm/shadow-utils".to_string(),
+                component(&[("/usr/bin/passwd", 0o104755), ("/usr/bin/chage", 0o100755)]),
+            ),
+            (
+                "rpm/sudo".to_string(),
+                component(&[("/usr/bin/sudo", 0o104111)]),
+            ),
+            (
+                "rpm/bash".to_string(),
+                component(&[("/usr/bin/bash", 0o100755)]),
+            ),
+        ]);
+        let layer = |components: &[&str]| PlanLayer {
+            id: components[0].to_string(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+            size: 0,
+            stability: 0.0,
+            content_class: ContentClass::Hot,
+            estimated_compressed_size: None,
+            digest: None,
+        };
+        let plan = Plan {
+            layers: vec![
+                layer(&["rpm/bash"]),
+                layer(&["rpm/shadow-utils", "rpm/sudo"]),
+            ],
+            ..Default::default()
+        };
+
+        let report = SecurityReport::new(flag_components(&components), &plan);
+        assert_eq!(report.totals, BTreeMap::from([(SecurityFlag::Setuid, 2)]));
+        // the layer without flagged files is left out
+        assert_eq!(report.layers.len(), 1);
+        assert_eq!(report.layers[0].id, "rpm/shadow-utils");
+        let files: Vec<(&str, &str, &str)> = report.layers[0]
+            .components
+            .iter()
+            .flat_map(|c| {
+                c.files
+                    .iter()
+                    .map(|f| (c.name.as_str(), f.path.as_str(), f.mode.as_str()))
+            })
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("rpm/shadow-utils", "/usr/bin/passwd", "4755"),
+                ("rpm/sudo", "/usr/bin/sudo", "4111"),
+            ]
+        );
+    }
+}
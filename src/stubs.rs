@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::components::{Component, FileInfo, FileMap, FileType};
+
+/// Directories which filesystems are mounted over at runtime or on install,
+/// and so are only empty stubs in images.
+const MOUNT_POINTS: &[&str] = &["/proc", "/sys", "/dev", "/run", "/boot/efi"];
+
+/// Xattrs marking a directory as opaque in an overlayfs upper directory; the
+/// `user.` one is used with the `userxattr` mount option (e.g. rootless).
+const OPAQUE_XATTRS: &[&str] = &["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// Whether `key` is one of the xattrs marking a directory opaque.
+pub fn is_opaque_xattr(key: &str) -> bool {
+    OPAQUE_XATTRS.contains(&key)
+}
+
+/// What to do with mount-point stubs or opaque directories. Different
+/// consumers want different things, e.g. container runtimes create mount
+/// points as needed, while `bootc install` expects them in the image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialDirPolicy {
+    /// Keep them as plain directories
+    #[default]
+    Keep,
+    /// Leave them out of the image, along with anything under them
+    Drop,
+    /// Keep them, with an opaque whiteout marker so that whatever lower layers
+    /// have in them is hidden
+    Opaque,
+}
+
+/// Apply `mount_points` to the empty mount-point stubs of `files`, and
+/// `opaque_dirs` to the directories marked opaque by overlayfs. The overlayfs
+/// xattrs are always removed, since they're meaningless in a layer.
+pub fn apply_special_dir_policies(
+    files: &mut FileMap,
+    mount_points: SpecialDirPolicy,
+    opaque_dirs: SpecialDirPolicy,
+) {
+    let mut opaque = Vec::new();
+    for (path, info) in files.iter_mut() {
+        let mut marked = false;
+        info.xattrs.retain(|(key, value)| {
+            let is_marker = is_opaque_xattr(key);
+            marked |= is_marker && value.as_slice() == b"y";
+            !is_marker
+        });
+        if marked && info.file_type == FileType::Directory {
+            opaque.push(path.clone());
+        }
+    }
+    for path in &opaque {
+        apply_policy(files, path, opaque_dirs);
+    }
+
+    let stubs: Vec<&Utf8Path> = MOUNT_POINTS
+        .iter()
+        .map(Utf8Path::new)
+        .filter(|path| {
+            files
+                .get(*path)
+                .is_some_and(|info| info.file_type == FileType::Directory)
+                && descendants(files, path).next().is_none()
+        })
+        .collect();
+    for path in stubs {
+        apply_policy(files, path, mount_points);
+    }
+
+    if !opaque.is_empty() {
+        tracing::debug!(dirs = opaque.len(), policy = ?opaque_dirs, "handled opaque directories");
+    }
+}
+
+fn apply_policy(files: &mut FileMap, path: &Utf8Path, policy: SpecialDirPolicy) {
+    match policy {
+        SpecialDirPolicy::Keep => {}
+        SpecialDirPolicy::Drop => {
+            let dropped: Vec<Utf8PathBuf> = descendants(files, path).cloned().collect();
+            for descendant in dropped {
+                files.remove(&descendant);
+            }
+            files.remove(path);
+            tracing::debug!(path = %path, "dropped directory");
+        }
+        SpecialDirPolicy::Opaque => {
+            if let Some(info) = files.get_mut(path) {
+                info.opaque = true;
+            }
+        }
+    }
+}
+
+/// The paths under `path` in `files`.
+fn descendants<'a>(
+    files: &'a FileMap,
+    path: &'a Utf8Path,
+) -> impl Iterator<Item = &'a Utf8PathBuf> {
+    files
+        .range::<Utf8Path, _>((Bound::Excluded(path), Bound::Unbounded))
+        .map(|(p, _)| p)
+        .take_while(move |p| p.starts_with(path))
+}
+
+/// Move the opaque markers of directories to the lowest layer containing them
+/// or anything under them. A marker hides what lower layers have in the
+/// directory, so anywhere higher would hide part of its own content.
+/// `components` are in layer order, bottom first.
+pub fn place_opaque_markers(components: &mut [(String, Component)]) {
+    let mut opaque: HashMap<Utf8PathBuf, FileInfo> = HashMap::new();
+    for (_, component) in components.iter_mut() {
+        for (path, info) in component.files.iter_mut().filter(|(_, i)| i.opaque) {
+            info.opaque = false;
+            opaque.entry(path.clone()).or_insert_with(|| info.clone());
+        }
+    }
+
+    for (path, info) in opaque {
+        let lowest = components.iter_mut().find(|(_, component)| {
+            component.files.contains_key(&path)
+                || descendants(&component.files, &path).next().is_some()
+        });
+        if let Some((name, component)) = lowest {
+            tracing::trace!(path = %path, layer = %name, "placing opaque marker");
+            component.files.entry(path).or_insert(info).opaque = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(paths: &[&str]) -> FileMap {
+        paths
+            .iter()
+            .map(|p| {
+                let file_type = if p.ends_with('/') {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                };
+                (
+                    Utf8PathBuf::from(p.trim_end_matches('/')),
+                    FileInfo::dummy(file_type),
+                )
+            })
+            .collect()
+    }
+
+    fn paths(files: &FileMap) -> Vec<&str> {
+        files.keys().map(|p| p.as_str()).collect()
+    }
+
+    #[test]
+    fn test_mount_points() {
+        let input = files(&[
+            "/proc/",
+            "/run/",
+            "/run/lock/",
+            "/usr/",
+            "/boot/",
+            "/boot/efi/",
+        ]);
+
+        let mut kept = input.clone();
+        apply_special_dir_policies(&mut kept, SpecialDirPolicy::Keep, SpecialDirPolicy::Keep);
+        assert_eq!(paths(&kept), paths(&input));
+
+        // /run isn't empty, so isn't a stub
+        let mut dropped = input.clone();
+        apply_special_dir_policies(&mut dropped, SpecialDirPolicy::Drop, SpecialDirPolicy::Keep);
+        assert_eq!(paths(&dropped), vec!["/boot", "/run", "/run/lock", "/usr"]);
+
+        let mut marked = input.clone();
+        apply_special_dir_policies(
+            &mut marked,
+            SpecialDirPolicy::Opaque,
+            SpecialDirPolicy::Keep,
+        );
+        let opaque: Vec<_> = marked
+            .iter()
+            .filter(|(_, i)| i.opaque)
+            .map(|(p, _)| p.as_str())
+            .collect();
+        assert_eq!(opaque, vec!["/boot/efi", "/proc"]);
+    }
+
+    #[test]
+    fn test_opaque_dirs() {
+        let mut input = files(&["/a/", "/a/f", "/b/", "/b/f", "/c/"]);
+        let opaque_xattr = |key: &str, value: &[u8]| vec![(key.to_string(), value.to_vec())];
+        input.get_mut(Utf8Path::new("/a")).unwrap().xattrs =
+            opaque_xattr("trusted.overlay.opaque", b"y");
+        input.get_mut(Utf8Path::new("/b")).unwrap().xattrs =
+            opaque_xattr("user.overlay.opaque", b"y");
+        // not actually opaque
+        input.get_mut(Utf8Path::new("/c")).unwrap().xattrs =
+            opaque_xattr("user.overlay.opaque", b"n");
+
+        let mut dropped = input.clone();
+        apply_special_dir_policies(&mut dropped, SpecialDirPolicy::Keep, SpecialDirPolicy::Drop);
+        assert_eq!(paths(&dropped), vec!["/c"]);
+        assert!(dropped.values().all(|i| i.xattrs.is_empty()));
+
+        let mut marked = input.clone();
+        apply_special_dir_policies(
+            &mut marked,
+            SpecialDirPolicy::Keep,
+            SpecialDirPolicy::Opaque,
+        );
+        assert!(marked[Utf8Path::new("/a")].opaque);
+        assert!(marked[Utf8Path::new("/b")].opaque);
+        assert!(!marked[Utf8Path::new("/c")].opaque);
+        assert!(marked.values().all(|i| i.xattrs.is_empty()));
+    }
+
+    #[test]
+    fn test_place_opaque_markers() {
+        let component = |files| Component {
+            mtime_clamp: 0,
+            stability: 0.0,
+            isolated: false,
+            files,
+        };
+        let mut dirs = files(&["/a/", "/b/"]);
+        for info in dirs.values_mut() {
+            info.opaque = true;
+        }
+        let mut components = vec![
+            ("low".to_string(), component(files(&["/a/low"]))),
+            ("mid".to_string(), component(dirs)),
+            (
+                "high".to_string(),
+                component(files(&["/a/high", "/b/high"])),
+            ),
+        ];
+
+        place_opaque_markers(&mut components);
+        let opaque = |i: usize| -> Vec<&str> {
+            components[i]
+                .1
+                .files
+                .iter()
+                .filter(|(_, info)| info.opaque)
+                .map(|(p, _)| p.as_str())
+                .collect()
+        };
+        // /a has content in the lowest layer, so that's where its marker goes
+        assert_eq!(opaque(0), vec!["/a"]);
+        assert_eq!(opaque(1), vec!["/b"]);
+        assert!(opaque(2).is_empty());
+    }
+}
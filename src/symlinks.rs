@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use clap::ValueEnum;
+
+use crate::components::{Component, FileInfo, FileType};
+
+/// What to do with symlinks that are absolute or whose target escapes the
+/// rootfs through `..`. Both resolve differently depending on where the image
+/// is deployed (e.g. when inspected from the host rather than in a container).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Keep them as is
+    #[default]
+    Ignore,
+    /// Keep them as is, but warn about each one
+    Warn,
+    /// Rewrite them to equivalent targets relative to the link
+    Rewrite,
+    /// Fail the build
+    Fail,
+}
+
+/// Apply `policy` to all absolute or escaping symlinks in `components`.
+///
+/// Targets set by an earlier pass (e.g. path rewrites) take precedence over
+/// the target on disk.
+pub fn apply_symlink_policy(
+    rootfs: &Dir,
+    policy: SymlinkPolicy,
+    components: &mut HashMap<String, Component>,
+) -> Result<()> {
+    let rewrite = match policy {
+        SymlinkPolicy::Ignore => return Ok(()),
+        SymlinkPolicy::Warn | SymlinkPolicy::Fail => false,
+        SymlinkPolicy::Rewrite => true,
+    };
+
+    let mut offending = 0usize;
+    for component in components.values_mut() {
+        for (path, info) in component.files.iter_mut() {
+            if info.file_type != FileType::Symlink {
+                continue;
+            }
+            let target = link_target(rootfs, path, info)
+                .with_context(|| format!("reading symlink {path}"))?;
+            let Some(new_target) = relative_target(path, &target) else {
+                continue;
+            };
+            offending += 1;
+            anyhow::ensure!(
+                policy != SymlinkPolicy::Fail,
+                "symlink {path} -> {target} is absolute or escapes the rootfs"
+            );
+            if rewrite {
+                tracing::debug!(path = %path, target = %target, new_target = %new_target, "symlink made relative");
+                info.link_target = Some(new_target);
+            } else {
+                tracing::warn!(path = %path, target = %target, "symlink is absolute or escapes the rootfs");
+            }
+        }
+    }
+
+    if offending > 0 {
+        tracing::info!(symlinks = offending, policy = ?policy, "handled absolute or escaping symlinks");
+    }
+    Ok(())
+}
+
+fn link_target(rootfs: &Dir, path: &Utf8Path, info: &FileInfo) -> Result<Utf8PathBuf> {
+    if let Some(target) = &info.link_target {
+        return Ok(target.clone());
+    }
+    // relocated symlinks are still at their original path on disk
+    let path = info.source.as_deref().unwrap_or(path);
+    let target = rootfs.read_link_contents(path.strip_prefix("/").unwrap_or(path))?;
+    Utf8PathBuf::try_from(target).context("non-UTF-8 symlink target")
+}
+
+/// If the target of the symlink at `path` is absolute or escapes the root,
+/// return the equivalent relative target, as it would resolve with the rootfs
+/// as `/`. Returns `None` if the target is fine as is.
+fn relative_target(path: &Utf8Path, target: &Utf8Path) -> Option<Utf8PathBuf> {
+    let parent = path.parent().unwrap_or(Utf8Path::new("/"));
+
+    // resolve lexically, clamping `..` at the root like the kernel does
+    let mut resolved: Vec<&str> = Vec::new();
+    let mut escapes = target.is_absolute();
+    let start = if target.is_absolute() {
+        Utf8Path::new("/")
+    } else {
+        parent
+    };
+    for component in start.components().chain(target.components()) {
+        match component {
+            Utf8Component::Normal(n) => resolved.push(n),
+            Utf8Component::ParentDir => escapes |= resolved.pop().is_none(),
+            Utf8Component::RootDir | Utf8Component::CurDir | Utf8Component::Prefix(_) => {}
+        }
+    }
+    if !escapes {
+        return None;
+    }
+
+    let base: Vec<&str> = parent.components().filter_map(normal).collect();
+    let common = base
+        .iter()
+        .zip(&resolved)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = Utf8PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &resolved[common..] {
+        relative.push(component);
+    }
+    if relative.as_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+fn normal(component: Utf8Component<'_>) -> Option<&str> {
+    match component {
+        Utf8Component::Normal(n) => Some(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+
+    #[test]
+    fn test_relative_target() {
+        let rel = |path: &str, target: &str| {
+            relative_target(Utf8Path::new(path), Utf8Path::new(target)).map(|p| p.to_string())
+        };
+        assert_eq!(rel("/usr/bin/foo", "../lib/foo"), None);
+        assert_eq!(rel("/usr/bin/foo", "foo.real"), None);
+        assert_eq!(
+            rel("/usr/bin/foo", "/usr/lib/foo").as_deref(),
+            Some("../lib/foo")
+        );
+        assert_eq!(rel("/usr/bin/foo", "/usr/bin").as_deref(), Some("."));
+        assert_eq!(rel("/usr/bin/foo", "/").as_deref(), Some("../.."));
+        assert_eq!(
+            rel("/escape", "../../../etc/passwd").as_deref(),
+            Some("etc/passwd")
+        );
+        assert_eq!(rel("/usr/foo", "../../usr/lib/x").as_deref(), Some("lib/x"));
+        assert_eq!(
+            rel("/etc/alternatives/java", "/usr/lib/jvm/bin/java").as_deref(),
+            Some("../../usr/lib/jvm/bin/java")
+        );
+    }
+
+    #[test]
+    fn test_apply_symlink_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs
+            .symlink_contents("/usr/lib/foo", "usr/bin/foo")
+            .unwrap();
+        rootfs.symlink("bar.real", "usr/bin/bar").unwrap();
+        rootfs
+            .symlink_contents("../../../etc/passwd", "escape")
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let components = HashMap::from([(
+            "test".to_string(),
+            Component {
+                mtime_clamp: 0,
+                stability: 0.0,
+                isolated: false,
+                files,
+            },
+        )]);
+
+        let mut c = components.clone();
+        apply_symlink_policy(&rootfs, SymlinkPolicy::Fail, &mut c).unwrap_err();
+
+        let mut c = components.clone();
+        apply_symlink_policy(&rootfs, SymlinkPolicy::Warn, &mut c).unwrap();
+        assert!(c["test"].files.values().all(|f| f.link_target.is_none()));
+
+        let mut c = components;
+        apply_symlink_policy(&rootfs, SymlinkPolicy::Rewrite, &mut c).unwrap();
+        let target = |path: &str| {
+            c["test"].files[Utf8Path::new(path)]
+                .link_target
+                .as_ref()
+                .map(|t| t.to_string())
+        };
+        assert_eq!(target("/usr/bin/foo").as_deref(), Some("../lib/foo"));
+        assert_eq!(target("/usr/bin/bar"), None);
+        assert_eq!(target("/escape").as_deref(), Some("etc/passwd"));
+    }
+
+    #[test]
+    fn test_apply_symlink_policy_rewritten() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/vendor/bin").unwrap();
+        rootfs.create_dir_all("usr/lib").unwrap();
+        rootfs
+            .symlink_contents("/usr/bin/python3", "opt/vendor/bin/python")
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let mut components = HashMap::from([(
+            "vendor".to_string(),
+            Component {
+                mtime_clamp: 0,
+                stability: 0.0,
+                isolated: false,
+                files,
+            },
+        )]);
+        let rules =
+            crate::rewrite::parse_rewrite_rules(&["/opt/vendor=/usr/lib/vendor".into()]).unwrap();
+        crate::rewrite::apply_rewrites(&rootfs, &rules, &mut components).unwrap();
+
+        // the relocated symlink is read from its original path
+        apply_symlink_policy(&rootfs, SymlinkPolicy::Warn, &mut components).unwrap();
+        apply_symlink_policy(&rootfs, SymlinkPolicy::Rewrite, &mut components).unwrap();
+        let link = &components["vendor"].files[Utf8Path::new("/usr/lib/vendor/bin/python")];
+        assert_eq!(
+            link.link_target.as_deref(),
+            Some(Utf8Path::new("../../../bin/python3"))
+        );
+    }
+}
@@ -1,13 +1,18 @@
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use ocidir::BlobWriter;
 use ocidir::oci_spec::image as oci_image;
+use serde::{Deserialize, Serialize};
 
+use crate::cancel::CancellationToken;
 use crate::components::{FileInfo, FileMap, FileType};
+use crate::owners::OwnerNames;
 
 /// Compression options for OCI archives.
 pub enum ArchiveCompression {
@@ -17,10 +22,105 @@ pub enum ArchiveCompression {
     Gzip(flate2::Compression),
 }
 
+/// Metadata normalization applied to entries as they are written.
+///
+/// Entries never carry atime, ctime or birth time records regardless of these
+/// settings; only the mtime is written.
+#[derive(Debug, Clone, Default)]
+pub struct Normalization {
+    /// Force directory permission bits to 0755. The setuid, setgid and sticky
+    /// bits are kept.
+    pub dir_perms: bool,
+    /// Drop `user.*` xattrs.
+    pub drop_user_xattrs: bool,
+    /// Leave the metadata of entries carrying an EVM signature untouched, since
+    /// normalizing it would invalidate the signature.
+    pub preserve_ima: bool,
+    /// Also record user and group names next to the numeric ids, which are
+    /// always written.
+    pub owner_names: Option<Arc<OwnerNames>>,
+}
+
+/// Xattr holding the EVM signature or HMAC of a file's metadata.
+pub const EVM_XATTR: &str = "security.evm";
+
+/// Whether `xattrs` include an EVM signature.
+pub fn has_evm(xattrs: &[(String, Vec<u8>)]) -> bool {
+    xattrs.iter().any(|(k, _)| k == EVM_XATTR)
+}
+
+/// ID of the gzip extra subfield (RFC 1952) holding the [`LayerMetadata`].
+pub const LAYER_METADATA_SUBFIELD: [u8; 2] = *b"CK";
+
+/// Metadata about a layer embedded at the end of its gzip blob, so that update
+/// tooling can introspect the blob without decompressing it or fetching the
+/// manifest.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct LayerMetadata {
+    /// The components in the layer.
+    pub components: Vec<String>,
+    /// Size of the layer tar, i.e. before compression.
+    pub uncompressed_size: u64,
+}
+
+/// Encode `metadata` as an empty gzip member with the FEXTRA flag set, and
+/// the metadata as JSON in a [`LAYER_METADATA_SUBFIELD`] subfield.
+pub fn layer_metadata_member(metadata: &LayerMetadata) -> std::io::Result<Vec<u8>> {
+    let json = serde_json::to_vec(metadata)?;
+    // the whole extra field must fit in 16 bits, with the subfield header
+    let len = u16::try_from(json.len())
+        .ok()
+        .filter(|len| *len <= u16::MAX - 4)
+        .ok_or_else(|| std::io::Error::other("layer metadata too large"))?;
+    let mut extra = Vec::with_capacity(json.len() + 4);
+    extra.extend_from_slice(&LAYER_METADATA_SUBFIELD);
+    extra.extend_from_slice(&len.to_le_bytes());
+    extra.extend_from_slice(&json);
+    flate2::GzBuilder::new()
+        .extra(extra)
+        .write(Vec::new(), flate2::Compression::none())
+        .finish()
+}
+
+/// Gzip encoder which ends the blob with the [`LayerMetadata`] of the layer,
+/// as an empty gzip member (see [`layer_metadata_member`]). Decompressors
+/// concatenate members, so the uncompressed layer (and its diff ID) is the
+/// same as without it.
+pub struct GzipWithMetadata<'a> {
+    encoder: flate2::write::GzEncoder<BlobWriter<'a>>,
+    components: Vec<String>,
+    /// Bytes written so far, before compression.
+    written: u64,
+}
+
+impl Write for GzipWithMetadata<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.encoder.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl<'a> ocidir::WriteComplete<BlobWriter<'a>> for GzipWithMetadata<'a> {
+    fn complete(self) -> std::io::Result<BlobWriter<'a>> {
+        let mut bw = self.encoder.finish()?;
+        bw.write_all(&layer_metadata_member(&LayerMetadata {
+            components: self.components,
+            uncompressed_size: self.written,
+        })?)?;
+        Ok(bw)
+    }
+}
+
 /// Layer writer that can be either compressed or uncompressed.
 pub enum LayerWriter<'a> {
     Uncompressed(ocidir::LayerWriter<'a, BlobWriter<'a>>),
     Gzip(ocidir::LayerWriter<'a, flate2::write::GzEncoder<BlobWriter<'a>>>),
+    GzipWithMetadata(ocidir::LayerWriter<'a, GzipWithMetadata<'a>>),
 }
 
 impl<'a> Write for LayerWriter<'a> {
@@ -28,6 +128,7 @@ impl<'a> Write for LayerWriter<'a> {
         match self {
             LayerWriter::Uncompressed(w) => w.write(buf),
             LayerWriter::Gzip(w) => w.write(buf),
+            LayerWriter::GzipWithMetadata(w) => w.write(buf),
         }
     }
 
@@ -35,6 +136,7 @@ impl<'a> Write for LayerWriter<'a> {
         match self {
             LayerWriter::Uncompressed(w) => w.flush(),
             LayerWriter::Gzip(w) => w.flush(),
+            LayerWriter::GzipWithMetadata(w) => w.flush(),
         }
     }
 }
@@ -45,23 +147,60 @@ impl<'a> LayerWriter<'a> {
         match self {
             LayerWriter::Uncompressed(w) => w.complete().context("completing uncompressed layer"),
             LayerWriter::Gzip(w) => w.complete().context("completing gzip layer"),
+            LayerWriter::GzipWithMetadata(w) => w.complete().context("completing gzip layer"),
         }
     }
 }
 
-/// Create a tar builder for a new layer in an OCI directory.
+/// Files up to this size count as small when sizing write batches.
+const SMALL_FILE_SIZE: u64 = 16 * 1024;
+/// Bounds of the size of write batches.
+const MIN_BATCH_SIZE: usize = 64 * 1024;
+const MAX_BATCH_SIZE: usize = 1024 * 1024;
+
+/// The size of the batches in which to write the tar stream of a layer
+/// holding `files`.
+///
+/// Every entry is at least a 512-byte header plus its padded content, each
+/// written separately. Without batching, all of these small writes go through
+/// digesting and compression one by one, which for layers of many tiny files
+/// (icons, locales, bytecode) costs more than the data itself. The batch grows
+/// with the number of small files so that such layers go through in large
+/// chunks, without allocating much for layers of a few big files.
+pub fn batch_size(files: &FileMap) -> usize {
+    let small_files = files
+        .values()
+        .filter(|f| f.file_type != FileType::File || f.size <= SMALL_FILE_SIZE)
+        .count();
+    small_files
+        .saturating_mul(4096)
+        .clamp(MIN_BATCH_SIZE, MAX_BATCH_SIZE)
+}
+
+/// Tar builder for a layer, writing in batches (see [`batch_size`]). Batching
+/// only coalesces writes, so the stream (and its digests) is unchanged.
+pub type LayerBuilder<'a> = tar::Builder<BufWriter<LayerWriter<'a>>>;
+
+/// Create a tar builder for a new layer in an OCI directory, writing in
+/// batches of `batch_size` bytes.
+///
+/// If `metadata_components` is set, gzip layers end with their
+/// [`LayerMetadata`] listing these components. Uncompressed layers can't
+/// embed it without changing their content, and so don't.
 pub fn create_layer(
     oci_dir: &ocidir::OciDir,
     compression: crate::ocibuilder::Compression,
-) -> Result<tar::Builder<LayerWriter<'_>>> {
-    let layer_writer = match compression {
-        crate::ocibuilder::Compression::None => {
+    metadata_components: Option<Vec<String>>,
+    batch_size: usize,
+) -> Result<LayerBuilder<'_>> {
+    let layer_writer = match (compression, metadata_components) {
+        (crate::ocibuilder::Compression::None, _) => {
             let layer_writer = oci_dir
                 .create_uncompressed_layer()
                 .context("creating uncompressed layer writer")?;
             LayerWriter::Uncompressed(layer_writer)
         }
-        crate::ocibuilder::Compression::Gzip(level) => {
+        (crate::ocibuilder::Compression::Gzip(level), None) => {
             let level = flate2::Compression::new(level);
             let layer_writer = oci_dir
                 .create_custom_layer(
@@ -71,8 +210,38 @@ pub fn create_layer(
                 .context("creating gzip layer writer")?;
             LayerWriter::Gzip(layer_writer)
         }
+        (crate::ocibuilder::Compression::Gzip(level), Some(components)) => {
+            let level = flate2::Compression::new(level);
+            let layer_writer = oci_dir
+                .create_custom_layer(
+                    |bw| {
+                        Ok(GzipWithMetadata {
+                            encoder: flate2::write::GzEncoder::new(bw, level),
+                            components,
+                            written: 0,
+                        })
+                    },
+                    oci_image::MediaType::ImageLayerGzip,
+                )
+                .context("creating gzip layer writer")?;
+            LayerWriter::GzipWithMetadata(layer_writer)
+        }
     };
-    Ok(tar::Builder::new(layer_writer))
+    Ok(tar::Builder::new(BufWriter::with_capacity(
+        batch_size,
+        layer_writer,
+    )))
+}
+
+/// Finish the tar stream of a layer, flushing the last batch, and return the
+/// layer writer to complete.
+pub fn finish_layer(mut tar_builder: LayerBuilder<'_>) -> Result<LayerWriter<'_>> {
+    tar_builder.finish().context("finishing layer tar")?;
+    let writer = tar_builder.into_inner().context("getting layer writer")?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("flushing layer writer")
 }
 
 /// Build a tar layer from a list of files and return the completed layer.
@@ -85,6 +254,8 @@ pub fn write_files_to_tar<W: Write>(
     rootfs: &Dir,
     files: &FileMap,
     mtime_clamp: u64,
+    normalization: Normalization,
+    cancellation: &CancellationToken,
 ) -> Result<()> {
     // Stack of written directory paths - leverages sorted iteration order
     let mut dir_stack: Vec<&Utf8Path> = Vec::new();
@@ -92,6 +263,9 @@ pub fn write_files_to_tar<W: Write>(
     let mut inode_to_path: HashMap<u64, Utf8PathBuf> = HashMap::new();
 
     for (path, file_info) in files {
+        cancellation.check()?;
+        check_entry_path(path)?;
+
         // Pop directories that are not ancestors of current path
         while let Some(top) = dir_stack.last() {
             if path.starts_with(top) && path.as_path() != *top {
@@ -115,19 +289,24 @@ pub fn write_files_to_tar<W: Write>(
             // XXX: somehow reuse existing FileInfos for that dir, which may
             // live in other components
             let ancestor_info = if let Some(info) = files.get(&ancestor_path) {
+                // it was written already, as something else
+                anyhow::ensure!(
+                    info.file_type == FileType::Directory,
+                    "{path} is under {ancestor}, which isn't a directory"
+                );
                 info.clone()
             } else {
-                let rel_path = ancestor.strip_prefix("/").unwrap_or(ancestor);
-                let metadata = rootfs
-                    .symlink_metadata(rel_path)
-                    .with_context(|| format!("getting metadata for {}", ancestor))?;
-                let xattrs = crate::scan::read_xattrs(rootfs, rel_path.as_str())
-                    .with_context(|| format!("reading xattrs for {}", ancestor))?;
-                FileInfo::from_metadata(&metadata, FileType::Directory, xattrs)
+                parent_dir_info(rootfs, ancestor)?
             };
             tracing::trace!(path = %ancestor, "writing parent directory");
-            write_dir_entry(tar_builder, ancestor, mtime_clamp, &ancestor_info)
-                .with_context(|| format!("writing parent directory {}", ancestor))?;
+            write_dir_entry(
+                tar_builder,
+                ancestor,
+                mtime_clamp,
+                &ancestor_info,
+                &normalization,
+            )
+            .with_context(|| format!("writing parent directory {}", ancestor))?;
             dir_stack.push(ancestor);
         }
 
@@ -135,7 +314,14 @@ pub fn write_files_to_tar<W: Write>(
         if file_info.file_type != FileType::Directory && file_info.nlink > 1 {
             if let Some(first_path) = inode_to_path.get(&file_info.ino) {
                 tracing::trace!(path = %path, target = %first_path, "writing hardlink");
-                write_hardlink_entry(tar_builder, path, first_path, mtime_clamp, file_info)?;
+                write_hardlink_entry(
+                    tar_builder,
+                    path,
+                    first_path,
+                    mtime_clamp,
+                    file_info,
+                    &normalization,
+                )?;
                 continue;
             }
             // First occurrence of this hardlinked file/symlink
@@ -145,51 +331,293 @@ pub fn write_files_to_tar<W: Write>(
         match file_info.file_type {
             FileType::Directory => {
                 tracing::trace!(path = %path, "writing directory");
-                write_dir_entry(tar_builder, path, mtime_clamp, file_info)?;
+                write_dir_entry(tar_builder, path, mtime_clamp, file_info, &normalization)?;
+                if file_info.opaque {
+                    write_opaque_marker(tar_builder, path, mtime_clamp)?;
+                }
                 // We might enter this directory in the next iteration; push it
                 dir_stack.push(path.as_path());
             }
             FileType::File => {
                 tracing::trace!(path = %path, "writing file");
-                write_file_entry(tar_builder, rootfs, path, mtime_clamp, file_info)?;
+                write_file_entry(
+                    tar_builder,
+                    rootfs,
+                    path,
+                    mtime_clamp,
+                    file_info,
+                    &normalization,
+                )?;
             }
             FileType::Symlink => {
                 tracing::trace!(path = %path, "writing symlink");
-                write_symlink_entry(tar_builder, rootfs, path, mtime_clamp, file_info)?;
+                write_symlink_entry(
+                    tar_builder,
+                    rootfs,
+                    path,
+                    mtime_clamp,
+                    file_info,
+                    &normalization,
+                )?;
             }
         }
     }
     Ok(())
 }
 
-/// Write the OCI directory as a tar archive to a writer.
+/// The metadata of the directory at `path` in `rootfs`, for writing it as
+/// the parent of content of the layer when it's not part of it.
+pub fn parent_dir_info(rootfs: &Dir, path: &Utf8Path) -> Result<FileInfo> {
+    let rel_path = strip_root_prefix(path);
+    let metadata = rootfs
+        .symlink_metadata(rel_path)
+        .with_context(|| format!("getting metadata for {}", path))?;
+    let mut xattrs = crate::scan::read_xattrs(rootfs, rel_path.as_str())
+        .with_context(|| format!("reading xattrs for {}", path))?;
+    // only the layer owning the directory may mark it opaque
+    xattrs.retain(|(key, _)| !crate::stubs::is_opaque_xattr(key));
+    Ok(FileInfo::from_metadata(
+        &metadata,
+        FileType::Directory,
+        xattrs,
+    ))
+}
+
+/// Writer for OCI archives which allows streaming blobs into the archive
+/// before the rest of the OCI directory is ready.
 // XXX: Consider upstreaming this to ocidir-rs.
-pub fn write_oci_archive<W: Write>(
-    oci_dir: &Dir,
-    writer: W,
-    compression: ArchiveCompression,
-) -> Result<()> {
-    match compression {
-        ArchiveCompression::None => write_oci_archive_to(oci_dir, writer),
-        ArchiveCompression::Gzip(level) => {
-            let gzip_writer = flate2::write::GzEncoder::new(writer, level);
-            write_oci_archive_to(oci_dir, gzip_writer)
+pub struct OciArchiveWriter<W: Write> {
+    tar: tar::Builder<ArchiveWriter<W>>,
+    /// Paths already in the archive, relative to the OCI directory.
+    written: HashSet<PathBuf>,
+}
+
+/// Underlying archive writer that can be either compressed or uncompressed.
+enum ArchiveWriter<W: Write> {
+    Uncompressed(W),
+    Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveWriter::Uncompressed(w) => w.write(buf),
+            ArchiveWriter::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Uncompressed(w) => w.flush(),
+            ArchiveWriter::Gzip(w) => w.flush(),
         }
     }
 }
 
+impl<W: Write> OciArchiveWriter<W> {
+    /// Create a new archive writer.
+    pub fn new(writer: W, compression: ArchiveCompression) -> Self {
+        let writer = match compression {
+            ArchiveCompression::None => ArchiveWriter::Uncompressed(writer),
+            ArchiveCompression::Gzip(level) => {
+                ArchiveWriter::Gzip(flate2::write::GzEncoder::new(writer, level))
+            }
+        };
+        Self {
+            tar: tar::Builder::new(writer),
+            written: HashSet::new(),
+        }
+    }
+
+    /// Append the blob with the given digest (e.g. `sha256:abcd...`) from the
+    /// OCI directory to the archive ahead of everything else.
+    pub fn append_blob(&mut self, oci_dir: &Dir, digest: &str) -> Result<()> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .with_context(|| format!("invalid digest {digest}"))?;
+        let algorithm_dir = Path::new("blobs").join(algorithm);
+        for dir in [PathBuf::from("blobs"), algorithm_dir.clone()] {
+            if !self.written.contains(&dir) {
+                self.append_dir(&dir)?;
+                self.written.insert(dir);
+            }
+        }
+
+        let path = algorithm_dir.join(hex);
+        // identical layers share a blob
+        if self.written.contains(&path) {
+            return Ok(());
+        }
+        let file = oci_dir
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        self.append_file(&path, file)?;
+        self.written.insert(path);
+        Ok(())
+    }
+
+    /// Append everything from the OCI directory not already in the archive,
+    /// finish the archive and return the underlying writer.
+    pub fn finish(mut self, oci_dir: &Dir) -> Result<W> {
+        use cap_std_ext::cap_std::fs::FileType as CapFileType;
+        use cap_std_ext::dirext::CapStdExtDirExt;
+        use std::ops::ControlFlow;
+
+        let config = cap_std_ext::dirext::WalkConfiguration::default().sort_by_file_name();
+
+        oci_dir
+            .walk(&config, |component| {
+                let path = component.path;
+                if self.written.contains(path) {
+                    return Ok(ControlFlow::Continue(()));
+                }
+                // if the d_type is missing (e.g. fuse), fallback to `stat`
+                // XXX: cap-std docs mention is_unknown() exists, but it doesn't; add it
+                // XXX: though probably should extend WalkConfiguration to have a ensure_file_type bool
+                let file_type = if component.file_type == CapFileType::unknown() {
+                    component
+                        .dir
+                        .symlink_metadata(component.filename)
+                        .with_context(|| format!("getting metadata for {}", path.display()))?
+                        .file_type()
+                } else {
+                    component.file_type
+                };
+                if file_type.is_dir() {
+                    self.append_dir(path)?;
+                } else if file_type.is_file() {
+                    let file = component
+                        .dir
+                        .open(component.filename)
+                        .with_context(|| format!("opening {}", path.display()))?;
+                    self.append_file(path, file)?;
+                } else {
+                    anyhow::bail!(
+                        "unsupported file type for {} ({:?})",
+                        path.display(),
+                        file_type
+                    );
+                }
+                Ok::<_, anyhow::Error>(ControlFlow::Continue(()))
+            })
+            .context("walking OCI directory")?;
+
+        let writer = self.tar.into_inner().context("finishing tar archive")?;
+        match writer {
+            ArchiveWriter::Uncompressed(w) => Ok(w),
+            ArchiveWriter::Gzip(w) => w.finish().context("finishing gzip stream"),
+        }
+    }
+
+    fn append_dir(&mut self, path: &Path) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        // Tar directories need a trailing slash
+        let path_str = format!("{}/", path.display());
+        self.tar
+            .append_data(&mut header, &path_str, std::io::empty())
+            .with_context(|| format!("appending directory {}", path.display()))
+    }
+
+    fn append_file(&mut self, path: &Path, file: cap_std_ext::cap_std::fs::File) -> Result<()> {
+        let size = file
+            .metadata()
+            .with_context(|| format!("getting metadata for {}", path.display()))?
+            .len();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        self.tar
+            .append_data(&mut header, path, file)
+            .with_context(|| format!("appending {}", path.display()))
+    }
+}
+
+/// Check that `path` is safe to write as a layer entry: absolute and
+/// normalized, so that its entry name is relative to the root of the layer,
+/// and without any `..` which could make extractors write outside of it.
+fn check_entry_path(path: &Utf8Path) -> Result<()> {
+    let mut components = path.components();
+    anyhow::ensure!(
+        components.next() == Some(Utf8Component::RootDir),
+        "refusing to write entry for relative path {path:?}"
+    );
+    anyhow::ensure!(
+        components.clone().next().is_some(),
+        "refusing to write entry for the root directory"
+    );
+    anyhow::ensure!(
+        components.all(|c| matches!(c, Utf8Component::Normal(_))),
+        "refusing to write entry for path {path:?} with `..` components"
+    );
+    // components() skips `.`, repeated and trailing separators; compare as
+    // strings since path equality does too
+    anyhow::ensure!(
+        path.components().collect::<Utf8PathBuf>().as_str() == path.as_str(),
+        "refusing to write entry for non-normalized path {path:?}"
+    );
+    Ok(())
+}
+
 /// Strip leading "/" from a path, returning the path unchanged if no prefix.
-fn strip_root_prefix(path: &Utf8Path) -> &Utf8Path {
+pub fn strip_root_prefix(path: &Utf8Path) -> &Utf8Path {
     path.strip_prefix("/").unwrap_or(path)
 }
 
 /// Prepare a tar header with common metadata from FileInfo.
-fn write_header_from_file_info(header: &mut tar::Header, file_info: &FileInfo, mtime_clamp: u64) {
+fn write_header_from_file_info(
+    header: &mut tar::Header,
+    file_info: &FileInfo,
+    mtime_clamp: u64,
+    normalization: &Normalization,
+) -> Result<()> {
     let mtime = std::cmp::min(file_info.mtime, mtime_clamp);
     header.set_mtime(mtime);
     header.set_uid(file_info.uid as u64);
     header.set_gid(file_info.gid as u64);
     header.set_mode(file_info.mode);
+    if let Some(names) = &normalization.owner_names {
+        // names that don't fit the header were skipped when loaded
+        if let Some(user) = names.user(file_info.uid) {
+            header
+                .set_username(user)
+                .with_context(|| format!("setting user name {user}"))?;
+        }
+        if let Some(group) = names.group(file_info.gid) {
+            header
+                .set_groupname(group)
+                .with_context(|| format!("setting group name {group}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// The mode `file_info` is written with, after normalization. Hardlinks are
+/// written with the permission bits only.
+pub fn normalized_mode(file_info: &FileInfo, normalization: &Normalization) -> u32 {
+    if file_info.file_type == FileType::Directory
+        && normalization.dir_perms
+        && !(normalization.preserve_ima && has_evm(&file_info.xattrs))
+    {
+        // only the permission bits; e.g. sticky /var/tmp stays sticky
+        (file_info.mode & !0o777) | 0o755
+    } else {
+        file_info.mode
+    }
+}
+
+/// Whether the xattr `key` is written, after normalization.
+pub fn keeps_xattr(key: &str, normalization: &Normalization) -> bool {
+    !(normalization.drop_user_xattrs && key.starts_with("user."))
 }
 
 /// Append xattrs as PAX extensions to the tar stream.
@@ -200,15 +628,16 @@ fn append_xattrs<W: Write>(
     tar_builder: &mut tar::Builder<W>,
     xattrs: &[(String, Vec<u8>)],
     path: &str,
+    normalization: &Normalization,
 ) -> Result<()> {
-    if xattrs.is_empty() {
-        return Ok(());
-    }
-
     let pax_extensions: Vec<_> = xattrs
         .iter()
+        .filter(|(k, _)| keeps_xattr(k, normalization))
         .map(|(k, v)| (format!("SCHILY.xattr.{k}"), v.clone()))
         .collect();
+    if pax_extensions.is_empty() {
+        return Ok(());
+    }
 
     tar_builder
         .append_pax_extensions(
@@ -227,14 +656,16 @@ fn write_dir_entry<W: Write>(
     path: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    normalization: &Normalization,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
 
     let mut header = tar::Header::new_gnu();
     header.set_entry_type(tar::EntryType::Directory);
     header.set_size(0);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
-    append_xattrs(tar_builder, &file_info.xattrs, path.as_str())
+    write_header_from_file_info(&mut header, file_info, mtime_clamp, normalization)?;
+    header.set_mode(normalized_mode(file_info, normalization));
+    append_xattrs(tar_builder, &file_info.xattrs, path.as_str(), normalization)
         .with_context(|| format!("appending xattrs for {}", path))?;
 
     let tar_dir_path = if rel_path.as_str().is_empty() {
@@ -249,6 +680,33 @@ fn write_dir_entry<W: Write>(
     Ok(())
 }
 
+/// Name of the whiteout marking a directory opaque, per the OCI image spec.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Write the whiteout marking the directory at `path` opaque. It's written
+/// right after the directory, before its content, as some implementations
+/// apply it as soon as they encounter it.
+fn write_opaque_marker<W: Write>(
+    tar_builder: &mut tar::Builder<W>,
+    path: &Utf8Path,
+    mtime_clamp: u64,
+) -> Result<()> {
+    let rel_path = strip_root_prefix(path).join(OPAQUE_WHITEOUT);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime_clamp);
+    tar_builder
+        .append_data(&mut header, rel_path.as_str(), std::io::empty())
+        .with_context(|| format!("appending opaque whiteout for {}", path))?;
+
+    Ok(())
+}
+
 /// Write a hardlink entry to the tar archive.
 fn write_hardlink_entry<W: Write>(
     tar_builder: &mut tar::Builder<W>,
@@ -256,6 +714,7 @@ fn write_hardlink_entry<W: Write>(
     link_target: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    normalization: &Normalization,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
     let rel_target = strip_root_prefix(link_target);
@@ -263,7 +722,7 @@ fn write_hardlink_entry<W: Write>(
     let mut header = tar::Header::new_gnu();
     header.set_entry_type(tar::EntryType::Link);
     header.set_size(0);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
+    write_header_from_file_info(&mut header, file_info, mtime_clamp, normalization)?;
     // Mask out file type bits; it's harmless but it matches what GNU tar
     // and Python's tarfile do as well. Not doing this does though result in
     // libarchive's strmode not showing the file as 'h' which shows up in diffs
@@ -284,27 +743,68 @@ fn write_file_entry<W: Write>(
     path: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    normalization: &Normalization,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
+    let source = file_info.source.as_deref().unwrap_or(path);
 
     let file = rootfs
-        .open(rel_path)
-        .with_context(|| format!("opening {}", path))?;
+        .open(strip_root_prefix(source))
+        .with_context(|| format!("opening {}", source))?;
 
     let mut header = tar::Header::new_gnu();
     header.set_entry_type(tar::EntryType::Regular);
     header.set_size(file_info.size);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
-    append_xattrs(tar_builder, &file_info.xattrs, path.as_str())
+    write_header_from_file_info(&mut header, file_info, mtime_clamp, normalization)?;
+    append_xattrs(tar_builder, &file_info.xattrs, path.as_str(), normalization)
         .with_context(|| format!("appending xattrs for {}", path))?;
 
+    // the header was written from the size at scan time; make sure the
+    // content matches it, rather than silently writing a corrupt archive if
+    // the file changed since (e.g. when building from a live system)
+    let size = file
+        .metadata()
+        .with_context(|| format!("getting metadata of {}", source))?
+        .len();
+    if size != file_info.size {
+        anyhow::bail!(
+            "{} changed size since it was scanned ({} -> {} bytes)",
+            source,
+            file_info.size,
+            size
+        );
+    }
+    let content = ExactReader {
+        inner: file.take(file_info.size),
+        remaining: file_info.size,
+    };
     tar_builder
-        .append_data(&mut header, rel_path.as_str(), file)
+        .append_data(&mut header, rel_path.as_str(), content)
         .with_context(|| format!("appending file {}", path))?;
 
     Ok(())
 }
 
+/// Reader which fails if its inner reader ends before `remaining` bytes.
+struct ExactReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && self.remaining > 0 && !buf.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("file shrank since scan ({} bytes missing)", self.remaining),
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
 /// Write a symlink entry to the tar archive.
 fn write_symlink_entry<W: Write>(
     tar_builder: &mut tar::Builder<W>,
@@ -312,18 +812,25 @@ fn write_symlink_entry<W: Write>(
     path: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    normalization: &Normalization,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
 
-    let target = rootfs
-        .read_link_contents(rel_path)
-        .with_context(|| format!("reading symlink {}", path))?;
+    let target = match &file_info.link_target {
+        Some(target) => target.as_std_path().to_path_buf(),
+        None => {
+            let source = file_info.source.as_deref().unwrap_or(path);
+            rootfs
+                .read_link_contents(strip_root_prefix(source))
+                .with_context(|| format!("reading symlink {}", source))?
+        }
+    };
 
     let mut header = tar::Header::new_gnu();
     header.set_entry_type(tar::EntryType::Symlink);
     header.set_size(0);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
-    append_xattrs(tar_builder, &file_info.xattrs, path.as_str())
+    write_header_from_file_info(&mut header, file_info, mtime_clamp, normalization)?;
+    append_xattrs(tar_builder, &file_info.xattrs, path.as_str(), normalization)
         .with_context(|| format!("appending xattrs for {}", path))?;
 
     tar_builder
@@ -333,84 +840,6 @@ fn write_symlink_entry<W: Write>(
     Ok(())
 }
 
-fn write_oci_archive_to<W: Write>(oci_dir: &Dir, writer: W) -> Result<()> {
-    use cap_std_ext::cap_std::fs::FileType as CapFileType;
-    use cap_std_ext::dirext::CapStdExtDirExt;
-    use std::ops::ControlFlow;
-
-    // Template headers for directories and files - cloned and modified as needed
-    let dir_header = {
-        let mut header = tar::Header::new_gnu();
-        header.set_entry_type(tar::EntryType::Directory);
-        header.set_size(0);
-        header.set_mode(0o755);
-        header.set_mtime(0);
-        header.set_uid(0);
-        header.set_gid(0);
-        header
-    };
-    let file_header = {
-        let mut header = tar::Header::new_gnu();
-        header.set_entry_type(tar::EntryType::Regular);
-        header.set_mode(0o644);
-        header.set_mtime(0);
-        header.set_uid(0);
-        header.set_gid(0);
-        header
-    };
-
-    let mut tar = tar::Builder::new(writer);
-    let config = cap_std_ext::dirext::WalkConfiguration::default().sort_by_file_name();
-
-    oci_dir
-        .walk(&config, |component| {
-            let path = component.path;
-            // if the d_type is missing (e.g. fuse), fallback to `stat`
-            // XXX: cap-std docs mention is_unknown() exists, but it doesn't; add it
-            // XXX: though probably should extend WalkConfiguration to have a ensure_file_type bool
-            let file_type = if component.file_type == CapFileType::unknown() {
-                component
-                    .dir
-                    .symlink_metadata(component.filename)
-                    .with_context(|| format!("getting metadata for {}", path.display()))?
-                    .file_type()
-            } else {
-                component.file_type
-            };
-            if file_type.is_dir() {
-                let mut header = dir_header.clone();
-                // Tar directories need a trailing slash
-                let path_str = format!("{}/", path.display());
-                tar.append_data(&mut header, &path_str, std::io::empty())
-                    .with_context(|| format!("appending directory {}", path.display()))?;
-            } else if file_type.is_file() {
-                let file = component
-                    .dir
-                    .open(component.filename)
-                    .with_context(|| format!("opening {}", path.display()))?;
-                let size = file
-                    .metadata()
-                    .with_context(|| format!("getting metadata for {}", path.display()))?
-                    .len();
-                let mut header = file_header.clone();
-                header.set_size(size);
-                tar.append_data(&mut header, path, file)
-                    .with_context(|| format!("appending {}", path.display()))?;
-            } else {
-                anyhow::bail!(
-                    "unsupported file type for {} ({:?})",
-                    path.display(),
-                    file_type
-                );
-            }
-            Ok::<_, anyhow::Error>(ControlFlow::Continue(()))
-        })
-        .context("walking OCI directory")?;
-
-    tar.finish().context("finishing tar archive")?;
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -436,7 +865,15 @@ mod tests {
         let mut output = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut output);
-            write_files_to_tar(&mut tar_builder, &rootfs, &files, mtime_clamp).unwrap();
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                mtime_clamp,
+                Normalization::default(),
+                &CancellationToken::new(),
+            )
+            .unwrap();
             tar_builder.finish().unwrap();
         }
         output
@@ -487,6 +924,307 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_write_files_to_tar_normalization() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("dir").unwrap();
+        rootfs.write("dir/file", "content").unwrap();
+        rootfs.setxattr("dir/file", "user.testattr", b"x").unwrap();
+        std::fs::set_permissions(
+            tmp.path().join("dir"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o700),
+        )
+        .unwrap();
+        rootfs.create_dir("sticky").unwrap();
+        std::fs::set_permissions(
+            tmp.path().join("sticky"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o1777),
+        )
+        .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let normalization = Normalization {
+            dir_perms: true,
+            drop_user_xattrs: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                1000,
+                normalization,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let gnu = entry.header().as_gnu().unwrap();
+            // unset, rather than 0
+            assert!(
+                gnu.atime.iter().all(|&b| b == 0),
+                "{path} should have no atime"
+            );
+            assert!(
+                gnu.ctime.iter().all(|&b| b == 0),
+                "{path} should have no ctime"
+            );
+            if path == "dir/" {
+                assert_eq!(entry.header().mode().unwrap() & 0o7777, 0o755);
+            }
+            if path == "sticky/" {
+                assert_eq!(entry.header().mode().unwrap() & 0o7777, 0o1755);
+            }
+            if let Some(pax) = entry.pax_extensions().unwrap() {
+                for ext in pax {
+                    let key = ext.unwrap().key().unwrap().to_string();
+                    assert!(!key.starts_with("SCHILY.xattr.user."), "{path} has {key}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_files_to_tar_opaque() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("a/b").unwrap();
+        rootfs.write("a/b/file", "content").unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        files.get_mut(Utf8Path::new("/a/b")).unwrap().opaque = true;
+
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        write_files_to_tar(
+            &mut tar_builder,
+            &rootfs,
+            &files,
+            0,
+            Normalization::default(),
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let data = tar_builder.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        // the marker comes right after its directory
+        assert_eq!(paths, vec!["a/", "a/b/", "a/b/.wh..wh..opq", "a/b/file"]);
+    }
+
+    #[test]
+    fn test_write_files_to_tar_changed_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        // the file grows between the scan and the write
+        rootfs.write("file", "more content").unwrap();
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        let err = write_files_to_tar(
+            &mut tar_builder,
+            &rootfs,
+            &files,
+            0,
+            Normalization::default(),
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("changed size since it was scanned"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_check_entry_path() {
+        check_entry_path(Utf8Path::new("/usr/bin/ls")).unwrap();
+        for path in [
+            "usr/bin/ls",
+            "/",
+            "/usr/../etc/passwd",
+            "/usr/bin/..",
+            "/usr/./bin",
+            "/usr//bin",
+            "/usr/bin/",
+        ] {
+            assert!(check_entry_path(Utf8Path::new(path)).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_write_files_to_tar_parent_not_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        // e.g. from a bad rewrite
+        let info = files[Utf8Path::new("/file")].clone();
+        files.insert("/file/child".into(), info);
+
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        let err = write_files_to_tar(
+            &mut tar_builder,
+            &rootfs,
+            &files,
+            0,
+            Normalization::default(),
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("/file/child is under /file, which isn't a directory"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_write_files_to_tar_owner_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("etc").unwrap();
+        rootfs
+            .write(
+                "etc/passwd",
+                "root:x:0:0::/root:/bin/bash\nuser:x:1000:1000::/:/bin/sh\n",
+            )
+            .unwrap();
+        rootfs.write("etc/group", "root:x:0:\n").unwrap();
+        rootfs.write("file", "content").unwrap();
+
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        for info in files.values_mut() {
+            (info.uid, info.gid) = (1000, 1000);
+        }
+        let owners = |normalization: Normalization| {
+            let mut output = Vec::new();
+            {
+                let mut tar_builder = tar::Builder::new(&mut output);
+                write_files_to_tar(
+                    &mut tar_builder,
+                    &rootfs,
+                    &files,
+                    1000,
+                    normalization,
+                    &CancellationToken::new(),
+                )
+                .unwrap();
+                tar_builder.finish().unwrap();
+            }
+            let mut archive = tar::Archive::new(output.as_slice());
+            let entry = archive
+                .entries()
+                .unwrap()
+                .map(|e| e.unwrap())
+                .find(|e| e.path().unwrap().to_str() == Some("file"))
+                .unwrap();
+            let header = entry.header();
+            let name = |name: Option<&str>| name.filter(|n| !n.is_empty()).map(str::to_string);
+            (
+                header.uid().unwrap(),
+                header.gid().unwrap(),
+                name(header.username().unwrap()),
+                name(header.groupname().unwrap()),
+            )
+        };
+
+        // numeric ids only by default
+        assert_eq!(owners(Normalization::default()), (1000, 1000, None, None));
+
+        // names come from the rootfs; the ids are still there, and ids without
+        // names stay numeric only
+        let normalization = Normalization {
+            owner_names: Some(Arc::new(OwnerNames::load(&rootfs).unwrap())),
+            ..Default::default()
+        };
+        assert_eq!(
+            owners(normalization),
+            (1000, 1000, Some("user".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn test_write_files_to_tar_preserve_ima() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("signed").unwrap();
+        rootfs.create_dir("unsigned").unwrap();
+        for dir in ["signed", "unsigned"] {
+            std::fs::set_permissions(
+                tmp.path().join(dir),
+                std::os::unix::fs::PermissionsExt::from_mode(0o700),
+            )
+            .unwrap();
+        }
+
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        // security.* xattrs can't be set unprivileged; inject them instead
+        files.get_mut(Utf8Path::new("/signed")).unwrap().xattrs = vec![
+            ("security.evm".to_string(), b"\x05sig".to_vec()),
+            ("security.ima".to_string(), b"\x03sig".to_vec()),
+        ];
+        let normalization = Normalization {
+            dir_perms: true,
+            drop_user_xattrs: true,
+            preserve_ima: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                1000,
+                normalization,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let mut found = 0;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mode = entry.header().mode().unwrap() & 0o7777;
+            match path.as_str() {
+                "signed/" => {
+                    assert_eq!(mode, 0o700);
+                    let keys: Vec<String> = entry
+                        .pax_extensions()
+                        .unwrap()
+                        .unwrap()
+                        .map(|ext| ext.unwrap().key().unwrap().to_string())
+                        .collect();
+                    assert!(keys.contains(&"SCHILY.xattr.security.evm".to_string()));
+                    assert!(keys.contains(&"SCHILY.xattr.security.ima".to_string()));
+                    found += 1;
+                }
+                "unsigned/" => {
+                    assert_eq!(mode, 0o755);
+                    found += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(found, 2);
+    }
+
     #[test]
     fn test_write_files_to_tar_symlink() {
         let output = write_tar_bytes(
@@ -563,11 +1301,12 @@ mod tests {
     }
 
     #[test]
-    fn test_write_oci_archive_uncompressed() {
+    fn test_oci_archive_writer_uncompressed() {
         let (_tmp, oci_dir) = create_minimal_oci_dir();
 
-        let mut output = Vec::new();
-        write_oci_archive(&oci_dir, &mut output, ArchiveCompression::None).unwrap();
+        let output = OciArchiveWriter::new(Vec::new(), ArchiveCompression::None)
+            .finish(&oci_dir)
+            .unwrap();
 
         // Verify it's a valid tar
         let mut archive = tar::Archive::new(output.as_slice());
@@ -576,15 +1315,14 @@ mod tests {
     }
 
     #[test]
-    fn test_write_oci_archive_gzip() {
+    fn test_oci_archive_writer_gzip() {
         let (_tmp, oci_dir) = create_minimal_oci_dir();
 
-        let mut output = Vec::new();
-        write_oci_archive(
-            &oci_dir,
-            &mut output,
+        let output = OciArchiveWriter::new(
+            Vec::new(),
             ArchiveCompression::Gzip(flate2::Compression::fast()),
         )
+        .finish(&oci_dir)
         .unwrap();
 
         // Verify it's gzip compressed (magic bytes)
@@ -598,6 +1336,57 @@ mod tests {
         assert!(!entries.is_empty());
     }
 
+    #[test]
+    fn test_oci_archive_writer_append_blob() {
+        let (_tmp, oci_dir) = create_minimal_oci_dir();
+        oci_dir.create_dir_all("blobs/sha256").unwrap();
+        oci_dir.write("blobs/sha256/aaaa", "first").unwrap();
+        oci_dir.write("blobs/sha256/bbbb", "second").unwrap();
+
+        let mut writer = OciArchiveWriter::new(Vec::new(), ArchiveCompression::None);
+        writer.append_blob(&oci_dir, "sha256:bbbb").unwrap();
+        // appending the same blob again is a no-op
+        writer.append_blob(&oci_dir, "sha256:bbbb").unwrap();
+        let output = writer.finish(&oci_dir).unwrap();
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "blobs/",
+                "blobs/sha256/",
+                "blobs/sha256/bbbb",
+                "blobs/sha256/aaaa",
+                "oci-layout",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let files = |n: usize, size: u64| -> FileMap {
+            (0..n)
+                .map(|i| {
+                    let info = FileInfo {
+                        size,
+                        ..FileInfo::dummy(FileType::File)
+                    };
+                    (Utf8PathBuf::from(format!("/f{i}")), info)
+                })
+                .collect()
+        };
+        assert_eq!(batch_size(&FileMap::new()), MIN_BATCH_SIZE);
+        // big files don't need bigger batches
+        assert_eq!(batch_size(&files(1000, 1024 * 1024)), MIN_BATCH_SIZE);
+        assert_eq!(batch_size(&files(100, 100)), 100 * 4096);
+        assert_eq!(batch_size(&files(100_000, 100)), MAX_BATCH_SIZE);
+    }
+
     #[test]
     fn test_write_files_to_tar_hardlinks() {
         let tmp = tempfile::tempdir().unwrap();
@@ -632,7 +1421,15 @@ mod tests {
         let mut output = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut output);
-            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000).unwrap();
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                1000,
+                Normalization::default(),
+                &CancellationToken::new(),
+            )
+            .unwrap();
             tar_builder.finish().unwrap();
         }
 
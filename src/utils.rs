@@ -42,6 +42,27 @@ pub fn format_size(bytes: u64) -> String {
     }
 }
 
+/// Parse a byte count with an optional binary unit suffix, e.g. `512`, `500M`
+/// or `2GiB`. Units are powers of 1024, as in [`format_size`].
+pub fn parse_size(s: &str) -> Result<u64> {
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits_end);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid size: {s}"))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => anyhow::bail!("invalid size unit in {s}; expected one of K, M, G or T"),
+    };
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size overflows: {s}"))
+}
+
 /// Returns the peak resident set size (VmHWM) in bytes.
 pub fn get_peak_rss() -> Result<u64> {
     let status =
@@ -141,6 +162,11 @@ pub fn calculate_stability(changelog_times: &[u64], buildtime: u64, now: u64) ->
     (-lambda * STABILITY_PERIOD_DAYS).exp()
 }
 
+/// Convert a stability interval in days to a probability using the Poisson model.
+pub fn interval_to_stability(interval_days: u64) -> f64 {
+    (-STABILITY_PERIOD_DAYS / interval_days as f64).exp()
+}
+
 /// Canonicalize the parent directory of a path by resolving symlinks.
 ///
 /// Given `/lib/modules/5.x/vmlinuz`, if `/lib` -> `usr/lib`, returns
@@ -250,7 +276,7 @@ fn canonicalize_dir_path(
 }
 
 /// Normalize a path by resolving `.` and `..` components.
-fn normalize_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+pub fn normalize_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
     let mut result = Utf8PathBuf::new();
     for component in path.components() {
         use camino::Utf8Component;
@@ -269,6 +295,53 @@ fn normalize_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
     Ok(result)
 }
 
+/// Compute the SHA-256 digest of a file in the rootfs.
+pub fn compute_sha256(rootfs: &Dir, path: &Utf8Path) -> Result<String> {
+    let rel_path = path.strip_prefix("/").unwrap_or(path.as_ref());
+    let mut file = rootfs
+        .open(rel_path.as_str())
+        .with_context(|| format!("opening {path} for hashing"))?;
+
+    let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())
+        .context("creating SHA-256 hasher")?;
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("hashing {path}"))?;
+
+    let digest = hasher.finish().context("finalizing SHA-256 hash")?;
+    Ok(hex::encode(digest))
+}
+
+/// Match `name` against a shell-style glob `pattern`, where `*` matches any
+/// sequence of characters (including `/`) and `?` matches a single character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` seen and the name position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` swallow one more character
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 /// Reads a file into a [`String`] after checking its length does not exceed `max_size`
 pub fn read_file_contents_to_string_checked(
     file: &mut cap_std::fs::File,
@@ -312,6 +385,23 @@ mod tests {
         assert!(parse_rfc3339_epoch("1969-12-31T23:59:59Z").is_err());
     }
 
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("rpm/glibc", "rpm/glibc"));
+        assert!(!glob_match("rpm/glibc", "rpm/glibc-common"));
+        assert!(glob_match("rpm/glibc*", "rpm/glibc-common"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match(
+            "bigfiles/*",
+            "bigfiles/usr/share/game/assets.pak"
+        ));
+        assert!(glob_match("*/*.pak", "bigfiles/usr/share/game/assets.pak"));
+        assert!(!glob_match("*.pak", "bigfiles/assets.pak.bak"));
+        assert!(glob_match("rpm/kernel-?", "rpm/kernel-5"));
+        assert!(!glob_match("rpm/kernel-?", "rpm/kernel-"));
+        assert!(glob_match("*a*b*c", "xxaxxbxxbxc"));
+    }
+
     #[test]
     fn test_format_size() {
         assert_eq!(format_size(0), "0 B");
@@ -324,6 +414,19 @@ mod tests {
         assert_eq!(format_size(1610612736), "1.5 GiB");
     }
 
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("1K").unwrap(), 1024);
+        assert_eq!(parse_size("500M").unwrap(), 500 << 20);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert_eq!(parse_size("3 gb").unwrap(), 3 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("99999999T").is_err());
+    }
+
     #[test]
     fn test_get_goarch() {
         assert_eq!(get_goarch(Some("x86_64")), "amd64");